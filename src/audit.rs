@@ -0,0 +1,75 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cfg::Cfg;
+
+/// What was done to an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Delete,
+    Rename,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub action: AuditAction,
+    /// Who asked for the change, e.g. the client address.
+    pub actor: String,
+    pub image: String,
+    /// Hex SHA-256 of the image's content.
+    pub digest: String,
+    /// The new name of a renamed image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<String>,
+    /// Generated manifests that referred to the image when it was changed.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub manifests: Vec<String>,
+    /// Why the change was not made. Absent when it was.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+/// Append-only record of destructive changes to images, one JSON object per line.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn from_cfg(cfg: &Cfg) -> Self {
+        Self::new(&cfg.audit_log)
+    }
+
+    /// Appends `entry`. Blocking.
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        let _guard = self.lock.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+}
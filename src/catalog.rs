@@ -0,0 +1,495 @@
+use std::{io, path::PathBuf, sync::Arc};
+
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+use utoipa::ToSchema;
+
+use crate::{
+    cfg::Cfg,
+    compression::Compressed,
+    convert::Conversion,
+    delta::Delta,
+    staging::{Reservation, Staged, StorageUsage},
+    store::{self, materialize, ByteStream, ImageStore},
+};
+
+/// A human-readable image name, the content it refers to and what is known about it.
+///
+/// Stored as a JSON sidecar in the `refs` namespace under the image name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ImageRef {
+    pub name: String,
+    /// Lowercase hexadecimal SHA-256 of the image.
+    pub digest: String,
+    pub size: u64,
+    #[serde(alias = "created")]
+    pub uploaded: DateTime<Utc>,
+    #[serde(flatten)]
+    pub details: ImageDetails,
+}
+
+/// Metadata supplied by whoever uploads an image.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ImageDetails {
+    pub uploader: Option<String>,
+    /// Firmware version the image claims to be.
+    pub version: Option<String>,
+    /// Hardware the image is built for.
+    pub hardware_class: Option<String>,
+    pub notes: Option<String>,
+    /// Set on raw binaries converted from another image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub converted_from: Option<Conversion>,
+    /// Set on deltas, naming the images they were computed between.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<Delta>,
+    /// Set on compressed payloads, describing the content before compression.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compressed>,
+}
+
+impl ImageDetails {
+    /// Sets the field named `name` from a form value, ignoring unknown names and blank values.
+    ///
+    /// Returns whether `name` is a metadata field.
+    pub fn set(&mut self, name: &str, value: String) -> bool {
+        let field = match name {
+            "uploader" => &mut self.uploader,
+            "version" => &mut self.version,
+            "hardware_class" => &mut self.hardware_class,
+            "notes" => &mut self.notes,
+            _ => return false,
+        };
+        *field = Some(value).filter(|value| !value.trim().is_empty());
+        true
+    }
+}
+
+/// What [`Catalog::add`] did with an upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Added {
+    /// The name is new.
+    Created(ImageRef),
+    /// The name already referred to identical content.
+    Unchanged(ImageRef),
+    /// The name already refers to different content; nothing was stored.
+    Conflict(ImageRef),
+}
+
+/// What [`Catalog::rename`] did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Renamed {
+    /// The image now has the new name.
+    Renamed(ImageRef),
+    /// The new name already refers to this image; nothing was changed.
+    Conflict(ImageRef),
+}
+
+/// Held while names are changed; see [`Catalog::lock`].
+pub struct Changes<'a> {
+    _guard: MutexGuard<'a, ()>,
+}
+
+/// Content-addressed image storage.
+///
+/// Each distinct image is stored once as a blob named after its SHA-256
+/// digest. Image names are small references to those blobs, so uploading the
+/// same content under several names stores it only once.
+#[derive(Clone)]
+pub struct Catalog {
+    blobs: Arc<dyn ImageStore>,
+    refs: Arc<dyn ImageStore>,
+    /// Held while names are changed, so a blob found unused is not given a
+    /// new name before it is deleted.
+    changes: Arc<Mutex<()>>,
+    /// Kept up to date by `add` and `delete` once blobs have been counted.
+    storage: StorageUsage,
+}
+
+impl Catalog {
+    pub fn new(blobs: Arc<dyn ImageStore>, refs: Arc<dyn ImageStore>) -> Self {
+        Catalog {
+            blobs,
+            refs,
+            changes: Arc::new(Mutex::new(())),
+            storage: StorageUsage::default(),
+        }
+    }
+
+    pub fn from_cfg(cfg: &Cfg) -> io::Result<Self> {
+        Ok(Catalog::new(
+            store::open(cfg, "blobs")?,
+            store::open(cfg, "refs")?,
+        ))
+    }
+
+    /// Lists every image, sorted by name.
+    pub async fn list(&self) -> io::Result<Vec<ImageRef>> {
+        let mut images = Vec::new();
+        for object in self.refs.list().await? {
+            if let Some(image) = self.get(&object.name).await? {
+                images.push(image);
+            }
+        }
+        Ok(images)
+    }
+
+    /// Bytes taken up by stored images. Content stored under several names counts once.
+    ///
+    /// The blobs are listed the first time only; changes are counted as they are made.
+    pub async fn usage(&self) -> io::Result<u64> {
+        if let Some(stored) = self.storage.stored() {
+            return Ok(stored);
+        }
+        let _changes = self.changes.lock().await;
+        if let Some(stored) = self.storage.stored() {
+            return Ok(stored);
+        }
+        let stored = self.blobs.list().await?.iter().map(|blob| blob.size).sum();
+        self.storage.set_stored(stored);
+        Ok(stored)
+    }
+
+    /// Starts setting storage aside for an upload, under the limits configured in `cfg`.
+    pub async fn reserve(&self, cfg: &Cfg) -> io::Result<Reservation> {
+        self.usage().await?;
+        Ok(self.storage.reserve(cfg))
+    }
+
+    pub async fn get(&self, name: &str) -> io::Result<Option<ImageRef>> {
+        if store::check_name(name).is_err() {
+            return Ok(None);
+        }
+        match self.refs.get(name).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stops names from changing until the returned guard is dropped.
+    ///
+    /// Deleting and renaming need it, so whatever the caller checked before
+    /// either still holds when the change is made.
+    pub async fn lock(&self) -> Changes<'_> {
+        Changes {
+            _guard: self.changes.lock().await,
+        }
+    }
+
+    /// Stores a received file under `name`, reusing an existing blob with the same content.
+    pub async fn add(
+        &self,
+        name: &str,
+        staged: Staged,
+        details: ImageDetails,
+    ) -> io::Result<Added> {
+        store::check_name(name)?;
+        let _changes = self.changes.lock().await;
+        if let Some(existing) = self.get(name).await? {
+            return Ok(if existing.digest == staged.digest {
+                Added::Unchanged(existing)
+            } else {
+                Added::Conflict(existing)
+            });
+        }
+        let blob = blob_name(&staged.digest);
+        if self.blobs.stat(&blob).await?.is_none() {
+            self.blobs.put(&blob, &staged.path).await?;
+            self.storage.stored_more(staged.size);
+        }
+        let image = ImageRef {
+            name: name.to_string(),
+            digest: staged.digest,
+            size: staged.size,
+            uploaded: Utc::now(),
+            details,
+        };
+        self.put_ref(&image).await?;
+        Ok(Added::Created(image))
+    }
+
+    /// Reads `len` bytes of the content of `image` starting at `start`.
+    pub async fn read_range(&self, image: &ImageRef, start: u64, len: u64) -> io::Result<Bytes> {
+        self.blobs
+            .get_range(&blob_name(&image.digest), start, len)
+            .await
+    }
+
+    /// Streams `len` bytes of the content of `image` starting at `start`.
+    pub async fn read_stream(
+        &self,
+        image: &ImageRef,
+        start: u64,
+        len: u64,
+    ) -> io::Result<ByteStream> {
+        self.blobs
+            .get_stream(&blob_name(&image.digest), start, len)
+            .await
+    }
+
+    /// Removes the image called `name`, and its blob once no other name refers to it.
+    pub async fn delete(&self, _changes: &Changes<'_>, name: &str) -> io::Result<Option<ImageRef>> {
+        let Some(image) = self.get(name).await? else {
+            return Ok(None);
+        };
+        self.refs.delete(name).await?;
+        let still_used = self
+            .list()
+            .await?
+            .iter()
+            .any(|other| other.digest == image.digest);
+        if !still_used {
+            self.blobs.delete(&blob_name(&image.digest)).await?;
+            self.storage.stored_less(image.size);
+        }
+        Ok(Some(image))
+    }
+
+    /// Gives `image` the name `to`, unless `to` already names an image.
+    pub async fn rename(
+        &self,
+        _changes: &Changes<'_>,
+        image: &ImageRef,
+        to: &str,
+    ) -> io::Result<Renamed> {
+        if let Some(existing) = self.get(to).await? {
+            return Ok(Renamed::Conflict(existing));
+        }
+        let renamed = ImageRef {
+            name: to.to_string(),
+            ..image.clone()
+        };
+        self.put_ref(&renamed).await?;
+        self.refs.delete(&image.name).await?;
+        Ok(Renamed::Renamed(renamed))
+    }
+
+    async fn put_ref(&self, image: &ImageRef) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(image).map_err(io::Error::other)?;
+        self.refs.put_bytes(&image.name, Bytes::from(json)).await?;
+        Ok(())
+    }
+
+    /// Makes the blob with `digest` available as a local file.
+    ///
+    /// See [`materialize`] for when the returned guard matters.
+    pub async fn materialize(
+        &self,
+        digest: &str,
+        staging_dir: &str,
+    ) -> io::Result<(PathBuf, Option<tempfile::TempPath>)> {
+        materialize(self.blobs.as_ref(), &blob_name(digest), staging_dir).await
+    }
+}
+
+/// Returns the name the blob with `digest` is stored under.
+pub fn blob_name(digest: &str) -> String {
+    format!("sha256-{}", digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{staging::StagedUpload, store::local::LocalStore};
+
+    fn stage(dir: &std::path::Path, data: &[u8]) -> Staged {
+        let mut upload = StagedUpload::create(dir.join("staging")).unwrap();
+        upload.write(data).unwrap();
+        upload.finish().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn identical_content_is_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = Arc::new(LocalStore::new(dir.path().join("blobs")));
+        let catalog = Catalog::new(
+            blobs.clone(),
+            Arc::new(LocalStore::new(dir.path().join("refs"))),
+        );
+
+        let details = ImageDetails {
+            version: Some("1.2.0".to_string()),
+            ..ImageDetails::default()
+        };
+        let a = match catalog
+            .add("a.sgi", stage(dir.path(), b"firmware"), details.clone())
+            .await
+            .unwrap()
+        {
+            Added::Created(image) => image,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            "c3bf47ea1f4a4a605470313cacb3a44f4a461f68c6faeab07e737610cb5ac835",
+            a.digest
+        );
+        assert_eq!(8, a.size);
+        assert_eq!(Some(a.clone()), catalog.get("a.sgi").await.unwrap());
+        assert_eq!(details, a.details);
+        assert!(matches!(
+            catalog
+                .add(
+                    "a.sgi",
+                    stage(dir.path(), b"firmware"),
+                    ImageDetails::default()
+                )
+                .await
+                .unwrap(),
+            Added::Unchanged(_)
+        ));
+        assert!(matches!(
+            catalog
+                .add(
+                    "a.sgi",
+                    stage(dir.path(), b"other"),
+                    ImageDetails::default()
+                )
+                .await
+                .unwrap(),
+            Added::Conflict(_)
+        ));
+        assert!(matches!(
+            catalog
+                .add(
+                    "b.sgi",
+                    stage(dir.path(), b"firmware"),
+                    ImageDetails::default()
+                )
+                .await
+                .unwrap(),
+            Added::Created(_)
+        ));
+
+        assert_eq!(1, blobs.list().await.unwrap().len());
+        let names: Vec<String> = catalog
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|image| image.name)
+            .collect();
+        assert_eq!(vec!["a.sgi", "b.sgi"], names);
+        assert!(std::fs::read_dir(dir.path().join("staging"))
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn blobs_are_not_deleted_while_a_new_name_refers_to_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(
+            Arc::new(LocalStore::new(dir.path().join("blobs"))),
+            Arc::new(LocalStore::new(dir.path().join("refs"))),
+        );
+        for i in 0..20 {
+            let name = format!("{}.sgi", i);
+            catalog
+                .add(
+                    "old.sgi",
+                    stage(dir.path(), b"firmware"),
+                    ImageDetails::default(),
+                )
+                .await
+                .unwrap();
+            let (deleted, added) = futures_util::join!(
+                async {
+                    let changes = catalog.lock().await;
+                    catalog.delete(&changes, "old.sgi").await
+                },
+                catalog.add(
+                    &name,
+                    stage(dir.path(), b"firmware"),
+                    ImageDetails::default()
+                )
+            );
+            deleted.unwrap();
+            let Added::Created(image) = added.unwrap() else {
+                panic!("{} was not created", name);
+            };
+            assert_eq!(
+                Bytes::from_static(b"firm"),
+                catalog.read_range(&image, 0, 4).await.unwrap()
+            );
+            catalog.delete(&catalog.lock().await, &name).await.unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn usage_is_counted_once_and_kept_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = Arc::new(LocalStore::new(dir.path().join("blobs")));
+        let refs = Arc::new(LocalStore::new(dir.path().join("refs")));
+        Catalog::new(blobs.clone(), refs.clone())
+            .add(
+                "a.sgi",
+                stage(dir.path(), b"firmware"),
+                ImageDetails::default(),
+            )
+            .await
+            .unwrap();
+
+        let catalog = Catalog::new(blobs, refs);
+        assert_eq!(8, catalog.usage().await.unwrap());
+        for name in ["b.sgi", "c.sgi"] {
+            catalog
+                .add(name, stage(dir.path(), b"other"), ImageDetails::default())
+                .await
+                .unwrap();
+        }
+        assert_eq!(13, catalog.usage().await.unwrap());
+        catalog
+            .delete(&catalog.lock().await, "b.sgi")
+            .await
+            .unwrap();
+        assert_eq!(13, catalog.usage().await.unwrap());
+        catalog
+            .delete(&catalog.lock().await, "a.sgi")
+            .await
+            .unwrap();
+        assert_eq!(5, catalog.usage().await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn renaming_never_replaces_an_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(
+            Arc::new(LocalStore::new(dir.path().join("blobs"))),
+            Arc::new(LocalStore::new(dir.path().join("refs"))),
+        );
+        let Added::Created(a) = catalog
+            .add("a.sgi", stage(dir.path(), b"one"), ImageDetails::default())
+            .await
+            .unwrap()
+        else {
+            panic!("a.sgi was not created");
+        };
+        let Added::Created(b) = catalog
+            .add("b.sgi", stage(dir.path(), b"two"), ImageDetails::default())
+            .await
+            .unwrap()
+        else {
+            panic!("b.sgi was not created");
+        };
+
+        let changes = catalog.lock().await;
+        assert_eq!(
+            Renamed::Conflict(b.clone()),
+            catalog.rename(&changes, &a, "b.sgi").await.unwrap()
+        );
+        assert_eq!(Some(a.clone()), catalog.get("a.sgi").await.unwrap());
+        assert_eq!(Some(b), catalog.get("b.sgi").await.unwrap());
+
+        let Renamed::Renamed(c) = catalog.rename(&changes, &a, "c.sgi").await.unwrap() else {
+            panic!("a.sgi was not renamed");
+        };
+        assert_eq!(a.digest, c.digest);
+        assert_eq!(None, catalog.get("a.sgi").await.unwrap());
+    }
+}
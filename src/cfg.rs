@@ -1,11 +1,15 @@
 use std::path::PathBuf;
 
 use clap::builder::PossibleValue;
-use config::Config;
+use config::{Config, ConfigError};
 use directories::UserDirs;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 
+use crate::{client_auth::ClientScope, image_type::ImageType};
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum CfgOutputFormat {
     JSON,
@@ -37,12 +41,106 @@ impl clap::ValueEnum for CfgOutputFormat {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Cfg {
     pub verbose: String,
     pub address: String,
     pub port: u16,
+    /// PEM certificate chain served for HTTPS, leaf first. Empty serves plain HTTP.
+    pub tls_certificate: String,
+    /// PEM private key matching the leaf of `tls_certificate`.
+    pub tls_key: String,
+    /// PEM CA certificates that client certificates, when presented, must chain to.
+    /// Empty does not ask clients for certificates.
+    pub tls_client_ca: String,
+    /// With HTTPS on, also listen for plain HTTP on this port and redirect it to HTTPS. 0 disables.
+    pub http_redirect_port: u16,
+    /// What names the principal of a client certificate: `san` takes its first DNS,
+    /// email or URI subject alternative name and falls back to the subject CN,
+    /// `subject` always takes the subject CN.
+    pub client_principal: String,
+    /// Route scopes that only clients with a certificate from `tls_client_ca`,
+    /// and optionally only certain principals, may use.
+    pub client_certificate_scopes: Vec<ClientScope>,
+    /// JSON file of user accounts, managed with the `user` subcommand. Empty turns
+    /// accounts off, letting anyone who can reach the server do anything.
+    pub users_file: String,
+    /// Key session cookies are signed with. Empty picks a random key on every start.
+    pub session_secret: String,
+    /// Seconds a sign-in lasts.
+    pub session_ttl: usize,
+    /// JSON file of hashed API tokens, managed with the `token` subcommand and
+    /// the `/tokens` page. Only used when `users_file` is set.
+    pub tokens_file: String,
     pub template_glob: String,
+    /// Where uploaded firmware images are stored: `local` or `s3`.
+    pub storage_backend: String,
+    /// Directory uploaded firmware images are stored in by the `local` backend.
+    pub uploads_dir: String,
+    /// Directory uploads are written to before they are handed to the storage backend.
+    pub staging_dir: String,
+    /// Largest accepted upload, in bytes.
+    pub max_upload_size: usize,
+    /// Total bytes all stored images may take up. 0 means unlimited.
+    pub max_storage_size: usize,
+    /// Seconds an unfinished resumable upload may sit idle before it is discarded.
+    pub upload_session_ttl: usize,
+    /// Unfinished resumable uploads one user, or one client address when
+    /// accounts are off, may have open at once. 0 means unlimited.
+    pub max_upload_sessions: usize,
+    /// Accepted image types. A name must have one of their extensions, and the
+    /// content must start with one of that type's `magic` signatures, if any.
+    pub image_types: Vec<ImageType>,
+    /// Byte written into gaps between segments when converting to a raw binary.
+    pub convert_fill_byte: u8,
+    /// Address the first byte of a converted binary belongs at, e.g. `0x08000000`.
+    /// Empty uses the lowest address in the image.
+    pub convert_base_address: String,
+    /// Compression applied to uploaded payloads unless the upload chooses:
+    /// `none`, `gzip`, `zstd` or `lzma`.
+    pub payload_compression: String,
+    /// Largest base or target image, in bytes, a delta is computed for.
+    /// Computing one takes about 25 times the base's size in memory. 0 means unlimited.
+    pub max_delta_image_size: usize,
+    /// Deltas, conversions and compressions run at once; more wait their turn.
+    /// Each holds its images in memory.
+    pub derive_workers: usize,
+    /// Endpoint of an S3-compatible service, e.g. `http://127.0.0.1:9000`. Empty for AWS.
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    /// Prepended to every object key, e.g. `images/`.
+    pub s3_prefix: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    /// Address the bucket as `endpoint/bucket` rather than `bucket.endpoint`, as MinIO expects.
+    pub s3_path_style: bool,
+    /// Directory generated manifests are written to.
+    pub manifests_dir: String,
+    /// How manifests are created: `tool` runs `manifest_tool`, `native` encodes them in-process
+    /// in manifest-tool's format, `fixme` in fixme's own format, which manifest-tool's update
+    /// clients cannot read.
+    pub manifest_encoder: String,
+    /// Program invoked to create manifests.
+    pub manifest_tool: String,
+    /// Vendor UUID written into manifests encoded in-process.
+    pub vendor_id: String,
+    /// Device class UUID written into manifests encoded in-process.
+    pub class_id: String,
+    /// Private key used to sign manifests.
+    pub signing_key: String,
+    /// Certificate matching `signing_key`.
+    pub certificate: String,
+    /// PEM or DER certificate file, or a directory of them, that manifest signers must chain to.
+    pub trust_store: String,
+    /// Number of manifest jobs run concurrently.
+    pub job_workers: usize,
+    /// Number of manifest jobs that may wait for a worker.
+    pub job_queue_size: usize,
+    /// Number of jobs kept for status polling and the job history page.
+    pub job_history: usize,
+    /// File that deletions and renames of images are appended to, one JSON object per line.
+    pub audit_log: String,
 }
 
 impl Default for Cfg {
@@ -51,7 +149,49 @@ impl Default for Cfg {
             verbose: "info".to_string(),
             address: "127.0.0.1".to_string(),
             port: 8080,
+            tls_certificate: String::new(),
+            tls_key: String::new(),
+            tls_client_ca: String::new(),
+            http_redirect_port: 0,
+            client_principal: "san".to_string(),
+            client_certificate_scopes: Vec::new(),
+            users_file: String::new(),
+            session_secret: String::new(),
+            session_ttl: 8 * 60 * 60,
+            tokens_file: "./tokens.json".to_string(),
             template_glob: default_template_glob(),
+            storage_backend: "local".to_string(),
+            uploads_dir: "./uploads".to_string(),
+            staging_dir: "./staging".to_string(),
+            max_upload_size: 256 * 1024 * 1024,
+            max_storage_size: 0,
+            upload_session_ttl: 24 * 60 * 60,
+            max_upload_sessions: 8,
+            image_types: ImageType::defaults(),
+            convert_fill_byte: 0xff,
+            convert_base_address: String::new(),
+            payload_compression: "none".to_string(),
+            max_delta_image_size: 16 * 1024 * 1024,
+            derive_workers: 2,
+            s3_endpoint: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_bucket: String::new(),
+            s3_prefix: String::new(),
+            s3_access_key: String::new(),
+            s3_secret_key: String::new(),
+            s3_path_style: true,
+            manifests_dir: "./manifests".to_string(),
+            manifest_encoder: "tool".to_string(),
+            manifest_tool: "manifest-tool".to_string(),
+            vendor_id: "00000000-0000-0000-0000-000000000000".to_string(),
+            class_id: "00000000-0000-0000-0000-000000000000".to_string(),
+            signing_key: ".update-certificates/default.key.pem".to_string(),
+            certificate: ".update-certificates/default.der".to_string(),
+            trust_store: String::new(),
+            job_workers: 2,
+            job_queue_size: 32,
+            job_history: 100,
+            audit_log: "./audit.log".to_string(),
         }
     }
 }
@@ -74,14 +214,162 @@ impl From<Config> for Cfg {
         if let Ok(o) = value.get_int("port") {
             cfg.port = o as u16;
         }
+        if let Ok(o) = value.get_string("tls_certificate") {
+            cfg.tls_certificate = o;
+        }
+        if let Ok(o) = value.get_string("tls_key") {
+            cfg.tls_key = o;
+        }
+        if let Ok(o) = value.get_string("tls_client_ca") {
+            cfg.tls_client_ca = o;
+        }
+        if let Ok(o) = value.get_int("http_redirect_port") {
+            cfg.http_redirect_port = o as u16;
+        }
+        if let Ok(o) = value.get_string("client_principal") {
+            cfg.client_principal = o;
+        }
+        match value.get::<Vec<ClientScope>>("client_certificate_scopes") {
+            Ok(o) => cfg.client_certificate_scopes = o,
+            Err(ConfigError::NotFound(_)) => {}
+            Err(e) => warn!("Ignoring invalid client_certificate_scopes: {}", e),
+        }
+        if let Ok(o) = value.get_string("users_file") {
+            cfg.users_file = o;
+        }
+        if let Ok(o) = value.get_string("session_secret") {
+            cfg.session_secret = o;
+        }
+        if let Ok(o) = value.get_int("session_ttl") {
+            cfg.session_ttl = o as usize;
+        }
+        if let Ok(o) = value.get_string("tokens_file") {
+            cfg.tokens_file = o;
+        }
         if let Ok(o) = value.get_string("template_glob") {
             cfg.template_glob = o;
         }
+        if let Ok(o) = value.get_string("storage_backend") {
+            cfg.storage_backend = o;
+        }
+        if let Ok(o) = value.get_string("uploads_dir") {
+            cfg.uploads_dir = o;
+        }
+        if let Ok(o) = value.get_string("staging_dir") {
+            cfg.staging_dir = o;
+        }
+        if let Ok(o) = value.get_int("max_upload_size") {
+            cfg.max_upload_size = o as usize;
+        }
+        if let Ok(o) = value.get_int("max_storage_size") {
+            cfg.max_storage_size = o as usize;
+        }
+        if let Ok(o) = value.get_int("upload_session_ttl") {
+            cfg.upload_session_ttl = o as usize;
+        }
+        if let Ok(o) = value.get_int("max_upload_sessions") {
+            cfg.max_upload_sessions = o as usize;
+        }
+        match value.get::<Vec<ImageType>>("image_types") {
+            Ok(o) => cfg.image_types = o,
+            Err(ConfigError::NotFound(_)) => {}
+            Err(e) => warn!("Ignoring invalid image_types: {}", e),
+        }
+        if let Ok(o) = value.get_int("convert_fill_byte") {
+            cfg.convert_fill_byte = o as u8;
+        }
+        if let Ok(o) = value.get_string("convert_base_address") {
+            cfg.convert_base_address = o;
+        }
+        if let Ok(o) = value.get_string("payload_compression") {
+            cfg.payload_compression = o;
+        }
+        if let Ok(o) = value.get_int("max_delta_image_size") {
+            cfg.max_delta_image_size = o as usize;
+        }
+        if let Ok(o) = value.get_int("derive_workers") {
+            cfg.derive_workers = o as usize;
+        }
+        if let Ok(o) = value.get_string("s3_endpoint") {
+            cfg.s3_endpoint = o;
+        }
+        if let Ok(o) = value.get_string("s3_region") {
+            cfg.s3_region = o;
+        }
+        if let Ok(o) = value.get_string("s3_bucket") {
+            cfg.s3_bucket = o;
+        }
+        if let Ok(o) = value.get_string("s3_prefix") {
+            cfg.s3_prefix = o;
+        }
+        if let Ok(o) = value.get_string("s3_access_key") {
+            cfg.s3_access_key = o;
+        }
+        if let Ok(o) = value.get_string("s3_secret_key") {
+            cfg.s3_secret_key = o;
+        }
+        if let Ok(o) = value.get_bool("s3_path_style") {
+            cfg.s3_path_style = o;
+        }
+        if let Ok(o) = value.get_string("manifests_dir") {
+            cfg.manifests_dir = o;
+        }
+        if let Ok(o) = value.get_string("manifest_encoder") {
+            cfg.manifest_encoder = o;
+        }
+        if let Ok(o) = value.get_string("manifest_tool") {
+            cfg.manifest_tool = o;
+        }
+        if let Ok(o) = value.get_string("vendor_id") {
+            cfg.vendor_id = o;
+        }
+        if let Ok(o) = value.get_string("class_id") {
+            cfg.class_id = o;
+        }
+        if let Ok(o) = value.get_string("signing_key") {
+            cfg.signing_key = o;
+        }
+        if let Ok(o) = value.get_string("certificate") {
+            cfg.certificate = o;
+        }
+        if let Ok(o) = value.get_string("trust_store") {
+            cfg.trust_store = o;
+        }
+        if let Ok(o) = value.get_int("job_workers") {
+            cfg.job_workers = o as usize;
+        }
+        if let Ok(o) = value.get_int("job_queue_size") {
+            cfg.job_queue_size = o as usize;
+        }
+        if let Ok(o) = value.get_int("job_history") {
+            cfg.job_history = o as usize;
+        }
+        if let Ok(o) = value.get_string("audit_log") {
+            cfg.audit_log = o;
+        }
         // FUTURE add more parsing for new fields added to Cfg struct
         cfg
     }
 }
 
+/// Loads the configuration file at `path`, falling back to the defaults.
+///
+/// A missing file is not an error. Settings may also be supplied through
+/// environment variables using the `FIXME_` prefix, e.g. `FIXME_uploads_dir`.
+pub fn load_cfg(path: &str) -> Cfg {
+    let config = Config::builder()
+        .add_source(config::File::from(PathBuf::from(path)).required(false))
+        .add_source(config::Environment::with_prefix("FIXME").prefix_separator("_"))
+        .build();
+    match config {
+        Ok(config) => Cfg::from(config),
+        Err(e) => {
+            warn!("Failed to load config '{}': {}", path, e);
+            Cfg::default()
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn write_cfg(out: &mut dyn Write, settings: &Cfg, fmt: &CfgOutputFormat) {
     match fmt {
@@ -175,7 +463,104 @@ mod tests {
         verbose: info
         address: 127.0.0.1
         port: 8080
+        tls_certificate: ''
+        tls_key: ''
+        tls_client_ca: ''
+        http_redirect_port: 0
+        client_principal: san
+        client_certificate_scopes: []
+        users_file: ''
+        session_secret: ''
+        session_ttl: 28800
+        tokens_file: ./tokens.json
         template_glob: {}
+        storage_backend: local
+        uploads_dir: ./uploads
+        staging_dir: ./staging
+        max_upload_size: 268435456
+        max_storage_size: 0
+        upload_session_ttl: 86400
+        max_upload_sessions: 8
+        image_types:
+        - name: sgi
+          extensions:
+          - sgi
+          - cgi
+          magic: []
+        - name: bin
+          extensions:
+          - bin
+          magic: []
+        - name: hex
+          extensions:
+          - hex
+          magic:
+          - 3a
+        - name: elf
+          extensions:
+          - elf
+          magic:
+          - 7f454c46
+        - name: uf2
+          extensions:
+          - uf2
+          magic:
+          - 5546320a57515d9e
+        - name: srec
+          extensions:
+          - srec
+          - s19
+          - s28
+          - s37
+          - mot
+          magic:
+          - '5330'
+          - '5331'
+          - '5332'
+          - '5333'
+        - name: delta
+          extensions:
+          - delta
+          magic:
+          - 465844454c544131
+        - name: gzip
+          extensions:
+          - gz
+          magic:
+          - 1f8b
+        - name: zstd
+          extensions:
+          - zst
+          magic:
+          - 28b52ffd
+        - name: lzma
+          extensions:
+          - lzma
+          magic: []
+        convert_fill_byte: 255
+        convert_base_address: ''
+        payload_compression: none
+        max_delta_image_size: 16777216
+        derive_workers: 2
+        s3_endpoint: ''
+        s3_region: us-east-1
+        s3_bucket: ''
+        s3_prefix: ''
+        s3_access_key: ''
+        s3_secret_key: ''
+        s3_path_style: true
+        manifests_dir: ./manifests
+        manifest_encoder: tool
+        manifest_tool: manifest-tool
+        vendor_id: 00000000-0000-0000-0000-000000000000
+        class_id: 00000000-0000-0000-0000-000000000000
+        signing_key: .update-certificates/default.key.pem
+        certificate: .update-certificates/default.der
+        trust_store: ''
+        job_workers: 2
+        job_queue_size: 32
+        job_history: 100
+        audit_log: ./audit.log
 
         "#,
             default_template_glob()
@@ -0,0 +1,462 @@
+//! Client certificate authentication.
+//!
+//! With `tls_client_ca` set, clients may present a certificate during the TLS
+//! handshake. The handshake fails unless it verifies, so a certificate that
+//! reaches this module is trusted; its subject or subject alternative names
+//! become the connection's [`Principal`]. `client_certificate_scopes` then
+//! decides which routes require one, and from whom.
+
+use std::{any::Any, fmt, str::FromStr};
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Extensions, Payload, ServiceRequest, ServiceResponse},
+    error::ErrorUnauthorized,
+    http::StatusCode,
+    middleware::Next,
+    rt::net::TcpStream,
+    web, FromRequest, HttpRequest,
+};
+use futures_util::future::{ready, Ready};
+use log::debug;
+use openssl::{nid::Nid, x509::X509Ref};
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::Cfg, route::routed_path, service::ServiceError};
+
+/// A route scope that requires a client certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientScope {
+    /// Path prefix the scope covers, e.g. `/api` or `/image-upload`.
+    pub path: String,
+    /// Principals allowed in the scope. Empty allows any trusted certificate.
+    #[serde(default)]
+    pub principals: Vec<String>,
+}
+
+impl ClientScope {
+    fn prefix(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+
+    /// Whether `path` lies in the scope, comparing whole path segments.
+    fn covers(&self, path: &str) -> bool {
+        let prefix = self.prefix();
+        prefix.is_empty()
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    pub fn allows(&self, principal: &Principal) -> bool {
+        self.principals.is_empty() || self.principals.contains(&principal.name)
+    }
+}
+
+/// The most specific of `scopes` covering `path`.
+pub fn scope_for<'a>(scopes: &'a [ClientScope], path: &str) -> Option<&'a ClientScope> {
+    scopes
+        .iter()
+        .filter(|scope| scope.covers(path))
+        .max_by_key(|scope| scope.prefix().len())
+}
+
+/// Which part of a client certificate names its principal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrincipalSource {
+    /// The first DNS, email or URI subject alternative name, else the subject CN.
+    AltName,
+    /// The subject CN.
+    Subject,
+}
+
+/// A `client_principal` that is neither `san` nor `subject`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPrincipalSource(pub String);
+
+impl fmt::Display for UnknownPrincipalSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown client_principal '{}', expected san or subject",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownPrincipalSource {}
+
+impl FromStr for PrincipalSource {
+    type Err = UnknownPrincipalSource;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "san" => Ok(PrincipalSource::AltName),
+            "subject" => Ok(PrincipalSource::Subject),
+            _ => Err(UnknownPrincipalSource(s.to_string())),
+        }
+    }
+}
+
+/// The identity a verified client certificate was issued to.
+///
+/// Handlers take it as an extractor, or as `Option<Principal>` where a
+/// certificate is not required.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    /// What `client_principal` picked, and what scopes list.
+    pub name: String,
+    /// The certificate subject, e.g. `O=Example, CN=ci-01`.
+    pub subject: String,
+    /// Subject alternative names such as `DNS:ci.example.com`.
+    pub alt_names: Vec<String>,
+}
+
+impl Principal {
+    /// Names the holder of `certificate`, or `None` when it carries no usable name.
+    pub fn from_certificate(certificate: &X509Ref, source: PrincipalSource) -> Option<Principal> {
+        let mut subject = Vec::new();
+        let mut common_name = None;
+        for entry in certificate.subject_name().entries() {
+            let Ok(value) = entry.data().to_string() else {
+                continue;
+            };
+            let nid = entry.object().nid();
+            if nid == Nid::COMMONNAME {
+                common_name = Some(value.clone());
+            }
+            subject.push(format!("{}={}", nid.short_name().unwrap_or("?"), value));
+        }
+
+        let mut alt_names = Vec::new();
+        for name in certificate.subject_alt_names().into_iter().flatten() {
+            let alt_name = name
+                .dnsname()
+                .map(|value| ("DNS", value))
+                .or_else(|| name.email().map(|value| ("email", value)))
+                .or_else(|| name.uri().map(|value| ("URI", value)));
+            if let Some((kind, value)) = alt_name {
+                alt_names.push((kind, value.to_string()));
+            }
+        }
+
+        let name = match source {
+            PrincipalSource::AltName => alt_names
+                .first()
+                .map(|(_, value)| value.clone())
+                .or(common_name),
+            PrincipalSource::Subject => common_name,
+        }?;
+        Some(Principal {
+            name,
+            subject: subject.join(", "),
+            alt_names: alt_names
+                .into_iter()
+                .map(|(kind, value)| format!("{}:{}", kind, value))
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.conn_data::<Principal>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("No client certificate was presented")),
+        )
+    }
+}
+
+/// Hook for `HttpServer::on_connect` that records the principal of each TLS connection.
+pub fn on_connect(source: PrincipalSource) -> impl Fn(&dyn Any, &mut Extensions) + Send + Sync {
+    move |connection, data| {
+        let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+            return;
+        };
+        let Some(certificate) = stream.ssl().peer_certificate() else {
+            return;
+        };
+        match Principal::from_certificate(&certificate, source) {
+            Some(principal) => {
+                debug!("Client authenticated as '{}'", principal.name);
+                data.insert(principal);
+            }
+            None => debug!("Ignoring a client certificate without a subject CN or SAN"),
+        }
+    }
+}
+
+/// Middleware refusing requests in `client_certificate_scopes` without an allowed principal.
+pub async fn require_client_certificates<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let refusal = {
+        let cfg = req
+            .app_data::<web::Data<Cfg>>()
+            .expect("Cfg is registered as app data");
+        match routed_path(req.request()) {
+            Err(e) => Some(e),
+            Ok(path) => match scope_for(&cfg.client_certificate_scopes, path) {
+                None => None,
+                Some(scope) => match req.conn_data::<Principal>() {
+                    None => Some(ServiceError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "Client certificate required",
+                        format!(
+                            "{} requires a client certificate issued by a trusted CA.",
+                            path
+                        ),
+                    )),
+                    Some(principal) if !scope.allows(principal) => Some(ServiceError::new(
+                        StatusCode::FORBIDDEN,
+                        "Principal not allowed",
+                        format!("'{}' may not use {}.", principal.name, path),
+                    )),
+                    Some(_) => None,
+                },
+            },
+        }
+    };
+    let Some(refusal) = refusal else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    let response = refusal.to_response(req.request());
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        path::Path,
+    };
+
+    use actix_web::{middleware::from_fn, App, HttpResponse, HttpServer};
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    use super::*;
+    use crate::test_certs::{issue, self_signed, Identity};
+
+    fn write(dir: &Path, name: &str, pem: Vec<u8>) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn principals_come_from_the_san_or_the_subject() {
+        let ca = self_signed("Example CA");
+        let ci = issue("ci-01", Some("ci.example.com"), Some(&ca));
+        let device = issue("device-42", None, Some(&ca));
+
+        let principal = Principal::from_certificate(&ci.certificate, PrincipalSource::AltName);
+        assert_eq!(
+            Some(Principal {
+                name: "ci.example.com".to_string(),
+                subject: "O=Example, CN=ci-01".to_string(),
+                alt_names: vec!["DNS:ci.example.com".to_string()],
+            }),
+            principal
+        );
+        let principal =
+            Principal::from_certificate(&ci.certificate, PrincipalSource::Subject).unwrap();
+        assert_eq!("ci-01", principal.name);
+        let principal =
+            Principal::from_certificate(&device.certificate, PrincipalSource::AltName).unwrap();
+        assert_eq!("device-42", principal.name);
+        assert!("SAN".parse::<PrincipalSource>().is_ok());
+        assert!("issuer".parse::<PrincipalSource>().is_err());
+    }
+
+    #[test]
+    fn the_most_specific_scope_applies() {
+        let scopes = vec![
+            ClientScope {
+                path: "/".to_string(),
+                principals: vec![],
+            },
+            ClientScope {
+                path: "/api/".to_string(),
+                principals: vec!["ci.example.com".to_string()],
+            },
+        ];
+        assert_eq!(Some(&scopes[1]), scope_for(&scopes, "/api"));
+        assert_eq!(Some(&scopes[1]), scope_for(&scopes, "/api/v1/images"));
+        assert_eq!(Some(&scopes[0]), scope_for(&scopes, "/apis"));
+        assert_eq!(None, scope_for(&scopes[1..], "/images"));
+    }
+
+    #[actix_web::test]
+    async fn scopes_apply_to_the_decoded_path() {
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        let cfg = Cfg {
+            client_certificate_scopes: vec![ClientScope {
+                path: "/api".to_string(),
+                principals: vec![],
+            }],
+            ..Cfg::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(cfg))
+                .wrap(from_fn(require_client_certificates))
+                .default_service(web::to(whoami)),
+        )
+        .await;
+        let expectations = [
+            ("/images", StatusCode::OK),
+            ("/api/v1/images", StatusCode::UNAUTHORIZED),
+            ("/%61pi/v1/images", StatusCode::UNAUTHORIZED),
+            ("/%2561pi/v1/images", StatusCode::OK),
+            ("/images/../api/v1/images", StatusCode::BAD_REQUEST),
+            ("//api/v1/images", StatusCode::BAD_REQUEST),
+            ("/api/./v1/images", StatusCode::BAD_REQUEST),
+        ];
+        for (path, status) in expectations {
+            let request = TestRequest::get().uri(path).to_request();
+            assert_eq!(
+                status,
+                call_service(&app, request).await.status(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    /// Sends `GET path` over TLS, presenting `identity`, and returns the status line and body.
+    fn get(port: u16, path: &str, identity: Option<&Identity>) -> Result<String, String> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some(identity) = identity {
+            connector.set_certificate(&identity.certificate).unwrap();
+            connector.set_private_key(&identity.key).unwrap();
+        }
+        let tcp = std::net::TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        let mut stream = connector
+            .build()
+            .connect("localhost", tcp)
+            .map_err(|e| e.to_string())?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| e.to_string())?;
+        let status = response.lines().next().unwrap_or_default().to_string();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        Ok(format!("{} {}", status, body))
+    }
+
+    async fn whoami(principal: Option<Principal>) -> HttpResponse {
+        HttpResponse::Ok().body(principal.map_or("anonymous".to_string(), |p| p.name))
+    }
+
+    #[actix_web::test]
+    async fn scopes_require_trusted_allowed_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = self_signed("Example CA");
+        let server = issue("localhost", Some("localhost"), Some(&ca));
+        let ci = issue("ci-01", Some("ci.example.com"), Some(&ca));
+        let device = issue("device-42", None, Some(&ca));
+        let rogue_ca = self_signed("Rogue CA");
+        let rogue = issue("ci-01", Some("ci.example.com"), Some(&rogue_ca));
+
+        let cfg = Cfg {
+            tls_certificate: write(
+                dir.path(),
+                "server.pem",
+                server.certificate.to_pem().unwrap(),
+            ),
+            tls_key: write(
+                dir.path(),
+                "server.key",
+                server.key.private_key_to_pem_pkcs8().unwrap(),
+            ),
+            tls_client_ca: write(dir.path(), "ca.pem", ca.certificate.to_pem().unwrap()),
+            client_certificate_scopes: vec![
+                ClientScope {
+                    path: "/api".to_string(),
+                    principals: vec!["ci.example.com".to_string()],
+                },
+                ClientScope {
+                    path: "/devices".to_string(),
+                    principals: vec![],
+                },
+            ],
+            ..Cfg::default()
+        };
+        let acceptor = crate::tls::acceptor(&cfg).unwrap().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app_cfg = cfg.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(app_cfg.clone()))
+                .wrap(from_fn(require_client_certificates))
+                .default_service(web::to(whoami))
+        })
+        .workers(1)
+        .on_connect(on_connect(PrincipalSource::AltName))
+        .listen_openssl(listener, acceptor)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let responses = web::block(move || {
+            [
+                get(port, "/images", None),
+                get(port, "/images", Some(&device)),
+                get(port, "/api/v1/images", None),
+                get(port, "/api/v1/images", Some(&device)),
+                get(port, "/api/v1/images", Some(&ci)),
+                get(port, "/devices/42", Some(&device)),
+                get(port, "/devices/42", Some(&rogue)),
+            ]
+        })
+        .await
+        .unwrap();
+        handle.stop(true).await;
+
+        assert_eq!(Ok("HTTP/1.1 200 OK anonymous"), responses[0].as_deref());
+        assert_eq!(Ok("HTTP/1.1 200 OK device-42"), responses[1].as_deref());
+        assert!(responses[2].as_ref().unwrap().starts_with("HTTP/1.1 401"));
+        assert!(responses[3].as_ref().unwrap().starts_with("HTTP/1.1 403"));
+        assert_eq!(
+            Ok("HTTP/1.1 200 OK ci.example.com"),
+            responses[4].as_deref()
+        );
+        assert_eq!(Ok("HTTP/1.1 200 OK device-42"), responses[5].as_deref());
+        // A certificate from an unknown CA fails the handshake.
+        assert!(
+            !responses[6]
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with("HTTP/1.1 200"),
+            "{:?}",
+            responses[6]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+
+use clap::ArgMatches;
+use cor_args::{ArgHandler, DefaultHandler, EnvHandler, Handler};
+use log::info;
+
+use super::{Command, FixmeError};
+use crate::{
+    cfg::{default_config_path, load_cfg},
+    convert::{convert, ConvertError, ConvertOptions, Format},
+    service::ConvertRequest,
+    APP_PREFIX,
+};
+
+/// Converts an Intel HEX, S-record, ELF or UF2 file to a raw binary.
+pub struct Convert {
+    input: PathBuf,
+    output: PathBuf,
+    format: Option<Format>,
+    options: ConvertOptions,
+}
+
+impl Convert {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, ConvertError> {
+        let config_path = ArgHandler::new(matches)
+            .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
+                Box::new(DefaultHandler::new(
+                    &default_config_path().display().to_string(),
+                )),
+            )))
+            .handle_request("config")
+            .expect("No config path");
+        let cfg = load_cfg(&config_path);
+
+        let mut request = ConvertRequest::default();
+        for field in ["format", "fill_byte", "base_address"] {
+            if let Some(value) = matches.get_one::<String>(field) {
+                request.set(field, value.clone());
+            }
+        }
+        let (format, options) = request.options(&cfg)?;
+        let path_arg = |name: &str| {
+            matches
+                .get_one::<PathBuf>(name)
+                .cloned()
+                .unwrap_or_default()
+        };
+        Ok(Convert {
+            input: path_arg("input"),
+            output: path_arg("output"),
+            format,
+            options,
+        })
+    }
+}
+
+impl Command for Convert {
+    fn execute(&self) -> Result<(), Box<dyn FixmeError>> {
+        let input = std::fs::read(&self.input).map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        let converted = convert(&input, self.format, &self.options)
+            .map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        std::fs::write(&self.output, &converted.binary)
+            .map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        info!(
+            "Converted {} from {} to {}",
+            self.input.display(),
+            converted.format,
+            self.output.display()
+        );
+        println!(
+            "{}: {} bytes at {:#x} from {}",
+            self.output.display(),
+            converted.binary.len(),
+            converted.base_address,
+            converted.format
+        );
+        Ok(())
+    }
+}
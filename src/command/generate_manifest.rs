@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use clap::ArgMatches;
+use cor_args::{ArgHandler, DefaultHandler, EnvHandler, Handler};
+use log::{debug, info};
+
+use super::{Command, FixmeError};
+use crate::{
+    cfg::{default_config_path, load_cfg},
+    manifest::{Generator, ManifestError, ManifestRequest},
+    APP_PREFIX,
+};
+
+/// Creates a manifest without starting the web server.
+pub struct GenerateManifest {
+    generator: Generator,
+    request: ManifestRequest,
+}
+
+impl GenerateManifest {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, ManifestError> {
+        let config_path = ArgHandler::new(matches)
+            .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
+                Box::new(DefaultHandler::new(
+                    &default_config_path().display().to_string(),
+                )),
+            )))
+            .handle_request("config")
+            .ok_or_else(|| {
+                ManifestError::Invalid("no configuration file path given".to_string())
+            })?;
+        let cfg = load_cfg(&config_path);
+
+        let path_arg = |name: &str, fallback: &str| {
+            matches
+                .get_one::<PathBuf>(name)
+                .cloned()
+                .unwrap_or_else(|| PathBuf::from(fallback))
+        };
+        let request = ManifestRequest {
+            image: path_arg("image", ""),
+            payload_uri: matches
+                .get_one::<String>("uri")
+                .cloned()
+                .unwrap_or_default(),
+            signing_key: path_arg("key", &cfg.signing_key),
+            certificate: path_arg("certificate", &cfg.certificate),
+            output: path_arg("output", ""),
+            payload_digest: None,
+            delta: None,
+            compression: None,
+        };
+        debug!("{:?}", request);
+        Ok(GenerateManifest {
+            generator: Generator::from_cfg(&cfg)?,
+            request,
+        })
+    }
+}
+
+impl Command for GenerateManifest {
+    fn execute(&self) -> Result<(), Box<dyn FixmeError>> {
+        let log = self
+            .generator
+            .generate(&self.request)
+            .map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        debug!("{}", log);
+        info!("Wrote manifest to {}", self.request.output.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fmt, path::PathBuf};
+
+use clap::ArgMatches;
+use cor_args::{ArgHandler, DefaultHandler, EnvHandler, Handler};
+
+use super::{Command, FixmeError};
+use crate::{
+    cfg::{default_config_path, load_cfg},
+    manifest::inspect::{inspect, TrustStore},
+    APP_PREFIX,
+};
+
+/// A manifest that decoded fine but did not pass verification.
+#[derive(Debug)]
+pub struct NotVerified(String);
+
+impl fmt::Display for NotVerified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "manifest not verified: {}", self.0)
+    }
+}
+
+impl std::error::Error for NotVerified {}
+
+impl FixmeError for NotVerified {}
+
+/// Prints what a manifest contains and fails unless it verifies.
+pub struct InspectManifest {
+    manifest: PathBuf,
+    trust_store: String,
+}
+
+impl InspectManifest {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let config_path = ArgHandler::new(matches)
+            .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
+                Box::new(DefaultHandler::new(
+                    &default_config_path().display().to_string(),
+                )),
+            )))
+            .handle_request("config")
+            .expect("No config path");
+        let cfg = load_cfg(&config_path);
+        InspectManifest {
+            manifest: matches
+                .get_one::<PathBuf>("manifest")
+                .cloned()
+                .unwrap_or_default(),
+            trust_store: matches
+                .get_one::<String>("trust_store")
+                .cloned()
+                .unwrap_or(cfg.trust_store),
+        }
+    }
+}
+
+impl Command for InspectManifest {
+    fn execute(&self) -> Result<(), Box<dyn FixmeError>> {
+        let bytes =
+            std::fs::read(&self.manifest).map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        let trust_store = TrustStore::configured(&self.trust_store)
+            .map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        let inspection = inspect(&bytes, trust_store.as_ref())
+            .map_err(|e| Box::new(e) as Box<dyn FixmeError>)?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&inspection).expect("Failed to serialize inspection")
+        );
+        if inspection.signature_valid == Some(false) {
+            return Err(Box::new(NotVerified(
+                "the signature is invalid".to_string(),
+            )));
+        }
+        // An unknown signer, whose signature could not be checked, is never trusted.
+        if let Some(reason) = inspection.trust_error {
+            return Err(Box::new(NotVerified(reason)));
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
+pub mod convert;
+pub mod generate_manifest;
+pub mod inspect_manifest;
 pub mod run;
+pub mod token;
+pub mod user;
 
 use std::error::Error;
 
@@ -1,16 +1,44 @@
-use actix_web::{rt, web, HttpServer};
+use actix_web::{middleware::from_fn, rt, web, HttpServer};
 use clap::ArgMatches;
 use cor_args::{ArgHandler, CfgFileHandler, DefaultHandler, EnvHandler, Handler};
-use log::{debug, info};
+use log::{debug, info, warn};
 use tera::Tera;
 
 use crate::{
-    cfg::{default_config_path, default_template_glob, Cfg},
+    catalog::Catalog,
+    cfg::{default_config_path, default_template_glob, load_cfg, Cfg},
+    client_auth::PrincipalSource,
+    job::JobQueue,
+    manifest::{inspect::TrustStore, Generator},
+    service::ImageService,
+    session::Sessions,
+    upload_session::UploadSessions,
     APP_PREFIX,
 };
 
 fn run_http_server(cfg: &Cfg) -> std::io::Result<()> {
-    info!("Running HTTP Server at http://{}:{}", cfg.address, cfg.port);
+    let tls = crate::tls::acceptor(cfg).map_err(std::io::Error::other)?;
+    let principal_source: PrincipalSource = cfg
+        .client_principal
+        .parse()
+        .map_err(std::io::Error::other)?;
+    let accounts = Sessions::from_cfg(cfg)?;
+    match &accounts {
+        Some(sessions) if sessions.users().users()?.is_empty() => warn!(
+            "No users in {}, add one with the user subcommand to sign in",
+            cfg.users_file
+        ),
+        Some(_) => {}
+        None => warn!("users_file is not set, anyone who can reach the server may change it"),
+    }
+    if tls.is_some() {
+        info!(
+            "Running HTTPS Server at https://{}:{}",
+            cfg.address, cfg.port
+        );
+    } else {
+        info!("Running HTTP Server at http://{}:{}", cfg.address, cfg.port);
+    }
     // let template_dir = cfg
     //     .template_dir
     //     .clone()
@@ -21,35 +49,160 @@ fn run_http_server(cfg: &Cfg) -> std::io::Result<()> {
     // // let template_dir = Arc::new(template_dir);
     // let tera = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).unwrap();
     let tera = Tera::new(&cfg.template_glob).unwrap();
-    let server = HttpServer::new(move || {
-        actix_web::App::new()
-            .app_data(web::Data::new(tera.clone()))
-            .route("/", web::get().to(crate::route::index::index))
-            .route("/images", web::get().to(crate::route::images::images))
-            .route(
-                "/image-upload",
-                web::get().to(crate::route::image_upload::image_upload_get),
-            )
-            .route(
-                "/image-upload",
-                web::post().to(crate::route::image_upload::image_upload),
-            )
-            .route(
-                "/generate-manifest",
-                web::post().to(crate::route::script::execute_script),
-            )
-            .route("/manifest", web::get().to(crate::route::manifest::manifest))
+    let app_cfg = cfg.clone();
+    let address = (cfg.address.clone(), cfg.port);
+    let redirect_address = (cfg.address.clone(), cfg.http_redirect_port);
+    rt::System::new().block_on(async move {
+        let generator =
+            Generator::from_cfg(&app_cfg).map_err(|e| std::io::Error::other(e.to_string()))?;
+        let catalog = Catalog::from_cfg(&app_cfg)?;
+        let jobs = JobQueue::start(generator, catalog.clone(), &app_cfg);
+        let sessions = UploadSessions::start(&app_cfg)?;
+        let trust_store = web::Data::new(
+            TrustStore::configured(&app_cfg.trust_store)
+                .map_err(|e| std::io::Error::other(e.to_string()))?,
+        );
+        let service = ImageService::new(app_cfg.clone(), catalog, jobs.clone());
+        let redirect_cfg = app_cfg.clone();
+        let server = HttpServer::new(move || {
+            actix_web::App::new()
+                .app_data(web::Data::new(tera.clone()))
+                .app_data(web::Data::new(app_cfg.clone()))
+                .app_data(web::Data::new(jobs.clone()))
+                .app_data(web::Data::new(service.clone()))
+                .app_data(web::Data::new(sessions.clone()))
+                .app_data(trust_store.clone())
+                .configure(|config| {
+                    if let Some(accounts) = &accounts {
+                        config.app_data(web::Data::new(accounts.clone()));
+                    }
+                })
+                .wrap(from_fn(crate::csrf::require_csrf_token))
+                .wrap(from_fn(crate::session::enforce_roles))
+                .wrap(from_fn(crate::client_auth::require_client_certificates))
+                .configure(crate::route::api::configure)
+                .configure(crate::route::openapi::configure)
+                .route("/", web::get().to(crate::route::index::index))
+                .route("/login", web::get().to(crate::route::login::login_get))
+                .route("/login", web::post().to(crate::route::login::login))
+                .route("/logout", web::get().to(crate::route::login::logout_get))
+                .route("/logout", web::post().to(crate::route::login::logout))
+                .route("/tokens", web::get().to(crate::route::tokens::tokens))
+                .route("/tokens", web::post().to(crate::route::tokens::create))
+                .route(
+                    "/tokens/{id}/revoke",
+                    web::post().to(crate::route::tokens::revoke),
+                )
+                .route("/images", web::get().to(crate::route::images::images))
+                .route("/images/{name}", web::get().to(crate::route::images::image))
+                .route(
+                    "/images/{name}/download",
+                    web::get().to(crate::route::images::download),
+                )
+                .route(
+                    "/images/{name}/delete",
+                    web::post().to(crate::route::images::delete),
+                )
+                .route(
+                    "/images/{name}/rename",
+                    web::post().to(crate::route::images::rename),
+                )
+                .route(
+                    "/images/{name}/convert",
+                    web::post().to(crate::route::images::convert),
+                )
+                .route(
+                    "/images/{name}/delta",
+                    web::post().to(crate::route::images::delta),
+                )
+                .route(
+                    "/images/{name}/compress",
+                    web::post().to(crate::route::images::compress),
+                )
+                .route(
+                    "/image-upload",
+                    web::get().to(crate::route::image_upload::image_upload_get),
+                )
+                .route(
+                    "/image-upload",
+                    web::post().to(crate::route::image_upload::image_upload),
+                )
+                .route(
+                    "/generate-manifest",
+                    web::post().to(crate::route::script::execute_script),
+                )
+                .route("/manifest", web::get().to(crate::route::manifest::manifest))
+                .route(
+                    "/manifests/inspect",
+                    web::get().to(crate::route::inspect::inspect_get),
+                )
+                .route(
+                    "/manifests/inspect",
+                    web::post().to(crate::route::inspect::inspect),
+                )
+                .route(
+                    "/manifests/{file}",
+                    web::get().to(crate::route::manifest::manifest_file),
+                )
+                .route("/uploads", web::post().to(crate::route::uploads::create))
+                .route(
+                    "/uploads/{id}",
+                    web::get().to(crate::route::uploads::status),
+                )
+                .route(
+                    "/uploads/{id}",
+                    web::head().to(crate::route::uploads::status),
+                )
+                .route(
+                    "/uploads/{id}",
+                    web::put().to(crate::route::uploads::append),
+                )
+                .route(
+                    "/uploads/{id}",
+                    web::delete().to(crate::route::uploads::cancel),
+                )
+                .route(
+                    "/uploads/{id}/finalize",
+                    web::post().to(crate::route::uploads::finalize),
+                )
+                .route("/jobs", web::get().to(crate::route::jobs::jobs))
+                .route("/jobs/{id}", web::get().to(crate::route::jobs::job))
+                .route(
+                    "/jobs/{id}/events",
+                    web::get().to(crate::route::jobs::job_events),
+                )
+                .route(
+                    "/jobs/{id}/manifest",
+                    web::get().to(crate::route::jobs::job_manifest),
+                )
+        })
+        .on_connect(crate::client_auth::on_connect(principal_source));
+        let server = match tls {
+            Some(acceptor) => server.bind_openssl(address, acceptor)?,
+            None => return server.bind(address)?.run().await,
+        }
+        .run();
+        if redirect_cfg.http_redirect_port == 0 {
+            return server.await;
+        }
+        info!(
+            "Redirecting http://{}:{} to HTTPS",
+            redirect_cfg.address, redirect_cfg.http_redirect_port
+        );
+        let redirect = HttpServer::new(move || {
+            actix_web::App::new()
+                .app_data(web::Data::new(redirect_cfg.clone()))
+                .default_service(web::to(crate::tls::redirect_to_https))
+        })
+        .bind(redirect_address)?
+        .run();
+        futures_util::future::try_join(server, redirect)
+            .await
+            .map(|_| ())
     })
-    .bind((cfg.address.as_str(), cfg.port));
-
-    if let Ok(server) = server {
-        rt::System::new().block_on(server.run())
-    } else {
-        unimplemented!()
-    }
 }
 
-pub fn run(matches: &ArgMatches) {
+pub fn run(matches: &ArgMatches) -> std::io::Result<()> {
     let config_path = ArgHandler::new(matches)
         .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
             Box::new(DefaultHandler::new(
@@ -58,7 +211,7 @@ pub fn run(matches: &ArgMatches) {
         )))
         .handle_request("config");
     let config_path = config_path.expect("No config path");
-    let mut cfg = Cfg::default();
+    let mut cfg = load_cfg(&config_path);
 
     let template_glob = ArgHandler::new(matches)
         .next(Box::new(
@@ -90,15 +243,11 @@ pub fn run(matches: &ArgMatches) {
         )))
         .handle_request("port");
     if let Some(port) = port {
-        cfg.port = port.parse::<u16>().expect(&format!(
-            "Failed to convert {} to unsigned 16-bit integer",
-            port
-        ))
+        cfg.port = port
+            .parse::<u16>()
+            .unwrap_or_else(|_| panic!("Failed to convert {} to unsigned 16-bit integer", port))
     }
     // FUTURE add more parsing for new fields added to Cfg struct
     debug!("{}", cfg);
-    match run_http_server(&cfg) {
-        Ok(_) => {}
-        Err(_) => {}
-    }
+    run_http_server(&cfg)
 }
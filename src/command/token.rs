@@ -0,0 +1,156 @@
+use std::io;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use cor_args::{ArgHandler, DefaultHandler, EnvHandler, Handler};
+
+use super::{Command, FixmeError};
+use crate::{
+    cfg::{default_config_path, load_cfg},
+    tokens::{expiry_after_days, validate_token_name, Scope, TokenStore},
+    users::UserStore,
+    APP_PREFIX,
+};
+
+enum TokenAction {
+    Create {
+        name: String,
+        owner: Option<String>,
+        scopes: Vec<Scope>,
+        expires: Option<DateTime<Utc>>,
+    },
+    Revoke {
+        id: String,
+    },
+    List,
+}
+
+/// Creates, revokes and lists the API tokens in `tokens_file`.
+pub struct TokenCommand {
+    tokens: TokenStore,
+    users: UserStore,
+    action: TokenAction,
+}
+
+fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+impl TokenCommand {
+    pub fn from_matches(matches: &ArgMatches) -> io::Result<Self> {
+        let config_path = ArgHandler::new(matches)
+            .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
+                Box::new(DefaultHandler::new(
+                    &default_config_path().display().to_string(),
+                )),
+            )))
+            .handle_request("config")
+            .expect("No config path");
+        let cfg = load_cfg(&config_path);
+        if cfg.users_file.is_empty() {
+            return Err(invalid_input(
+                "API tokens need user accounts, but users_file is not set in the configuration",
+            ));
+        }
+
+        let action = match matches.subcommand() {
+            Some(("create", sub_m)) => {
+                let name = sub_m.get_one::<String>("name").cloned().unwrap_or_default();
+                validate_token_name(&name).map_err(invalid_input)?;
+                let scopes = sub_m
+                    .get_many::<String>("scope")
+                    .into_iter()
+                    .flatten()
+                    .map(|scope| scope.parse())
+                    .collect::<Result<Vec<Scope>, _>>()
+                    .map_err(invalid_input)?;
+                if scopes.is_empty() {
+                    return Err(invalid_input("give at least one --scope"));
+                }
+                let days = match sub_m.get_one::<String>("expires_in") {
+                    Some(days) => days.parse::<i64>().map_err(|_| {
+                        invalid_input(format!("'{}' is not a number of days", days))
+                    })?,
+                    None => 90,
+                };
+                let expires = expiry_after_days(days).map_err(invalid_input)?;
+                TokenAction::Create {
+                    name,
+                    owner: sub_m.get_one::<String>("owner").cloned(),
+                    scopes,
+                    expires,
+                }
+            }
+            Some(("revoke", sub_m)) => TokenAction::Revoke {
+                id: sub_m.get_one::<String>("id").cloned().unwrap_or_default(),
+            },
+            _ => TokenAction::List,
+        };
+        Ok(TokenCommand {
+            tokens: TokenStore::new(&cfg.tokens_file),
+            users: UserStore::new(&cfg.users_file),
+            action,
+        })
+    }
+
+    fn run(&self) -> io::Result<()> {
+        match &self.action {
+            TokenAction::Create {
+                name,
+                owner,
+                scopes,
+                expires,
+            } => {
+                if let Some(owner) = owner {
+                    let Some(user) = self.users.find(owner)? else {
+                        return Err(invalid_input(format!("there is no user named '{}'", owner)));
+                    };
+                    if let Some(scope) = scopes.iter().find(|scope| scope.role() > user.role) {
+                        return Err(invalid_input(format!(
+                            "{} is a {} and cannot be granted {}",
+                            user.name, user.role, scope
+                        )));
+                    }
+                }
+                let (token, secret) =
+                    self.tokens
+                        .create(name, owner.as_deref(), scopes.clone(), *expires)?;
+                eprintln!(
+                    "Created token {} '{}'; it is not shown again:",
+                    token.id, token.name
+                );
+                println!("{}", secret);
+            }
+            TokenAction::Revoke { id } => {
+                let Some(token) = self.tokens.revoke(id)? else {
+                    return Err(invalid_input(format!("there is no token with ID '{}'", id)));
+                };
+                println!("Revoked {} '{}'", token.id, token.name);
+            }
+            TokenAction::List => {
+                let format = |time: Option<DateTime<Utc>>, none: &str| {
+                    time.map_or(none.to_string(), |time| time.to_rfc3339())
+                };
+                for token in self.tokens.tokens()? {
+                    let scopes: Vec<&str> = token.scopes.iter().map(Scope::as_str).collect();
+                    println!(
+                        "{}\t{}\t{}\t{}\texpires {}\tlast used {}",
+                        token.id,
+                        token.name,
+                        token.owner.as_deref().unwrap_or("service"),
+                        scopes.join(","),
+                        format(token.expires, "never"),
+                        format(token.last_used, "never"),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Command for TokenCommand {
+    fn execute(&self) -> Result<(), Box<dyn FixmeError>> {
+        self.run().map_err(|e| Box::new(e) as Box<dyn FixmeError>)
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use clap::ArgMatches;
+use cor_args::{ArgHandler, DefaultHandler, EnvHandler, Handler};
+
+use super::{Command, FixmeError};
+use crate::{
+    cfg::{default_config_path, load_cfg},
+    users::{hash_password, validate_user_name, Role, User, UserStore},
+    APP_PREFIX,
+};
+
+enum UserAction {
+    Add { name: String, role: Role },
+    Remove { name: String },
+    List,
+}
+
+/// Adds, removes and lists the accounts in `users_file`.
+pub struct UserCommand {
+    users: UserStore,
+    action: UserAction,
+}
+
+fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+/// Reads a password from the first line of standard input, prompting on a terminal.
+fn read_password(name: &str) -> io::Result<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password for {}: ", name);
+        io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    stdin.lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err(invalid_input("the password must not be empty"));
+    }
+    Ok(password)
+}
+
+impl UserCommand {
+    pub fn from_matches(matches: &ArgMatches) -> io::Result<Self> {
+        let config_path = ArgHandler::new(matches)
+            .next(Box::new(EnvHandler::new().prefix(APP_PREFIX).next(
+                Box::new(DefaultHandler::new(
+                    &default_config_path().display().to_string(),
+                )),
+            )))
+            .handle_request("config")
+            .expect("No config path");
+        let cfg = load_cfg(&config_path);
+        if cfg.users_file.is_empty() {
+            return Err(invalid_input("users_file is not set in the configuration"));
+        }
+
+        let name = |matches: &ArgMatches| -> io::Result<String> {
+            let name = matches
+                .get_one::<String>("name")
+                .cloned()
+                .unwrap_or_default();
+            validate_user_name(&name).map_err(invalid_input)?;
+            Ok(name)
+        };
+        let action = match matches.subcommand() {
+            Some(("add", sub_m)) => UserAction::Add {
+                name: name(sub_m)?,
+                role: sub_m
+                    .get_one::<String>("role")
+                    .map_or("viewer", String::as_str)
+                    .parse()
+                    .map_err(invalid_input)?,
+            },
+            Some(("remove", sub_m)) => UserAction::Remove { name: name(sub_m)? },
+            _ => UserAction::List,
+        };
+        Ok(UserCommand {
+            users: UserStore::new(&cfg.users_file),
+            action,
+        })
+    }
+
+    fn run(&self) -> io::Result<()> {
+        match &self.action {
+            UserAction::Add { name, role } => {
+                let password = hash_password(&read_password(name)?)?;
+                self.users.save(User {
+                    name: name.clone(),
+                    role: *role,
+                    password,
+                })?;
+                println!("Saved {} as {}", name, role);
+            }
+            UserAction::Remove { name } => {
+                if !self.users.remove(name)? {
+                    return Err(invalid_input(format!("there is no user named '{}'", name)));
+                }
+                println!("Removed {}", name);
+            }
+            UserAction::List => {
+                for user in self.users.users()? {
+                    println!("{}\t{}", user.name, user.role);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Command for UserCommand {
+    fn execute(&self) -> Result<(), Box<dyn FixmeError>> {
+        self.run().map_err(|e| Box::new(e) as Box<dyn FixmeError>)
+    }
+}
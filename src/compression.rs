@@ -0,0 +1,162 @@
+//! Compression of payloads, which devices undo before installing them.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use flate2::write::GzEncoder;
+use liblzma::stream::{LzmaOptions, Stream};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Ways a payload can be compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    /// The legacy `.lzma` format of the LZMA SDK, not `.xz`.
+    Lzma,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lzma => "lzma",
+        }
+    }
+
+    /// Appended to the name of a compressed image.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+            Compression::Lzma => "lzma",
+        }
+    }
+
+    /// Parses a choice from a form or the configuration, where `none` or nothing means none.
+    pub fn parse_choice(value: &str) -> Result<Option<Compression>, UnknownCompression> {
+        match value.trim() {
+            "" | "none" => Ok(None),
+            value => value.parse().map(Some),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Compression::Lzma => {
+                let options = LzmaOptions::new_preset(6)?;
+                let stream = Stream::new_lzma_encoder(&options)?;
+                let mut encoder = liblzma::write::XzEncoder::new_stream(Vec::new(), stream);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Undoes [`Compression::compress`], as a device would.
+    #[cfg(test)]
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut output = Vec::new();
+        match self {
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut output)?;
+            }
+            Compression::Zstd => output = zstd::decode_all(data)?,
+            Compression::Lzma => {
+                let stream = Stream::new_lzma_decoder(u64::MAX)?;
+                liblzma::read::XzDecoder::new_stream(data, stream).read_to_end(&mut output)?;
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A compression that is not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCompression(pub String);
+
+impl fmt::Display for UnknownCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown compression '{}', expected none, gzip, zstd or lzma",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownCompression {}
+
+impl FromStr for Compression {
+    type Err = UnknownCompression;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "lzma" => Ok(Compression::Lzma),
+            _ => Err(UnknownCompression(s.to_string())),
+        }
+    }
+}
+
+/// How a compressed image was made, recorded with it.
+///
+/// The image's own digest and size are those of the compressed payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Compressed {
+    pub algorithm: Compression,
+    /// Name of the uncompressed image, which is kept.
+    pub image: String,
+    /// Hex SHA-256 of the uncompressed content.
+    pub uncompressed_digest: String,
+    pub uncompressed_size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_algorithm_round_trips() {
+        let data = b"firmware ".repeat(1000);
+        for algorithm in [Compression::Gzip, Compression::Zstd, Compression::Lzma] {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(compressed.len() < data.len() / 10, "{}", algorithm);
+            assert_eq!(data, algorithm.decompress(&compressed).unwrap());
+        }
+        // The legacy format starts with the default properties byte, unlike `.xz`.
+        assert_eq!(0x5d, Compression::Lzma.compress(b"").unwrap()[0]);
+    }
+
+    #[test]
+    fn choices_are_parsed() {
+        assert_eq!(Ok(None), Compression::parse_choice(" none "));
+        assert_eq!(Ok(None), Compression::parse_choice(""));
+        assert_eq!(
+            Ok(Some(Compression::Zstd)),
+            Compression::parse_choice("zstd")
+        );
+        assert!(Compression::parse_choice("bzip2").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! ELF executables: the file contents of every loadable program segment,
+//! placed at its physical (load) address as `objcopy -O binary` does.
+
+use super::{ConvertError, Segment};
+
+const PT_LOAD: u32 = 1;
+
+/// Reads fixed-size fields of either byte order, failing on truncated input.
+struct Reader<'a> {
+    input: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: u64) -> Result<[u8; N], ConvertError> {
+        offset
+            .try_into()
+            .ok()
+            .and_then(|offset: usize| self.input.get(offset..offset.checked_add(N)?))
+            .map(|bytes| bytes.try_into().unwrap())
+            .ok_or_else(|| ConvertError::parse("the ELF file is truncated"))
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, ConvertError> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, ConvertError> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, ConvertError> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+}
+
+pub fn parse(input: &[u8]) -> Result<Vec<Segment>, ConvertError> {
+    if input.len() < 16 || !input.starts_with(b"\x7fELF") {
+        return Err(ConvertError::parse("not an ELF file"));
+    }
+    let is_64 = match input[4] {
+        1 => false,
+        2 => true,
+        class => return Err(ConvertError::parse(format!("unknown ELF class {}", class))),
+    };
+    let big_endian = match input[5] {
+        1 => false,
+        2 => true,
+        data => {
+            return Err(ConvertError::parse(format!(
+                "unknown ELF byte order {}",
+                data
+            )))
+        }
+    };
+    let reader = Reader { input, big_endian };
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        (reader.u64(0x20)?, reader.u16(0x36)?, reader.u16(0x38)?)
+    } else {
+        (
+            reader.u32(0x1c)? as u64,
+            reader.u16(0x2a)?,
+            reader.u16(0x2c)?,
+        )
+    };
+
+    let truncated = || ConvertError::parse("the ELF file is truncated");
+    let mut segments = Vec::new();
+    for index in 0..phnum as u64 {
+        let header = index
+            .checked_mul(phentsize as u64)
+            .and_then(|offset| phoff.checked_add(offset))
+            .ok_or_else(truncated)?;
+        let field = |offset: u64| header.checked_add(offset).ok_or_else(truncated);
+        let (kind, offset, address, size) = if is_64 {
+            (
+                reader.u32(header)?,
+                reader.u64(field(8)?)?,
+                reader.u64(field(24)?)?,
+                reader.u64(field(32)?)?,
+            )
+        } else {
+            (
+                reader.u32(header)?,
+                reader.u32(field(4)?)? as u64,
+                reader.u32(field(12)?)? as u64,
+                reader.u32(field(16)?)? as u64,
+            )
+        };
+        if kind != PT_LOAD || size == 0 {
+            continue;
+        }
+        let data = offset
+            .checked_add(size)
+            .and_then(|end| input.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?))
+            .ok_or_else(|| ConvertError::parse("a segment lies beyond the end of the file"))?;
+        segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a little-endian ELF32 file with one loadable and one other segment.
+    fn elf32() -> Vec<u8> {
+        let mut elf = vec![0u8; 0x34 + 2 * 0x20];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        elf[0x1c..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2a..0x2c].copy_from_slice(&0x20u16.to_le_bytes());
+        elf[0x2c..0x2e].copy_from_slice(&2u16.to_le_bytes());
+        let data_offset = elf.len() as u32;
+        // PT_LOAD, linked at 0x2000_0000 but loaded at 0x0800_0000.
+        let load = [PT_LOAD, data_offset, 0x2000_0000, 0x0800_0000, 4, 8];
+        for (i, field) in load.iter().enumerate() {
+            elf[0x34 + i * 4..0x34 + i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        // PT_NOTE
+        elf[0x54..0x58].copy_from_slice(&4u32.to_le_bytes());
+        elf.extend_from_slice(&[1, 2, 3, 4]);
+        elf
+    }
+
+    #[test]
+    fn loadable_segments_are_placed_at_their_load_address() {
+        assert_eq!(
+            vec![Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4]
+            }],
+            parse(&elf32()).unwrap()
+        );
+        let mut truncated = elf32();
+        truncated.truncate(truncated.len() - 1);
+        assert!(parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn header_offsets_near_the_end_of_the_address_space_are_rejected() {
+        let mut elf = vec![0u8; 0x40];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(parse(&elf), Err(ConvertError::Parse { .. })));
+    }
+}
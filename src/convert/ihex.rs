@@ -0,0 +1,111 @@
+//! Intel HEX: `:LLAAAATT<data>CC` records, one per line.
+
+use super::{decode_hex, ConvertError, Segment};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+pub fn parse(input: &[u8]) -> Result<Vec<Segment>, ConvertError> {
+    let mut segments = Vec::new();
+    // Added to every record address, set by extended address records.
+    let mut offset = 0u64;
+    let mut ended = false;
+
+    for (index, line) in input.split(|byte| *byte == b'\n').enumerate() {
+        let number = index + 1;
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            continue;
+        }
+        if ended {
+            return Err(ConvertError::at_line(
+                number,
+                "data after the end-of-file record",
+            ));
+        }
+        let digits = line
+            .strip_prefix(b":")
+            .ok_or_else(|| ConvertError::at_line(number, "records must start with ':'"))?;
+        let record = decode_hex(digits)
+            .ok_or_else(|| ConvertError::at_line(number, "invalid hexadecimal digits"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(ConvertError::at_line(
+                number,
+                "record length does not match",
+            ));
+        }
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            return Err(ConvertError::at_line(number, "checksum mismatch"));
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as u64;
+        let data = &record[4..record.len() - 1];
+
+        match record[3] {
+            DATA => segments.push(Segment {
+                address: offset + address,
+                data: data.to_vec(),
+            }),
+            END_OF_FILE => ended = true,
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                offset = (u16::from_be_bytes([data[0], data[1]]) as u64) << 4
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                offset = (u16::from_be_bytes([data[0], data[1]]) as u64) << 16
+            }
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            kind => {
+                return Err(ConvertError::at_line(
+                    number,
+                    format!("unsupported record type {:02X}", kind),
+                ))
+            }
+        }
+    }
+    if !ended {
+        return Err(ConvertError::parse("missing end-of-file record"));
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_placed_at_linear_addresses() {
+        let input = b":020000040800F2\n\
+                      :0400000001020304F2\n\
+                      :020010000506E3\r\n\
+                      :00000001FF\n";
+        assert_eq!(
+            vec![
+                Segment {
+                    address: 0x0800_0000,
+                    data: vec![1, 2, 3, 4]
+                },
+                Segment {
+                    address: 0x0800_0010,
+                    data: vec![5, 6]
+                },
+            ],
+            parse(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn corrupt_records_are_reported_by_line() {
+        assert_eq!(
+            Err(ConvertError::at_line(1, "checksum mismatch")),
+            parse(b":0400000001020304F3\n:00000001FF\n")
+        );
+        assert_eq!(
+            Err(ConvertError::parse("missing end-of-file record")),
+            parse(b":0400000001020304F2\n")
+        );
+    }
+}
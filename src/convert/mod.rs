@@ -0,0 +1,447 @@
+//! Normalizes firmware build outputs into the flat binary a payload must be.
+//!
+//! Every input format is parsed into [`Segment`]s of bytes at absolute
+//! addresses, which [`flatten`] lays out from a base address, filling the gaps.
+
+mod elf;
+mod ihex;
+mod srec;
+mod uf2;
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{cfg::Cfg, command::FixmeError};
+
+/// Formats that can be converted to a raw binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Ihex,
+    Srec,
+    Elf,
+    Uf2,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Ihex => "ihex",
+            Format::Srec => "srec",
+            Format::Elf => "elf",
+            Format::Uf2 => "uf2",
+        }
+    }
+
+    /// Recognizes a format by the first bytes of a file.
+    pub fn detect(content: &[u8]) -> Option<Format> {
+        if content.starts_with(b"\x7fELF") {
+            Some(Format::Elf)
+        } else if content.starts_with(uf2::MAGIC) {
+            Some(Format::Uf2)
+        } else if content.starts_with(b":") {
+            Some(Format::Ihex)
+        } else if content.len() >= 2 && content[0] == b'S' && content[1].is_ascii_digit() {
+            Some(Format::Srec)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ihex" | "hex" => Ok(Format::Ihex),
+            "srec" => Ok(Format::Srec),
+            "elf" => Ok(Format::Elf),
+            "uf2" => Ok(Format::Uf2),
+            _ => Err(ConvertError::Invalid(format!(
+                "unknown format '{}', expected ihex, srec, elf or uf2",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the segments of an image are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvertOptions {
+    /// Written wherever no segment provides data.
+    pub fill_byte: u8,
+    /// Address of the first output byte. Defaults to the lowest segment address.
+    pub base_address: Option<u64>,
+    /// Largest output accepted, guarding against segments far apart.
+    pub max_size: u64,
+}
+
+impl ConvertOptions {
+    pub fn from_cfg(cfg: &Cfg) -> Result<Self, ConvertError> {
+        Ok(ConvertOptions {
+            fill_byte: cfg.convert_fill_byte,
+            base_address: parse_address(&cfg.convert_base_address)?,
+            max_size: cfg.max_upload_size as u64,
+        })
+    }
+}
+
+/// Reasons an image could not be converted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConvertError {
+    /// The input is not in a format that can be converted.
+    UnknownFormat,
+    /// The input is malformed. `line` is 1-based for text formats.
+    Parse { line: Option<usize>, reason: String },
+    /// Two segments provide different data for the same address.
+    Overlap(u64),
+    /// A segment starts below the requested base address.
+    BelowBase { address: u64, base: u64 },
+    /// The flattened image would exceed the size limit.
+    TooLarge { size: u64, limit: u64 },
+    /// An option is unusable.
+    Invalid(String),
+}
+
+impl ConvertError {
+    fn parse(reason: impl Into<String>) -> Self {
+        ConvertError::Parse {
+            line: None,
+            reason: reason.into(),
+        }
+    }
+
+    fn at_line(line: usize, reason: impl Into<String>) -> Self {
+        ConvertError::Parse {
+            line: Some(line),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::UnknownFormat => {
+                write!(f, "not an Intel HEX, S-record, ELF or UF2 file")
+            }
+            ConvertError::Parse {
+                line: Some(line),
+                reason,
+            } => write!(f, "line {}: {}", line, reason),
+            ConvertError::Parse { line: None, reason } => write!(f, "{}", reason),
+            ConvertError::Overlap(address) => {
+                write!(f, "conflicting data for address {:#x}", address)
+            }
+            ConvertError::BelowBase { address, base } => write!(
+                f,
+                "data at {:#x} lies below the base address {:#x}",
+                address, base
+            ),
+            ConvertError::TooLarge { size, limit } => write!(
+                f,
+                "the binary would be {} bytes, more than the limit of {}",
+                size, limit
+            ),
+            ConvertError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl FixmeError for ConvertError {}
+
+/// Bytes to be placed at an absolute address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Parses `address` as hexadecimal with a `0x` prefix or as decimal. Empty means none.
+pub fn parse_address(address: &str) -> Result<Option<u64>, ConvertError> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Ok(None);
+    }
+    let parsed = match address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => address.replace('_', "").parse(),
+    };
+    parsed
+        .map(Some)
+        .map_err(|_| ConvertError::Invalid(format!("'{}' is not an address", address)))
+}
+
+/// Parses `input` in `format`, or in the format detected from its content.
+pub fn parse(input: &[u8], format: Option<Format>) -> Result<(Format, Vec<Segment>), ConvertError> {
+    let format = match format {
+        Some(format) => format,
+        None => Format::detect(input).ok_or(ConvertError::UnknownFormat)?,
+    };
+    let segments = match format {
+        Format::Ihex => ihex::parse(input)?,
+        Format::Srec => srec::parse(input)?,
+        Format::Elf => elf::parse(input)?,
+        Format::Uf2 => uf2::parse(input)?,
+    };
+    Ok((format, segments))
+}
+
+/// Lays `segments` out as one contiguous binary.
+///
+/// Returns the base address used together with the binary.
+pub fn flatten(
+    mut segments: Vec<Segment>,
+    options: &ConvertOptions,
+) -> Result<(u64, Vec<u8>), ConvertError> {
+    segments.retain(|segment| !segment.data.is_empty());
+    segments.sort_by_key(|segment| segment.address);
+    let lowest = segments.first().map(|segment| segment.address).unwrap_or(0);
+    let base = options.base_address.unwrap_or(lowest);
+    if lowest < base {
+        return Err(ConvertError::BelowBase {
+            address: lowest,
+            base,
+        });
+    }
+    let mut end = base;
+    for segment in &segments {
+        let segment_end = segment
+            .address
+            .checked_add(segment.data.len() as u64)
+            .ok_or_else(|| {
+                ConvertError::parse(format!(
+                    "data at {:#x} extends past the end of the address space",
+                    segment.address
+                ))
+            })?;
+        end = end.max(segment_end);
+    }
+    let size = end - base;
+    if size > options.max_size {
+        return Err(ConvertError::TooLarge {
+            size,
+            limit: options.max_size,
+        });
+    }
+
+    let mut binary = vec![options.fill_byte; size as usize];
+    // Offset up to which earlier segments wrote. The segments are sorted by
+    // address, so everything between a segment's start and this was written
+    // by the segment reaching furthest, and is all an overlap can touch.
+    let mut written = 0;
+    for segment in segments {
+        let start = (segment.address - base) as usize;
+        let end = start + segment.data.len();
+        let overlap = written.clamp(start, end) - start;
+        if let Some(i) = (0..overlap).find(|&i| binary[start + i] != segment.data[i]) {
+            return Err(ConvertError::Overlap(base + (start + i) as u64));
+        }
+        binary[start + overlap..end].copy_from_slice(&segment.data[overlap..]);
+        written = written.max(end);
+    }
+    Ok((base, binary))
+}
+
+/// Converts `input` to a raw binary.
+pub fn convert(
+    input: &[u8],
+    format: Option<Format>,
+    options: &ConvertOptions,
+) -> Result<Converted, ConvertError> {
+    let (format, segments) = parse(input, format)?;
+    let (base_address, binary) = flatten(segments, options)?;
+    Ok(Converted {
+        format,
+        base_address,
+        binary,
+    })
+}
+
+/// Where a converted image came from, recorded with the binary.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Conversion {
+    /// Name of the original image, which is kept.
+    pub image: String,
+    /// Hex SHA-256 of the original image.
+    pub digest: String,
+    pub format: Format,
+    /// Address the first byte of the binary belongs at.
+    pub base_address: u64,
+    pub fill_byte: u8,
+}
+
+/// The result of [`convert`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Converted {
+    pub format: Format,
+    /// Address the first byte of `binary` belongs at.
+    pub base_address: u64,
+    pub binary: Vec<u8>,
+}
+
+/// Decodes a pair of hexadecimal digits per byte, as used by the text formats.
+fn decode_hex(digits: &[u8]) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ConvertOptions {
+        ConvertOptions {
+            fill_byte: 0xff,
+            base_address: None,
+            max_size: 1024,
+        }
+    }
+
+    #[test]
+    fn gaps_are_filled_from_the_base_address() {
+        let segments = vec![
+            Segment {
+                address: 0x104,
+                data: vec![3, 4],
+            },
+            Segment {
+                address: 0x100,
+                data: vec![1, 2],
+            },
+        ];
+        assert_eq!(
+            (0x100, vec![1, 2, 0xff, 0xff, 3, 4]),
+            flatten(segments.clone(), &options()).unwrap()
+        );
+
+        let based = ConvertOptions {
+            fill_byte: 0,
+            base_address: Some(0xfe),
+            ..options()
+        };
+        assert_eq!(
+            (0xfe, vec![0, 0, 1, 2, 0, 0, 3, 4]),
+            flatten(segments.clone(), &based).unwrap()
+        );
+
+        let above = ConvertOptions {
+            base_address: Some(0x101),
+            ..options()
+        };
+        assert!(matches!(
+            flatten(segments.clone(), &above),
+            Err(ConvertError::BelowBase { .. })
+        ));
+
+        let small = ConvertOptions {
+            max_size: 4,
+            ..options()
+        };
+        assert!(matches!(
+            flatten(segments, &small),
+            Err(ConvertError::TooLarge { size: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn conflicting_segments_are_rejected() {
+        let segments = vec![
+            Segment {
+                address: 0,
+                data: vec![1, 2],
+            },
+            Segment {
+                address: 1,
+                data: vec![3],
+            },
+        ];
+        assert_eq!(Err(ConvertError::Overlap(1)), flatten(segments, &options()));
+
+        let contained = vec![
+            Segment {
+                address: 0,
+                data: vec![1, 2, 3, 4],
+            },
+            Segment {
+                address: 1,
+                data: vec![2, 3],
+            },
+            Segment {
+                address: 3,
+                data: vec![4, 5],
+            },
+        ];
+        assert_eq!(Ok((0, vec![1, 2, 3, 4, 5])), flatten(contained, &options()));
+    }
+
+    #[test]
+    fn segments_at_the_end_of_the_address_space_are_rejected() {
+        let segments = vec![
+            Segment {
+                address: 0,
+                data: vec![1],
+            },
+            Segment {
+                address: u64::MAX - 1,
+                data: vec![2, 3, 4],
+            },
+        ];
+        assert!(matches!(
+            flatten(segments, &options()),
+            Err(ConvertError::Parse { .. })
+        ));
+        let far = vec![
+            Segment {
+                address: 0,
+                data: vec![1],
+            },
+            Segment {
+                address: u64::MAX - 1,
+                data: vec![2],
+            },
+        ];
+        assert!(matches!(
+            flatten(far, &options()),
+            Err(ConvertError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn addresses_are_hex_or_decimal() {
+        assert_eq!(Ok(None), parse_address(" "));
+        assert_eq!(Ok(Some(0x0800_0000)), parse_address("0x0800_0000"));
+        assert_eq!(Ok(Some(4096)), parse_address("4096"));
+        assert!(parse_address("0xzz").is_err());
+    }
+
+    #[test]
+    fn formats_are_detected_by_content() {
+        assert_eq!(Some(Format::Ihex), Format::detect(b":00000001FF"));
+        assert_eq!(Some(Format::Srec), Format::detect(b"S00600004844521B"));
+        assert_eq!(Some(Format::Elf), Format::detect(b"\x7fELF"));
+        assert_eq!(None, Format::detect(b"\x00\x01"));
+    }
+}
@@ -0,0 +1,108 @@
+//! Motorola S-records: `S<type><count><address><data><checksum>`, one per line.
+
+use super::{decode_hex, ConvertError, Segment};
+
+pub fn parse(input: &[u8]) -> Result<Vec<Segment>, ConvertError> {
+    let mut segments = Vec::new();
+    let mut ended = false;
+
+    for (index, line) in input.split(|byte| *byte == b'\n').enumerate() {
+        let number = index + 1;
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            continue;
+        }
+        if ended {
+            return Err(ConvertError::at_line(
+                number,
+                "data after the termination record",
+            ));
+        }
+        let (kind, digits) = match line {
+            [b'S', kind, digits @ ..] if kind.is_ascii_digit() => (kind - b'0', digits),
+            _ => {
+                return Err(ConvertError::at_line(
+                    number,
+                    "records must start with 'S' and a type digit",
+                ))
+            }
+        };
+        let record = decode_hex(digits)
+            .ok_or_else(|| ConvertError::at_line(number, "invalid hexadecimal digits"))?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(ConvertError::at_line(
+                number,
+                "record length does not match",
+            ));
+        }
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0xff {
+            return Err(ConvertError::at_line(number, "checksum mismatch"));
+        }
+        let address_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => {
+                return Err(ConvertError::at_line(
+                    number,
+                    format!("unsupported record type S{}", kind),
+                ))
+            }
+        };
+        // The count byte, the address and the checksum surround the data.
+        if record.len() < address_len + 2 {
+            return Err(ConvertError::at_line(number, "record too short"));
+        }
+        let address = record[1..1 + address_len]
+            .iter()
+            .fold(0u64, |address, byte| address << 8 | *byte as u64);
+        let data = &record[1 + address_len..record.len() - 1];
+
+        match kind {
+            1..=3 => segments.push(Segment {
+                address,
+                data: data.to_vec(),
+            }),
+            7..=9 => ended = true,
+            // Header and record counts carry nothing to place.
+            _ => {}
+        }
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_records_of_every_address_width_are_placed() {
+        let input = b"S00600004844521B\n\
+                      S107001001020304DE\n\
+                      S2060100000506ED\n\
+                      S307080000000708E1\n\
+                      S9030000FC\n";
+        assert_eq!(
+            vec![
+                Segment {
+                    address: 0x10,
+                    data: vec![1, 2, 3, 4]
+                },
+                Segment {
+                    address: 0x10000,
+                    data: vec![5, 6]
+                },
+                Segment {
+                    address: 0x0800_0000,
+                    data: vec![7, 8]
+                },
+            ],
+            parse(input).unwrap()
+        );
+        assert_eq!(
+            Err(ConvertError::at_line(1, "checksum mismatch")),
+            parse(b"S107001001020304DF\n")
+        );
+    }
+}
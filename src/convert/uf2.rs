@@ -0,0 +1,85 @@
+//! UF2: 512-byte blocks, each carrying up to 476 bytes for one flash address.
+
+use super::{ConvertError, Segment};
+
+/// The two start magic numbers every block begins with, little-endian.
+pub const MAGIC: &[u8] = b"UF2\n\x57\x51\x5d\x9e";
+const MAGIC_END: u32 = 0x0ab1_6f30;
+const BLOCK_SIZE: usize = 512;
+const MAX_PAYLOAD: usize = 476;
+/// The block is not meant for main flash, e.g. a comment or a file container entry.
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+
+fn u32_at(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn parse(input: &[u8]) -> Result<Vec<Segment>, ConvertError> {
+    if !input.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(ConvertError::parse(format!(
+            "the file is not a whole number of {}-byte blocks",
+            BLOCK_SIZE
+        )));
+    }
+    let mut segments = Vec::new();
+    for (index, block) in input.chunks(BLOCK_SIZE).enumerate() {
+        if !block.starts_with(MAGIC) || u32_at(block, BLOCK_SIZE - 4) != MAGIC_END {
+            return Err(ConvertError::parse(format!(
+                "block {} has no UF2 magic numbers",
+                index
+            )));
+        }
+        if u32_at(block, 8) & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+        let payload_size = u32_at(block, 16) as usize;
+        if payload_size > MAX_PAYLOAD {
+            return Err(ConvertError::parse(format!(
+                "block {} claims {} payload bytes",
+                index, payload_size
+            )));
+        }
+        segments.push(Segment {
+            address: u32_at(block, 12) as u64,
+            data: block[32..32 + payload_size].to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(flags: u32, address: u32, payload: &[u8]) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(MAGIC);
+        block[8..12].copy_from_slice(&flags.to_le_bytes());
+        block[12..16].copy_from_slice(&address.to_le_bytes());
+        block[16..20].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        block[32..32 + payload.len()].copy_from_slice(payload);
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn main_flash_blocks_are_placed() {
+        let mut input = block(0, 0x1000, &[1, 2]);
+        input.extend(block(FLAG_NOT_MAIN_FLASH, 0, &[9]));
+        input.extend(block(0x2000, 0x1100, &[3]));
+        assert_eq!(
+            vec![
+                Segment {
+                    address: 0x1000,
+                    data: vec![1, 2]
+                },
+                Segment {
+                    address: 0x1100,
+                    data: vec![3]
+                },
+            ],
+            parse(&input).unwrap()
+        );
+        assert!(parse(&input[..BLOCK_SIZE - 1]).is_err());
+    }
+}
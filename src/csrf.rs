@@ -0,0 +1,477 @@
+//! Protection against requests forged by other sites' pages.
+//!
+//! Every session has its own token, an HMAC of the session ID, which
+//! [`page_context`](crate::route::page_context) hands to the templates and
+//! `csrf_field.html` puts into every form. The middleware checks it on every
+//! request that changes something and is authenticated by a session cookie.
+//! The sign-in form, shown before there is a session, gets a token bound to a
+//! nonce in a cookie of its own instead, so a foreign page cannot sign a
+//! browser in to an account of its choosing.
+//!
+//! Browsers present client certificates as readily as cookies, but there is
+//! no session to bind a token to. Changes made with a certificate and no
+//! session are refused when the browser reports, through `Sec-Fetch-Site` or
+//! else `Origin`, that they come from another site. Requests with an API
+//! token carry no ambient credentials and are left alone.
+//!
+//! The token may be sent in an `X-CSRF-Token` header, as the `csrf_token`
+//! field of a URL-encoded form, or as the first part of a multipart form.
+//! Multipart bodies are streamed into storage, so only their first part is
+//! read ahead.
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::{
+        header::{self, HeaderName},
+        Method, StatusCode,
+    },
+    middleware::Next,
+    web::{self, Bytes, BytesMut},
+    HttpMessage,
+};
+use futures_util::{stream, StreamExt};
+use openssl::memcmp;
+
+use crate::{
+    client_auth::Principal,
+    service::ServiceError,
+    session::{CurrentUser, Sessions, LOGIN_COOKIE},
+};
+
+/// Form field the token is sent in.
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Header scripts may send the token in instead.
+pub static CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Most bytes of a body read ahead to find the token.
+const MAX_PREFIX: usize = 16 * 1024;
+
+/// The anti-forgery token of the request's session, kept in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+/// Whether `method` only reads.
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Reads `payload` until `done` holds for what was read or [`MAX_PREFIX`] bytes are in.
+async fn read_prefix(
+    payload: &mut Payload,
+    done: impl Fn(&[u8]) -> bool,
+) -> Result<BytesMut, PayloadError> {
+    let mut prefix = BytesMut::new();
+    while prefix.len() < MAX_PREFIX && !done(&prefix) {
+        match payload.next().await {
+            Some(chunk) => prefix.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    Ok(prefix)
+}
+
+/// Whether a browser reports sending `req` from this server's own pages, or
+/// from no page at all. Requests reporting neither `Sec-Fetch-Site` nor
+/// `Origin` come from clients other than browsers and pass.
+fn from_own_pages(req: &ServiceRequest) -> bool {
+    let headers = req.headers();
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return site == "same-origin" || site == "none";
+    }
+    match headers.get(header::ORIGIN) {
+        Some(origin) => {
+            let info = req.connection_info();
+            *origin == format!("{}://{}", info.scheme(), info.host())
+        }
+        None => true,
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The value of the first part of a multipart body, if that part is the token.
+fn first_part_token(body: &[u8], boundary: &str) -> Option<String> {
+    let body = body.strip_prefix(format!("--{}\r\n", boundary).as_bytes())?;
+    let headers_end = find(body, b"\r\n\r\n")?;
+    let headers = std::str::from_utf8(&body[..headers_end]).ok()?;
+    let names_token = headers.lines().any(|line| {
+        line.to_ascii_lowercase()
+            .starts_with("content-disposition:")
+            && line.contains(&format!("name=\"{}\"", CSRF_FIELD))
+    });
+    if !names_token {
+        return None;
+    }
+    let value = &body[headers_end + 4..];
+    let value_end = find(value, format!("\r\n--{}", boundary).as_bytes())?;
+    String::from_utf8(value[..value_end].to_vec()).ok()
+}
+
+/// The token sent with `req`, reading ahead into form bodies and putting back what was read.
+async fn sent_token(req: &mut ServiceRequest) -> Result<Option<String>, PayloadError> {
+    if let Some(token) = req
+        .headers()
+        .get(&CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Ok(Some(token.to_string()));
+    }
+    let Ok(Some(mime)) = req.mime_type() else {
+        return Ok(None);
+    };
+    let boundary = mime
+        .get_param("boundary")
+        .map(|boundary| boundary.as_str().to_string());
+    let form = match (mime.essence_str(), boundary) {
+        ("application/x-www-form-urlencoded", _) => None,
+        ("multipart/form-data", Some(boundary)) => Some(boundary),
+        _ => return Ok(None),
+    };
+
+    let mut payload = req.take_payload();
+    let prefix = match &form {
+        // URL-encoded forms are small, and the token may be anywhere in them.
+        None => read_prefix(&mut payload, |_| false).await?,
+        Some(boundary) => {
+            let delimiter = format!("\r\n--{}", boundary);
+            read_prefix(&mut payload, |read| {
+                find(read, b"\r\n\r\n")
+                    .is_some_and(|headers| find(&read[headers..], delimiter.as_bytes()).is_some())
+            })
+            .await?
+        }
+    };
+    let token = match &form {
+        None => web::Query::<Vec<(String, String)>>::from_query(
+            std::str::from_utf8(&prefix).unwrap_or_default(),
+        )
+        .ok()
+        .and_then(|fields| {
+            fields
+                .into_inner()
+                .into_iter()
+                .find(|(name, _)| name == CSRF_FIELD)
+                .map(|(_, value)| value)
+        }),
+        Some(boundary) => first_part_token(&prefix, boundary),
+    };
+
+    let prefix: Bytes = prefix.freeze();
+    let rest = stream::once(async move { Ok(prefix) }).chain(payload);
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(rest),
+    });
+    Ok(token)
+}
+
+/// Refuses `req` as forged.
+fn refuse<B>(req: ServiceRequest, detail: &'static str) -> ServiceResponse<EitherBody<B>> {
+    let error = ServiceError::new(StatusCode::FORBIDDEN, "Invalid form token", detail);
+    let response = error.to_response(req.request());
+    req.into_response(response).map_into_right_body()
+}
+
+/// Middleware that keeps the [`CsrfToken`] of the session, or of the sign-in
+/// form, in the request extensions and refuses changes made without it, and
+/// refuses changes made from other sites with a client certificate.
+///
+/// Runs after [`enforce_roles`](crate::session::enforce_roles), which finds the session.
+pub async fn require_csrf_token<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let (session, by_token) = match req.extensions().get::<CurrentUser>() {
+        Some(user) => (user.session.clone(), user.token.is_some()),
+        None => (None, false),
+    };
+    let sessions = req.app_data::<web::Data<Sessions>>().cloned();
+    let signing_in = session.is_none() && req.match_info().as_str() == "/login";
+    let mut login_cookie = None;
+    let expected = match (session, &sessions) {
+        (Some(session), Some(sessions)) => Some(sessions.csrf_token(&session)),
+        (None, Some(sessions)) if signing_in => {
+            let nonce = match req.cookie(LOGIN_COOKIE) {
+                Some(cookie) => cookie.value().to_string(),
+                None if is_safe(req.method()) => {
+                    let cookie = sessions.login_cookie()?;
+                    let nonce = cookie.value().to_string();
+                    login_cookie = Some(cookie);
+                    nonce
+                }
+                None => {
+                    return Ok(refuse(
+                        req,
+                        "Your browser did not send the sign-in form's cookie. \
+                         Allow cookies for this server, reload the page and try again.",
+                    ))
+                }
+            };
+            Some(sessions.login_csrf_token(&nonce))
+        }
+        _ => None,
+    };
+    let Some(expected) = expected else {
+        let ambient = !by_token && req.conn_data::<Principal>().is_some();
+        if ambient && !is_safe(req.method()) && !from_own_pages(&req) {
+            return Ok(refuse(
+                req,
+                "Changes made with a client certificate must come from this server's own pages.",
+            ));
+        }
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    req.extensions_mut().insert(CsrfToken(expected.clone()));
+    if is_safe(req.method()) {
+        let mut response = next.call(req).await?;
+        if let Some(cookie) = login_cookie {
+            response.response_mut().add_cookie(&cookie)?;
+        }
+        return Ok(response.map_into_left_body());
+    }
+
+    let valid = match sent_token(&mut req).await {
+        Ok(Some(sent)) => {
+            sent.len() == expected.len() && memcmp::eq(sent.as_bytes(), expected.as_bytes())
+        }
+        Ok(None) => false,
+        Err(e) => {
+            let error = ServiceError::bad_request("Malformed request body", e.to_string());
+            let response = error.to_response(req.request());
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    if valid {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+    Ok(refuse(
+        req,
+        "The form was not sent from this server's pages or your session changed. \
+         Reload the page and try again.",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::header,
+        middleware::from_fn,
+        test::{call_and_read_body, call_service, init_service, TestRequest},
+        App, HttpResponse,
+    };
+
+    use super::*;
+    use crate::{
+        session::enforce_roles,
+        tokens::TokenStore,
+        users::{hash_password, Role, User, UserStore},
+    };
+
+    #[test]
+    fn token_is_read_from_the_first_multipart_part_only() {
+        let body =
+            b"--xyz\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc\r\n--xyz\r\n";
+        assert_eq!(Some("abc".to_string()), first_part_token(body, "xyz"));
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"uri\"\r\n\r\nabc\r\n--xyz\r\n";
+        assert_eq!(None, first_part_token(body, "xyz"));
+        assert_eq!(None, first_part_token(b"--xyz\r\nContent-Dis", "xyz"));
+    }
+
+    #[actix_web::test]
+    async fn changes_made_with_a_session_need_its_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = UserStore::new(dir.path().join("users.json"));
+        users
+            .save(User {
+                name: "ulla".to_string(),
+                role: Role::Uploader,
+                password: hash_password("secret").unwrap(),
+            })
+            .unwrap();
+        let sessions = Sessions::new(
+            users.clone(),
+            TokenStore::new(dir.path().join("tokens.json")),
+            b"0123456789abcdef0123456789abcdef",
+            3600,
+            false,
+        )
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(sessions.clone()))
+                .wrap(from_fn(require_csrf_token))
+                .wrap(from_fn(enforce_roles))
+                .default_service(web::to(|body: Bytes| async move {
+                    HttpResponse::Ok().body(body)
+                })),
+        )
+        .await;
+        let user = users.find("ulla").unwrap().unwrap();
+        let cookie = sessions.start(&user).unwrap();
+        let token = sessions.csrf_token(&sessions.verify(cookie.value()).unwrap());
+        let post = |content_type: &str, body: String| {
+            TestRequest::post()
+                .uri("/image-upload")
+                .cookie(cookie.clone())
+                .insert_header((header::CONTENT_TYPE, content_type.to_string()))
+                .set_payload(body)
+        };
+        let multipart = "multipart/form-data; boundary=xyz";
+        let part = |name: &str, value: &str| {
+            format!(
+                "--xyz\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            )
+        };
+
+        let form = format!("uri=x&csrf_token={}", token);
+        let request = post("application/x-www-form-urlencoded", form.clone()).to_request();
+        assert_eq!(form.as_bytes(), call_and_read_body(&app, request).await);
+        let body = format!(
+            "{}{}--xyz--\r\n",
+            part("csrf_token", &token),
+            part("uri", "x")
+        );
+        let request = post(multipart, body.clone()).to_request();
+        assert_eq!(body.as_bytes(), call_and_read_body(&app, request).await);
+        let request = post("application/json", "{}".to_string())
+            .insert_header((CSRF_HEADER.clone(), token.clone()))
+            .to_request();
+        assert_eq!(StatusCode::OK, call_service(&app, request).await.status());
+
+        let refused = [
+            post("application/x-www-form-urlencoded", "uri=x".to_string()),
+            post(
+                "application/x-www-form-urlencoded",
+                "csrf_token=forged".to_string(),
+            ),
+            post(
+                multipart,
+                format!(
+                    "{}{}--xyz--\r\n",
+                    part("uri", "x"),
+                    part("csrf_token", &token)
+                ),
+            ),
+            post("application/json", "{}".to_string()),
+        ];
+        for request in refused {
+            let response = call_service(&app, request.to_request()).await;
+            assert_eq!(StatusCode::FORBIDDEN, response.status());
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_sign_in_form_needs_the_token_bound_to_its_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = Sessions::new(
+            UserStore::new(dir.path().join("users.json")),
+            TokenStore::new(dir.path().join("tokens.json")),
+            b"0123456789abcdef0123456789abcdef",
+            3600,
+            false,
+        )
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(sessions.clone()))
+                .wrap(from_fn(require_csrf_token))
+                .wrap(from_fn(enforce_roles))
+                .default_service(web::to(|req: actix_web::HttpRequest| async move {
+                    let token = req.extensions().get::<CsrfToken>().cloned();
+                    HttpResponse::Ok().body(token.map(|token| token.0).unwrap_or_default())
+                })),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().uri("/login").to_request()).await;
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == LOGIN_COOKIE)
+            .unwrap()
+            .into_owned();
+        let token = actix_web::test::read_body(response).await;
+        assert_eq!(sessions.login_csrf_token(cookie.value()).as_bytes(), token);
+        let request = TestRequest::get().uri("/login").cookie(cookie.clone());
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(0, response.response().cookies().count());
+
+        let sign_in = |token: &str| {
+            TestRequest::post()
+                .uri("/login")
+                .set_form([("csrf_token", token), ("username", "vera")])
+        };
+        let token = sessions.login_csrf_token(cookie.value());
+        let other = sessions.login_csrf_token("0123");
+        let expectations = [
+            (sign_in(&token).cookie(cookie.clone()), StatusCode::OK),
+            (sign_in(&token), StatusCode::FORBIDDEN),
+            (
+                sign_in(&other).cookie(cookie.clone()),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                TestRequest::post().uri("/login").cookie(cookie.clone()),
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+        for (request, status) in expectations {
+            assert_eq!(
+                status,
+                call_service(&app, request.to_request()).await.status()
+            );
+        }
+    }
+
+    #[test]
+    fn browsers_must_report_sending_from_this_server() {
+        let request = |headers: &[(&str, &str)]| {
+            let mut request = TestRequest::post()
+                .uri("/image-upload")
+                .insert_header((header::HOST, "fixme.example.com"));
+            for header in headers {
+                request = request.insert_header(*header);
+            }
+            request.to_srv_request()
+        };
+        let expectations = [
+            (vec![], true),
+            (vec![("sec-fetch-site", "same-origin")], true),
+            (vec![("sec-fetch-site", "none")], true),
+            (vec![("sec-fetch-site", "same-site")], false),
+            (vec![("sec-fetch-site", "cross-site")], false),
+            (
+                vec![
+                    ("sec-fetch-site", "cross-site"),
+                    ("origin", "http://fixme.example.com"),
+                ],
+                false,
+            ),
+            (vec![("origin", "http://fixme.example.com")], true),
+            (vec![("origin", "https://fixme.example.com")], false),
+            (vec![("origin", "http://evil.example.com")], false),
+            (vec![("origin", "null")], false),
+        ];
+        for (headers, expected) in expectations {
+            assert_eq!(
+                expected,
+                from_own_pages(&request(&headers)),
+                "{:?}",
+                headers
+            );
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Binary deltas between two firmware images, in the manner of bsdiff.
+//!
+//! A delta lets a device running `base` rebuild `target` from far fewer bytes
+//! than the target itself. The layout follows bsdiff 4, minus its compression:
+//!
+//! ```text
+//! magic           "FXDELTA1"
+//! control length  u64 LE, bytes
+//! diff length     u64 LE, bytes
+//! target size     u64 LE
+//! control block   (add u64 LE, copy u64 LE, seek i64 LE) per step
+//! diff block      bytes added to the base, wrapping
+//! extra block     bytes copied as they are
+//! ```
+//!
+//! Each step adds `add` diff bytes to as many base bytes at the current base
+//! position, appends `copy` extra bytes, then moves the base position by `seek`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What every delta starts with.
+pub const MAGIC: &[u8] = b"FXDELTA1";
+const HEADER_LEN: usize = 32;
+
+/// Why a delta could not be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaError(pub String);
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupt delta: {}", self.0)
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// The images a delta was computed between, recorded with the delta.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Delta {
+    /// Name of the image a device must already run.
+    pub base: String,
+    /// Hex SHA-256 of `base`.
+    pub base_digest: String,
+    /// Name of the image the delta rebuilds.
+    pub target: String,
+    /// Hex SHA-256 of `target`.
+    pub target_digest: String,
+    pub target_size: u64,
+}
+
+/// Sorts every suffix of `old`, preceded by the empty suffix.
+///
+/// Uses prefix doubling: each round sorts by the ranks of the first `k` and
+/// the next `k` bytes until all ranks are distinct.
+fn suffix_array(old: &[u8]) -> Vec<usize> {
+    let n = old.len();
+    let mut sa: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = old.iter().map(|byte| *byte as usize).collect();
+    let mut next = vec![0; n];
+    let mut k = 1;
+    // Shorter suffixes sort first, so running past the end ranks lowest.
+    let key = |rank: &[usize], i: usize, k: usize| (rank[i], rank.get(i + k).map_or(0, |r| r + 1));
+    let mut sorted = n < 2;
+    while !sorted {
+        sa.sort_unstable_by_key(|&i| key(&rank, i, k));
+        next[sa[0]] = 0;
+        for w in 1..n {
+            let distinct = key(&rank, sa[w - 1], k) != key(&rank, sa[w], k);
+            next[sa[w]] = next[sa[w - 1]] + distinct as usize;
+        }
+        std::mem::swap(&mut rank, &mut next);
+        sorted = rank[sa[n - 1]] == n - 1;
+        k *= 2;
+    }
+    sa.insert(0, n);
+    sa
+}
+
+fn match_len(old: &[u8], new: &[u8]) -> usize {
+    old.iter().zip(new).take_while(|(a, b)| a == b).count()
+}
+
+/// Finds the longest prefix of `new` occurring in `old`, returning its position and length.
+fn search(sa: &[usize], old: &[u8], new: &[u8]) -> (usize, usize) {
+    let (mut start, mut end) = (0, sa.len() - 1);
+    while end - start >= 2 {
+        let middle = start + (end - start) / 2;
+        let suffix = &old[sa[middle]..];
+        let len = suffix.len().min(new.len());
+        if suffix[..len] < new[..len] {
+            start = middle;
+        } else {
+            end = middle;
+        }
+    }
+    let at_start = match_len(&old[sa[start]..], new);
+    let at_end = match_len(&old[sa[end]..], new);
+    if at_start > at_end {
+        (sa[start], at_start)
+    } else {
+        (sa[end], at_end)
+    }
+}
+
+/// Computes the delta turning `old` into `new`.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let sa = suffix_array(old);
+    // The algorithm moves back and forth, so positions are signed.
+    let (old_size, new_size) = (old.len() as i64, new.len() as i64);
+    let same = |o: i64, n: i64| {
+        (0..old_size).contains(&o)
+            && (0..new_size).contains(&n)
+            && old[o as usize] == new[n as usize]
+    };
+
+    let mut control = Vec::new();
+    let mut diff_block = Vec::new();
+    let mut extra = Vec::new();
+    let (mut scan, mut len, mut pos) = (0i64, 0i64, 0i64);
+    let (mut last_scan, mut last_pos, mut last_offset) = (0i64, 0i64, 0i64);
+
+    while scan < new_size {
+        // Bytes matching at the previous offset; a new match must beat them.
+        let mut old_score = 0i64;
+        scan += len;
+        let mut scored = scan;
+        while scan < new_size {
+            let (found, found_len) = search(&sa, old, &new[scan as usize..]);
+            pos = found as i64;
+            len = found_len as i64;
+            while scored < scan + len {
+                if same(scored + last_offset, scored) {
+                    old_score += 1;
+                }
+                scored += 1;
+            }
+            if (len == old_score && len != 0) || len > old_score + 8 {
+                break;
+            }
+            if same(scan + last_offset, scan) {
+                old_score -= 1;
+            }
+            scan += 1;
+        }
+        if len == old_score && scan != new_size {
+            continue;
+        }
+
+        // Extend the previous match forwards and this one backwards while
+        // at least half of the bytes agree.
+        let mut forward = 0;
+        let (mut score, mut best) = (0, 0);
+        let mut i = 0;
+        while last_scan + i < scan && last_pos + i < old_size {
+            if same(last_pos + i, last_scan + i) {
+                score += 1;
+            }
+            i += 1;
+            if score * 2 - i > best * 2 - forward {
+                best = score;
+                forward = i;
+            }
+        }
+        let mut backward = 0;
+        if scan < new_size {
+            let (mut score, mut best) = (0, 0);
+            let mut i = 1;
+            while scan >= last_scan + i && pos >= i {
+                if same(pos - i, scan - i) {
+                    score += 1;
+                }
+                if score * 2 - i > best * 2 - backward {
+                    best = score;
+                    backward = i;
+                }
+                i += 1;
+            }
+        }
+        // Split the bytes both extensions claim where it serves best.
+        if last_scan + forward > scan - backward {
+            let overlap = (last_scan + forward) - (scan - backward);
+            let (mut score, mut best, mut split) = (0, 0, 0);
+            for i in 0..overlap {
+                if same(
+                    last_pos + forward - overlap + i,
+                    last_scan + forward - overlap + i,
+                ) {
+                    score += 1;
+                }
+                if same(pos - backward + i, scan - backward + i) {
+                    score -= 1;
+                }
+                if score > best {
+                    best = score;
+                    split = i + 1;
+                }
+            }
+            forward += split - overlap;
+            backward -= split;
+        }
+
+        for i in 0..forward {
+            let (n, o) = ((last_scan + i) as usize, (last_pos + i) as usize);
+            diff_block.push(new[n].wrapping_sub(old[o]));
+        }
+        let copy = (scan - backward) - (last_scan + forward);
+        extra.extend_from_slice(&new[(last_scan + forward) as usize..(scan - backward) as usize]);
+        control.push((
+            forward as u64,
+            copy as u64,
+            (pos - backward) - (last_pos + forward),
+        ));
+
+        last_scan = scan - backward;
+        last_pos = pos - backward;
+        last_offset = pos - scan;
+    }
+
+    let mut delta =
+        Vec::with_capacity(HEADER_LEN + control.len() * 24 + diff_block.len() + extra.len());
+    delta.extend_from_slice(MAGIC);
+    delta.extend_from_slice(&(control.len() as u64 * 24).to_le_bytes());
+    delta.extend_from_slice(&(diff_block.len() as u64).to_le_bytes());
+    delta.extend_from_slice(&(new.len() as u64).to_le_bytes());
+    for (add, copy, seek) in control {
+        delta.extend_from_slice(&add.to_le_bytes());
+        delta.extend_from_slice(&copy.to_le_bytes());
+        delta.extend_from_slice(&seek.to_le_bytes());
+    }
+    delta.extend_from_slice(&diff_block);
+    delta.extend_from_slice(&extra);
+    delta
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Rebuilds the target from `old` and a delta made by [`diff`].
+pub fn apply(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    let corrupt = |reason: &str| DeltaError(reason.to_string());
+    if delta.len() < HEADER_LEN || !delta.starts_with(MAGIC) {
+        return Err(corrupt("missing header"));
+    }
+    let control_len = u64_at(delta, 8);
+    let diff_len = u64_at(delta, 16);
+    let new_size = u64_at(delta, 24);
+    let blocks = &delta[HEADER_LEN..];
+    if !control_len.is_multiple_of(24)
+        || control_len
+            .checked_add(diff_len)
+            .is_none_or(|len| len > blocks.len() as u64)
+    {
+        return Err(corrupt("block lengths exceed the delta"));
+    }
+    let (control, rest) = blocks.split_at(control_len as usize);
+    let (mut diff_block, mut extra) = rest.split_at(diff_len as usize);
+
+    let mut new = Vec::with_capacity(new_size.min(delta.len() as u64 * 8) as usize);
+    let mut old_pos = 0i64;
+    for step in control.chunks(24) {
+        let (add, copy) = (u64_at(step, 0), u64_at(step, 8));
+        let seek = u64_at(step, 16) as i64;
+        if add > diff_block.len() as u64 || copy > extra.len() as u64 {
+            return Err(corrupt("a step reads past its block"));
+        }
+        let (add, copy) = (add as usize, copy as usize);
+        let base = usize::try_from(old_pos)
+            .ok()
+            .and_then(|start| old.get(start..start.checked_add(add)?))
+            .ok_or_else(|| corrupt("a step reads past the base image"))?;
+        new.extend(
+            base.iter()
+                .zip(&diff_block[..add])
+                .map(|(o, d)| o.wrapping_add(*d)),
+        );
+        new.extend_from_slice(&extra[..copy]);
+        if new.len() as u64 > new_size {
+            return Err(corrupt("the target is longer than declared"));
+        }
+        diff_block = &diff_block[add..];
+        extra = &extra[copy..];
+        old_pos = old_pos
+            .checked_add(add as i64)
+            .and_then(|pos| pos.checked_add(seek))
+            .ok_or_else(|| corrupt("a step seeks out of range"))?;
+    }
+    if new.len() as u64 != new_size {
+        return Err(corrupt("the target is shorter than declared"));
+    }
+    Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let delta = diff(old, new);
+        assert_eq!(new, &apply(old, &delta).unwrap()[..]);
+        delta
+    }
+
+    #[test]
+    fn suffixes_are_sorted() {
+        let old = b"banana";
+        let sa = suffix_array(old);
+        assert_eq!(vec![6, 5, 3, 1, 0, 4, 2], sa);
+        assert_eq!(vec![0], suffix_array(b""));
+    }
+
+    #[test]
+    fn deltas_rebuild_the_target() {
+        round_trip(b"", b"");
+        round_trip(b"", b"firmware");
+        round_trip(b"firmware", b"");
+        round_trip(b"firmware 1.0", b"firmware 1.1");
+
+        // A patched build: mostly equal, some bytes changed, code inserted and shifted.
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[100] ^= 0xff;
+        new.splice(5000..5000, b"a new function".iter().copied());
+        new.truncate(18_000);
+        new.extend_from_slice(&[0xff; 300]);
+        let delta = round_trip(&old, &new);
+        // Matched bytes diff to zero, which is what makes deltas compress well.
+        let changed = delta.iter().filter(|byte| **byte != 0).count();
+        assert!(changed < new.len() / 10, "{} bytes", changed);
+    }
+
+    #[test]
+    fn corrupt_deltas_are_rejected() {
+        let old = b"firmware 1.0";
+        let delta = diff(old, b"firmware 1.1");
+        assert!(apply(old, &delta[..delta.len() - 1]).is_err());
+        assert!(apply(b"", &delta).is_err());
+        let mut wrong_size = delta.clone();
+        wrong_size[24] += 1;
+        assert!(apply(old, &wrong_size).is_err());
+        assert!(apply(old, b"FXDELTA0").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+
+/// Longest accepted file name, in bytes. Matches the limit of common filesystems.
+pub const MAX_FILENAME_LEN: usize = 255;
+
+/// Device names Windows reserves regardless of extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a client-supplied file name was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidFilename {
+    Empty,
+    /// The name is `..` or contains a `..` path component.
+    Traversal,
+    /// The name starts at a filesystem root or drive.
+    Absolute,
+    /// The name contains a directory separator.
+    Separator,
+    ControlCharacter,
+    /// The name is `.`, hidden, or a reserved device name.
+    Reserved,
+    TooLong(usize),
+}
+
+impl fmt::Display for InvalidFilename {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidFilename::Empty => write!(f, "the file name is empty"),
+            InvalidFilename::Traversal => {
+                write!(f, "the file name must not refer to a parent directory ('..')")
+            }
+            InvalidFilename::Absolute => write!(f, "the file name must not be an absolute path"),
+            InvalidFilename::Separator => {
+                write!(f, "the file name must not contain '/' or '\\'")
+            }
+            InvalidFilename::ControlCharacter => {
+                write!(f, "the file name must not contain control characters")
+            }
+            InvalidFilename::Reserved => write!(
+                f,
+                "the file name is reserved (hidden files and device names such as CON or NUL are not allowed)"
+            ),
+            InvalidFilename::TooLong(len) => write!(
+                f,
+                "the file name is {} bytes long, the limit is {}",
+                len, MAX_FILENAME_LEN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidFilename {}
+
+/// Checks a file name received from a client before it is used to store anything.
+///
+/// Surrounding whitespace is trimmed; the trimmed name is returned when it is
+/// a single, plain path component.
+pub fn validate_filename(name: &str) -> Result<&str, InvalidFilename> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(InvalidFilename::Empty);
+    }
+    if name.len() > MAX_FILENAME_LEN {
+        return Err(InvalidFilename::TooLong(name.len()));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(InvalidFilename::ControlCharacter);
+    }
+    if name.split(['/', '\\']).any(|component| component == "..") {
+        return Err(InvalidFilename::Traversal);
+    }
+    let bytes = name.as_bytes();
+    if name.starts_with(['/', '\\'])
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return Err(InvalidFilename::Absolute);
+    }
+    if name.contains(['/', '\\']) {
+        return Err(InvalidFilename::Separator);
+    }
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if name.starts_with('.')
+        || RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Err(InvalidFilename::Reserved);
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        assert_eq!(Ok("sbh.sgi"), validate_filename("sbh.sgi"));
+        assert_eq!(Ok("fw v1.2.bin"), validate_filename("  fw v1.2.bin\t"));
+        assert_eq!(Ok("console.bin"), validate_filename("console.bin"));
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        assert_eq!(Err(InvalidFilename::Empty), validate_filename(""));
+        assert_eq!(Err(InvalidFilename::Empty), validate_filename("   "));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        for name in ["..", "../x.sgi", "../../etc/passwd", "a/../b", "..\\x.sgi"] {
+            assert_eq!(
+                Err(InvalidFilename::Traversal),
+                validate_filename(name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        for name in ["/etc/passwd", "\\x.sgi", "C:\\x.sgi", "c:x.sgi"] {
+            assert_eq!(
+                Err(InvalidFilename::Absolute),
+                validate_filename(name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_directories() {
+        for name in ["dir/x.sgi", "dir\\x.sgi"] {
+            assert_eq!(
+                Err(InvalidFilename::Separator),
+                validate_filename(name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for name in ["a\0.sgi", "a\n.sgi", "a\x1b.sgi", "a\u{7f}.sgi"] {
+            assert_eq!(
+                Err(InvalidFilename::ControlCharacter),
+                validate_filename(name),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        for name in [
+            ".", ".hidden", "CON", "nul.sgi", "Com1.bin", "LPT9", "aux .txt",
+        ] {
+            assert_eq!(
+                Err(InvalidFilename::Reserved),
+                validate_filename(name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_over_long_names() {
+        let longest = format!("{}.sgi", "a".repeat(MAX_FILENAME_LEN - 4));
+        assert_eq!(Ok(longest.as_str()), validate_filename(&longest));
+        let name = format!("{}.sgi", "a".repeat(MAX_FILENAME_LEN));
+        assert_eq!(
+            Err(InvalidFilename::TooLong(MAX_FILENAME_LEN + 4)),
+            validate_filename(&name)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Which files count as firmware images.
+//!
+//! A name is accepted when its extension belongs to one of the configured
+//! types, and its content when it starts with one of that type's signatures.
+//! Listing, uploading and renaming all go through [`ImageTypes`], so they
+//! cannot disagree about what an image is.
+
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    delta,
+    manifest::native::{from_hex, to_hex},
+};
+
+/// Leading bytes that identify a file format, written as hexadecimal in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature(pub Vec<u8>);
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        from_hex(&hex)
+            .map(Signature)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// One accepted kind of image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageType {
+    pub name: String,
+    /// File name extensions without the dot, compared case-insensitively.
+    pub extensions: Vec<String>,
+    /// The content must start with one of these. Empty accepts any content.
+    #[serde(default)]
+    pub magic: Vec<Signature>,
+}
+
+impl ImageType {
+    fn new(name: &str, extensions: &[&str], magic: &[&[u8]]) -> Self {
+        ImageType {
+            name: name.to_string(),
+            extensions: extensions.iter().map(|ext| ext.to_string()).collect(),
+            magic: magic.iter().map(|sig| Signature(sig.to_vec())).collect(),
+        }
+    }
+
+    /// The types accepted unless configured otherwise.
+    pub fn defaults() -> Vec<ImageType> {
+        vec![
+            ImageType::new("sgi", &["sgi", "cgi"], &[]),
+            ImageType::new("bin", &["bin"], &[]),
+            ImageType::new("hex", &["hex"], &[b":"]),
+            ImageType::new("elf", &["elf"], &[b"\x7fELF"]),
+            ImageType::new("uf2", &["uf2"], &[b"UF2\n\x57\x51\x5d\x9e"]),
+            ImageType::new(
+                "srec",
+                &["srec", "s19", "s28", "s37", "mot"],
+                &[b"S0", b"S1", b"S2", b"S3"],
+            ),
+            ImageType::new("delta", &["delta"], &[delta::MAGIC]),
+            ImageType::new("gzip", &["gz"], &[b"\x1f\x8b"]),
+            ImageType::new("zstd", &["zst"], &[b"\x28\xb5\x2f\xfd"]),
+            // The legacy format has no magic number.
+            ImageType::new("lzma", &["lzma"], &[]),
+        ]
+    }
+
+    /// Whether `head`, the beginning of a file, fits this type.
+    pub fn matches(&self, head: &[u8]) -> bool {
+        self.magic.is_empty() || self.magic.iter().any(|sig| head.starts_with(&sig.0))
+    }
+}
+
+/// Why a file is not accepted as an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unsupported {
+    /// No type has the file's extension.
+    Extension(Vec<String>),
+    /// The content does not start with a signature of the type its extension names.
+    Content(String),
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsupported::Extension(accepted) => write!(
+                f,
+                "only files ending in .{} are accepted",
+                accepted.join(", .")
+            ),
+            Unsupported::Content(name) => {
+                write!(f, "the content is not a valid {} image", name)
+            }
+        }
+    }
+}
+
+/// The configured image types.
+#[derive(Debug, Clone)]
+pub struct ImageTypes(Vec<ImageType>);
+
+impl ImageTypes {
+    pub fn new(types: Vec<ImageType>) -> Self {
+        ImageTypes(types)
+    }
+
+    /// The type a file called `name` must be, judged by its extension.
+    pub fn for_name(&self, name: &str) -> Result<&ImageType, Unsupported> {
+        let extension = Path::new(name).extension().and_then(|ext| ext.to_str());
+        extension
+            .and_then(|extension| {
+                self.0.iter().find(|image_type| {
+                    image_type
+                        .extensions
+                        .iter()
+                        .any(|candidate| candidate.eq_ignore_ascii_case(extension))
+                })
+            })
+            .ok_or_else(|| Unsupported::Extension(self.extensions().map(str::to_owned).collect()))
+    }
+
+    /// Every accepted extension, without the dot.
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .flat_map(|image_type| image_type.extensions.iter().map(String::as_str))
+    }
+
+    /// Checks both the name and the beginning of the content of a file.
+    ///
+    /// `head` needs at most [`Self::head_len`] bytes.
+    pub fn check(&self, name: &str, head: &[u8]) -> Result<&ImageType, Unsupported> {
+        let image_type = self.for_name(name)?;
+        if image_type.matches(head) {
+            Ok(image_type)
+        } else {
+            Err(Unsupported::Content(image_type.name.clone()))
+        }
+    }
+
+    /// How many leading bytes of a file [`Self::check`] looks at.
+    pub fn head_len(&self) -> usize {
+        self.0
+            .iter()
+            .flat_map(|image_type| image_type.magic.iter())
+            .map(|sig| sig.0.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_content_are_checked_together() {
+        let types = ImageTypes::new(ImageType::defaults());
+
+        assert_eq!("sgi", types.check("fw.SGI", b"anything").unwrap().name);
+        assert_eq!(
+            "elf",
+            types.check("fw.elf", b"\x7fELF\x02\x01").unwrap().name
+        );
+        assert_eq!("hex", types.check("fw.hex", b":10010000").unwrap().name);
+        assert_eq!(
+            Err(Unsupported::Content("uf2".to_string())),
+            types.check("fw.uf2", b"UF2\n\0\0\0\0")
+        );
+        assert!(matches!(
+            types.check("fw.txt", b""),
+            Err(Unsupported::Extension(_))
+        ));
+        assert!(types.for_name("sgi").is_err());
+        assert_eq!(8, types.head_len());
+    }
+
+    #[test]
+    fn signatures_are_configured_as_hex() {
+        let image_type: ImageType =
+            serde_yaml::from_str("name: elf\nextensions: [elf]\nmagic: ['7f454c46']").unwrap();
+        assert_eq!(vec![Signature(b"\x7fELF".to_vec())], image_type.magic);
+        assert!(
+            serde_yaml::from_str::<ImageType>("name: x\nextensions: []\nmagic: ['zz']").is_err()
+        );
+    }
+}
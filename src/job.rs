@@ -0,0 +1,606 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    sync::{broadcast, mpsc},
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    catalog::{Catalog, ImageRef},
+    cfg::Cfg,
+    manifest::{record::ManifestRecord, tool::ManifestTool, Generator, ManifestRequest},
+};
+
+pub type JobId = Uuid;
+
+/// Number of events a slow subscriber may fall behind before it misses some.
+const EVENT_BUFFER: usize = 256;
+
+/// Bytes of each output stream a job keeps; older lines are dropped beyond it.
+pub const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Longest output line recorded; longer lines are split.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Succeeded | JobState::Failed)
+    }
+}
+
+/// A manifest generation job and everything it has reported so far.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Job {
+    #[schema(value_type = Uuid)]
+    pub id: JobId,
+    pub image: String,
+    /// Hex SHA-256 of the image the manifest describes.
+    pub digest: String,
+    pub payload_uri: String,
+    /// Who requested the job: the signed-in user or API token, else the
+    /// client certificate's principal, if any.
+    pub requested_by: Option<String>,
+    pub state: JobState,
+    pub created: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Whether older output was dropped to keep within [`MAX_OUTPUT`].
+    pub truncated: bool,
+    pub error: Option<String>,
+    /// Lines of both streams in the order they were produced, as replayed to new subscribers.
+    #[serde(skip)]
+    pub log: VecDeque<JobEvent>,
+    /// Bytes of output held in `log`.
+    #[serde(skip)]
+    log_bytes: usize,
+    #[serde(skip)]
+    pub output: PathBuf,
+    #[serde(skip)]
+    events: broadcast::Sender<JobEvent>,
+}
+
+/// Something a running job reported, in the order it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    State(JobState),
+    Stdout(String),
+    Stderr(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Returned by [`JobQueue::enqueue`] when every queue slot is taken.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the manifest job queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Runs manifest jobs on a fixed number of workers.
+///
+/// Jobs wait in a bounded queue until a worker picks them up. The most recent
+/// `history` jobs are kept in memory so their status can be polled.
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: Arc<Mutex<VecDeque<Job>>>,
+    sender: mpsc::Sender<(JobId, ManifestRequest)>,
+    history: usize,
+    catalog: Catalog,
+    staging_dir: String,
+}
+
+impl JobQueue {
+    /// Creates the queue and spawns its workers on the current actix runtime.
+    ///
+    /// Job images are read from `catalog`. The number of workers, the queue size
+    /// and the history length come from `cfg`.
+    pub fn start(generator: Generator, catalog: Catalog, cfg: &Cfg) -> Self {
+        let (sender, receiver) = mpsc::channel(cfg.job_queue_size.max(1));
+        let queue = JobQueue {
+            jobs: Arc::new(Mutex::new(VecDeque::new())),
+            sender,
+            history: cfg.job_history.max(1),
+            catalog,
+            staging_dir: cfg.staging_dir.clone(),
+        };
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        for worker in 0..cfg.job_workers.max(1) {
+            let queue = queue.clone();
+            let generator = generator.clone();
+            let receiver = receiver.clone();
+            rt::spawn(async move {
+                loop {
+                    let next = receiver.lock().await.recv().await;
+                    match next {
+                        Some((id, request)) => {
+                            debug!("Worker {} running job {}", worker, id);
+                            queue.execute(&generator, id, request).await;
+                        }
+                        None => break,
+                    }
+                }
+            });
+        }
+        queue
+    }
+
+    /// Queues `request` and returns the ID used to poll its status.
+    pub fn enqueue(
+        &self,
+        image: &ImageRef,
+        request: ManifestRequest,
+        requested_by: Option<String>,
+    ) -> Result<JobId, QueueFull> {
+        let id = Uuid::new_v4();
+        let job = Job {
+            id,
+            image: image.name.clone(),
+            digest: image.digest.clone(),
+            payload_uri: request.payload_uri.clone(),
+            requested_by,
+            state: JobState::Queued,
+            created: Utc::now(),
+            started: None,
+            finished: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            truncated: false,
+            error: None,
+            log: VecDeque::new(),
+            log_bytes: 0,
+            output: request.output.clone(),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        };
+        // Workers look jobs up under this lock, so one taken off the queue is
+        // always found, and a refused job never pushes a finished one out.
+        let mut jobs = self.jobs.lock().unwrap();
+        if self.sender.try_send((id, request)).is_err() {
+            return Err(QueueFull);
+        }
+        jobs.push_back(job);
+        while jobs.len() > self.history {
+            // Never forget a job that has not finished yet.
+            match jobs.iter().position(|job| job.state.is_finished()) {
+                Some(index) => {
+                    jobs.remove(index);
+                }
+                None => break,
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &JobId) -> Option<Job> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.id == *id)
+            .cloned()
+    }
+
+    /// Returns a snapshot of a job along with a receiver for everything it
+    /// reports afterwards, so subscribers never miss or repeat output.
+    pub fn subscribe(&self, id: &JobId) -> Option<(Job, broadcast::Receiver<JobEvent>)> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|job| job.id == *id)?;
+        Some((job.clone(), job.events.subscribe()))
+    }
+
+    /// Returns the retained jobs, newest first.
+    pub fn recent(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    fn update(&self, id: &JobId, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|job| job.id == *id)
+        {
+            f(job);
+        }
+    }
+
+    async fn execute(&self, generator: &Generator, id: JobId, mut request: ManifestRequest) {
+        let (image, digest) = match self.get(&id) {
+            Some(job) => (job.image, job.digest),
+            None => return,
+        };
+        self.update(&id, |job| {
+            job.started = Some(Utc::now());
+            job.set_state(JobState::Running);
+        });
+        // Held until the job is done so a downloaded image is not removed early.
+        let _downloaded = match self.catalog.materialize(&digest, &self.staging_dir).await {
+            Ok((path, downloaded)) => {
+                request.image = path;
+                downloaded
+            }
+            Err(e) => {
+                self.fail(&id, format!("failed to read image '{}': {}", image, e));
+                return;
+            }
+        };
+        match generator {
+            Generator::Tool(tool) => self.run_tool(tool, id, request).await,
+            Generator::Native(_) | Generator::ToolFormat(_) => {
+                let generator = generator.clone();
+                match rt::task::spawn_blocking(move || generator.generate(&request)).await {
+                    Ok(Ok(log)) => {
+                        self.update(&id, |job| job.report(Stream::Stdout, log));
+                        self.succeed(&id).await;
+                    }
+                    Ok(Err(e)) => self.fail(&id, e.to_string()),
+                    Err(e) => self.fail(&id, e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Runs `manifest-tool`, streaming its output into the job as it is produced.
+    async fn run_tool(&self, tool: &ManifestTool, id: JobId, request: ManifestRequest) {
+        if let Some(parent) = request.output.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                self.fail(&id, e.to_string());
+                return;
+            }
+        }
+        let child = tokio::process::Command::from(tool.command(&request))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                self.fail(&id, format!("failed to run manifest-tool: {}", e));
+                return;
+            }
+        };
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        tokio::join!(
+            self.forward(&id, stdout, Stream::Stdout),
+            self.forward(&id, stderr, Stream::Stderr),
+        );
+        match child.wait().await {
+            Ok(status) if status.success() => self.succeed(&id).await,
+            Ok(status) => self.fail(
+                &id,
+                match status.code() {
+                    Some(code) => format!("manifest-tool exited with status {}", code),
+                    None => "manifest-tool was terminated by a signal".to_string(),
+                },
+            ),
+            Err(e) => self.fail(&id, format!("failed to run manifest-tool: {}", e)),
+        }
+    }
+
+    /// Records each line `output` produces and passes it on to subscribers.
+    ///
+    /// Bytes that are not UTF-8 are replaced rather than ending the output, so
+    /// the tool never blocks on a pipe nobody reads.
+    async fn forward(&self, id: &JobId, output: Option<impl AsyncRead + Unpin>, stream: Stream) {
+        let Some(output) = output else {
+            return;
+        };
+        let mut output = BufReader::new(output);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match (&mut output)
+                .take(MAX_LINE as u64)
+                .read_until(b'\n', &mut line)
+                .await
+            {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to read the output of job {}: {}", id, e);
+                    break;
+                }
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches('\n').trim_end_matches('\r');
+            self.update(id, |job| job.report(stream, text.to_string()));
+        }
+    }
+
+    /// Records which image the new manifest was generated from, then marks the job done.
+    async fn succeed(&self, id: &JobId) {
+        let Some(job) = self.get(id) else {
+            return;
+        };
+        let record = ManifestRecord {
+            file: job
+                .output
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            image: job.image,
+            image_digest: job.digest,
+            payload_uri: job.payload_uri,
+            created: Utc::now(),
+            job: Some(job.id),
+        };
+        let saved = rt::task::spawn_blocking(move || record.save(&job.output)).await;
+        if let Some(e) = match saved {
+            Ok(saved) => saved.err().map(|e| e.to_string()),
+            Err(e) => Some(e.to_string()),
+        } {
+            self.fail(id, format!("failed to record the manifest: {}", e));
+            return;
+        }
+        self.update(id, |job| {
+            job.finished = Some(Utc::now());
+            job.set_state(JobState::Succeeded);
+        });
+    }
+
+    fn fail(&self, id: &JobId, error: String) {
+        warn!("Job {} failed: {}", id, error);
+        self.update(id, |job| {
+            job.finished = Some(Utc::now());
+            job.error = Some(error);
+            job.set_state(JobState::Failed);
+        });
+    }
+}
+
+impl Job {
+    /// Appends `line` to the job's output and passes it on to subscribers.
+    ///
+    /// Only the last [`MAX_OUTPUT`] bytes of each stream are kept.
+    fn report(&mut self, stream: Stream, line: String) {
+        let (text, event) = match stream {
+            Stream::Stdout => (&mut self.stdout, JobEvent::Stdout(line.clone())),
+            Stream::Stderr => (&mut self.stderr, JobEvent::Stderr(line.clone())),
+        };
+        text.push_str(&line);
+        text.push('\n');
+        if text.len() > MAX_OUTPUT {
+            let excess = text.len() - MAX_OUTPUT;
+            // Cut after a newline, which is always a character boundary.
+            let cut = text.as_bytes()[excess..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map_or(text.len(), |end| excess + end + 1);
+            text.drain(..cut);
+            self.truncated = true;
+        }
+        self.log_bytes += line.len() + 1;
+        self.log.push_back(event.clone());
+        while self.log_bytes > 2 * MAX_OUTPUT {
+            match self.log.pop_front() {
+                Some(JobEvent::Stdout(line) | JobEvent::Stderr(line)) => {
+                    self.log_bytes -= line.len() + 1
+                }
+                Some(JobEvent::State(_)) => {}
+                None => break,
+            }
+        }
+        let _ = self.events.send(event);
+    }
+
+    fn set_state(&mut self, state: JobState) {
+        self.state = state;
+        // Nobody listening is not an error.
+        let _ = self.events.send(JobEvent::State(state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{catalog::Added, staging::StagedUpload, store::local::LocalStore};
+
+    /// Starts a queue over a catalog holding `a.sgi`, returning a request for a manifest of it.
+    async fn start(
+        program: &str,
+        history: usize,
+    ) -> (JobQueue, ImageRef, ManifestRequest, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(
+            Arc::new(LocalStore::new(dir.path().join("blobs"))),
+            Arc::new(LocalStore::new(dir.path().join("refs"))),
+        );
+        let mut upload = StagedUpload::create(dir.path()).unwrap();
+        upload.write(b"firmware").unwrap();
+        let image = match catalog
+            .add("a.sgi", upload.finish().unwrap(), Default::default())
+            .await
+            .unwrap()
+        {
+            Added::Created(image) => image,
+            other => panic!("unexpected {:?}", other),
+        };
+        let cfg = Cfg {
+            job_workers: 1,
+            job_queue_size: 4,
+            job_history: history,
+            manifests_dir: dir.path().join("manifests").to_str().unwrap().to_string(),
+            ..Cfg::default()
+        };
+        let request = ManifestRequest::for_upload(&cfg, &image, "http://example.com");
+        let queue = JobQueue::start(Generator::Tool(ManifestTool::new(program)), catalog, &cfg);
+        (queue, image, request, dir)
+    }
+
+    async fn wait_for(queue: &JobQueue, id: &JobId) -> Job {
+        for _ in 0..100 {
+            let job = queue.get(id).unwrap();
+            if job.state.is_finished() {
+                return job;
+            }
+            rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("job {} did not finish", id);
+    }
+
+    #[actix_web::test]
+    async fn jobs_report_the_tool_exit_status() {
+        let (succeeding, image, request, dir) = start("true", 10).await;
+        let id = succeeding.enqueue(&image, request, None).unwrap();
+        assert_eq!(JobState::Succeeded, wait_for(&succeeding, &id).await.state);
+        let records = ManifestRecord::for_digest(&dir.path().join("manifests"), &image.digest);
+        assert_eq!(
+            vec![Some(id)],
+            records.unwrap().iter().map(|r| r.job).collect::<Vec<_>>()
+        );
+
+        let (failing, image, request, _dir) = start("false", 10).await;
+        let id = failing.enqueue(&image, request, None).unwrap();
+        let job = wait_for(&failing, &id).await;
+        assert_eq!(JobState::Failed, job.state);
+        assert_eq!(
+            Some("manifest-tool exited with status 1".to_string()),
+            job.error
+        );
+    }
+
+    #[actix_web::test]
+    async fn history_keeps_the_newest_jobs() {
+        let (queue, image, request, _dir) = start("true", 2).await;
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let id = queue.enqueue(&image, request.clone(), None).unwrap();
+            wait_for(&queue, &id).await;
+            ids.push(id);
+        }
+        let recent: Vec<JobId> = queue.recent().iter().map(|job| job.id).collect();
+        assert_eq!(vec![ids[2], ids[1]], recent);
+    }
+
+    #[actix_web::test]
+    async fn output_is_kept_in_order_past_bytes_that_are_not_utf8() {
+        let scripts = tempfile::tempdir().unwrap();
+        let script = scripts.path().join("manifest-tool");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\necho one\nsleep 0.2\necho two >&2\nsleep 0.2\n\
+             printf 'thr\\377ee\\r\\n'\nsleep 0.2\necho four >&2\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let (queue, image, request, _dir) = start(script.to_str().unwrap(), 10).await;
+        let id = queue.enqueue(&image, request, None).unwrap();
+        let job = wait_for(&queue, &id).await;
+
+        assert_eq!(JobState::Succeeded, job.state);
+        assert_eq!("one\nthr\u{fffd}ee\n", job.stdout);
+        assert_eq!("two\nfour\n", job.stderr);
+        assert_eq!(
+            vec![
+                JobEvent::Stdout("one".to_string()),
+                JobEvent::Stderr("two".to_string()),
+                JobEvent::Stdout("thr\u{fffd}ee".to_string()),
+                JobEvent::Stderr("four".to_string()),
+            ],
+            Vec::from(job.log)
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_the_latest_output_is_kept() {
+        let scripts = tempfile::tempdir().unwrap();
+        let script = scripts.path().join("manifest-tool");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\nseq 1 30000\nhead -c 20000 /dev/zero | tr '\\0' x\necho\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let (queue, image, request, _dir) = start(script.to_str().unwrap(), 10).await;
+        let id = queue.enqueue(&image, request, None).unwrap();
+        let job = wait_for(&queue, &id).await;
+
+        assert_eq!(JobState::Succeeded, job.state);
+        assert!(job.truncated);
+        assert!(job.stdout.len() <= MAX_OUTPUT);
+        assert!(job.stdout.starts_with(|c: char| c.is_ascii_digit()));
+        assert!(job
+            .stdout
+            .ends_with(&format!("\n{}\n", "x".repeat(20000 % MAX_LINE))));
+        assert!(job.stdout.lines().all(|line| line.len() <= MAX_LINE));
+        let logged: usize = job
+            .log
+            .iter()
+            .map(|event| match event {
+                JobEvent::Stdout(line) | JobEvent::Stderr(line) => line.len() + 1,
+                JobEvent::State(_) => 0,
+            })
+            .sum();
+        assert!(logged <= 2 * MAX_OUTPUT);
+    }
+
+    #[actix_web::test]
+    async fn refused_jobs_do_not_displace_finished_ones() {
+        let scripts = tempfile::tempdir().unwrap();
+        let script = scripts.path().join("manifest-tool");
+        std::fs::write(&script, "#!/bin/sh\nsleep 0.3\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        // One finished job, one running and four queued fill the history exactly.
+        let (queue, image, request, _dir) = start(script.to_str().unwrap(), 6).await;
+        let finished = queue.enqueue(&image, request.clone(), None).unwrap();
+        wait_for(&queue, &finished).await;
+        let running = queue.enqueue(&image, request.clone(), None).unwrap();
+        while queue.get(&running).unwrap().state != JobState::Running {
+            rt::time::sleep(Duration::from_millis(5)).await;
+        }
+        for _ in 0..4 {
+            queue.enqueue(&image, request.clone(), None).unwrap();
+        }
+
+        assert!(queue.enqueue(&image, request, None).is_err());
+        assert_eq!(6, queue.recent().len());
+        assert!(queue.get(&finished).is_some());
+    }
+}
@@ -1,14 +1,35 @@
+mod audit;
+mod catalog;
 mod cfg;
+mod client_auth;
 mod command;
+mod compression;
+mod convert;
+mod csrf;
+mod delta;
+mod filename;
+mod image_type;
+mod job;
+mod manifest;
 mod route;
+mod service;
+mod session;
+mod staging;
+mod store;
+#[cfg(test)]
+mod test_certs;
+mod tls;
+mod tokens;
+mod upload_session;
+mod users;
 
 use cfg::default_config_path;
 use clap::{value_parser, Arg};
+use command::Command as _;
 use cor_args::{ArgHandler, CfgFileHandler, DefaultHandler, EnvHandler, FileHandler, Handler};
 use log::{debug, error, info, trace, warn, LevelFilter};
 use std::path::PathBuf;
 
-
 const APP_NAME: &str = "FIXME";
 const APP_PREFIX: &str = "FIXME_";
 
@@ -95,7 +116,7 @@ impl App {
                         .value_name("FILE")
                         // .default_value(&default_config_path_value)
                         .help("Sets a custom config file")
-                        .value_parser(value_parser!(PathBuf)),
+                        .global(true),
                 )
                 .arg(
                     Arg::new("verbosity")
@@ -144,7 +165,184 @@ impl App {
                         ),
                 )
                 .subcommand(
-                    clap::Command::new("generate-manifest").about("Generates a manifest file"),
+                    clap::Command::new("generate-manifest")
+                        .about("Generates a manifest file")
+                        .arg(
+                            Arg::new("image")
+                                .long("image")
+                                .short('i')
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("The firmware image the manifest describes"),
+                        )
+                        .arg(
+                            Arg::new("uri")
+                                .long("uri")
+                                .short('u')
+                                .required(true)
+                                .value_name("URI")
+                                .help("The URI devices will download the payload from"),
+                        )
+                        .arg(
+                            Arg::new("key")
+                                .long("key")
+                                .short('k')
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("The private key used to sign the manifest"),
+                        )
+                        .arg(
+                            Arg::new("certificate")
+                                .long("certificate")
+                                .short('C')
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("The certificate matching the signing key"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .long("output")
+                                .short('o')
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("Where to write the manifest"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("inspect-manifest")
+                        .about("Decodes a manifest and verifies its signature")
+                        .arg(
+                            Arg::new("manifest")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("The manifest to inspect"),
+                        )
+                        .arg(
+                            Arg::new("trust_store")
+                                .long("trust-store")
+                                .short('T')
+                                .value_name("PATH")
+                                .help("Certificate file or directory the signer must chain to"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("convert")
+                        .about("Converts an Intel HEX, S-record, ELF or UF2 file to a raw binary")
+                        .arg(
+                            Arg::new("input")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("The file to convert"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .long("output")
+                                .short('o')
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .value_name("FILE")
+                                .help("Where to write the binary"),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .short('f')
+                                .value_name("FORMAT")
+                                .help("ihex, srec, elf or uf2; detected from the content if omitted"),
+                        )
+                        .arg(
+                            Arg::new("fill_byte")
+                                .long("fill-byte")
+                                .value_name("BYTE")
+                                .help("Byte written into gaps between segments"),
+                        )
+                        .arg(
+                            Arg::new("base_address")
+                                .long("base-address")
+                                .short('b')
+                                .value_name("ADDRESS")
+                                .help("Address of the first output byte; the lowest address if omitted"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("user")
+                        .about("Manages the user accounts in users_file")
+                        .subcommand(
+                            clap::Command::new("add")
+                                .about("Adds a user or changes their role and password, read from stdin")
+                                .arg(
+                                    Arg::new("name")
+                                        .required(true)
+                                        .value_name("NAME")
+                                        .help("The user name"),
+                                )
+                                .arg(
+                                    Arg::new("role")
+                                        .long("role")
+                                        .short('r')
+                                        .value_name("ROLE")
+                                        .help("viewer, uploader or release_manager; viewer if omitted"),
+                                ),
+                        )
+                        .subcommand(
+                            clap::Command::new("remove").about("Removes a user").arg(
+                                Arg::new("name")
+                                    .required(true)
+                                    .value_name("NAME")
+                                    .help("The user name"),
+                            ),
+                        )
+                        .subcommand(clap::Command::new("list").about("Lists the users and their roles")),
+                )
+                .subcommand(
+                    clap::Command::new("token")
+                        .about("Manages the API tokens in tokens_file")
+                        .subcommand(
+                            clap::Command::new("create")
+                                .about("Creates a token and prints it, the only time it is shown")
+                                .arg(
+                                    Arg::new("name")
+                                        .required(true)
+                                        .value_name("NAME")
+                                        .help("What the token is for"),
+                                )
+                                .arg(
+                                    Arg::new("scope")
+                                        .long("scope")
+                                        .short('s')
+                                        .required(true)
+                                        .action(clap::ArgAction::Append)
+                                        .value_name("SCOPE")
+                                        .help("images:read, images:write or manifests:create; repeat for more"),
+                                )
+                                .arg(
+                                    Arg::new("owner")
+                                        .long("owner")
+                                        .short('o')
+                                        .value_name("USER")
+                                        .help("The user a personal token acts as; a service token if omitted"),
+                                )
+                                .arg(
+                                    Arg::new("expires_in")
+                                        .long("expires-in")
+                                        .short('e')
+                                        .value_name("DAYS")
+                                        .help("Days until the token expires, 0 for never; 90 if omitted"),
+                                ),
+                        )
+                        .subcommand(
+                            clap::Command::new("revoke").about("Revokes a token").arg(
+                                Arg::new("id")
+                                    .required(true)
+                                    .value_name("ID")
+                                    .help("The token ID, as listed"),
+                            ),
+                        )
+                        .subcommand(clap::Command::new("list").about("Lists the tokens without revealing them")),
                 ),
         }
     }
@@ -180,14 +378,37 @@ impl App {
         }
 
         match matches.subcommand() {
-            Some(("run", sub_m)) => command::run::run(sub_m),
+            Some(("run", sub_m)) => command::run::run(sub_m).map_err(|e| e.to_string())?,
+            Some(("generate-manifest", sub_m)) => {
+                command::generate_manifest::GenerateManifest::from_matches(sub_m)
+                    .map_err(|e| e.to_string())?
+                    .execute()
+                    .map_err(|e| e.to_string())?
+            }
+            Some(("inspect-manifest", sub_m)) => {
+                command::inspect_manifest::InspectManifest::from_matches(sub_m)
+                    .execute()
+                    .map_err(|e| e.to_string())?
+            }
+            Some(("convert", sub_m)) => command::convert::Convert::from_matches(sub_m)
+                .map_err(|e| e.to_string())?
+                .execute()
+                .map_err(|e| e.to_string())?,
+            Some(("user", sub_m)) => command::user::UserCommand::from_matches(sub_m)
+                .map_err(|e| e.to_string())?
+                .execute()
+                .map_err(|e| e.to_string())?,
+            Some(("token", sub_m)) => command::token::TokenCommand::from_matches(sub_m)
+                .map_err(|e| e.to_string())?
+                .execute()
+                .map_err(|e| e.to_string())?,
             subcommand => eprintln!("Invalid subcommand {:?}", subcommand),
         }
         Ok(())
     }
 
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.run_with_args(std::env::args().into_iter())
+        self.run_with_args(std::env::args())
     }
 }
 
@@ -0,0 +1,310 @@
+//! Just enough DER to encode and decode update manifests.
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const UTF8_STRING: u8 = 0x0c;
+const SEQUENCE: u8 = 0x30;
+/// Context-specific, constructed: `[n]` with implicit tagging of a SEQUENCE.
+const CONTEXT_CONSTRUCTED: u8 = 0xa0;
+
+/// Encodes a definite length.
+fn length(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+/// Encodes a tag-length-value triple.
+pub fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    length(value.len(), &mut out);
+    out.extend_from_slice(value);
+    out
+}
+
+/// Encodes a non-negative INTEGER.
+pub fn integer(value: u64) -> Vec<u8> {
+    unsigned(INTEGER, value)
+}
+
+/// Encodes an ENUMERATED value.
+pub fn enumerated(value: u64) -> Vec<u8> {
+    unsigned(ENUMERATED, value)
+}
+
+pub fn boolean(value: bool) -> Vec<u8> {
+    tlv(BOOLEAN, &[if value { 0xff } else { 0 }])
+}
+
+/// Encodes a non-negative integer under `tag`.
+fn unsigned(tag: u8, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    let mut content = Vec::with_capacity(9);
+    // A leading 1 bit would make the value negative.
+    if bytes[skip] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend_from_slice(&bytes[skip..]);
+    tlv(tag, &content)
+}
+
+pub fn octet_string(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+pub fn utf8_string(value: &str) -> Vec<u8> {
+    tlv(UTF8_STRING, value.as_bytes())
+}
+
+/// Encodes a SEQUENCE of already encoded elements.
+pub fn sequence(elements: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &elements.concat())
+}
+
+/// Encodes elements as the SEQUENCE tagged `[number] IMPLICIT`.
+pub fn tagged(number: u8, elements: &[Vec<u8>]) -> Vec<u8> {
+    tlv(CONTEXT_CONSTRUCTED | number, &elements.concat())
+}
+
+/// Why DER input could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError(pub String);
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "malformed manifest: {}", self.0)
+    }
+}
+
+/// Reads DER elements one after another.
+pub struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Reader { input }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    /// Reads the next element, which must carry `tag`, returning its contents.
+    pub fn read(&mut self, tag: u8) -> Result<&'a [u8], DecodeError> {
+        let (&actual, rest) = self
+            .input
+            .split_first()
+            .ok_or_else(|| DecodeError("unexpected end of input".to_string()))?;
+        if actual != tag {
+            return Err(DecodeError(format!(
+                "expected tag {:#04x}, found {:#04x}",
+                tag, actual
+            )));
+        }
+        let (&first, mut rest) = rest
+            .split_first()
+            .ok_or_else(|| DecodeError("missing length".to_string()))?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+                return Err(DecodeError("unsupported length".to_string()));
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            rest = &rest[count..];
+            len
+        };
+        if rest.len() < len {
+            return Err(DecodeError("element is longer than the input".to_string()));
+        }
+        let (contents, rest) = rest.split_at(len);
+        self.input = rest;
+        Ok(contents)
+    }
+
+    /// Reads the next element whatever its tag, returning the tag and contents.
+    pub fn any(&mut self) -> Result<(u8, &'a [u8]), DecodeError> {
+        let tag = *self
+            .input
+            .first()
+            .ok_or_else(|| DecodeError("unexpected end of input".to_string()))?;
+        Ok((tag, self.read(tag)?))
+    }
+
+    /// Reads a non-negative INTEGER that fits in 64 bits.
+    pub fn integer(&mut self) -> Result<u64, DecodeError> {
+        self.unsigned(INTEGER)
+    }
+
+    /// Reads an ENUMERATED value.
+    pub fn enumerated(&mut self) -> Result<u64, DecodeError> {
+        self.unsigned(ENUMERATED)
+    }
+
+    pub fn boolean(&mut self) -> Result<bool, DecodeError> {
+        match self.read(BOOLEAN)? {
+            [value] => Ok(*value != 0),
+            _ => Err(DecodeError("invalid boolean".to_string())),
+        }
+    }
+
+    /// Reads a non-negative integer carrying `tag`.
+    fn unsigned(&mut self, tag: u8) -> Result<u64, DecodeError> {
+        let contents = self.read(tag)?;
+        if contents.is_empty() || contents[0] & 0x80 != 0 {
+            return Err(DecodeError("expected a non-negative integer".to_string()));
+        }
+        let contents = if contents[0] == 0 {
+            &contents[1..]
+        } else {
+            contents
+        };
+        if contents.len() > 8 {
+            return Err(DecodeError("integer is too large".to_string()));
+        }
+        Ok(contents
+            .iter()
+            .fold(0u64, |value, b| (value << 8) | *b as u64))
+    }
+
+    pub fn octet_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        self.read(OCTET_STRING)
+    }
+
+    pub fn utf8_string(&mut self) -> Result<&'a str, DecodeError> {
+        std::str::from_utf8(self.read(UTF8_STRING)?)
+            .map_err(|_| DecodeError("invalid UTF-8 string".to_string()))
+    }
+
+    /// Reads a SEQUENCE, returning a reader over its elements.
+    pub fn sequence(&mut self) -> Result<Reader<'a>, DecodeError> {
+        Ok(Reader::new(self.read(SEQUENCE)?))
+    }
+
+    /// Reads the INTEGER that comes next, if one does.
+    pub fn optional_integer(&mut self) -> Result<Option<u64>, DecodeError> {
+        if self.input.first() != Some(&INTEGER) {
+            return Ok(None);
+        }
+        self.integer().map(Some)
+    }
+
+    /// Reads the UTF8String that comes next, if one does.
+    pub fn optional_utf8_string(&mut self) -> Result<Option<&'a str>, DecodeError> {
+        if self.input.first() != Some(&UTF8_STRING) {
+            return Ok(None);
+        }
+        self.utf8_string().map(Some)
+    }
+
+    /// Reads the SEQUENCE that comes next, if one does.
+    pub fn optional_sequence(&mut self) -> Result<Option<Reader<'a>>, DecodeError> {
+        if self.input.first() != Some(&SEQUENCE) {
+            return Ok(None);
+        }
+        self.sequence().map(Some)
+    }
+
+    /// Reads the element tagged `[number]` if it comes next, returning a reader over its elements.
+    pub fn optional_tagged(&mut self, number: u8) -> Result<Option<Reader<'a>>, DecodeError> {
+        let tag = CONTEXT_CONSTRUCTED | number;
+        if self.input.first() != Some(&tag) {
+            return Ok(None);
+        }
+        Ok(Some(Reader::new(self.read(tag)?)))
+    }
+
+    /// Reads a SEQUENCE, also returning its complete encoding.
+    pub fn sequence_with_encoding(&mut self) -> Result<(Reader<'a>, &'a [u8]), DecodeError> {
+        let start = self.input;
+        let reader = self.sequence()?;
+        let consumed = start.len() - self.input.len();
+        Ok((reader, &start[..consumed]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let encoded = sequence(&[
+            integer(u64::MAX),
+            octet_string(&[0xab; 200]),
+            utf8_string("http://example.com"),
+        ]);
+        let mut outer = Reader::new(&encoded);
+        let mut reader = outer.sequence().unwrap();
+        assert!(outer.is_empty());
+        assert_eq!(u64::MAX, reader.integer().unwrap());
+        assert_eq!(&[0xab; 200][..], reader.octet_string().unwrap());
+        assert_eq!("http://example.com", reader.utf8_string().unwrap());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn optional_tagged_elements() {
+        let encoded = [tagged(0, &[integer(7)]), integer(8)].concat();
+        let mut reader = Reader::new(&encoded);
+        assert!(reader.optional_tagged(1).unwrap().is_none());
+        let mut inner = reader.optional_tagged(0).unwrap().unwrap();
+        assert_eq!(7, inner.integer().unwrap());
+        assert!(reader.optional_tagged(0).unwrap().is_none());
+        assert_eq!(8, reader.integer().unwrap());
+    }
+
+    #[test]
+    fn optional_universal_elements() {
+        let encoded = [
+            enumerated(1),
+            sequence(&[]),
+            boolean(true),
+            utf8_string("default"),
+        ]
+        .concat();
+        let mut reader = Reader::new(&encoded);
+        assert!(reader.optional_utf8_string().unwrap().is_none());
+        assert_eq!(1, reader.enumerated().unwrap());
+        assert!(reader.optional_utf8_string().unwrap().is_none());
+        assert!(reader.optional_sequence().unwrap().unwrap().is_empty());
+        assert!(reader.optional_sequence().unwrap().is_none());
+        assert_eq!((0x01, &[0xff][..]), reader.any().unwrap());
+        assert_eq!("default", reader.optional_utf8_string().unwrap().unwrap());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let encoded = octet_string(b"firmware");
+        let mut reader = Reader::new(&encoded[..encoded.len() - 1]);
+        assert!(reader.octet_string().is_err());
+    }
+
+    #[test]
+    fn integers_are_minimal_and_positive() {
+        assert_eq!(vec![0x02, 0x01, 0x00], integer(0));
+        assert_eq!(vec![0x02, 0x01, 0x7f], integer(127));
+        assert_eq!(vec![0x02, 0x02, 0x00, 0x80], integer(128));
+        assert_eq!(vec![0x02, 0x02, 0x01, 0x00], integer(256));
+    }
+
+    #[test]
+    fn long_lengths() {
+        let encoded = octet_string(&[0u8; 300]);
+        assert_eq!(&[0x04, 0x82, 0x01, 0x2c], &encoded[..4]);
+        assert_eq!(304, encoded.len());
+    }
+}
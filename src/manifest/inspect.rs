@@ -0,0 +1,315 @@
+use std::path::Path;
+
+use openssl::{
+    hash::MessageDigest,
+    stack::Stack,
+    x509::{
+        store::{X509Store, X509StoreBuilder},
+        X509NameRef, X509StoreContext, X509,
+    },
+};
+use serde::Serialize;
+
+use super::{
+    native::{to_hex, Manifest, SignedManifest},
+    tool_format::{SignedResource, ToolManifest},
+    ManifestError,
+};
+
+/// Details of the certificate a manifest was signed with.
+#[derive(Debug, Clone, Serialize)]
+pub struct Signer {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub sha256_fingerprint: String,
+}
+
+impl Signer {
+    fn from_certificate(certificate: &X509) -> Result<Self, ManifestError> {
+        Ok(Signer {
+            subject: format_name(certificate.subject_name()),
+            issuer: format_name(certificate.issuer_name()),
+            serial: certificate
+                .serial_number()
+                .to_bn()?
+                .to_hex_str()?
+                .to_string(),
+            not_before: certificate.not_before().to_string(),
+            not_after: certificate.not_after().to_string(),
+            sha256_fingerprint: to_hex(&certificate.digest(MessageDigest::sha256())?),
+        })
+    }
+}
+
+/// Formats a distinguished name as `CN=..., O=...`.
+pub fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = String::from_utf8_lossy(entry.data().as_slice());
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A decoded manifest, in whichever format it was written.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "format", rename_all = "kebab-case")]
+pub enum InspectedManifest {
+    Fixme(Manifest),
+    ManifestTool(ToolManifest),
+}
+
+/// What a manifest contains and whether it can be trusted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Inspection {
+    pub manifest: InspectedManifest,
+    /// SHA-256 fingerprint of the certificate the manifest was signed with.
+    pub signer_fingerprint: String,
+    /// The signer certificate, unless the manifest only names it by fingerprint
+    /// and the trust store has no certificate with that fingerprint.
+    pub signer: Option<Signer>,
+    /// The signature matches the signer certificate; `None` when the certificate is unknown.
+    pub signature_valid: Option<bool>,
+    /// The signer certificate chains to the configured trust store.
+    pub trusted: bool,
+    /// Why the signer is not trusted, if it is not.
+    pub trust_error: Option<String>,
+}
+
+/// Certificates manifest signers must chain to.
+pub struct TrustStore {
+    store: X509Store,
+    certificates: Vec<X509>,
+}
+
+impl TrustStore {
+    /// Loads the trust store at `path`, or none when `path` is empty as it is by default.
+    pub fn configured(path: &str) -> Result<Option<Self>, ManifestError> {
+        if path.is_empty() {
+            Ok(None)
+        } else {
+            Self::load(Path::new(path)).map(Some)
+        }
+    }
+
+    /// Loads every PEM or DER certificate in a file, or in the files of a directory.
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        let mut files = Vec::new();
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                if entry.path().is_file() {
+                    files.push(entry.path());
+                }
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+
+        let mut builder = X509StoreBuilder::new()?;
+        let mut trusted = Vec::new();
+        for file in files {
+            let bytes = std::fs::read(&file)?;
+            let certificates = X509::stack_from_pem(&bytes)
+                .ok()
+                .filter(|certificates| !certificates.is_empty())
+                .or_else(|| X509::from_der(&bytes).ok().map(|c| vec![c]))
+                .unwrap_or_default();
+            for certificate in certificates {
+                builder.add_cert(certificate.clone())?;
+                trusted.push(certificate);
+            }
+        }
+        if trusted.is_empty() {
+            return Err(ManifestError::Invalid(format!(
+                "no certificates found in trust store {}",
+                path.display()
+            )));
+        }
+        Ok(TrustStore {
+            store: builder.build(),
+            certificates: trusted,
+        })
+    }
+
+    /// The trusted certificate with the SHA-256 `fingerprint`, if there is one.
+    pub fn find(&self, fingerprint: &[u8]) -> Option<&X509> {
+        self.certificates.iter().find(|certificate| {
+            certificate
+                .digest(MessageDigest::sha256())
+                .is_ok_and(|digest| *digest == *fingerprint)
+        })
+    }
+
+    /// Checks that `certificate` chains to one of the trusted certificates.
+    pub fn verify(&self, certificate: &X509) -> Result<(), String> {
+        let chain = Stack::new().map_err(|e| e.to_string())?;
+        let mut context = X509StoreContext::new().map_err(|e| e.to_string())?;
+        context
+            .init(&self.store, certificate, &chain, |context| {
+                Ok(if context.verify_cert()? {
+                    Ok(())
+                } else {
+                    Err(context.error().error_string().to_string())
+                })
+            })
+            .map_err(|e| e.to_string())?
+    }
+}
+
+/// Decodes a manifest in fixme's or manifest-tool's format and checks its signature and signer.
+///
+/// Manifests written by manifest-tool only name their signer by fingerprint, so
+/// their signer certificate has to be in the trust store to be checked at all.
+pub fn inspect(
+    bytes: &[u8],
+    trust_store: Option<&TrustStore>,
+) -> Result<Inspection, ManifestError> {
+    let (manifest, fingerprint, certificate, signature_valid) = match SignedManifest::decode(bytes)
+    {
+        Ok(signed) => {
+            let signature_valid = signed.signature_is_valid()?;
+            let fingerprint = signed.certificate.digest(MessageDigest::sha256())?.to_vec();
+            (
+                InspectedManifest::Fixme(signed.manifest),
+                fingerprint,
+                Some(signed.certificate),
+                Some(signature_valid),
+            )
+        }
+        Err(ManifestError::UnsupportedFormat) => {
+            let signed = SignedResource::decode(bytes)?;
+            let certificate =
+                trust_store.and_then(|store| store.find(&signed.certificate_fingerprint));
+            let signature_valid = match certificate {
+                Some(certificate) => Some(signed.signature_is_valid(certificate)?),
+                None => None,
+            };
+            (
+                InspectedManifest::ManifestTool(signed.manifest),
+                signed.certificate_fingerprint,
+                certificate.cloned(),
+                signature_valid,
+            )
+        }
+        Err(e) => return Err(e),
+    };
+    let trust = match (trust_store, &certificate) {
+        (None, _) => Err("no trust store is configured".to_string()),
+        (Some(_), None) => Err(format!(
+            "no certificate in the trust store has the signer's fingerprint {}",
+            to_hex(&fingerprint)
+        )),
+        (Some(store), Some(certificate)) => store.verify(certificate),
+    };
+    Ok(Inspection {
+        manifest,
+        signer_fingerprint: to_hex(&fingerprint),
+        signer: certificate
+            .as_ref()
+            .map(Signer::from_certificate)
+            .transpose()?,
+        signature_valid,
+        trusted: trust.is_ok(),
+        trust_error: trust.err(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use openssl::pkey::{PKey, Private};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        manifest::{native::NativeEncoder, tool_format::ToolFormatEncoder, ManifestRequest},
+        test_certs::{self_signed, Identity},
+    };
+
+    fn request(dir: &Path, key: &PKey<Private>, certificate: &X509) -> ManifestRequest {
+        std::fs::write(dir.join("image.bin"), b"firmware").unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(dir.join("cert.pem"), certificate.to_pem().unwrap()).unwrap();
+        ManifestRequest {
+            image: dir.join("image.bin"),
+            payload_uri: "http://example.com/image.bin".to_string(),
+            signing_key: dir.join("key.pem"),
+            certificate: dir.join("cert.pem"),
+            output: dir.join("image.bin.manifest"),
+            payload_digest: None,
+            delta: None,
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn both_formats_are_inspected() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        let trust_store = TrustStore::load(&request.certificate).unwrap();
+        let fingerprint = to_hex(&certificate.digest(MessageDigest::sha256()).unwrap());
+
+        NativeEncoder::new(Uuid::new_v4(), Uuid::new_v4())
+            .generate(&request)
+            .unwrap();
+        let bytes = std::fs::read(&request.output).unwrap();
+        let inspection = inspect(&bytes, Some(&trust_store)).unwrap();
+        assert!(matches!(inspection.manifest, InspectedManifest::Fixme(_)));
+        assert_eq!(Some(true), inspection.signature_valid);
+        assert!(inspection.trusted);
+        assert_eq!(fingerprint, inspection.signer_fingerprint);
+
+        ToolFormatEncoder::new(Uuid::new_v4(), Uuid::new_v4())
+            .generate(&request)
+            .unwrap();
+        let bytes = std::fs::read(&request.output).unwrap();
+        let inspection = inspect(&bytes, Some(&trust_store)).unwrap();
+        assert!(matches!(
+            inspection.manifest,
+            InspectedManifest::ManifestTool(_)
+        ));
+        assert_eq!(Some(true), inspection.signature_valid);
+        assert!(inspection.trusted, "{:?}", inspection.trust_error);
+        assert_eq!(fingerprint, inspection.signer_fingerprint);
+        assert_eq!(
+            fingerprint,
+            inspection.signer.as_ref().unwrap().sha256_fingerprint
+        );
+        let json = serde_json::to_value(&inspection).unwrap();
+        assert_eq!("manifest-tool", json["manifest"]["format"]);
+        assert_eq!("raw-binary", json["manifest"]["payload_format"]);
+    }
+
+    #[test]
+    fn unknown_signers_of_tool_manifests_are_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        ToolFormatEncoder::new(Uuid::new_v4(), Uuid::new_v4())
+            .generate(&request)
+            .unwrap();
+        let bytes = std::fs::read(&request.output).unwrap();
+        let fingerprint = to_hex(&certificate.digest(MessageDigest::sha256()).unwrap());
+
+        let other = dir.path().join("other.pem");
+        std::fs::write(&other, self_signed("other").certificate.to_pem().unwrap()).unwrap();
+        let trust_store = TrustStore::load(&other).unwrap();
+        for trust_store in [None, Some(&trust_store)] {
+            let inspection = inspect(&bytes, trust_store).unwrap();
+            assert!(inspection.signer.is_none());
+            assert_eq!(None, inspection.signature_valid);
+            assert!(!inspection.trusted);
+            assert_eq!(fingerprint, inspection.signer_fingerprint);
+        }
+        let inspection = inspect(&bytes, Some(&trust_store)).unwrap();
+        assert!(inspection.trust_error.unwrap().contains(&fingerprint));
+    }
+}
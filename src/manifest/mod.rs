@@ -0,0 +1,210 @@
+mod der;
+pub mod inspect;
+pub mod native;
+pub mod record;
+pub mod tool;
+pub mod tool_format;
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    catalog::{blob_name, ImageRef},
+    cfg::Cfg,
+    command::FixmeError,
+    compression::Compressed,
+    delta::Delta,
+};
+use native::NativeEncoder;
+use tool::ManifestTool;
+use tool_format::ToolFormatEncoder;
+
+/// Reasons a manifest could not be produced.
+#[derive(Debug)]
+pub enum ManifestError {
+    /// An input could not be read or the manifest could not be written.
+    Io(std::io::Error),
+    /// `manifest-tool` could not be started.
+    Spawn(std::io::Error),
+    /// `manifest-tool` ran but exited unsuccessfully.
+    Failed { status: Option<i32>, stderr: String },
+    /// The signing key or certificate could not be used.
+    Crypto(openssl::error::ErrorStack),
+    /// The configuration or an input is unusable.
+    Invalid(String),
+    /// The input is not a manifest in fixme's format, such as one written by `manifest-tool`.
+    UnsupportedFormat,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io(e) => write!(f, "{}", e),
+            ManifestError::Spawn(e) => write!(f, "failed to run manifest-tool: {}", e),
+            ManifestError::Failed { status, stderr } => {
+                match status {
+                    Some(code) => write!(f, "manifest-tool exited with status {}", code)?,
+                    None => write!(f, "manifest-tool was terminated by a signal")?,
+                }
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            ManifestError::Crypto(e) => write!(f, "signing failed: {}", e),
+            ManifestError::Invalid(reason) => write!(f, "{}", reason),
+            ManifestError::UnsupportedFormat => write!(
+                f,
+                "unsupported manifest format: expected a manifest in fixme's format or one \
+                 written by manifest-tool 1.x"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl FixmeError for ManifestError {}
+
+impl From<std::io::Error> for ManifestError {
+    fn from(value: std::io::Error) -> Self {
+        ManifestError::Io(value)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for ManifestError {
+    fn from(value: openssl::error::ErrorStack) -> Self {
+        ManifestError::Crypto(value)
+    }
+}
+
+/// How manifests get created, chosen by the `manifest_encoder` setting.
+#[derive(Debug, Clone)]
+pub enum Generator {
+    /// Shell out to the Python `manifest-tool`.
+    Tool(ManifestTool),
+    /// Encode and sign in-process, in fixme's own manifest format.
+    Native(NativeEncoder),
+    /// Encode and sign in-process, in manifest-tool's format.
+    ToolFormat(ToolFormatEncoder),
+}
+
+impl Generator {
+    pub fn from_cfg(cfg: &Cfg) -> Result<Self, ManifestError> {
+        let parse = |name: &str, value: &str| {
+            Uuid::parse_str(value).map_err(|e| {
+                ManifestError::Invalid(format!("{} '{}' is not a UUID: {}", name, value, e))
+            })
+        };
+        match cfg.manifest_encoder.as_str() {
+            "tool" => Ok(Generator::Tool(ManifestTool::new(&cfg.manifest_tool))),
+            "native" => Ok(Generator::ToolFormat(ToolFormatEncoder::new(
+                parse("vendor_id", &cfg.vendor_id)?,
+                parse("class_id", &cfg.class_id)?,
+            ))),
+            "fixme" => Ok(Generator::Native(NativeEncoder::new(
+                parse("vendor_id", &cfg.vendor_id)?,
+                parse("class_id", &cfg.class_id)?,
+            ))),
+            other => Err(ManifestError::Invalid(format!(
+                "unknown manifest_encoder '{}', expected 'tool', 'native' or 'fixme'",
+                other
+            ))),
+        }
+    }
+
+    /// Creates the manifest described by `request`, returning a log of what was done.
+    pub fn generate(&self, request: &ManifestRequest) -> Result<String, ManifestError> {
+        match self {
+            Generator::Tool(_) if request.delta.is_some() || request.compression.is_some() => {
+                Err(ManifestError::Invalid(
+                    "manifests for deltas and compressed payloads need manifest_encoder 'fixme'"
+                        .to_string(),
+                ))
+            }
+            Generator::Tool(tool) => tool
+                .run(request)
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned()),
+            Generator::Native(encoder) => encoder.generate(request).map(|manifest| {
+                format!(
+                    "Wrote manifest {} for {} ({} bytes, sequence number {})",
+                    request.output.display(),
+                    manifest.payload_uri,
+                    manifest.payload_size,
+                    manifest.sequence_number
+                )
+            }),
+            Generator::ToolFormat(encoder) => encoder.generate(request).map(|manifest| {
+                format!(
+                    "Wrote manifest {} for {} ({} bytes, timestamp {})",
+                    request.output.display(),
+                    manifest.payload_uri,
+                    manifest.payload_size,
+                    manifest.timestamp
+                )
+            }),
+        }
+    }
+}
+
+/// Everything needed to produce one signed update manifest.
+///
+/// Both the `generate-manifest` subcommand and the `/generate-manifest` route
+/// build one of these so the two always hand `manifest-tool` the same inputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRequest {
+    /// Firmware image the manifest describes.
+    pub image: PathBuf,
+    /// URI devices will fetch the payload from.
+    pub payload_uri: String,
+    /// Private key used to sign the manifest.
+    pub signing_key: PathBuf,
+    /// Certificate matching `signing_key`.
+    pub certificate: PathBuf,
+    /// Where the manifest is written.
+    pub output: PathBuf,
+    /// Hex SHA-256 of `image` when it is already known, saving a second pass over the file.
+    pub payload_digest: Option<String>,
+    /// Set when `image` is a delta, making the manifest a delta manifest.
+    pub delta: Option<Delta>,
+    /// Set when `image` is compressed, for devices to decompress it.
+    pub compression: Option<Compressed>,
+}
+
+impl ManifestRequest {
+    /// Builds a request for an uploaded image.
+    ///
+    /// `image` points at the image's blob in the local uploads directory; the
+    /// job queue replaces it when the blob lives elsewhere. The manifest is
+    /// written to the manifests directory, named after the image.
+    pub fn for_upload(cfg: &Cfg, image: &ImageRef, payload_uri: &str) -> Self {
+        ManifestRequest {
+            image: Path::new(&cfg.uploads_dir)
+                .join("blobs")
+                .join(blob_name(&image.digest)),
+            payload_uri: payload_uri.to_string(),
+            signing_key: PathBuf::from(&cfg.signing_key),
+            certificate: PathBuf::from(&cfg.certificate),
+            output: Path::new(&cfg.manifests_dir).join(manifest_file_name(&image.name)),
+            payload_digest: Some(image.digest.clone()),
+            delta: image.details.delta.clone(),
+            compression: image.details.compression.clone(),
+        }
+    }
+}
+
+/// Returns the file name a manifest for `image_name` generated now is stored under.
+///
+/// The name carries the time so manifests generated earlier are kept.
+pub fn manifest_file_name(image_name: &str) -> String {
+    format!(
+        "{}-{}.manifest",
+        image_name,
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    )
+}
@@ -0,0 +1,586 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::Utc;
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sha::Sha256,
+    sign::{Signer, Verifier},
+    x509::X509,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{der, ManifestError, ManifestRequest};
+
+/// Version of the manifest layout written by [`NativeEncoder`].
+pub const MANIFEST_VERSION: u64 = 1;
+/// Version of manifests carrying any of the optional elements, such as a [`DeltaPayload`].
+pub const EXTENDED_MANIFEST_VERSION: u64 = 2;
+
+/// The signed part of an update manifest in fixme's own format.
+///
+/// This is not the ASN.1 schema `manifest-tool` writes, and update clients
+/// built for its manifests cannot read it. Encoded as the DER `SEQUENCE` below, which is then wrapped together with
+/// its ECDSA-P256/SHA-256 signature and the signer certificate:
+///
+/// ```text
+/// SignedManifest ::= SEQUENCE {
+///     manifest        Manifest,
+///     signature       OCTET STRING,
+///     certificate     OCTET STRING
+/// }
+/// Manifest ::= SEQUENCE {
+///     version         INTEGER,
+///     sequenceNumber  INTEGER,
+///     vendorId        OCTET STRING (SIZE(16)),
+///     classId         OCTET STRING (SIZE(16)),
+///     payloadUri      UTF8String,
+///     payloadDigest   OCTET STRING (SIZE(32)),
+///     payloadSize     INTEGER,
+///     delta           [0] DeltaPayload OPTIONAL,      -- version 2 only
+///     compression     [1] CompressedPayload OPTIONAL  -- version 2 only
+/// }
+/// DeltaPayload ::= SEQUENCE {
+///     baseDigest      OCTET STRING (SIZE(32)),
+///     installedDigest OCTET STRING (SIZE(32)),
+///     installedSize   INTEGER
+/// }
+/// CompressedPayload ::= SEQUENCE {
+///     algorithm           UTF8String,  -- "gzip", "zstd" or "lzma"
+///     uncompressedDigest  OCTET STRING (SIZE(32)),
+///     uncompressedSize    INTEGER
+/// }
+/// ```
+///
+/// A device decompresses a compressed payload first, then applies it as a
+/// delta if it is one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Manifest {
+    pub version: u64,
+    pub sequence_number: u64,
+    pub vendor_id: Uuid,
+    pub class_id: Uuid,
+    pub payload_uri: String,
+    #[serde(serialize_with = "serialize_hex")]
+    pub payload_digest: Vec<u8>,
+    pub payload_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<DeltaPayload>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressedPayload>,
+}
+
+/// Describes a payload that patches the image a device runs.
+///
+/// The device must check it runs the base image before applying the patch, and
+/// the result against the installed digest and size afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeltaPayload {
+    #[serde(serialize_with = "serialize_hex")]
+    pub base_digest: Vec<u8>,
+    #[serde(serialize_with = "serialize_hex")]
+    pub installed_digest: Vec<u8>,
+    pub installed_size: u64,
+}
+
+/// Describes a payload to decompress before use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompressedPayload {
+    pub algorithm: String,
+    #[serde(serialize_with = "serialize_hex")]
+    pub uncompressed_digest: Vec<u8>,
+    pub uncompressed_size: u64,
+}
+
+pub(super) fn serialize_hex<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(bytes))
+}
+
+/// Formats bytes as lowercase hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses lowercase or uppercase hexadecimal.
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, ManifestError> {
+    let invalid = || ManifestError::Invalid(format!("'{}' is not a hexadecimal digest", hex));
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+impl Manifest {
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut fields = vec![
+            der::integer(self.version),
+            der::integer(self.sequence_number),
+            der::octet_string(self.vendor_id.as_bytes()),
+            der::octet_string(self.class_id.as_bytes()),
+            der::utf8_string(&self.payload_uri),
+            der::octet_string(&self.payload_digest),
+            der::integer(self.payload_size),
+        ];
+        if let Some(delta) = &self.delta {
+            fields.push(der::tagged(
+                0,
+                &[
+                    der::octet_string(&delta.base_digest),
+                    der::octet_string(&delta.installed_digest),
+                    der::integer(delta.installed_size),
+                ],
+            ));
+        }
+        if let Some(compression) = &self.compression {
+            fields.push(der::tagged(
+                1,
+                &[
+                    der::utf8_string(&compression.algorithm),
+                    der::octet_string(&compression.uncompressed_digest),
+                    der::integer(compression.uncompressed_size),
+                ],
+            ));
+        }
+        der::sequence(&fields)
+    }
+
+    /// Signs the manifest, returning the encoded `SignedManifest`.
+    pub fn sign(&self, key: &PKey<Private>, certificate: &X509) -> Result<Vec<u8>, ManifestError> {
+        let encoded = self.to_der();
+        let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+        signer.update(&encoded)?;
+        let signature = signer.sign_to_vec()?;
+        Ok(der::sequence(&[
+            encoded,
+            der::octet_string(&signature),
+            der::octet_string(&certificate.to_der()?),
+        ]))
+    }
+}
+
+impl Manifest {
+    fn decode(reader: &mut der::Reader) -> Result<Self, der::DecodeError> {
+        let uuid = |bytes: &[u8], name: &str| {
+            Uuid::from_slice(bytes).map_err(|_| der::DecodeError(format!("{} is not a UUID", name)))
+        };
+        let version = reader.integer()?;
+        if version != MANIFEST_VERSION && version != EXTENDED_MANIFEST_VERSION {
+            return Err(der::DecodeError(format!(
+                "unsupported manifest version {}",
+                version
+            )));
+        }
+        let mut manifest = Manifest {
+            version,
+            sequence_number: reader.integer()?,
+            vendor_id: uuid(reader.octet_string()?, "vendorId")?,
+            class_id: uuid(reader.octet_string()?, "classId")?,
+            payload_uri: reader.utf8_string()?.to_string(),
+            payload_digest: reader.octet_string()?.to_vec(),
+            payload_size: reader.integer()?,
+            delta: None,
+            compression: None,
+        };
+        if let Some(mut delta) = reader.optional_tagged(0)? {
+            manifest.delta = Some(DeltaPayload {
+                base_digest: delta.octet_string()?.to_vec(),
+                installed_digest: delta.octet_string()?.to_vec(),
+                installed_size: delta.integer()?,
+            });
+        }
+        if let Some(mut compression) = reader.optional_tagged(1)? {
+            manifest.compression = Some(CompressedPayload {
+                algorithm: compression.utf8_string()?.to_string(),
+                uncompressed_digest: compression.octet_string()?.to_vec(),
+                uncompressed_size: compression.integer()?,
+            });
+        }
+        let extended = manifest.delta.is_some() || manifest.compression.is_some();
+        if extended != (version == EXTENDED_MANIFEST_VERSION) {
+            return Err(der::DecodeError(format!(
+                "version {} manifests {} optional elements",
+                version,
+                if extended { "cannot have" } else { "must have" }
+            )));
+        }
+        Ok(manifest)
+    }
+}
+
+/// A decoded `SignedManifest`.
+#[derive(Debug, Clone)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    pub signature: Vec<u8>,
+    pub certificate: X509,
+    /// The manifest exactly as it was signed.
+    encoded: Vec<u8>,
+}
+
+impl SignedManifest {
+    /// Whether `bytes` start out like a `SignedManifest`: a `SEQUENCE` whose
+    /// first element is a `SEQUENCE` starting with a known version.
+    fn is_fixme_format(bytes: &[u8]) -> bool {
+        let version = der::Reader::new(bytes)
+            .sequence()
+            .and_then(|mut signed| signed.sequence())
+            .and_then(|mut manifest| manifest.integer());
+        matches!(version, Ok(MANIFEST_VERSION | EXTENDED_MANIFEST_VERSION))
+    }
+
+    /// Decodes a signed manifest, failing with [`ManifestError::UnsupportedFormat`]
+    /// for anything that is not in fixme's format.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ManifestError> {
+        if !Self::is_fixme_format(bytes) {
+            return Err(ManifestError::UnsupportedFormat);
+        }
+        let invalid = |e: der::DecodeError| ManifestError::Invalid(e.to_string());
+        let mut outer = der::Reader::new(bytes);
+        let mut signed = outer.sequence().map_err(invalid)?;
+        if !outer.is_empty() {
+            return Err(ManifestError::Invalid(
+                "malformed manifest: trailing data".to_string(),
+            ));
+        }
+        let (mut fields, encoded) = signed.sequence_with_encoding().map_err(invalid)?;
+        let manifest = Manifest::decode(&mut fields).map_err(invalid)?;
+        let signature = signed.octet_string().map_err(invalid)?.to_vec();
+        let certificate = X509::from_der(signed.octet_string().map_err(invalid)?)?;
+        Ok(SignedManifest {
+            manifest,
+            signature,
+            certificate,
+            encoded: encoded.to_vec(),
+        })
+    }
+
+    /// Checks the signature against the public key of the embedded certificate.
+    pub fn signature_is_valid(&self) -> Result<bool, ManifestError> {
+        let key = self.certificate.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(&self.encoded)?;
+        // A malformed signature is simply not a valid one.
+        Ok(verifier.verify(&self.signature).unwrap_or(false))
+    }
+}
+
+/// Computes the SHA-256 digest and size of a file without loading it whole.
+pub fn digest_file(path: &Path) -> std::io::Result<(Vec<u8>, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((hasher.finish().to_vec(), size))
+}
+
+/// Loads an ECDSA P-256 private key from a PEM or DER file.
+pub fn load_signing_key(path: &Path) -> Result<PKey<Private>, ManifestError> {
+    let bytes = std::fs::read(path)?;
+    let key = PKey::private_key_from_pem(&bytes).or_else(|_| PKey::private_key_from_der(&bytes))?;
+    let is_p256 = key
+        .ec_key()
+        .map(|ec| ec.group().curve_name() == Some(Nid::X9_62_PRIME256V1))
+        .unwrap_or(false);
+    if !is_p256 {
+        return Err(ManifestError::Invalid(format!(
+            "{} is not an ECDSA P-256 private key",
+            path.display()
+        )));
+    }
+    Ok(key)
+}
+
+/// Loads an X.509 certificate from a PEM or DER file.
+pub fn load_certificate(path: &Path) -> Result<X509, ManifestError> {
+    let bytes = std::fs::read(path)?;
+    Ok(X509::from_pem(&bytes).or_else(|_| X509::from_der(&bytes))?)
+}
+
+/// Loads the signing key and certificate named by `request`, checking they belong together.
+pub fn load_signer(request: &ManifestRequest) -> Result<(PKey<Private>, X509), ManifestError> {
+    let key = load_signing_key(&request.signing_key)?;
+    let certificate = load_certificate(&request.certificate)?;
+    if !certificate.public_key()?.public_eq(&key) {
+        return Err(ManifestError::Invalid(format!(
+            "{} does not match the signing key {}",
+            request.certificate.display(),
+            request.signing_key.display()
+        )));
+    }
+    Ok((key, certificate))
+}
+
+/// Writes a signed manifest to the output path of `request`.
+pub fn write_manifest(request: &ManifestRequest, signed: &[u8]) -> Result<(), ManifestError> {
+    if let Some(parent) = request.output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&request.output, signed)?;
+    Ok(())
+}
+
+/// Sequence number of the last manifest this process encoded.
+static LAST_SEQUENCE_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+/// A sequence number greater than any handed out before: the time in
+/// milliseconds, or one more than the last number if the clock has not moved on.
+pub fn next_sequence_number() -> u64 {
+    let now = Utc::now().timestamp_millis().max(0) as u64;
+    let last = LAST_SEQUENCE_NUMBER
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    now.max(last + 1)
+}
+
+/// Encodes and signs manifests in fixme's format, without `manifest-tool`.
+#[derive(Debug, Clone)]
+pub struct NativeEncoder {
+    vendor_id: Uuid,
+    class_id: Uuid,
+}
+
+impl NativeEncoder {
+    pub fn new(vendor_id: Uuid, class_id: Uuid) -> Self {
+        NativeEncoder {
+            vendor_id,
+            class_id,
+        }
+    }
+
+    /// Describes the payload in `request`, numbered with [`next_sequence_number`].
+    pub fn manifest_for(&self, request: &ManifestRequest) -> Result<Manifest, ManifestError> {
+        let (payload_digest, payload_size) = match &request.payload_digest {
+            Some(digest) => (from_hex(digest)?, std::fs::metadata(&request.image)?.len()),
+            None => digest_file(&request.image)?,
+        };
+        let delta = match &request.delta {
+            Some(delta) => Some(DeltaPayload {
+                base_digest: from_hex(&delta.base_digest)?,
+                installed_digest: from_hex(&delta.target_digest)?,
+                installed_size: delta.target_size,
+            }),
+            None => None,
+        };
+        let compression = match &request.compression {
+            Some(compressed) => Some(CompressedPayload {
+                algorithm: compressed.algorithm.to_string(),
+                uncompressed_digest: from_hex(&compressed.uncompressed_digest)?,
+                uncompressed_size: compressed.uncompressed_size,
+            }),
+            None => None,
+        };
+        Ok(Manifest {
+            version: if delta.is_some() || compression.is_some() {
+                EXTENDED_MANIFEST_VERSION
+            } else {
+                MANIFEST_VERSION
+            },
+            sequence_number: next_sequence_number(),
+            vendor_id: self.vendor_id,
+            class_id: self.class_id,
+            payload_uri: request.payload_uri.clone(),
+            payload_digest,
+            payload_size,
+            delta,
+            compression,
+        })
+    }
+
+    /// Writes the signed manifest for `request` to its output path.
+    pub fn generate(&self, request: &ManifestRequest) -> Result<Manifest, ManifestError> {
+        let (key, certificate) = load_signer(request)?;
+        let manifest = self.manifest_for(request)?;
+        write_manifest(request, &manifest.sign(&key, &certificate)?)?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::{Compressed, Compression},
+        delta::Delta,
+        test_certs::{self_signed, Identity},
+    };
+
+    fn request(dir: &Path, key: &PKey<Private>, certificate: &X509) -> ManifestRequest {
+        std::fs::write(dir.join("image.bin"), b"firmware").unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(dir.join("cert.der"), certificate.to_der().unwrap()).unwrap();
+        ManifestRequest {
+            image: dir.join("image.bin"),
+            payload_uri: "http://example.com/image.bin".to_string(),
+            signing_key: dir.join("key.pem"),
+            certificate: dir.join("cert.der"),
+            output: dir.join("out/image.bin.manifest"),
+            payload_digest: None,
+            delta: None,
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn generated_manifest_is_signed_by_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        let encoder = NativeEncoder::new(Uuid::new_v4(), Uuid::new_v4());
+
+        let manifest = encoder.generate(&request).unwrap();
+        assert_eq!(8, manifest.payload_size);
+        assert_eq!(
+            openssl::sha::sha256(b"firmware").to_vec(),
+            manifest.payload_digest
+        );
+
+        let signed = SignedManifest::decode(&std::fs::read(&request.output).unwrap()).unwrap();
+        assert_eq!(manifest, signed.manifest);
+        assert_eq!(
+            certificate.to_der().unwrap(),
+            signed.certificate.to_der().unwrap()
+        );
+        assert!(signed.signature_is_valid().unwrap());
+
+        let known = ManifestRequest {
+            payload_digest: Some(to_hex(&manifest.payload_digest)),
+            ..request
+        };
+        assert_eq!(
+            manifest.payload_digest,
+            encoder.manifest_for(&known).unwrap().payload_digest
+        );
+    }
+
+    #[test]
+    fn manifests_describe_compressed_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let base_digest = to_hex(&openssl::sha::sha256(b"firmware 1.0"));
+        let target_digest = to_hex(&openssl::sha::sha256(b"firmware 1.1"));
+        let request = ManifestRequest {
+            delta: Some(Delta {
+                base: "fw-1.0.bin".to_string(),
+                base_digest: base_digest.clone(),
+                target: "fw-1.1.bin".to_string(),
+                target_digest: target_digest.clone(),
+                target_size: 12,
+            }),
+            compression: Some(Compressed {
+                algorithm: Compression::Zstd,
+                image: "fw-1.1-from-fw-1.0.delta".to_string(),
+                uncompressed_digest: to_hex(&openssl::sha::sha256(b"delta")),
+                uncompressed_size: 60,
+            }),
+            ..request(dir.path(), &key, &certificate)
+        };
+        let encoder = NativeEncoder::new(Uuid::new_v4(), Uuid::new_v4());
+        let manifest = encoder.generate(&request).unwrap();
+        assert_eq!(EXTENDED_MANIFEST_VERSION, manifest.version);
+
+        let signed = SignedManifest::decode(&std::fs::read(&request.output).unwrap()).unwrap();
+        assert_eq!(manifest, signed.manifest);
+        let delta = signed.manifest.delta.unwrap();
+        assert_eq!(base_digest, to_hex(&delta.base_digest));
+        assert_eq!(target_digest, to_hex(&delta.installed_digest));
+        assert_eq!(12, delta.installed_size);
+        let compression = signed.manifest.compression.unwrap();
+        assert_eq!("zstd", compression.algorithm);
+        assert_eq!(60, compression.uncompressed_size);
+    }
+
+    #[test]
+    fn tampered_manifest_fails_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        let encoder = NativeEncoder::new(Uuid::new_v4(), Uuid::new_v4());
+        encoder.generate(&request).unwrap();
+
+        let mut bytes = std::fs::read(&request.output).unwrap();
+        let uri = bytes
+            .windows(7)
+            .position(|window| window == b"example")
+            .unwrap();
+        bytes[uri] = b'E';
+        let signed = SignedManifest::decode(&bytes).unwrap();
+        assert_eq!("http://Example.com/image.bin", signed.manifest.payload_uri);
+        assert!(!signed.signature_is_valid().unwrap());
+    }
+
+    #[test]
+    fn certificate_must_match_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = self_signed("fixme").key;
+        let other_certificate = self_signed("other").certificate;
+        let request = request(dir.path(), &key, &other_certificate);
+        let encoder = NativeEncoder::new(Uuid::new_v4(), Uuid::new_v4());
+        assert!(matches!(
+            encoder.generate(&request),
+            Err(ManifestError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn sequence_numbers_always_increase() {
+        let before = Utc::now().timestamp_millis() as u64;
+        let numbers: Vec<u64> = (0..1000).map(|_| next_sequence_number()).collect();
+        assert!(numbers[0] >= before);
+        assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn other_formats_are_unsupported() {
+        // Laid out like manifest-tool's v1 SignedResource: a Resource naming its
+        // URI and type, then the signature block.
+        let tool_v1 = der::sequence(&[
+            der::sequence(&[
+                der::utf8_string("http://example.com/image.manifest"),
+                der::tlv(0x0a, &[0]),
+                der::sequence(&[der::tlv(0x0a, &[1]), der::octet_string(b"description")]),
+            ]),
+            der::sequence(&[der::octet_string(&[0; 32]), der::sequence(&[])]),
+        ]);
+        // Laid out like manifest-tool's v3 envelope, which starts with its version.
+        let tool_v3 = der::sequence(&[
+            der::tlv(0x0a, &[3]),
+            der::sequence(&[der::integer(1)]),
+            der::octet_string(&[0; 64]),
+        ]);
+        for bytes in [tool_v1, tool_v3, b"{\"manifest\": 1}".to_vec(), vec![]] {
+            assert!(matches!(
+                SignedManifest::decode(&bytes),
+                Err(ManifestError::UnsupportedFormat)
+            ));
+        }
+
+        let malformed = der::sequence(&[der::sequence(&[der::integer(MANIFEST_VERSION)])]);
+        assert!(matches!(
+            SignedManifest::decode(&malformed),
+            Err(ManifestError::Invalid(_))
+        ));
+    }
+}
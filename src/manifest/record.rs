@@ -0,0 +1,133 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::catalog::ImageRef;
+
+/// Suffix of the sidecar describing a generated manifest.
+const SIDECAR_SUFFIX: &str = ".json";
+
+/// Which image a manifest in the manifests directory was generated from.
+///
+/// Kept as a JSON sidecar next to the manifest itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ManifestRecord {
+    /// File name of the manifest within the manifests directory.
+    pub file: String,
+    pub image: String,
+    /// Hex SHA-256 of the image the manifest describes.
+    pub image_digest: String,
+    pub payload_uri: String,
+    pub created: DateTime<Utc>,
+    /// The job that generated the manifest.
+    pub job: Option<Uuid>,
+}
+
+impl ManifestRecord {
+    fn sidecar(manifest: &Path) -> PathBuf {
+        let mut path = manifest.as_os_str().to_owned();
+        path.push(SIDECAR_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Writes the sidecar for the manifest at `manifest`.
+    pub fn save(&self, manifest: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        std::fs::write(Self::sidecar(manifest), json)
+    }
+
+    /// Lists every recorded manifest in `manifests_dir`, newest first.
+    pub fn list(manifests_dir: &Path) -> io::Result<Vec<ManifestRecord>> {
+        let entries = match std::fs::read_dir(manifests_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records: Vec<ManifestRecord> = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_sidecar = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(SIDECAR_SUFFIX));
+            if !is_sidecar {
+                continue;
+            }
+            match serde_json::from_slice(&std::fs::read(&path)?) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!(
+                    "Ignoring unreadable manifest record {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        records.sort_by_key(|record| std::cmp::Reverse(record.created));
+        Ok(records)
+    }
+
+    /// Lists the manifests describing the image with `digest`, newest first.
+    pub fn for_digest(manifests_dir: &Path, digest: &str) -> io::Result<Vec<ManifestRecord>> {
+        let mut records = Self::list(manifests_dir)?;
+        records.retain(|record| record.image_digest == digest);
+        Ok(records)
+    }
+
+    /// Lists the manifests generated from `image` under its current name, newest first.
+    ///
+    /// These are the manifests whose payload may be fetched by that name.
+    pub fn for_image(manifests_dir: &Path, image: &ImageRef) -> io::Result<Vec<ManifestRecord>> {
+        let mut records = Self::for_digest(manifests_dir, &image.digest)?;
+        records.retain(|record| record.image == image.name);
+        Ok(records)
+    }
+
+    /// Finds the record of the manifest called `file`.
+    pub fn find(manifests_dir: &Path, file: &str) -> io::Result<Option<ManifestRecord>> {
+        Ok(Self::list(manifests_dir)?
+            .into_iter()
+            .find(|record| record.file == file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_found_by_image_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = |file: &str, digest: &str| ManifestRecord {
+            file: file.to_string(),
+            image: "a.sgi".to_string(),
+            image_digest: digest.to_string(),
+            payload_uri: "http://example.com/a.sgi".to_string(),
+            created: Utc::now(),
+            job: None,
+        };
+        let first = record("a-1.manifest", "aa");
+        first.save(&dir.path().join(&first.file)).unwrap();
+        let second = record("a-2.manifest", "bb");
+        second.save(&dir.path().join(&second.file)).unwrap();
+        std::fs::write(dir.path().join("a-1.manifest"), b"manifest").unwrap();
+
+        assert_eq!(
+            vec![first.clone()],
+            ManifestRecord::for_digest(dir.path(), "aa").unwrap()
+        );
+        assert_eq!(
+            Some(second),
+            ManifestRecord::find(dir.path(), "a-2.manifest").unwrap()
+        );
+        assert!(ManifestRecord::list(&dir.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+}
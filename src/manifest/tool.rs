@@ -0,0 +1,102 @@
+use std::{
+    ffi::OsString,
+    process::{Command, Output},
+};
+
+use log::debug;
+
+use super::{ManifestError, ManifestRequest};
+
+/// Wrapper around the external `manifest-tool` program.
+#[derive(Debug, Clone)]
+pub struct ManifestTool {
+    program: String,
+}
+
+impl ManifestTool {
+    pub fn new(program: &str) -> Self {
+        ManifestTool {
+            program: program.to_string(),
+        }
+    }
+
+    /// Returns the arguments passed to `manifest-tool` for `request`.
+    pub fn args(request: &ManifestRequest) -> Vec<OsString> {
+        vec![
+            "create".into(),
+            "--payload".into(),
+            request.image.clone().into(),
+            "--uri".into(),
+            request.payload_uri.clone().into(),
+            "--private-key".into(),
+            request.signing_key.clone().into(),
+            "--certificate".into(),
+            request.certificate.clone().into(),
+            "--output-file".into(),
+            request.output.clone().into(),
+        ]
+    }
+
+    /// Returns the command that creates the manifest described by `request`.
+    pub fn command(&self, request: &ManifestRequest) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(Self::args(request));
+        command
+    }
+
+    /// Runs `manifest-tool` to completion, creating the output directory first.
+    pub fn run(&self, request: &ManifestRequest) -> Result<Output, ManifestError> {
+        if let Some(parent) = request.output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut command = self.command(request);
+        debug!("Executing {:?}", command);
+        let output = command.output().map_err(ManifestError::Spawn)?;
+        if !output.status.success() {
+            return Err(ManifestError::Failed {
+                status: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{catalog::ImageRef, cfg::Cfg};
+
+    #[test]
+    fn args_for_uploaded_image() {
+        let cfg = Cfg::default();
+        let image = ImageRef {
+            name: "sbh.sgi".to_string(),
+            digest: "c3bf47ea".to_string(),
+            size: 8,
+            uploaded: chrono::Utc::now(),
+            details: Default::default(),
+        };
+        let request = ManifestRequest::for_upload(&cfg, &image, "http://example.com/sbh.sgi");
+        let output = request.output.to_str().unwrap().to_string();
+        assert!(output.starts_with("./manifests/sbh.sgi-"), "{}", output);
+        assert!(output.ends_with(".manifest"), "{}", output);
+        let expected: Vec<OsString> = [
+            "create",
+            "--payload",
+            "./uploads/blobs/sha256-c3bf47ea",
+            "--uri",
+            "http://example.com/sbh.sgi",
+            "--private-key",
+            ".update-certificates/default.key.pem",
+            "--certificate",
+            ".update-certificates/default.der",
+            "--output-file",
+            &output,
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(expected, ManifestTool::args(&request));
+    }
+}
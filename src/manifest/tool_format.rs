@@ -0,0 +1,546 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    sha::sha256,
+    sign::{Signer, Verifier},
+    x509::X509,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    der,
+    native::{digest_file, from_hex, load_signer, serialize_hex, write_manifest},
+    ManifestError, ManifestRequest,
+};
+
+/// The only `manifestVersion` of manifest-tool's v1 format.
+pub const MANIFEST_VERSION: u64 = 1;
+/// `resourceType` of a resource holding a manifest rather than a payload.
+const RESOURCE_TYPE_MANIFEST: u64 = 0;
+/// `encryptionMode`: signed with ECDSA P-256 and SHA-256, payload not encrypted.
+const ENCRYPTION_NONE_ECC_SECP256R1_SHA256: u64 = 2;
+/// Names of the `PayloadDescription` formats, by their ENUMERATED value.
+const PAYLOAD_FORMATS: [&str; 6] = [
+    "undefined",
+    "raw-binary",
+    "cbor",
+    "hex-location-length-data",
+    "elf",
+    "bsdiff-stream",
+];
+/// `format` of a payload written to the device as it is.
+pub const RAW_BINARY: u64 = 1;
+/// The storage location manifest-tool names when none is given.
+const DEFAULT_STORAGE: &str = "default";
+
+/// An update manifest in the v1 format `manifest-tool create` writes, and that
+/// update clients built for manifest-tool read.
+///
+/// Only the elements fixme fills in or reports are kept; the rest are written
+/// with manifest-tool's defaults and skipped when decoding. The signed
+/// resource is encoded as below (IMPLICIT TAGS, `CHOICE`s of an ENUMERATED
+/// and an OBJECT IDENTIFIER always take the ENUMERATED):
+///
+/// ```text
+/// SignedResource ::= SEQUENCE {
+///     resource        Resource,
+///     signature       ResourceSignature
+/// }
+/// Resource ::= SEQUENCE {
+///     uri             UTF8String OPTIONAL,
+///     resourceType    ENUMERATED { manifest(0), payload(1) },
+///     resource        Manifest
+/// }
+/// Manifest ::= SEQUENCE {
+///     manifestVersion ENUMERATED { v1(1) },
+///     description     UTF8String OPTIONAL,
+///     timestamp       INTEGER,
+///     vendorId        OCTET STRING (SIZE(16)),
+///     classId         OCTET STRING (SIZE(16)),
+///     deviceId        OCTET STRING (SIZE(16)),
+///     nonce           OCTET STRING (SIZE(16)),
+///     vendorInfo      OCTET STRING,
+///     applyPeriod     SEQUENCE { validFrom INTEGER, validTo INTEGER } OPTIONAL,
+///     applyImmediately BOOLEAN,
+///     priority        INTEGER OPTIONAL,
+///     encryptionMode  ENUMERATED { none-ecc-secp256r1-sha256(2), ... },
+///     aliases         SEQUENCE OF ResourceAlias,
+///     dependencies    SEQUENCE OF ResourceReference,
+///     payload         PayloadDescription OPTIONAL
+/// }
+/// PayloadDescription ::= SEQUENCE {
+///     format          ENUMERATED { raw-binary(1), ... },
+///     encryptionInfo  SEQUENCE { ... } OPTIONAL,
+///     storageIdentifier UTF8String,
+///     reference       ResourceReference,
+///     ...
+/// }
+/// ResourceReference ::= SEQUENCE {
+///     hash            OCTET STRING,
+///     uri             UTF8String OPTIONAL,
+///     size            INTEGER
+/// }
+/// ResourceSignature ::= SEQUENCE {
+///     hash            OCTET STRING,
+///     signatures      SEQUENCE OF SignatureBlock,
+///     ...
+/// }
+/// SignatureBlock ::= SEQUENCE {
+///     signature       OCTET STRING,
+///     certificates    SEQUENCE OF CertificateReference
+/// }
+/// CertificateReference ::= SEQUENCE {
+///     fingerprint     OCTET STRING,
+///     uri             UTF8String
+/// }
+/// ```
+///
+/// `hash` is the SHA-256 of the encoded `Resource` and `signature` its
+/// ECDSA-P256/SHA-256 signature. The certificate is not embedded: devices,
+/// and inspection, look it up by its SHA-256 `fingerprint`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ToolManifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Seconds since the epoch; devices refuse a manifest no newer than the last one they applied.
+    pub timestamp: u64,
+    pub vendor_id: Uuid,
+    pub class_id: Uuid,
+    pub device_id: Uuid,
+    #[serde(serialize_with = "serialize_hex")]
+    pub nonce: Vec<u8>,
+    #[serde(serialize_with = "serialize_payload_format")]
+    pub payload_format: u64,
+    pub storage_identifier: String,
+    pub payload_uri: String,
+    #[serde(serialize_with = "serialize_hex")]
+    pub payload_digest: Vec<u8>,
+    pub payload_size: u64,
+}
+
+fn serialize_payload_format<S: serde::Serializer>(
+    format: &u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match PAYLOAD_FORMATS.get(*format as usize) {
+        Some(name) => serializer.serialize_str(name),
+        None => serializer.serialize_str(&format!("unknown ({})", format)),
+    }
+}
+
+impl ToolManifest {
+    /// Encodes the `Resource` holding the manifest, which is what gets signed.
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut fields = vec![der::enumerated(MANIFEST_VERSION)];
+        if let Some(description) = &self.description {
+            fields.push(der::utf8_string(description));
+        }
+        fields.extend([
+            der::integer(self.timestamp),
+            der::octet_string(self.vendor_id.as_bytes()),
+            der::octet_string(self.class_id.as_bytes()),
+            der::octet_string(self.device_id.as_bytes()),
+            der::octet_string(&self.nonce),
+            // vendorInfo
+            der::octet_string(&[]),
+            // applyImmediately
+            der::boolean(true),
+            der::enumerated(ENCRYPTION_NONE_ECC_SECP256R1_SHA256),
+            // aliases and dependencies
+            der::sequence(&[]),
+            der::sequence(&[]),
+            der::sequence(&[
+                der::enumerated(self.payload_format),
+                der::utf8_string(&self.storage_identifier),
+                der::sequence(&[
+                    der::octet_string(&self.payload_digest),
+                    der::utf8_string(&self.payload_uri),
+                    der::integer(self.payload_size),
+                ]),
+            ]),
+        ]);
+        der::sequence(&[
+            der::enumerated(RESOURCE_TYPE_MANIFEST),
+            der::sequence(&fields),
+        ])
+    }
+
+    /// Signs the manifest, returning the encoded `SignedResource`.
+    pub fn sign(&self, key: &PKey<Private>, certificate: &X509) -> Result<Vec<u8>, ManifestError> {
+        let resource = self.to_der();
+        let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+        signer.update(&resource)?;
+        let signature = signer.sign_to_vec()?;
+        let certificate_reference = der::sequence(&[
+            der::octet_string(&certificate.digest(MessageDigest::sha256())?),
+            der::utf8_string(""),
+        ]);
+        let signature_block = der::sequence(&[
+            der::octet_string(&signature),
+            der::sequence(&[certificate_reference]),
+        ]);
+        let resource_signature = der::sequence(&[
+            der::octet_string(&sha256(&resource)),
+            der::sequence(&[signature_block]),
+        ]);
+        Ok(der::sequence(&[resource, resource_signature]))
+    }
+
+    fn decode(reader: &mut der::Reader) -> Result<Self, der::DecodeError> {
+        let uuid = |bytes: &[u8], name: &str| {
+            Uuid::from_slice(bytes).map_err(|_| der::DecodeError(format!("{} is not a UUID", name)))
+        };
+        let version = reader.enumerated()?;
+        if version != MANIFEST_VERSION {
+            return Err(der::DecodeError(format!(
+                "unsupported manifest version {}",
+                version
+            )));
+        }
+        let description = reader.optional_utf8_string()?.map(str::to_owned);
+        let timestamp = reader.integer()?;
+        let vendor_id = uuid(reader.octet_string()?, "vendorId")?;
+        let class_id = uuid(reader.octet_string()?, "classId")?;
+        let device_id = uuid(reader.octet_string()?, "deviceId")?;
+        let nonce = reader.octet_string()?.to_vec();
+        // vendorInfo, applyPeriod, applyImmediately, priority and encryptionMode
+        reader.octet_string()?;
+        reader.optional_sequence()?;
+        reader.boolean()?;
+        reader.optional_integer()?;
+        reader.any()?;
+        // aliases and dependencies
+        reader.sequence()?;
+        reader.sequence()?;
+        let mut payload = reader
+            .optional_sequence()?
+            .ok_or_else(|| der::DecodeError("the manifest describes no payload".to_string()))?;
+        let payload_format = payload.enumerated()?;
+        // encryptionInfo
+        payload.optional_sequence()?;
+        let storage_identifier = payload.utf8_string()?.to_string();
+        let mut reference = payload.sequence()?;
+        let payload_digest = reference.octet_string()?.to_vec();
+        let payload_uri = reference
+            .optional_utf8_string()?
+            .unwrap_or_default()
+            .to_string();
+        let payload_size = reference.integer()?;
+        Ok(ToolManifest {
+            description,
+            timestamp,
+            vendor_id,
+            class_id,
+            device_id,
+            nonce,
+            payload_format,
+            storage_identifier,
+            payload_uri,
+            payload_digest,
+            payload_size,
+        })
+    }
+}
+
+/// A decoded `SignedResource` holding a manifest.
+#[derive(Debug, Clone)]
+pub struct SignedResource {
+    pub manifest: ToolManifest,
+    /// SHA-256 fingerprint of the certificate the manifest says it was signed with.
+    pub certificate_fingerprint: Vec<u8>,
+    hash: Vec<u8>,
+    signature: Vec<u8>,
+    /// The `Resource` exactly as it was signed.
+    resource: Vec<u8>,
+}
+
+impl SignedResource {
+    /// Whether `bytes` start out like a `SignedResource` holding a manifest:
+    /// a `SEQUENCE` whose first element is a `Resource` of type manifest.
+    pub fn is_tool_format(bytes: &[u8]) -> bool {
+        let resource_type = der::Reader::new(bytes)
+            .sequence()
+            .and_then(|mut signed| signed.sequence())
+            .and_then(|mut resource| {
+                resource.optional_utf8_string()?;
+                resource.enumerated()
+            });
+        resource_type == Ok(RESOURCE_TYPE_MANIFEST)
+    }
+
+    /// Decodes a manifest written by manifest-tool, failing with
+    /// [`ManifestError::UnsupportedFormat`] for anything else.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ManifestError> {
+        if !Self::is_tool_format(bytes) {
+            return Err(ManifestError::UnsupportedFormat);
+        }
+        let invalid = |e: der::DecodeError| ManifestError::Invalid(e.to_string());
+        let mut outer = der::Reader::new(bytes);
+        let mut signed = outer.sequence().map_err(invalid)?;
+        if !outer.is_empty() {
+            return Err(ManifestError::Invalid(
+                "malformed manifest: trailing data".to_string(),
+            ));
+        }
+        let (mut resource, encoded) = signed.sequence_with_encoding().map_err(invalid)?;
+        resource.optional_utf8_string().map_err(invalid)?;
+        resource.enumerated().map_err(invalid)?;
+        let mut fields = resource.sequence().map_err(invalid)?;
+        let manifest = ToolManifest::decode(&mut fields).map_err(invalid)?;
+
+        let mut resource_signature = signed.sequence().map_err(invalid)?;
+        let hash = resource_signature.octet_string().map_err(invalid)?.to_vec();
+        let mut blocks = resource_signature.sequence().map_err(invalid)?;
+        let unsigned = || ManifestError::Invalid("the manifest is not signed".to_string());
+        let mut block = blocks
+            .optional_sequence()
+            .map_err(invalid)?
+            .ok_or_else(unsigned)?;
+        let signature = block.octet_string().map_err(invalid)?.to_vec();
+        let mut certificates = block.sequence().map_err(invalid)?;
+        let mut certificate = certificates
+            .optional_sequence()
+            .map_err(invalid)?
+            .ok_or_else(unsigned)?;
+        let certificate_fingerprint = certificate.octet_string().map_err(invalid)?.to_vec();
+        Ok(SignedResource {
+            manifest,
+            certificate_fingerprint,
+            hash,
+            signature,
+            resource: encoded.to_vec(),
+        })
+    }
+
+    /// Checks the hash of the resource and its signature against the public key of `certificate`.
+    pub fn signature_is_valid(&self, certificate: &X509) -> Result<bool, ManifestError> {
+        if self.hash != sha256(&self.resource) {
+            return Ok(false);
+        }
+        let key = certificate.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(&self.resource)?;
+        // A malformed signature is simply not a valid one.
+        Ok(verifier.verify(&self.signature).unwrap_or(false))
+    }
+}
+
+/// Timestamp of the last manifest this process encoded.
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// A timestamp later than any handed out before: the time in seconds, or one
+/// more than the last timestamp if the clock has not moved on.
+///
+/// Seconds, like manifest-tool, so its manifests and these can follow one another.
+pub fn next_timestamp() -> u64 {
+    let now = Utc::now().timestamp().max(0) as u64;
+    let last = LAST_TIMESTAMP
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    now.max(last + 1)
+}
+
+/// Encodes and signs manifests in manifest-tool's format, without running it.
+#[derive(Debug, Clone)]
+pub struct ToolFormatEncoder {
+    vendor_id: Uuid,
+    class_id: Uuid,
+}
+
+impl ToolFormatEncoder {
+    pub fn new(vendor_id: Uuid, class_id: Uuid) -> Self {
+        ToolFormatEncoder {
+            vendor_id,
+            class_id,
+        }
+    }
+
+    /// Describes the payload in `request`, stamped with [`next_timestamp`].
+    pub fn manifest_for(&self, request: &ManifestRequest) -> Result<ToolManifest, ManifestError> {
+        if request.delta.is_some() || request.compression.is_some() {
+            return Err(ManifestError::Invalid(
+                "manifests for deltas and compressed payloads need manifest_encoder 'fixme'"
+                    .to_string(),
+            ));
+        }
+        let (payload_digest, payload_size) = match &request.payload_digest {
+            Some(digest) => (from_hex(digest)?, std::fs::metadata(&request.image)?.len()),
+            None => digest_file(&request.image)?,
+        };
+        let mut nonce = vec![0; 16];
+        rand_bytes(&mut nonce)?;
+        Ok(ToolManifest {
+            description: None,
+            timestamp: next_timestamp(),
+            vendor_id: self.vendor_id,
+            class_id: self.class_id,
+            device_id: Uuid::nil(),
+            nonce,
+            payload_format: RAW_BINARY,
+            storage_identifier: DEFAULT_STORAGE.to_string(),
+            payload_uri: request.payload_uri.clone(),
+            payload_digest,
+            payload_size,
+        })
+    }
+
+    /// Writes the signed manifest for `request` to its output path.
+    pub fn generate(&self, request: &ManifestRequest) -> Result<ToolManifest, ManifestError> {
+        let (key, certificate) = load_signer(request)?;
+        let manifest = self.manifest_for(request)?;
+        write_manifest(request, &manifest.sign(&key, &certificate)?)?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        manifest::native::{to_hex, SignedManifest},
+        test_certs::{self_signed, Identity},
+    };
+
+    fn request(dir: &Path, key: &PKey<Private>, certificate: &X509) -> ManifestRequest {
+        std::fs::write(dir.join("image.bin"), b"firmware").unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(dir.join("cert.der"), certificate.to_der().unwrap()).unwrap();
+        ManifestRequest {
+            image: dir.join("image.bin"),
+            payload_uri: "http://example.com/image.bin".to_string(),
+            signing_key: dir.join("key.pem"),
+            certificate: dir.join("cert.der"),
+            output: dir.join("out/image.bin.manifest"),
+            payload_digest: None,
+            delta: None,
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn generated_manifest_names_its_signer() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        let encoder = ToolFormatEncoder::new(Uuid::new_v4(), Uuid::new_v4());
+
+        let manifest = encoder.generate(&request).unwrap();
+        assert_eq!(8, manifest.payload_size);
+        assert_eq!(16, manifest.nonce.len());
+        let bytes = std::fs::read(&request.output).unwrap();
+        let signed = SignedResource::decode(&bytes).unwrap();
+        assert_eq!(manifest, signed.manifest);
+        assert_eq!(
+            to_hex(&openssl::sha::sha256(b"firmware")),
+            to_hex(&signed.manifest.payload_digest)
+        );
+        assert_eq!(
+            certificate
+                .digest(MessageDigest::sha256())
+                .unwrap()
+                .to_vec(),
+            signed.certificate_fingerprint
+        );
+        assert!(signed.signature_is_valid(&certificate).unwrap());
+        assert!(!signed
+            .signature_is_valid(&self_signed("other").certificate)
+            .unwrap());
+
+        // Neither format is mistaken for the other.
+        assert!(matches!(
+            SignedManifest::decode(&bytes),
+            Err(ManifestError::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn resources_follow_the_v1_schema() {
+        let manifest = ToolManifest {
+            description: None,
+            timestamp: 1_700_000_000,
+            vendor_id: Uuid::from_bytes([0x11; 16]),
+            class_id: Uuid::from_bytes([0x22; 16]),
+            device_id: Uuid::nil(),
+            nonce: vec![0x33; 16],
+            payload_format: RAW_BINARY,
+            storage_identifier: "default".to_string(),
+            payload_uri: "http://a/b".to_string(),
+            payload_digest: vec![0x44; 32],
+            payload_size: 8,
+        };
+        let mut expected = vec![0x30, 0x81, 0xa4, 0x0a, 0x01, 0x00, 0x30, 0x81, 0x9e];
+        expected.extend([0x0a, 0x01, 0x01]);
+        expected.extend([0x02, 0x04, 0x65, 0x53, 0xf1, 0x00]);
+        for byte in [0x11, 0x22, 0x00, 0x33] {
+            expected.extend([0x04, 0x10]);
+            expected.extend([byte; 16]);
+        }
+        expected.extend([0x04, 0x00, 0x01, 0x01, 0xff, 0x0a, 0x01, 0x02]);
+        expected.extend([0x30, 0x00, 0x30, 0x00]);
+        expected.extend([0x30, 0x3f, 0x0a, 0x01, 0x01, 0x0c, 0x07]);
+        expected.extend(b"default");
+        expected.extend([0x30, 0x31, 0x04, 0x20]);
+        expected.extend([0x44; 32]);
+        expected.extend([0x0c, 0x0a]);
+        expected.extend(b"http://a/b");
+        expected.extend([0x02, 0x01, 0x08]);
+        assert_eq!(expected, manifest.to_der());
+    }
+
+    #[test]
+    fn tampered_manifest_fails_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = request(dir.path(), &key, &certificate);
+        ToolFormatEncoder::new(Uuid::new_v4(), Uuid::new_v4())
+            .generate(&request)
+            .unwrap();
+
+        let mut bytes = std::fs::read(&request.output).unwrap();
+        let uri = bytes
+            .windows(7)
+            .position(|window| window == b"example")
+            .unwrap();
+        bytes[uri] = b'E';
+        let signed = SignedResource::decode(&bytes).unwrap();
+        assert_eq!("http://Example.com/image.bin", signed.manifest.payload_uri);
+        assert!(!signed.signature_is_valid(&certificate).unwrap());
+    }
+
+    #[test]
+    fn deltas_and_compressed_payloads_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("fixme");
+        let request = ManifestRequest {
+            compression: Some(crate::compression::Compressed {
+                algorithm: crate::compression::Compression::Gzip,
+                image: "image.bin".to_string(),
+                uncompressed_digest: to_hex(&[0; 32]),
+                uncompressed_size: 8,
+            }),
+            ..request(dir.path(), &key, &certificate)
+        };
+        assert!(matches!(
+            ToolFormatEncoder::new(Uuid::nil(), Uuid::nil()).generate(&request),
+            Err(ManifestError::Invalid(_))
+        ));
+        assert!(!request.output.exists());
+    }
+
+    #[test]
+    fn timestamps_always_increase() {
+        let before = Utc::now().timestamp() as u64;
+        let timestamps: Vec<u64> = (0..100).map(|_| next_timestamp()).collect();
+        assert!(timestamps[0] >= before);
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,1189 @@
+//! Versioned JSON API under `/api/v1`.
+//!
+//! Mirrors the HTML routes on top of the same [`ImageService`]. Every failure
+//! is answered with a JSON object of the form
+//! `{ "error": "<title>", "detail": "<explanation>" }`.
+
+use actix_multipart::Multipart;
+use actix_web::{
+    error::InternalError,
+    http::{header::ContentDisposition, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{
+    actor, identity, json_error,
+    openapi::{Binary, UploadForm},
+    uploads, ErrorBody,
+};
+use crate::{
+    catalog::Added,
+    catalog::ImageRef,
+    compression::Compression,
+    job::{Job, JobId},
+    service::{ConvertRequest, ImageService, ImageView, ServiceError, Uploaded},
+};
+
+/// Registers the API scope.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/v1")
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                let response =
+                    json_error(StatusCode::BAD_REQUEST, "Invalid JSON", &err.to_string());
+                InternalError::from_response(err, response).into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                let response =
+                    json_error(StatusCode::BAD_REQUEST, "Invalid query", &err.to_string());
+                InternalError::from_response(err, response).into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                let response = json_error(StatusCode::NOT_FOUND, "Not found", &err.to_string());
+                InternalError::from_response(err, response).into()
+            }))
+            .route("/images", web::get().to(list_images))
+            .route("/images", web::post().to(upload))
+            .route("/images/{name}", web::get().to(image))
+            .route("/images/{name}", web::delete().to(delete_image))
+            .route("/images/{name}/rename", web::post().to(rename_image))
+            .route("/images/{name}/convert", web::post().to(convert_image))
+            .route("/images/{name}/delta", web::post().to(create_delta))
+            .route("/images/{name}/compress", web::post().to(compress_image))
+            .route("/images/{name}/content", web::get().to(download))
+            .route(
+                "/images/{name}/manifests",
+                web::post().to(generate_manifest),
+            )
+            .route("/manifests/{file}", web::get().to(manifest_file))
+            .route("/jobs", web::get().to(jobs))
+            .route("/jobs/{id}", web::get().to(job))
+            .route("/uploads", web::post().to(uploads::create))
+            .route("/uploads/{id}", web::get().to(uploads::status))
+            .route("/uploads/{id}", web::head().to(uploads::status))
+            .route("/uploads/{id}", web::put().to(uploads::append))
+            .route("/uploads/{id}", web::delete().to(uploads::cancel))
+            .route("/uploads/{id}/finalize", web::post().to(uploads::finalize))
+            .default_service(web::to(|| async {
+                json_error(
+                    StatusCode::NOT_FOUND,
+                    "Not found",
+                    "There is no such API endpoint.",
+                )
+            })),
+    );
+}
+
+fn image_location(name: &str) -> String {
+    format!("/api/v1{}", super::image_url(name))
+}
+
+/// Lists the stored images.
+#[utoipa::path(
+    get,
+    path = "/api/v1/images",
+    tag = "images",
+    responses(
+        (status = 200, description = "The stored images", body = [ImageRef]),
+        (status = 500, description = "Storage error", body = ErrorBody),
+    )
+)]
+pub async fn list_images(service: web::Data<ImageService>) -> HttpResponse {
+    match service.list_images().await {
+        Ok(images) => HttpResponse::Ok().json(images),
+        Err(e) => e.to_json(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/images",
+    tag = "images",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "At least one image is new", body = Uploaded,
+            headers(("Location" = String, description = "The API URL of the last image"))),
+        (status = 200, description = "Every image was stored before with identical content", body = Uploaded),
+        (status = 400, description = "Invalid file name or malformed form", body = ErrorBody),
+        (status = 409, description = "A name already refers to different content", body = ErrorBody),
+        (status = 413, description = "An upload limit was exceeded", body = ErrorBody),
+        (status = 415, description = "An image is not of an accepted type", body = ErrorBody),
+    )
+)]
+/// Stores the images of a multipart form, answering 201 when any of them is new.
+pub async fn upload(
+    payload: Multipart,
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    let uploaded = match service.upload(payload, identity(&req).as_deref()).await {
+        Ok(uploaded) => uploaded,
+        Err(e) => return e.to_json(),
+    };
+    let Some(last) = uploaded.last() else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "No image",
+            "Send the image as a multipart part with a file name.",
+        );
+    };
+    let mut response = if uploaded.created.is_empty() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::Created()
+    };
+    response
+        .append_header(("Location", image_location(&last.name)))
+        .json(uploaded)
+}
+
+/// Returns an image's metadata and the manifests generated from it.
+#[utoipa::path(
+    get,
+    path = "/api/v1/images/{name}",
+    tag = "images",
+    params(("name" = String, Path, description = "Image name")),
+    responses(
+        (status = 200, description = "The image", body = ImageView),
+        (status = 404, description = "No such image", body = ErrorBody),
+    )
+)]
+pub async fn image(name: web::Path<String>, service: web::Data<ImageService>) -> HttpResponse {
+    match service.image_view(&name).await {
+        Ok(view) => HttpResponse::Ok().json(view),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// Downloads an image, honouring `Range` and conditional request headers.
+///
+/// The ETag is the image digest, so it only changes when the content does.
+#[utoipa::path(
+    get,
+    path = "/api/v1/images/{name}/content",
+    tag = "images",
+    params(
+        ("name" = String, Path, description = "Image name"),
+        ("Range" = Option<String>, Header, description = "Byte range to send, e.g. `bytes=0-1023`"),
+        ("If-None-Match" = Option<String>, Header, description = "ETags the client already holds"),
+        ("If-Range" = Option<String>, Header, description = "Only send the range if the ETag still matches"),
+    ),
+    responses(
+        (status = 200, description = "The image content", content_type = "application/octet-stream", body = Binary,
+            headers(("ETag" = String))),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream", body = Binary,
+            headers(("ETag" = String), ("Content-Range" = String))),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 416, description = "The range lies outside the image", headers(("Content-Range" = String))),
+    )
+)]
+pub async fn download(
+    req: HttpRequest,
+    name: web::Path<String>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    match super::download::serve(&req, &service, &name).await {
+        Ok(response) => response,
+        Err(e) => e.to_json(),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct Force {
+    /// Go ahead even though generated manifests refer to the image.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Deletes an image.
+///
+/// While manifests generated from the image refer to it by name, the request
+/// is refused with 409 unless `force` is set.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/images/{name}",
+    tag = "images",
+    params(("name" = String, Path, description = "Image name"), Force),
+    responses(
+        (status = 204, description = "The image was deleted"),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 409, description = "Generated manifests refer to the image", body = ErrorBody),
+    )
+)]
+pub async fn delete_image(
+    req: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<Force>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    match service.delete_image(&name, query.force, &actor(&req)).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.to_json(),
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Rename {
+    /// The new name.
+    pub name: String,
+    /// Go ahead even though generated manifests refer to the image by its old name.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Renames an image.
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/{name}/rename",
+    tag = "images",
+    params(("name" = String, Path, description = "Current image name")),
+    request_body = Rename,
+    responses(
+        (status = 200, description = "The renamed image", body = ImageRef,
+            headers(("Location" = String, description = "The API URL of the renamed image"))),
+        (status = 400, description = "Invalid new name", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 409, description = "The new name is taken or generated manifests refer to the image", body = ErrorBody),
+        (status = 415, description = "The new name is not of an accepted type, or the content does not fit it", body = ErrorBody),
+    )
+)]
+pub async fn rename_image(
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<Rename>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    match service
+        .rename_image(&name, &body.name, body.force, &actor(&req))
+        .await
+    {
+        Ok(image) => HttpResponse::Ok()
+            .append_header(("Location", image_location(&image.name)))
+            .json(image),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// Converts an image to a raw binary, stored alongside it as `<name>.bin`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/{name}/convert",
+    tag = "images",
+    params(("name" = String, Path, description = "Image to convert")),
+    request_body = ConvertRequest,
+    responses(
+        (status = 201, description = "The binary was stored", body = ImageRef,
+            headers(("Location" = String, description = "The API URL of the binary"))),
+        (status = 200, description = "The binary was stored before", body = ImageRef),
+        (status = 400, description = "Invalid conversion options", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 409, description = "The binary's name refers to different content", body = ErrorBody),
+        (status = 413, description = "The binary exceeds an upload limit", body = ErrorBody),
+        (status = 422, description = "The image cannot be converted", body = ErrorBody),
+    )
+)]
+pub async fn convert_image(
+    name: web::Path<String>,
+    body: web::Json<ConvertRequest>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    let (mut response, image) = match service.convert_image(&name, &body).await {
+        Ok(Added::Created(image)) => (HttpResponse::Created(), image),
+        Ok(Added::Unchanged(image)) => (HttpResponse::Ok(), image),
+        Ok(Added::Conflict(image)) => return ServiceError::image_exists(&image).to_json(),
+        Err(e) => return e.to_json(),
+    };
+    response
+        .append_header(("Location", image_location(&image.name)))
+        .json(image)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Compress {
+    pub compression: Compression,
+}
+
+/// Compresses an image, stored alongside as `<name>.gz`, `.zst` or `.lzma`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/{name}/compress",
+    tag = "images",
+    params(("name" = String, Path, description = "Image to compress")),
+    request_body = Compress,
+    responses(
+        (status = 201, description = "The compressed image was stored", body = ImageRef,
+            headers(("Location" = String, description = "The API URL of the compressed image"))),
+        (status = 200, description = "The compressed image was stored before", body = ImageRef),
+        (status = 400, description = "Unknown compression, or the image is compressed already", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 409, description = "The compressed image's name refers to different content", body = ErrorBody),
+        (status = 413, description = "The compressed image exceeds an upload limit", body = ErrorBody),
+    )
+)]
+pub async fn compress_image(
+    name: web::Path<String>,
+    body: web::Json<Compress>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    let (mut response, image) = match service.compress_image(&name, body.compression).await {
+        Ok(Added::Created(image)) => (HttpResponse::Created(), image),
+        Ok(Added::Unchanged(image)) => (HttpResponse::Ok(), image),
+        Ok(Added::Conflict(image)) => return ServiceError::image_exists(&image).to_json(),
+        Err(e) => return e.to_json(),
+    };
+    response
+        .append_header(("Location", image_location(&image.name)))
+        .json(image)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateDelta {
+    /// The image devices run now, which the delta patches.
+    pub base: String,
+}
+
+/// Computes a delta from another image to this one, stored as `<name>-from-<base>.delta`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/{name}/delta",
+    tag = "images",
+    params(("name" = String, Path, description = "Image the delta rebuilds")),
+    request_body = CreateDelta,
+    responses(
+        (status = 201, description = "The delta was stored", body = ImageRef,
+            headers(("Location" = String, description = "The API URL of the delta"))),
+        (status = 200, description = "The delta was stored before", body = ImageRef),
+        (status = 400, description = "No base image, or the images are identical", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 409, description = "The delta's name refers to different content", body = ErrorBody),
+        (status = 413, description = "An image exceeds max_delta_image_size or the delta exceeds an upload limit", body = ErrorBody),
+    )
+)]
+pub async fn create_delta(
+    name: web::Path<String>,
+    body: web::Json<CreateDelta>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    let (mut response, image) = match service.create_delta(&name, &body.base).await {
+        Ok(Added::Created(image)) => (HttpResponse::Created(), image),
+        Ok(Added::Unchanged(image)) => (HttpResponse::Ok(), image),
+        Ok(Added::Conflict(image)) => return ServiceError::image_exists(&image).to_json(),
+        Err(e) => return e.to_json(),
+    };
+    response
+        .append_header(("Location", image_location(&image.name)))
+        .json(image)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GenerateManifest {
+    /// URI devices will fetch the payload from.
+    pub payload_uri: String,
+}
+
+/// Queues a manifest job, answering 202 with the job and its location.
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/{name}/manifests",
+    tag = "manifests",
+    params(("name" = String, Path, description = "Image name")),
+    request_body = GenerateManifest,
+    responses(
+        (status = 202, description = "The job was queued", body = Job,
+            headers(("Location" = String, description = "The API URL of the job"))),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody),
+        (status = 503, description = "The job queue is full", body = ErrorBody),
+    )
+)]
+pub async fn generate_manifest(
+    name: web::Path<String>,
+    body: web::Json<GenerateManifest>,
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    let requested_by = identity(&req);
+    match service
+        .generate_manifest(&name, body.payload_uri.trim(), requested_by.as_deref())
+        .await
+    {
+        Ok(id) => HttpResponse::Accepted()
+            .append_header(("Location", format!("/api/v1/jobs/{}", id)))
+            .json(service.jobs().get(&id)),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// Downloads a generated manifest.
+#[utoipa::path(
+    get,
+    path = "/api/v1/manifests/{file}",
+    tag = "manifests",
+    params(("file" = String, Path, description = "Manifest file name")),
+    responses(
+        (status = 200, description = "The manifest", content_type = "application/octet-stream", body = Binary),
+        (status = 404, description = "No such manifest", body = ErrorBody),
+    )
+)]
+pub async fn manifest_file(
+    file: web::Path<String>,
+    service: web::Data<ImageService>,
+) -> HttpResponse {
+    match service.read_manifest(&file).await {
+        Ok(manifest) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header(ContentDisposition::attachment(file.as_str()))
+            .body(manifest),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// Lists the retained manifest jobs, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs",
+    tag = "manifests",
+    responses((status = 200, description = "Recent jobs", body = [Job]))
+)]
+pub async fn jobs(service: web::Data<ImageService>) -> HttpResponse {
+    HttpResponse::Ok().json(service.jobs().recent())
+}
+
+/// Reports the state and output of a manifest job.
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    tag = "manifests",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "No such job", body = ErrorBody),
+    )
+)]
+pub async fn job(id: web::Path<JobId>, service: web::Data<ImageService>) -> HttpResponse {
+    match service.jobs().get(&id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => json_error(
+            StatusCode::NOT_FOUND,
+            "Job not found",
+            &format!("There is no job with ID {}.", id),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_http::Request;
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        test, App,
+    };
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        audit::{AuditAction, AuditEntry},
+        catalog::Catalog,
+        cfg::Cfg,
+        job::JobQueue,
+        manifest::{record::ManifestRecord, tool::ManifestTool, Generator},
+        service::MAX_TEXT_FIELD,
+        store::{local::LocalStore, CHUNK_SIZE},
+        upload_session::UploadSessions,
+    };
+
+    fn service(dir: &std::path::Path) -> ImageService {
+        service_with(dir, Cfg::default())
+    }
+
+    /// A service over `dir`, otherwise configured by `cfg`.
+    fn service_with(dir: &std::path::Path, cfg: Cfg) -> ImageService {
+        let cfg = Cfg {
+            staging_dir: dir.join("staging").to_str().unwrap().to_string(),
+            manifests_dir: dir.join("manifests").to_str().unwrap().to_string(),
+            audit_log: dir.join("audit.log").to_str().unwrap().to_string(),
+            ..cfg
+        };
+        let catalog = Catalog::new(
+            Arc::new(LocalStore::new(dir.join("blobs"))),
+            Arc::new(LocalStore::new(dir.join("refs"))),
+        );
+        let jobs = JobQueue::start(
+            Generator::Tool(ManifestTool::new("true")),
+            catalog.clone(),
+            &cfg,
+        );
+        ImageService::new(cfg, catalog, jobs)
+    }
+
+    /// The API over `service`, with upload sessions configured like it.
+    async fn test_app(
+        service: &ImageService,
+    ) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(service.clone()))
+                .app_data(web::Data::new(UploadSessions::new(service.cfg()).unwrap()))
+                .configure(configure),
+        )
+        .await
+    }
+
+    /// Uploads `content` as version 1.0 of `filename`.
+    async fn upload(
+        app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+        filename: &str,
+        content: &str,
+    ) -> ServiceResponse {
+        upload_with(app, filename, content, &[("version", "1.0")]).await
+    }
+
+    /// Uploads `content` as `filename` in a form with the given text `fields`.
+    async fn upload_with(
+        app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+        filename: &str,
+        content: &str,
+        fields: &[(&str, &str)],
+    ) -> ServiceResponse {
+        let boundary = "fixme-boundary";
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{f}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n{c}\r\n",
+            b = boundary,
+            f = filename,
+            c = content
+        );
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", boundary));
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images")
+            .insert_header((
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(body)
+            .to_request();
+        test::call_service(app, request).await
+    }
+
+    #[actix_web::test]
+    async fn images_can_be_uploaded_listed_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+
+        let response = upload(&app, "a.sgi", "firmware").await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!(
+            "/api/v1/images/a.sgi",
+            response.headers().get("Location").unwrap()
+        );
+
+        let request = test::TestRequest::get().uri("/api/v1/images").to_request();
+        let images: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!("a.sgi", images[0]["name"]);
+        assert_eq!("1.0", images[0]["version"]);
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images/a.sgi/manifests")
+            .set_json(serde_json::json!({ "payload_uri": "http://example.com/a.sgi" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::ACCEPTED, response.status());
+
+        // The manifest job may already have recorded its manifest.
+        let request = test::TestRequest::delete()
+            .uri("/api/v1/images/a.sgi?force=true")
+            .to_request();
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            test::call_service(&app, request).await.status()
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Image not found", error["error"]);
+    }
+
+    #[actix_web::test]
+    async fn referenced_images_are_only_changed_with_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let uploaded: Value = test::read_body_json(upload(&app, "a.sgi", "firmware").await).await;
+        let manifests_dir = dir.path().join("manifests");
+        std::fs::create_dir_all(&manifests_dir).unwrap();
+        ManifestRecord {
+            file: "a.manifest".to_string(),
+            image: "a.sgi".to_string(),
+            image_digest: uploaded["created"][0]["digest"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+            payload_uri: "http://example.com/images/a.sgi/download".to_string(),
+            created: chrono::Utc::now(),
+            job: None,
+        }
+        .save(&manifests_dir.join("a.manifest"))
+        .unwrap();
+
+        let rename = |force: bool| {
+            test::TestRequest::post()
+                .uri("/api/v1/images/a.sgi/rename")
+                .set_json(serde_json::json!({ "name": "b.sgi", "force": force }))
+                .to_request()
+        };
+        let response = test::call_service(&app, rename(false)).await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Image in use", error["error"]);
+
+        let response = test::call_service(&app, rename(true)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "/api/v1/images/b.sgi",
+            response.headers().get("Location").unwrap()
+        );
+
+        // The manifest names a.sgi, so b.sgi can go without force.
+        let request = test::TestRequest::delete()
+            .uri("/api/v1/images/b.sgi")
+            .to_request();
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            test::call_service(&app, request).await.status()
+        );
+
+        let log = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let entries: Vec<AuditEntry> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, entries.len());
+        assert_eq!(AuditAction::Rename, entries[0].action);
+        assert_eq!(Some("b.sgi".to_string()), entries[0].renamed_to);
+        assert_eq!(vec!["a.manifest".to_string()], entries[0].manifests);
+        assert_eq!(AuditAction::Delete, entries[1].action);
+        assert_eq!("b.sgi", entries[1].image);
+        assert!(entries.iter().all(|entry| entry.error.is_none()));
+    }
+
+    #[actix_web::test]
+    async fn changes_are_reported_as_made_when_the_audit_log_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory cannot be appended to.
+        std::fs::create_dir_all(dir.path().join("audit.log")).unwrap();
+        let app = test_app(&service(dir.path())).await;
+        upload(&app, "a.sgi", "firmware").await;
+
+        let request = test::TestRequest::delete()
+            .uri("/api/v1/images/a.sgi")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Audit log unavailable", error["error"]);
+        assert!(error["detail"]
+            .as_str()
+            .unwrap()
+            .starts_with("The change was made"));
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi")
+            .to_request();
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            test::call_service(&app, request).await.status()
+        );
+    }
+
+    #[actix_web::test]
+    async fn downloads_support_ranges_and_etags() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        upload(&app, "a.sgi", "firmware").await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let etag = response.headers().get("ETag").unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("\"sha256-"));
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .insert_header(("If-None-Match", etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .insert_header(("Range", "bytes=2-4"))
+            .insert_header(("If-Range", etag))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(
+            "bytes 2-4/8",
+            response.headers().get("Content-Range").unwrap()
+        );
+        assert_eq!(&b"rmw"[..], test::read_body(response).await);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .insert_header(("Range", "bytes=2-4"))
+            .insert_header(("If-Range", "\"sha256-other\""))
+            .to_request();
+        assert_eq!(
+            &b"firmware"[..],
+            test::call_and_read_body(&app, request).await
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .insert_header(("Range", "bytes=8-"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+        assert_eq!(
+            "bytes */8",
+            response.headers().get("Content-Range").unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn large_images_are_streamed_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let content: String = (0..CHUNK_SIZE * 3 + 5)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        upload(&app, "a.sgi", &content).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            content.len().to_string(),
+            response
+                .headers()
+                .get("Content-Length")
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(content.as_bytes(), test::read_body(response).await);
+
+        let start = CHUNK_SIZE - 1;
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.sgi/content")
+            .insert_header(("Range", format!("bytes={}-", start)))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(
+            &content.as_bytes()[start..],
+            test::read_body(response).await
+        );
+    }
+
+    #[actix_web::test]
+    async fn images_are_converted_to_raw_binaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let hex = ":020000040800F2\n:0400000001020304F2\n:020008000506EB\n:00000001FF\n";
+        let uploaded: Value = test::read_body_json(upload(&app, "a.hex", hex).await).await;
+        let digest = uploaded["created"][0]["digest"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let convert = |body: Value| {
+            test::TestRequest::post()
+                .uri("/api/v1/images/a.hex/convert")
+                .set_json(body)
+                .to_request()
+        };
+        let response =
+            test::call_service(&app, convert(serde_json::json!({ "fill_byte": "0" }))).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let image: Value = test::read_body_json(response).await;
+        assert_eq!("a.bin", image["name"]);
+        assert_eq!(10, image["size"]);
+        assert_eq!("1.0", image["version"]);
+        assert_eq!("a.hex", image["converted_from"]["image"]);
+        assert_eq!(digest, image["converted_from"]["digest"]);
+        assert_eq!("ihex", image["converted_from"]["format"]);
+        assert_eq!(0x0800_0000u64, image["converted_from"]["base_address"]);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/a.bin/content")
+            .to_request();
+        let content = test::call_and_read_body(&app, request).await;
+        assert_eq!(&[1, 2, 3, 4, 0, 0, 0, 0, 5, 6][..], &content[..]);
+
+        // The same options produce the same binary, different ones conflict.
+        let response =
+            test::call_service(&app, convert(serde_json::json!({ "fill_byte": "0" }))).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = test::call_service(&app, convert(serde_json::json!({}))).await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+
+        let response =
+            test::call_service(&app, convert(serde_json::json!({ "format": "srec" }))).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+        let response =
+            test::call_service(&app, convert(serde_json::json!({ "fill_byte": "0x100" }))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[actix_web::test]
+    async fn deltas_rebuild_the_target_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let mut digests = Vec::new();
+        for (name, content) in [
+            ("fw-1.0.bin", "firmware 1.0"),
+            ("fw-1.1.bin", "firmware 1.1"),
+        ] {
+            let uploaded: Value = test::read_body_json(upload(&app, name, content).await).await;
+            digests.push(
+                uploaded["created"][0]["digest"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+
+        let delta = |base: &str| {
+            test::TestRequest::post()
+                .uri("/api/v1/images/fw-1.1.bin/delta")
+                .set_json(serde_json::json!({ "base": base }))
+                .to_request()
+        };
+        let response = test::call_service(&app, delta("fw-1.0.bin")).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!(
+            "/api/v1/images/fw-1.1-from-fw-1.0.delta",
+            response.headers().get("Location").unwrap()
+        );
+        let image: Value = test::read_body_json(response).await;
+        assert_eq!("1.0", image["version"]);
+        assert_eq!("fw-1.0.bin", image["delta"]["base"]);
+        assert_eq!(digests[0], image["delta"]["base_digest"]);
+        assert_eq!(digests[1], image["delta"]["target_digest"]);
+        assert_eq!(12, image["delta"]["target_size"]);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/fw-1.1-from-fw-1.0.delta/content")
+            .to_request();
+        let patch = test::call_and_read_body(&app, request).await;
+        assert_eq!(
+            b"firmware 1.1".to_vec(),
+            crate::delta::apply(b"firmware 1.0", &patch).unwrap()
+        );
+
+        let response = test::call_service(&app, delta("fw-1.0.bin")).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = test::call_service(&app, delta("fw-1.1.bin")).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let response = test::call_service(&app, delta("fw-0.9.bin")).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        // manifest-tool cannot describe a delta.
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images/fw-1.1-from-fw-1.0.delta/manifests")
+            .set_json(serde_json::json!({ "payload_uri": "http://example.com/fw.delta" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+    }
+
+    #[actix_web::test]
+    async fn payloads_are_compressed_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let content = "firmware ".repeat(100);
+        let response = upload_with(&app, "fw.bin", &content, &[("compression", "bzip2")]).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let uploaded: Value = test::read_body_json(
+            upload_with(&app, "fw.bin", &content, &[("compression", "gzip")]).await,
+        )
+        .await;
+        let original = &uploaded["created"][0];
+        let compressed = &uploaded["created"][1];
+        assert_eq!("fw.bin.gz", compressed["name"]);
+        assert_eq!("gzip", compressed["compression"]["algorithm"]);
+        assert_eq!("fw.bin", compressed["compression"]["image"]);
+        assert_eq!(
+            original["digest"],
+            compressed["compression"]["uncompressed_digest"]
+        );
+        assert_eq!(900, compressed["compression"]["uncompressed_size"]);
+        assert!(compressed["size"].as_u64().unwrap() < 100);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/fw.bin.gz/content")
+            .to_request();
+        let payload = test::call_and_read_body(&app, request).await;
+        assert_eq!(
+            content.as_bytes(),
+            &Compression::Gzip.decompress(&payload).unwrap()[..]
+        );
+
+        let compress = |name: &str, compression: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/images/{}/compress", name))
+                .set_json(serde_json::json!({ "compression": compression }))
+                .to_request()
+        };
+        let response = test::call_service(&app, compress("fw.bin", "zstd")).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let image: Value = test::read_body_json(response).await;
+        assert_eq!("fw.bin.zst", image["name"]);
+        assert_eq!("zstd", image["compression"]["algorithm"]);
+        let response = test::call_service(&app, compress("fw.bin.gz", "lzma")).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Already compressed", error["error"]);
+
+        // manifest-tool cannot describe a compressed payload.
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images/fw.bin.zst/manifests")
+            .set_json(serde_json::json!({ "payload_uri": "http://example.com/fw.bin.zst" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+    }
+
+    #[actix_web::test]
+    async fn failed_uploads_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+        let response =
+            upload_with(&app, "fw.bin", "first firmware", &[("compression", "gzip")]).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images/fw.bin/rename")
+            .set_json(serde_json::json!({ "name": "old.bin" }))
+            .to_request();
+        assert_eq!(
+            StatusCode::OK,
+            test::call_service(&app, request).await.status()
+        );
+
+        // fw.bin is stored before fw.bin.gz turns out to be taken.
+        let response = upload_with(
+            &app,
+            "fw.bin",
+            "second firmware",
+            &[("compression", "gzip")],
+        )
+        .await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        let request = test::TestRequest::get()
+            .uri("/api/v1/images/fw.bin")
+            .to_request();
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            test::call_service(&app, request).await.status()
+        );
+    }
+
+    #[actix_web::test]
+    async fn malformed_requests_get_json_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/images/a.sgi/manifests")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload("{")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Invalid JSON", error["error"]);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/jobs/not-a-uuid")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let response = upload(&app, "a.elf", "not an ELF file").await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, response.status());
+
+        let request = test::TestRequest::get().uri("/api/v1/nothing").to_request();
+        let error: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!("Not found", error["error"]);
+    }
+
+    #[actix_web::test]
+    async fn oversized_text_fields_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(&service(dir.path())).await;
+
+        let response = upload_with(
+            &app,
+            "a.sgi",
+            "firmware",
+            &[("version", &"1".repeat(MAX_TEXT_FIELD + 1))],
+        )
+        .await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let error: Value = test::read_body_json(response).await;
+        assert_eq!("Field too large", error["error"]);
+
+        let response = upload_with(
+            &app,
+            "a.sgi",
+            "firmware",
+            &[("version", &"1".repeat(MAX_TEXT_FIELD))],
+        )
+        .await;
+        assert_eq!(StatusCode::CREATED, response.status());
+    }
+
+    #[actix_web::test]
+    async fn deltas_are_only_computed_between_small_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Cfg {
+            max_delta_image_size: 12,
+            ..Cfg::default()
+        };
+        let app = test_app(&service_with(dir.path(), cfg)).await;
+        for (name, content) in [
+            ("a.sgi", "firmware 1.0"),
+            ("b.sgi", "firmware 1.1"),
+            ("c.sgi", "firmware 1.10"),
+        ] {
+            assert_eq!(
+                StatusCode::CREATED,
+                upload(&app, name, content).await.status()
+            );
+        }
+        let delta = |target: &str, base: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/images/{}/delta", target))
+                .set_json(serde_json::json!({ "base": base }))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, delta("c.sgi", "a.sgi")).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let response = test::call_service(&app, delta("a.sgi", "c.sgi")).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let response = test::call_service(&app, delta("b.sgi", "a.sgi")).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+    }
+
+    #[actix_web::test]
+    async fn uploads_under_way_share_the_storage_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Cfg {
+            max_storage_size: 20,
+            ..Cfg::default()
+        };
+        let service = service_with(dir.path(), cfg);
+        let app = test_app(&service).await;
+        let open = |length: u64| {
+            test::TestRequest::post()
+                .uri("/api/v1/uploads")
+                .set_json(serde_json::json!({ "filename": "b.sgi", "length": length }))
+                .to_request()
+        };
+
+        // An open session sets its announced length aside.
+        let response = test::call_service(&app, open(15)).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let session: Value = test::read_body_json(response).await;
+        let response = upload(&app, "a.sgi", "firmware").await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let request = test::TestRequest::delete()
+            .uri(&format!(
+                "/api/v1/uploads/{}",
+                session["id"].as_str().unwrap()
+            ))
+            .to_request();
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            test::call_service(&app, request).await.status()
+        );
+
+        // So does an upload still being received.
+        let mut reservation = service.reserve().await.unwrap();
+        reservation.take(15, 15).unwrap();
+        let response = upload(&app, "a.sgi", "firmware").await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        drop(reservation);
+        let response = upload(&app, "a.sgi", "firmware").await;
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        // Stored images count from then on.
+        let response = test::call_service(&app, open(13)).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let response = test::call_service(&app, open(12)).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+    }
+
+    #[actix_web::test]
+    async fn received_session_data_keeps_its_space_until_finalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Cfg {
+            max_storage_size: 10,
+            ..Cfg::default()
+        };
+        let app = test_app(&service_with(dir.path(), cfg)).await;
+        let request = test::TestRequest::post()
+            .uri("/api/v1/uploads")
+            .set_json(serde_json::json!({ "filename": "b.sgi" }))
+            .to_request();
+        let session: Value = test::call_and_read_body_json(&app, request).await;
+        let session = format!("/api/v1/uploads/{}", session["id"].as_str().unwrap());
+        let request = test::TestRequest::put()
+            .uri(&session)
+            .insert_header(("Upload-Offset", "0"))
+            .set_payload("firmware")
+            .to_request();
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            test::call_service(&app, request).await.status()
+        );
+
+        let response = upload(&app, "a.sgi", "firmware").await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        // Only whoever opened the session may finish it.
+        let finalize = || {
+            test::TestRequest::post()
+                .uri(&format!("{}/finalize", session))
+                .set_json(serde_json::json!({
+                    "sha256": "c3bf47ea1f4a4a605470313cacb3a44f4a461f68c6faeab07e737610cb5ac835"
+                }))
+        };
+        let request = finalize()
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = test::call_service(&app, finalize().to_request()).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+    }
+}
@@ -0,0 +1,114 @@
+//! Serving stored images over HTTP.
+//!
+//! Images are content addressed, so the digest doubles as a strong ETag: a
+//! name that is re-uploaded with different content gets a different tag.
+//! Clients can revalidate with `If-None-Match`, fetch part of an image with
+//! `Range`, and resume a download safely with `If-Range`.
+
+use actix_files::HttpRange;
+use actix_web::{
+    http::header::{
+        self, ContentDisposition, DispositionParam, DispositionType, EntityTag, Header as _,
+        IfNoneMatch, IfRange,
+    },
+    http::StatusCode,
+    mime, HttpRequest, HttpResponse,
+};
+
+use crate::{
+    catalog::ImageRef,
+    service::{ImageService, ServiceError},
+    store::CHUNK_SIZE,
+};
+
+/// The strong entity tag of an image.
+pub fn etag(image: &ImageRef) -> EntityTag {
+    EntityTag::new_strong(format!("sha256-{}", image.digest))
+}
+
+/// Whether an `If-None-Match` header lists the current tag.
+fn none_match(req: &HttpRequest, tag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => false,
+        Ok(IfNoneMatch::Items(items)) => items.iter().all(|item| !item.weak_eq(tag)),
+        Err(_) => true,
+    }
+}
+
+/// Whether a `Range` header may be honoured under the request's `If-Range`.
+///
+/// Only a strong tag can confirm the client holds the same content, so a date
+/// or another tag means the whole image is sent instead.
+fn range_applies(req: &HttpRequest, tag: &EntityTag) -> bool {
+    if !req.headers().contains_key(header::IF_RANGE) {
+        return true;
+    }
+    matches!(IfRange::parse(req), Ok(IfRange::EntityTag(ref item)) if item.strong_eq(tag))
+}
+
+/// Answers a download request for the image called `name`.
+///
+/// Only the first range of a multi-range request is served, like
+/// [`actix_files::NamedFile`] does. Anything larger than one chunk is streamed
+/// from the store rather than read into memory first.
+pub async fn serve(
+    req: &HttpRequest,
+    service: &ImageService,
+    name: &str,
+) -> Result<HttpResponse, ServiceError> {
+    let image = service.image(name).await?;
+    let tag = etag(&image);
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(header::ETag(tag.clone()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    if !none_match(req, &tag) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+    }
+    response
+        // Guessing from the extension would serve .sgi firmware as an SGI bitmap.
+        .content_type(mime::APPLICATION_OCTET_STREAM)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(image.name.clone())],
+        });
+
+    let range = match req.headers().get(header::RANGE) {
+        Some(range) if range_applies(req, &tag) => Some(range),
+        _ => None,
+    };
+    let Some(range) = range else {
+        let content = service.stream_image_range(&image, 0, image.size).await?;
+        return Ok(response.no_chunking(image.size).streaming(content));
+    };
+    let ranges = range
+        .to_str()
+        .ok()
+        .and_then(|range| HttpRange::parse(range, image.size).ok());
+    let Some(range) = ranges.and_then(|ranges| ranges.into_iter().next()) else {
+        return Ok(response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", image.size)))
+            .finish());
+    };
+    response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+        header::CONTENT_RANGE,
+        format!(
+            "bytes {}-{}/{}",
+            range.start,
+            range.start + range.length - 1,
+            image.size
+        ),
+    ));
+    if range.length <= CHUNK_SIZE as u64 {
+        let content = service
+            .read_image_range(&image, range.start, range.length)
+            .await?;
+        return Ok(response.body(content));
+    }
+    let content = service
+        .stream_image_range(&image, range.start, range.length)
+        .await?;
+    Ok(response.no_chunking(range.length).streaming(content))
+}
@@ -1,38 +1,197 @@
-use std::fs;
-
-use actix_web::{web, HttpResponse};
-use tera::Context;
-
-use super::VERSION;
-
-pub async fn images(tmpl: web::Data<tera::Tera>) -> actix_web::Result<HttpResponse> {
-    // Collect .cgi and .sgi files
-    let mut images: Vec<String> = Vec::new();
-
-    // Read the uploads directory
-    // let entries = fs::read_dir("./uploads")?;
-    if let Ok(entries) = fs::read_dir("./uploads") {
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
-
-            // Check if the entry is a file and if it has the correct extension
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    if extension == "cgi" || extension == "sgi" {
-                        if let Some(file_name) = path.file_name() {
-                            images.push(file_name.to_string_lossy().into_owned());
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    let mut ctx = Context::new();
-    ctx.insert("version", &VERSION);
-    ctx.insert("title", "Firmware Images");
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use super::{actor, image_url, page_context};
+use crate::{
+    catalog::Added,
+    compression::Compression,
+    convert::Format,
+    service::{ConvertRequest, ImageService, ServiceError},
+};
+
+pub async fn images(
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let images = match service.list_images().await {
+        Ok(images) => images,
+        Err(e) => return Ok(e.to_page(&tmpl)),
+    };
+
+    let mut ctx = page_context(&req, "Firmware Images");
     ctx.insert("images", &images);
     let rendered = tmpl.render("images.html", &ctx).unwrap();
     Ok(HttpResponse::Ok().body(rendered))
 }
+
+/// Shows an image's metadata and the manifests generated from it.
+pub async fn image(
+    req: HttpRequest,
+    name: web::Path<String>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let view = match service.image_view(&name).await {
+        Ok(view) => view,
+        Err(e) => return Ok(e.to_page(&tmpl)),
+    };
+
+    let mut ctx = page_context(&req, &view.image.name);
+    ctx.insert("url", &image_url(&view.image.name));
+    ctx.insert("image", &view.image);
+    ctx.insert("manifests", &view.manifests);
+    ctx.insert("references", &view.references);
+    let convertible = service
+        .image_types()
+        .for_name(&view.image.name)
+        .is_ok_and(|kind| kind.name.parse::<Format>().is_ok());
+    ctx.insert("convertible", &convertible);
+    // Any other image can be the base of a delta to this one.
+    let bases: Vec<String> = match service.list_images().await {
+        Ok(images) => images
+            .into_iter()
+            .map(|image| image.name)
+            .filter(|other| *other != view.image.name)
+            .collect(),
+        Err(e) => return Ok(e.to_page(&tmpl)),
+    };
+    ctx.insert("bases", &bases);
+    if let Some(conversion) = &view.image.details.converted_from {
+        ctx.insert(
+            "base_address",
+            &format!("{:#010x}", conversion.base_address),
+        );
+    }
+    let rendered = tmpl.render("image.html", &ctx).unwrap();
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+/// Serves an image, honouring `Range` and conditional request headers.
+pub async fn download(
+    req: HttpRequest,
+    name: web::Path<String>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match super::download::serve(&req, &service, &name).await {
+        Ok(response) => Ok(response),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteForm {
+    /// Present when the box confirming manifests will break is ticked.
+    pub force: Option<String>,
+}
+
+pub async fn delete(
+    req: HttpRequest,
+    name: web::Path<String>,
+    form: web::Form<DeleteForm>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match service
+        .delete_image(&name, form.force.is_some(), &actor(&req))
+        .await
+    {
+        Ok(_) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/images"))
+            .finish()),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameForm {
+    pub name: String,
+    /// Present when the box confirming manifests will break is ticked.
+    pub force: Option<String>,
+}
+
+pub async fn rename(
+    req: HttpRequest,
+    name: web::Path<String>,
+    form: web::Form<RenameForm>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match service
+        .rename_image(&name, &form.name, form.force.is_some(), &actor(&req))
+        .await
+    {
+        Ok(image) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", image_url(&image.name)))
+            .finish()),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
+
+/// Converts an image to a raw binary and shows the result.
+pub async fn convert(
+    name: web::Path<String>,
+    form: web::Form<ConvertRequest>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let mut request = ConvertRequest::default();
+    let form = form.into_inner();
+    for (field, value) in [
+        ("format", form.format),
+        ("fill_byte", form.fill_byte),
+        ("base_address", form.base_address),
+    ] {
+        request.set(field, value.unwrap_or_default());
+    }
+    match service.convert_image(&name, &request).await {
+        Ok(Added::Created(image) | Added::Unchanged(image)) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", image_url(&image.name)))
+            .finish()),
+        Ok(Added::Conflict(image)) => Ok(ServiceError::image_exists(&image).to_page(&tmpl)),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeltaForm {
+    pub base: String,
+}
+
+/// Computes a delta from another image and shows it.
+pub async fn delta(
+    name: web::Path<String>,
+    form: web::Form<DeltaForm>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match service.create_delta(&name, &form.base).await {
+        Ok(Added::Created(image) | Added::Unchanged(image)) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", image_url(&image.name)))
+            .finish()),
+        Ok(Added::Conflict(image)) => Ok(ServiceError::image_exists(&image).to_page(&tmpl)),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompressForm {
+    pub compression: Compression,
+}
+
+/// Compresses an image and shows the result.
+pub async fn compress(
+    name: web::Path<String>,
+    form: web::Form<CompressForm>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match service.compress_image(&name, form.compression).await {
+        Ok(Added::Created(image) | Added::Unchanged(image)) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", image_url(&image.name)))
+            .finish()),
+        Ok(Added::Conflict(image)) => Ok(ServiceError::image_exists(&image).to_page(&tmpl)),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
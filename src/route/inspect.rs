@@ -0,0 +1,110 @@
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use futures_util::StreamExt as _;
+
+use super::{error_page, json_error, page_context, wants_json};
+use crate::manifest::{
+    inspect::{inspect as inspect_manifest, TrustStore},
+    ManifestError,
+};
+
+/// Manifests are small; anything bigger than this is not one.
+const MAX_MANIFEST_SIZE: usize = 1024 * 1024;
+
+pub async fn inspect_get(
+    req: HttpRequest,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let ctx = page_context(&req, "Inspect Manifest");
+    let rendered = tmpl.render("manifest_inspect.html", &ctx).unwrap();
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+/// Decodes an uploaded manifest and verifies it against the trust store.
+pub async fn inspect(
+    mut payload: Multipart,
+    req: HttpRequest,
+    trust_store: web::Data<Option<TrustStore>>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let mut manifest = None;
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        if field.content_disposition().get_name() != Some("manifest") {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            bytes.extend_from_slice(&chunk?);
+            if bytes.len() > MAX_MANIFEST_SIZE {
+                return Ok(failure(
+                    &req,
+                    &tmpl,
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Manifest too large",
+                    &format!("Manifests may be at most {} bytes.", MAX_MANIFEST_SIZE),
+                ));
+            }
+        }
+        manifest = Some(bytes);
+    }
+    let manifest = match manifest {
+        Some(manifest) if !manifest.is_empty() => manifest,
+        _ => {
+            return Ok(failure(
+                &req,
+                &tmpl,
+                StatusCode::BAD_REQUEST,
+                "No manifest",
+                "Choose a manifest file to inspect.",
+            ))
+        }
+    };
+
+    let trust_store = trust_store.into_inner();
+    let inspection =
+        web::block(move || inspect_manifest(&manifest, trust_store.as_ref().as_ref())).await?;
+    let inspection = match inspection {
+        Ok(inspection) => inspection,
+        Err(e @ ManifestError::UnsupportedFormat) => {
+            return Ok(failure(
+                &req,
+                &tmpl,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported manifest format",
+                &e.to_string(),
+            ))
+        }
+        Err(e) => {
+            return Ok(failure(
+                &req,
+                &tmpl,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Manifest could not be inspected",
+                &e.to_string(),
+            ))
+        }
+    };
+    if wants_json(&req) {
+        return Ok(HttpResponse::Ok().json(inspection));
+    }
+
+    let mut ctx = page_context(&req, "Inspect Manifest");
+    ctx.insert("inspection", &inspection);
+    let rendered = tmpl.render("manifest_inspect.html", &ctx).unwrap();
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+fn failure(
+    req: &HttpRequest,
+    tmpl: &tera::Tera,
+    status: StatusCode,
+    title: &str,
+    detail: &str,
+) -> HttpResponse {
+    if wants_json(req) {
+        json_error(status, title, detail)
+    } else {
+        error_page(tmpl, status, title, detail)
+    }
+}
@@ -0,0 +1,196 @@
+use actix_web::{
+    http::{
+        header::{CacheControl, CacheDirective, ContentDisposition},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use futures_util::{stream, Stream};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use super::{error_page, page_context, wants_json};
+use crate::job::{JobEvent, JobId, JobQueue, JobState};
+
+pub async fn jobs(
+    req: HttpRequest,
+    jobs: web::Data<JobQueue>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = page_context(&req, "Manifest Jobs");
+    ctx.insert("jobs", &jobs.recent());
+    let rendered = tmpl.render("jobs.html", &ctx).unwrap();
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+/// Reports the state of one job, as JSON when the client asks for it.
+pub async fn job(
+    id: web::Path<JobId>,
+    req: HttpRequest,
+    jobs: web::Data<JobQueue>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let wants_json = wants_json(&req);
+    let job = match jobs.get(&id) {
+        Some(job) => job,
+        None if wants_json => return Ok(HttpResponse::NotFound().finish()),
+        None => {
+            return Ok(error_page(
+                &tmpl,
+                StatusCode::NOT_FOUND,
+                "Job not found",
+                &format!("There is no job with ID {}.", id),
+            ))
+        }
+    };
+    if wants_json {
+        return Ok(HttpResponse::Ok().json(job));
+    }
+
+    let mut ctx = page_context(&req, "Manifest Job");
+    ctx.insert("job", &job);
+    let rendered = tmpl.render("job.html", &ctx).unwrap();
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
+/// Downloads the manifest produced by a successful job.
+pub async fn job_manifest(
+    id: web::Path<JobId>,
+    jobs: web::Data<JobQueue>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let job = match jobs.get(&id) {
+        Some(job) if job.state == JobState::Succeeded => job,
+        _ => {
+            return Ok(error_page(
+                &tmpl,
+                StatusCode::NOT_FOUND,
+                "Manifest not found",
+                &format!("Job {} has not produced a manifest.", id),
+            ))
+        }
+    };
+    let file_name = job
+        .output
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let manifest = web::block(move || std::fs::read(job.output)).await??;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ContentDisposition::attachment(file_name))
+        .body(manifest))
+}
+
+/// Formats a job event as a Server-Sent Event.
+fn sse(event: &JobEvent) -> web::Bytes {
+    match event {
+        JobEvent::State(state) => sse_message("state", state.as_str()),
+        JobEvent::Stdout(line) => sse_message("stdout", line),
+        JobEvent::Stderr(line) => sse_message("stderr", line),
+    }
+}
+
+fn sse_message(name: &str, data: &str) -> web::Bytes {
+    let mut message = format!("event: {}\n", name);
+    for line in data.split('\n') {
+        message.push_str(&format!("data: {}\n", line));
+    }
+    message.push('\n');
+    web::Bytes::from(message)
+}
+
+/// Streams a job's output and state changes as Server-Sent Events.
+///
+/// Output produced before the client connected is replayed first. The stream
+/// ends once the job has finished, or with a `lagged` event when the client
+/// fell so far behind that output was lost; it should then reconnect to have
+/// the output replayed.
+pub async fn job_events(
+    id: web::Path<JobId>,
+    jobs: web::Data<JobQueue>,
+) -> actix_web::Result<HttpResponse> {
+    let (job, receiver) = match jobs.subscribe(&id) {
+        Some(subscription) => subscription,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    // The state goes last so clients that stop at a finished state still see the output.
+    let mut replay = Vec::from(job.log);
+    replay.push(JobEvent::State(job.state));
+    let replay = stream::iter(
+        replay
+            .iter()
+            .map(|event| Ok::<_, actix_web::Error>(sse(event)))
+            .collect::<Vec<_>>(),
+    );
+
+    let live = live_events(receiver, job.state.is_finished());
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(futures_util::StreamExt::chain(replay, live)))
+}
+
+/// Passes on what `receiver` gets until the job finishes or events are lost.
+fn live_events(
+    receiver: Receiver<JobEvent>,
+    finished: bool,
+) -> impl Stream<Item = actix_web::Result<web::Bytes>> {
+    stream::unfold(
+        (receiver, finished),
+        |(mut receiver, finished): (Receiver<JobEvent>, bool)| async move {
+            if finished {
+                return None;
+            }
+            match receiver.recv().await {
+                Ok(event) => {
+                    let finished = matches!(event, JobEvent::State(state) if state.is_finished());
+                    Some((Ok(sse(&event)), (receiver, finished)))
+                }
+                Err(RecvError::Lagged(missed)) => Some((
+                    Ok(sse_message("lagged", &missed.to_string())),
+                    (receiver, true),
+                )),
+                Err(RecvError::Closed) => None,
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn clients_that_fall_behind_are_told_so() {
+        let (sender, receiver) = broadcast::channel(4);
+        for i in 0..6 {
+            sender.send(JobEvent::Stdout(i.to_string())).unwrap();
+        }
+        let events: Vec<web::Bytes> = live_events(receiver, false)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(vec![sse_message("lagged", "2")], events);
+
+        let (sender, receiver) = broadcast::channel(4);
+        sender.send(JobEvent::Stdout("one".to_string())).unwrap();
+        sender.send(JobEvent::State(JobState::Succeeded)).unwrap();
+        sender.send(JobEvent::Stdout("late".to_string())).unwrap();
+        let events: Vec<web::Bytes> = live_events(receiver, false)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            vec![
+                sse_message("stdout", "one"),
+                sse_message("state", "succeeded")
+            ],
+            events
+        );
+    }
+}
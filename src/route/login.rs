@@ -0,0 +1,150 @@
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use log::{info, warn};
+use serde::Deserialize;
+
+use super::{actor, page_context};
+use crate::{
+    service::ServiceError,
+    session::{CurrentUser, Sessions},
+};
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    next: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    #[serde(default)]
+    next: String,
+}
+
+/// Where to go after signing in: `next` if it is a path on this server, else the home page.
+fn local_target(next: &str) -> &str {
+    let is_local = next.starts_with('/') && !next.starts_with("//") && !next.starts_with("/\\");
+    if is_local {
+        next
+    } else {
+        "/"
+    }
+}
+
+fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header((header::LOCATION, location))
+        .finish()
+}
+
+fn login_page(
+    req: &HttpRequest,
+    tmpl: &tera::Tera,
+    status: StatusCode,
+    username: &str,
+    next: &str,
+    error: Option<&str>,
+) -> HttpResponse {
+    let mut ctx = page_context(req, "Sign in");
+    ctx.insert("username", username);
+    ctx.insert("next", next);
+    ctx.insert("error", &error);
+    let rendered = tmpl.render("login.html", &ctx).unwrap();
+    HttpResponse::build(status).body(rendered)
+}
+
+pub async fn login_get(
+    req: HttpRequest,
+    query: web::Query<LoginQuery>,
+    sessions: Option<web::Data<Sessions>>,
+    tmpl: web::Data<tera::Tera>,
+) -> HttpResponse {
+    if sessions.is_none() {
+        return see_other(local_target(&query.next));
+    }
+    login_page(&req, &tmpl, StatusCode::OK, "", &query.next, None)
+}
+
+pub async fn login(
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+    sessions: Option<web::Data<Sessions>>,
+    tmpl: web::Data<tera::Tera>,
+) -> HttpResponse {
+    let Some(sessions) = sessions else {
+        return see_other(local_target(&form.next));
+    };
+    let form = form.into_inner();
+    let (users, username, password) = (
+        sessions.users().clone(),
+        form.username.clone(),
+        form.password,
+    );
+    let user = match web::block(move || users.authenticate(&username, &password)).await {
+        Ok(Ok(user)) => user,
+        Ok(Err(e)) => return ServiceError::from(e).to_page(&tmpl),
+        Err(e) => return ServiceError::from(e).to_page(&tmpl),
+    };
+    let Some(user) = user else {
+        warn!(
+            "Failed sign-in as '{}' from {}",
+            form.username.escape_debug(),
+            actor(&req)
+        );
+        return login_page(
+            &req,
+            &tmpl,
+            StatusCode::UNAUTHORIZED,
+            &form.username,
+            &form.next,
+            Some("Unknown user name or wrong password."),
+        );
+    };
+    let cookie = match sessions.start(&user) {
+        Ok(cookie) => cookie,
+        Err(e) => return ServiceError::from(e).to_page(&tmpl),
+    };
+    info!("{} signed in from {}", user.name, actor(&req));
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header((header::LOCATION, local_target(&form.next)))
+        .finish()
+}
+
+pub async fn logout_get(req: HttpRequest, tmpl: web::Data<tera::Tera>) -> HttpResponse {
+    let ctx = page_context(&req, "Sign out");
+    let rendered = tmpl.render("logout.html", &ctx).unwrap();
+    HttpResponse::Ok().body(rendered)
+}
+
+pub async fn logout(
+    user: Option<CurrentUser>,
+    sessions: Option<web::Data<Sessions>>,
+) -> HttpResponse {
+    let mut response = HttpResponse::SeeOther();
+    response.append_header((header::LOCATION, "/login"));
+    let session = user.as_ref().and_then(|user| user.session.as_ref());
+    if let (Some(sessions), Some(session)) = (sessions, session) {
+        info!("{} signed out", session.user);
+        response.cookie(sessions.end(session));
+    }
+    response.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_local_paths_are_followed_after_signing_in() {
+        assert_eq!("/images?x=1", local_target("/images?x=1"));
+        assert_eq!("/", local_target("//evil.example.com"));
+        assert_eq!("/", local_target("/\\evil.example.com"));
+        assert_eq!("/", local_target("https://evil.example.com"));
+        assert_eq!("/", local_target(""));
+    }
+}
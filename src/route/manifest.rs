@@ -1,37 +1,35 @@
-use std::fs;
+use actix_web::{http::header::ContentDisposition, web, HttpRequest, HttpResponse};
 
-use actix_web::{web, HttpResponse};
-use tera::Context;
+use super::page_context;
+use crate::service::ImageService;
 
-use super::VERSION;
+pub async fn manifest(
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    let images: Vec<String> = match service.list_images().await {
+        Ok(images) => images.into_iter().map(|image| image.name).collect(),
+        Err(e) => return Ok(e.to_page(&tmpl)),
+    };
 
-pub async fn manifest(tmpl: web::Data<tera::Tera>) -> actix_web::Result<HttpResponse> {
-    // Collect .cgi and .sgi files
-    let mut images: Vec<String> = Vec::new();
-
-    // Read the uploads directory
-    if let Ok(entries) = fs::read_dir("./uploads") {
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
-
-            // Check if the entry is a file and if it has the correct extension
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    if extension == "cgi" || extension == "sgi" {
-                        if let Some(file_name) = path.file_name() {
-                            images.push(file_name.to_string_lossy().into_owned());
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    let mut ctx = Context::new();
-    ctx.insert("version", &VERSION);
-    ctx.insert("title", "Manifest");
+    let mut ctx = page_context(&req, "Manifest");
     ctx.insert("images", &images);
     let rendered = tmpl.render("manifest.html", &ctx).unwrap();
     Ok(HttpResponse::Ok().body(rendered))
 }
+
+/// Downloads a generated manifest by file name.
+pub async fn manifest_file(
+    file: web::Path<String>,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
+) -> actix_web::Result<HttpResponse> {
+    match service.read_manifest(&file).await {
+        Ok(manifest) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header(ContentDisposition::attachment(file.as_str()))
+            .body(manifest)),
+        Err(e) => Ok(e.to_page(&tmpl)),
+    }
+}
@@ -1,9 +1,168 @@
-use std::env;
+use actix_web::{
+    http::{header::ACCEPT, StatusCode},
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Serialize;
+use tera::Context;
+use utoipa::ToSchema;
 
-pub mod script;
+use crate::{client_auth::Principal, csrf::CsrfToken, service::ServiceError, session::CurrentUser};
+
+pub mod api;
+pub mod download;
 pub mod image_upload;
 pub mod images;
 pub mod index;
+pub mod inspect;
+pub mod jobs;
+pub mod login;
 pub mod manifest;
+pub mod openapi;
+pub mod script;
+pub mod tokens;
+pub mod uploads;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Characters escaped when a name is used as a URL path segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Returns the URL of the detail page of the image called `name`.
+pub fn image_url(name: &str) -> String {
+    format!("/images/{}", utf8_percent_encode(name, PATH_SEGMENT))
+}
+
+/// Renders `error.html` with the given status code.
+pub fn error_page(
+    tmpl: &tera::Tera,
+    status: StatusCode,
+    title: &str,
+    detail: &str,
+) -> HttpResponse {
+    let mut ctx = Context::new();
+    ctx.insert("version", &VERSION);
+    ctx.insert("title", title);
+    ctx.insert("status", &status.as_u16());
+    ctx.insert("detail", detail);
+    match tmpl.render("error.html", &ctx) {
+        Ok(rendered) => HttpResponse::build(status).body(rendered),
+        Err(_) => HttpResponse::build(status).body(format!("{}: {}", title, detail)),
+    }
+}
+
+/// The JSON error object returned by machine-facing routes.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    /// Short, stable description of what went wrong.
+    #[schema(example = "Image not found")]
+    pub error: &'a str,
+    /// Explanation meant for a human.
+    pub detail: &'a str,
+}
+
+/// Builds the JSON error object returned by machine-facing routes.
+pub fn json_error(status: StatusCode, title: &str, detail: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: title,
+        detail,
+    })
+}
+
+/// Starts the context of a rendered page with what `base.html` needs, and the
+/// session's anti-forgery token for `csrf_field.html`.
+pub fn page_context(req: &HttpRequest, title: &str) -> Context {
+    let mut ctx = Context::new();
+    ctx.insert("version", &VERSION);
+    ctx.insert("title", title);
+    if let Some(user) = req.extensions().get::<CurrentUser>() {
+        ctx.insert("user", user);
+    }
+    if let Some(CsrfToken(token)) = req.extensions().get::<CsrfToken>() {
+        ctx.insert("csrf_token", token);
+    }
+    ctx
+}
+
+/// Who is making a request: the signed-in user, else the client certificate's principal.
+pub fn identity(req: &HttpRequest) -> Option<String> {
+    if let Some(user) = req.extensions().get::<CurrentUser>() {
+        return Some(user.name.clone());
+    }
+    req.conn_data::<Principal>()
+        .map(|principal| principal.name.clone())
+}
+
+/// Who made a request, as recorded in the audit log: the client address,
+/// preceded by the [`identity`] when there is one.
+pub fn actor(req: &HttpRequest) -> String {
+    let address = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    match identity(req) {
+        Some(identity) => format!("{} ({})", identity, address),
+        None => address,
+    }
+}
+
+/// The path `req` is routed by: percent-decoded as the router decodes it, so
+/// access rules see the path the handlers are chosen by.
+///
+/// Paths with empty, `.` or `..` segments are refused rather than normalised;
+/// no route needs them, and resolving them differently from the router would
+/// let a rule and a route disagree about where a request goes.
+pub fn routed_path(req: &HttpRequest) -> Result<&str, ServiceError> {
+    let path = req.match_info().as_str();
+    let segments = path.strip_prefix('/').unwrap_or(path);
+    let segments = segments.strip_suffix('/').unwrap_or(segments);
+    let normal = path.starts_with('/')
+        && (segments.is_empty()
+            || segments
+                .split('/')
+                .all(|segment| !matches!(segment, "" | "." | "..")));
+    if normal {
+        Ok(path)
+    } else {
+        Err(ServiceError::bad_request(
+            "Invalid path",
+            "Paths may not contain empty, '.' or '..' segments.",
+        ))
+    }
+}
+
+/// Whether the client asked for JSON rather than a rendered page.
+pub fn wants_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("application/json"))
+        .unwrap_or(false)
+}
+
+impl ServiceError {
+    /// Renders the error as an HTML page.
+    pub fn to_page(&self, tmpl: &tera::Tera) -> HttpResponse {
+        error_page(tmpl, self.status, self.title, &self.detail)
+    }
+
+    /// Renders the error as a JSON error object.
+    pub fn to_json(&self) -> HttpResponse {
+        json_error(self.status, self.title, &self.detail)
+    }
+
+    /// Renders the error as JSON for API routes and clients asking for it, else as a page.
+    pub fn to_response(&self, req: &HttpRequest) -> HttpResponse {
+        match req.app_data::<web::Data<tera::Tera>>() {
+            Some(tmpl) if !req.path().starts_with("/api/") && !wants_json(req) => {
+                self.to_page(tmpl)
+            }
+            _ => self.to_json(),
+        }
+    }
+}
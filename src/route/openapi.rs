@@ -0,0 +1,131 @@
+//! OpenAPI description of the JSON API and a bundled Swagger UI to explore it.
+//!
+//! The document is generated from the handler annotations in [`super::api`]
+//! and [`super::uploads`], so it changes together with the routes. The UI's
+//! assets are compiled into the binary and work without network access.
+
+use actix_web::web;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use super::{api, uploads};
+use crate::{catalog::ImageDetails, service::ConvertRequest};
+
+/// The multipart form accepted by `POST /api/v1/images`.
+///
+/// Only used to describe the request; the form is parsed by the image service.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    /// The image. Any number of file parts may be sent, each stored under its file name.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+    /// Applies to every image in the form.
+    #[serde(flatten)]
+    details: ImageDetails,
+    /// Also store every image converted to a raw binary named `<name>.bin`.
+    convert: Option<bool>,
+    /// Options for `convert`.
+    #[serde(flatten)]
+    conversion: ConvertRequest,
+    /// `none`, `gzip`, `zstd` or `lzma`: also store every payload compressed,
+    /// named `<payload>.gz`, `.zst` or `.lzma`. Defaults to `payload_compression`.
+    compression: Option<String>,
+}
+
+/// Raw bytes such as an image or a manifest.
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+#[allow(dead_code)]
+pub struct Binary(Vec<u8>);
+
+/// Adds the API tokens accepted as `Authorization: Bearer <token>` when accounts are on.
+struct ApiTokens;
+
+impl Modify for ApiTokens {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some(
+                "An API token with the scope the route needs: images:read, \
+                 images:write or manifests:create.",
+            ))
+            .build();
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("api_token", SecurityScheme::Http(scheme));
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "fixme", description = "Firmware image and manifest service."),
+    modifiers(&ApiTokens),
+    security((), ("api_token" = [])),
+    paths(
+        api::list_images,
+        api::upload,
+        api::image,
+        api::delete_image,
+        api::rename_image,
+        api::convert_image,
+        api::create_delta,
+        api::compress_image,
+        api::download,
+        api::generate_manifest,
+        api::manifest_file,
+        api::jobs,
+        api::job,
+        uploads::create,
+        uploads::status,
+        uploads::append,
+        uploads::finalize,
+        uploads::cancel,
+    ),
+    tags(
+        (name = "images", description = "Stored firmware images"),
+        (name = "manifests", description = "Manifest generation jobs and their output"),
+        (name = "uploads", description = "Resumable uploads"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Serves the document at `/api/openapi.json` and the UI at `/api/docs/`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(SwaggerUi::new("/api/docs/{_:.*}").url("/api/openapi.json", ApiDoc::openapi()));
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn document_describes_the_api() {
+        let app = test::init_service(App::new().configure(configure)).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/openapi.json")
+            .to_request();
+        let doc: Value = test::call_and_read_body_json(&app, request).await;
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(doc["paths"]["/api/v1/images/{name}/manifests"]["post"].is_object());
+        assert!(doc["components"]["schemas"]["ErrorBody"].is_object());
+        assert_eq!(
+            "bearer",
+            doc["components"]["securitySchemes"]["api_token"]["scheme"]
+        );
+
+        let request = test::TestRequest::get().uri("/api/docs/").to_request();
+        assert!(test::call_service(&app, request)
+            .await
+            .status()
+            .is_success());
+    }
+}
@@ -1,80 +1,45 @@
-use std::io::{self, ErrorKind};
-
 use actix_multipart::Multipart;
-use actix_web::{HttpRequest, HttpResponse};
-// use futures_util::{StreamExt, TryStreamExt};
-// use futures_util::StreamExt as _;
-use log::warn;
-// use log::debug;
-// use std::{io::Write, fs::create_dir_all, path::Path};
-
-async fn manifest_tool(_image_path: &str, _payload_uri: &str) -> Result<String, std::io::Error> {
-    // while let Some(item) = payload.next().await {
-    //     let mut field = item?;
-    //     let content_disposition = field.content_disposition();
-
-    //     if let Some(name) = content_disposition.get_name() {
-    //         debug!("Form name: {}", name);
-    //     }
-    // }
-    warn!("Executing manifest_tool");
-    let output = std::process::Command::new("manifest-tool")
-        .arg("--version")
-        // .args(&args)
-        .output()?;
-
-    if !output.status.success() {
-        let kind = ErrorKind::Other;
-        let err = io::Error::new(kind, "manifest-tool execution failed");
-        return Err(err);
-    }
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::StreamExt as _;
 
-    match String::from_utf8(output.stdout) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e.to_string())),
-    }
-
-    // Ok(())
-}
+use super::{identity, page_context};
+use crate::service::{read_text_field, ImageService};
 
 pub async fn execute_script(
-    mut _payload: Multipart,
-    _req: HttpRequest,
+    mut payload: Multipart,
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+    tmpl: web::Data<tera::Tera>,
 ) -> actix_web::Result<HttpResponse> {
-    // let mut image_filename = None;
-    // let mut payload_uri = None;
-    // while let Some(item) = payload.next().await {
-    //     let mut field = item?;
-    //     let content_disposition = field.content_disposition();
-
-    //     if let Some(field_name) = content_disposition.get_name() {
-    //         match field_name {
-    //             "image" => {
-    //                 image_filename = Some(content_disposition);
-    //             }
-    //             "uri" => {
-    //                 payload_uri = Some(field_name);
-    //             }
-    //             _ => {}
-    //         }
-    //     }
-    //     // if let Some(name) = content_disposition.get_name() {
-    //     //     debug!("Form name: {}", name);
-    //     //     // If the file already exists, return 409 with Location of the conflicted file path.
-    //     //     // if Path::new(&destination).exists() {
-    //     //     //     return Ok(HttpResponse::Conflict().append_header(("Location", destination)).finish());
-    //     //     // }
+    let mut image_filename = String::new();
+    let mut payload_uri = String::new();
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let field_name = field.content_disposition().get_name().map(str::to_owned);
+        let target = match field_name.as_deref() {
+            Some("file") => &mut image_filename,
+            Some("uri") => &mut payload_uri,
+            _ => continue,
+        };
+        match read_text_field(&mut field).await {
+            Ok(value) => *target = value,
+            Err(e) => return Ok(e.to_page(&tmpl)),
+        }
+    }
 
-    //     //     // File::create is blocking operation, use threadpool
-    //     //     let _file = web::block(|| manifest_tool(payload))
-    //     //     .await
-    //     //     .unwrap();
-    //     // }
-    // }
-    // manifest_tool(image_filename.unwrap(), payload_uri.unwrap());
-    if let Ok(output) = manifest_tool("sbh.sgi", "http://google.com").await {
-        warn!("OUTPUT: {}", output);
+    match service
+        .generate_manifest(&image_filename, &payload_uri, identity(&req).as_deref())
+        .await
+    {
+        Ok(id) => {
+            let job = service.jobs().get(&id);
+            let mut ctx = page_context(&req, "Manifest Job");
+            ctx.insert("job", &job);
+            let rendered = tmpl.render("job.html", &ctx).unwrap();
+            Ok(HttpResponse::Accepted()
+                .append_header(("Location", format!("/jobs/{}", id)))
+                .body(rendered))
+        }
+        Err(e) => Ok(e.to_page(&tmpl)),
     }
-    Ok(HttpResponse::Ok().finish())
-    // Ok(HttpResponse::SeeOther().append_header(("Location", "/")).finish())
 }
@@ -0,0 +1,327 @@
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::{actor, page_context};
+use crate::{
+    service::ServiceError,
+    session::{CurrentUser, Sessions},
+    tokens::{expiry_after_days, validate_token_name, ApiToken, Scope},
+    users::Role,
+};
+
+#[derive(Deserialize)]
+pub struct TokenForm {
+    name: String,
+    #[serde(default, rename = "images:read")]
+    images_read: Option<String>,
+    #[serde(default, rename = "images:write")]
+    images_write: Option<String>,
+    #[serde(default, rename = "manifests:create")]
+    manifests_create: Option<String>,
+    /// Days until the token expires; 0 never expires it.
+    expires_in_days: i64,
+    /// Create a service token rather than one acting as the signed-in user.
+    #[serde(default)]
+    service: Option<String>,
+}
+
+impl TokenForm {
+    fn scopes(&self) -> Vec<Scope> {
+        [
+            (Scope::ImagesRead, &self.images_read),
+            (Scope::ImagesWrite, &self.images_write),
+            (Scope::ManifestsCreate, &self.manifests_create),
+        ]
+        .into_iter()
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(scope, _)| scope)
+        .collect()
+    }
+}
+
+/// A token as listed on the page.
+#[derive(Serialize)]
+struct TokenView {
+    #[serde(flatten)]
+    token: ApiToken,
+    expired: bool,
+}
+
+/// The accounts and the signed-in user, or why the token pages cannot be used.
+fn accounts(
+    sessions: Option<web::Data<Sessions>>,
+    user: Option<CurrentUser>,
+) -> Result<(web::Data<Sessions>, CurrentUser), ServiceError> {
+    let Some(sessions) = sessions else {
+        return Err(ServiceError::new(
+            StatusCode::NOT_FOUND,
+            "API tokens are off",
+            "API tokens need user accounts; set users_file to turn them on.",
+        ));
+    };
+    let user = user.ok_or_else(|| {
+        ServiceError::new(
+            StatusCode::UNAUTHORIZED,
+            "Not signed in",
+            "Sign in at /login first.",
+        )
+    })?;
+    Ok((sessions, user))
+}
+
+/// Whether `user` may see and revoke `token`: their own, or any for release managers.
+fn manages(user: &CurrentUser, token: &ApiToken) -> bool {
+    user.role == Role::ReleaseManager || token.owner.as_deref() == Some(user.name.as_str())
+}
+
+/// Renders `tokens.html` with the tokens `user` manages and, once, a token just created.
+async fn tokens_page(
+    req: &HttpRequest,
+    tmpl: &tera::Tera,
+    sessions: &Sessions,
+    user: &CurrentUser,
+    status: StatusCode,
+    created: Option<(&ApiToken, &str)>,
+    error: Option<&str>,
+) -> HttpResponse {
+    let store = sessions.tokens().clone();
+    let tokens = match web::block(move || store.tokens()).await {
+        Ok(Ok(tokens)) => tokens,
+        Ok(Err(e)) => return ServiceError::from(e).to_page(tmpl),
+        Err(e) => return ServiceError::from(e).to_page(tmpl),
+    };
+    let now = Utc::now();
+    let tokens: Vec<TokenView> = tokens
+        .into_iter()
+        .filter(|token| manages(user, token))
+        .map(|token| TokenView {
+            expired: token.is_expired(now),
+            token,
+        })
+        .collect();
+    let scopes: Vec<&str> = Scope::ALL
+        .iter()
+        .filter(|scope| scope.role() <= user.role)
+        .map(Scope::as_str)
+        .collect();
+
+    let mut ctx = page_context(req, "API tokens");
+    ctx.insert("tokens", &tokens);
+    ctx.insert("scopes", &scopes);
+    ctx.insert("may_create_service", &(user.role == Role::ReleaseManager));
+    ctx.insert("error", &error);
+    if let Some((token, secret)) = created {
+        ctx.insert("created", token);
+        ctx.insert("secret", secret);
+    }
+    let rendered = tmpl.render("tokens.html", &ctx).unwrap();
+    HttpResponse::build(status).body(rendered)
+}
+
+/// Lists the signed-in user's API tokens, with a form to create one.
+pub async fn tokens(
+    req: HttpRequest,
+    user: Option<CurrentUser>,
+    sessions: Option<web::Data<Sessions>>,
+    tmpl: web::Data<tera::Tera>,
+) -> HttpResponse {
+    let (sessions, user) = match accounts(sessions, user) {
+        Ok(accounts) => accounts,
+        Err(e) => return e.to_page(&tmpl),
+    };
+    tokens_page(&req, &tmpl, &sessions, &user, StatusCode::OK, None, None).await
+}
+
+/// Creates an API token and shows it, the only time it can be seen.
+pub async fn create(
+    req: HttpRequest,
+    user: Option<CurrentUser>,
+    form: web::Form<TokenForm>,
+    sessions: Option<web::Data<Sessions>>,
+    tmpl: web::Data<tera::Tera>,
+) -> HttpResponse {
+    let (sessions, user) = match accounts(sessions, user) {
+        Ok(accounts) => accounts,
+        Err(e) => return e.to_page(&tmpl),
+    };
+    let scopes = form.scopes();
+    let service = form.service.is_some();
+    let expires = expiry_after_days(form.expires_in_days);
+    let error = if let Err(e) = validate_token_name(&form.name) {
+        Some(e)
+    } else if scopes.is_empty() {
+        Some("Choose at least one scope.".to_string())
+    } else if let Some(scope) = scopes.iter().find(|scope| scope.role() > user.role) {
+        Some(format!(
+            "A {} cannot grant {}.",
+            user.role.as_str().replace('_', " "),
+            scope
+        ))
+    } else if service && user.role != Role::ReleaseManager {
+        Some("Only release managers create service tokens.".to_string())
+    } else if let Err(e) = &expires {
+        Some(format!("Cannot use that expiry: {}.", e))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return tokens_page(
+            &req,
+            &tmpl,
+            &sessions,
+            &user,
+            StatusCode::BAD_REQUEST,
+            None,
+            Some(&error),
+        )
+        .await;
+    }
+
+    let expires = expires.ok().flatten();
+    let owner = (!service).then(|| user.name.clone());
+    let (store, name) = (sessions.tokens().clone(), form.name.clone());
+    let created = web::block(move || store.create(&name, owner.as_deref(), scopes, expires)).await;
+    let (token, secret) = match created {
+        Ok(Ok(created)) => created,
+        Ok(Err(e)) => return ServiceError::from(e).to_page(&tmpl),
+        Err(e) => return ServiceError::from(e).to_page(&tmpl),
+    };
+    info!(
+        "{} created API token {} '{}' from {}",
+        user.name,
+        token.id,
+        token.name.escape_debug(),
+        actor(&req)
+    );
+    tokens_page(
+        &req,
+        &tmpl,
+        &sessions,
+        &user,
+        StatusCode::CREATED,
+        Some((&token, &secret)),
+        None,
+    )
+    .await
+}
+
+/// Revokes an API token the signed-in user manages.
+pub async fn revoke(
+    req: HttpRequest,
+    user: Option<CurrentUser>,
+    id: web::Path<String>,
+    sessions: Option<web::Data<Sessions>>,
+    tmpl: web::Data<tera::Tera>,
+) -> HttpResponse {
+    let (sessions, user) = match accounts(sessions, user) {
+        Ok(accounts) => accounts,
+        Err(e) => return e.to_page(&tmpl),
+    };
+    let (store, check, id) = (sessions.tokens().clone(), user.clone(), id.into_inner());
+    let revoked = web::block(move || -> std::io::Result<Option<ApiToken>> {
+        match store.tokens()?.into_iter().find(|token| token.id == id) {
+            Some(token) if manages(&check, &token) => store.revoke(&token.id),
+            _ => Ok(None),
+        }
+    })
+    .await;
+    let token = match revoked {
+        Ok(Ok(Some(token))) => token,
+        Ok(Ok(None)) => {
+            return ServiceError::new(
+                StatusCode::NOT_FOUND,
+                "Token not found",
+                "You have no API token with that ID.",
+            )
+            .to_page(&tmpl)
+        }
+        Ok(Err(e)) => return ServiceError::from(e).to_page(&tmpl),
+        Err(e) => return ServiceError::from(e).to_page(&tmpl),
+    };
+    info!(
+        "{} revoked API token {} '{}' from {}",
+        user.name,
+        token.id,
+        token.name.escape_debug(),
+        actor(&req)
+    );
+    HttpResponse::SeeOther()
+        .append_header((header::LOCATION, "/tokens"))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::{
+        cfg::default_template_glob,
+        csrf::{require_csrf_token, CSRF_HEADER},
+        session::enforce_roles,
+        tokens::TokenStore,
+        users::{hash_password, User, UserStore},
+    };
+
+    #[actix_web::test]
+    async fn far_off_expiries_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = UserStore::new(dir.path().join("users.json"));
+        let user = User {
+            name: "vera".to_string(),
+            role: Role::Viewer,
+            password: hash_password("secret").unwrap(),
+        };
+        users.save(user.clone()).unwrap();
+        let sessions = Sessions::new(
+            users,
+            TokenStore::new(dir.path().join("tokens.json")),
+            b"0123456789abcdef0123456789abcdef",
+            3600,
+            false,
+        )
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(sessions.clone()))
+                .app_data(web::Data::new(
+                    tera::Tera::new(&default_template_glob()).unwrap(),
+                ))
+                .wrap(from_fn(require_csrf_token))
+                .wrap(from_fn(enforce_roles))
+                .route("/tokens", web::post().to(create)),
+        )
+        .await;
+        let cookie = sessions.start(&user).unwrap();
+        let csrf = sessions.csrf_token(&sessions.verify(cookie.value()).unwrap());
+        let create = |days: &str| {
+            TestRequest::post()
+                .uri("/tokens")
+                .cookie(cookie.clone())
+                .insert_header((CSRF_HEADER.clone(), csrf.clone()))
+                .set_form([
+                    ("name", "laptop"),
+                    ("images:read", "on"),
+                    ("expires_in_days", days),
+                ])
+                .to_request()
+        };
+
+        for days in ["9223372036854775807", "200000000", "-1"] {
+            let response = call_service(&app, create(days)).await;
+            assert_eq!(StatusCode::BAD_REQUEST, response.status(), "{} days", days);
+        }
+        let response = call_service(&app, create("30")).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!(1, sessions.tokens().tokens().unwrap().len());
+    }
+}
@@ -0,0 +1,390 @@
+//! Resumable uploads.
+//!
+//! A client creates a session with `POST /uploads`, sends the image in any
+//! number of `PUT /uploads/{id}` requests each carrying an `Upload-Offset`
+//! header, asks where to continue with `HEAD /uploads/{id}` after a broken
+//! connection, and finishes with `POST /uploads/{id}/finalize` naming the
+//! SHA-256 it expects the image to have.
+
+use actix_web::{
+    http::{
+        header::{HeaderName, HeaderValue, LOCATION},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt as _;
+use log::{info, warn};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use super::{identity, json_error, openapi::Binary, ErrorBody};
+use crate::{
+    catalog::{Added, ImageDetails, ImageRef},
+    filename::validate_filename,
+    service::{ImageService, ServiceError},
+    staging::StagedUpload,
+    upload_session::{SessionError, SessionId, UploadSession, UploadSessions},
+};
+
+/// Header carrying the offset a chunk starts at, and the committed offset in responses.
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+/// Header carrying the total size announced when the session was created.
+pub const UPLOAD_LENGTH: &str = "Upload-Length";
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewUpload {
+    pub filename: String,
+    /// Total size of the image, when known up front.
+    pub length: Option<u64>,
+    #[serde(flatten)]
+    pub details: ImageDetails,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Finalize {
+    /// Hex SHA-256 the complete image must have.
+    pub sha256: String,
+}
+
+fn session_error(e: SessionError) -> HttpResponse {
+    match e {
+        SessionError::NotFound => {
+            json_error(StatusCode::NOT_FOUND, "Upload not found", &e.to_string())
+        }
+        SessionError::Busy => json_error(StatusCode::CONFLICT, "Upload busy", &e.to_string()),
+        SessionError::OffsetMismatch { expected } => {
+            let mut response = json_error(StatusCode::CONFLICT, "Offset mismatch", &e.to_string());
+            response.headers_mut().insert(
+                HeaderName::from_static("upload-offset"),
+                HeaderValue::from(expected),
+            );
+            response
+        }
+        SessionError::TooMany { .. } => json_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many uploads",
+            &e.to_string(),
+        ),
+        SessionError::TooLarge(_) => json_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Image too large",
+            &format!("Cannot store the image: {}.", e),
+        ),
+    }
+}
+
+/// Who owns the sessions `req` opens: its [`identity`], else the client's address.
+fn owner(req: &HttpRequest) -> String {
+    identity(req).unwrap_or_else(|| match req.peer_addr() {
+        Some(address) => address.ip().to_string(),
+        None => "unknown".to_string(),
+    })
+}
+
+/// Responds with the session, its offset and, when known, its length.
+fn session_response(status: StatusCode, session: &UploadSession) -> HttpResponse {
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header((UPLOAD_OFFSET, session.offset.to_string()))
+        .insert_header(("Cache-Control", "no-store"));
+    if let Some(length) = session.length {
+        response.insert_header((UPLOAD_LENGTH, length.to_string()));
+    }
+    response.json(session)
+}
+
+fn too_large(filename: &str, e: impl std::fmt::Display) -> HttpResponse {
+    json_error(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "Image too large",
+        &format!("Cannot store '{}': {}.", filename, e),
+    )
+}
+
+/// Opens an upload session.
+#[utoipa::path(
+    post,
+    path = "/api/v1/uploads",
+    tag = "uploads",
+    request_body = NewUpload,
+    responses(
+        (status = 201, description = "The session was opened", body = UploadSession,
+            headers(("Location" = String), ("Upload-Offset" = u64))),
+        (status = 400, description = "Invalid file name", body = ErrorBody),
+        (status = 413, description = "The announced length exceeds an upload limit", body = ErrorBody),
+        (status = 415, description = "The file name is not of an accepted type", body = ErrorBody),
+        (status = 429, description = "Too many unfinished uploads are open", body = ErrorBody),
+    )
+)]
+pub async fn create(
+    new: web::Json<NewUpload>,
+    req: HttpRequest,
+    service: web::Data<ImageService>,
+    sessions: web::Data<UploadSessions>,
+) -> actix_web::Result<HttpResponse> {
+    let filename = match validate_filename(&new.filename) {
+        Ok(filename) => filename.to_string(),
+        Err(e) => {
+            return Ok(json_error(
+                StatusCode::BAD_REQUEST,
+                "Invalid file name",
+                &format!("Cannot store '{}': {}.", new.filename.escape_debug(), e),
+            ))
+        }
+    };
+    if let Err(e) = service.image_types().for_name(&filename) {
+        return Ok(ServiceError::unsupported(&filename, e).to_json());
+    }
+    // Sets the announced length aside for as long as the session is open.
+    let reservation = match service.reserve().await {
+        Ok(reservation) => reservation,
+        Err(e) => return Ok(e.to_json()),
+    };
+    let staging_dir = service.cfg().staging_dir.clone();
+    let upload = web::block(move || StagedUpload::create(&staging_dir)).await??;
+    let length = new.length;
+    let mut details = new.into_inner().details;
+    if let Some(identity) = identity(&req) {
+        details.uploader = Some(identity);
+    }
+    let session = match sessions.create(
+        &filename,
+        length,
+        details,
+        &owner(&req),
+        upload,
+        reservation,
+    ) {
+        Ok(session) => session,
+        Err(SessionError::TooLarge(e)) => return Ok(too_large(&filename, e)),
+        Err(e) => return Ok(session_error(e)),
+    };
+    info!("Opened upload session {} for '{}'", session.id, filename);
+    let mut response = session_response(StatusCode::CREATED, &session);
+    let location = format!("/uploads/{}", session.id);
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+    Ok(response)
+}
+
+/// Reports how much of an upload has been received, answering both GET and HEAD.
+#[utoipa::path(
+    get,
+    path = "/api/v1/uploads/{id}",
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "Upload session ID")),
+    responses(
+        (status = 200, description = "The session", body = UploadSession,
+            headers(("Upload-Offset" = u64), ("Upload-Length" = u64))),
+        (status = 404, description = "No such session", body = ErrorBody),
+    )
+)]
+pub async fn status(
+    id: web::Path<SessionId>,
+    req: HttpRequest,
+    sessions: web::Data<UploadSessions>,
+) -> actix_web::Result<HttpResponse> {
+    Ok(match sessions.get(&id, &owner(&req)) {
+        Some(session) => session_response(StatusCode::OK, &session),
+        None => session_error(SessionError::NotFound),
+    })
+}
+
+/// Appends the request body to an upload, starting at the `Upload-Offset` header.
+///
+/// Everything written before the request fails or the connection drops is
+/// kept, so the client can continue from the offset reported afterwards.
+#[utoipa::path(
+    put,
+    path = "/api/v1/uploads/{id}",
+    tag = "uploads",
+    params(
+        ("id" = Uuid, Path, description = "Upload session ID"),
+        ("Upload-Offset" = u64, Header, description = "Offset the body starts at"),
+    ),
+    request_body(content = Binary, content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The chunk was written", headers(("Upload-Offset" = u64))),
+        (status = 400, description = "Missing offset or interrupted upload", body = ErrorBody),
+        (status = 404, description = "No such session", body = ErrorBody),
+        (status = 409, description = "Wrong offset, session busy or upload too long", body = ErrorBody,
+            headers(("Upload-Offset" = u64, description = "The offset to continue from"))),
+        (status = 413, description = "An upload limit was exceeded", body = ErrorBody),
+    )
+)]
+pub async fn append(
+    id: web::Path<SessionId>,
+    req: HttpRequest,
+    mut payload: web::Payload,
+    sessions: web::Data<UploadSessions>,
+) -> actix_web::Result<HttpResponse> {
+    let offset = match req
+        .headers()
+        .get(UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        Some(offset) => offset,
+        None => {
+            return Ok(json_error(
+                StatusCode::BAD_REQUEST,
+                "Missing offset",
+                "Send the offset the body starts at in the Upload-Offset header.",
+            ))
+        }
+    };
+    // The writer hands the file back to the session however this request ends.
+    let (session, mut writer) = match sessions.acquire(&id, &owner(&req), offset) {
+        Ok(acquired) => acquired,
+        Err(e) => return Ok(session_error(e)),
+    };
+
+    let mut failure = None;
+    while let Some(chunk) = payload.next().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Upload session {} interrupted: {}", session.id, e);
+                failure = Some(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "Upload interrupted",
+                    &e.to_string(),
+                ));
+                break;
+            }
+        };
+        let size = writer.size() + data.len() as u64;
+        if session.length.is_some_and(|length| size > length) {
+            failure = Some(json_error(
+                StatusCode::CONFLICT,
+                "Upload too long",
+                &format!(
+                    "The upload was announced as {} bytes.",
+                    session.length.unwrap_or_default()
+                ),
+            ));
+            break;
+        }
+        if let Err(e) = writer.reserve(size) {
+            failure = Some(too_large(&session.filename, e));
+            break;
+        }
+        // filesystem operations are blocking, we have to use threadpool.
+        // A chunk cut off part way discards the session when the writer drops.
+        let (returned, result) = web::block(move || {
+            let result = writer.write(&data);
+            (writer, result)
+        })
+        .await?;
+        writer = returned;
+        result?;
+    }
+
+    let session = match writer.release() {
+        Some(session) => session,
+        None => return Ok(session_error(SessionError::NotFound)),
+    };
+    Ok(match failure {
+        Some(failure) => failure,
+        None => {
+            let mut response = HttpResponse::NoContent();
+            response.insert_header((UPLOAD_OFFSET, session.offset.to_string()));
+            response.finish()
+        }
+    })
+}
+
+/// Verifies the expected digest and stores the upload as an image.
+#[utoipa::path(
+    post,
+    path = "/api/v1/uploads/{id}/finalize",
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "Upload session ID")),
+    request_body = Finalize,
+    responses(
+        (status = 201, description = "The image was stored", body = ImageRef),
+        (status = 200, description = "The image was stored before with identical content", body = ImageRef),
+        (status = 404, description = "No such session", body = ErrorBody),
+        (status = 409, description = "The upload is incomplete or the name refers to different content", body = ErrorBody),
+        (status = 415, description = "The content does not fit the file name's type; the upload was discarded", body = ErrorBody),
+        (status = 422, description = "The digest does not match; the upload was discarded", body = ErrorBody),
+    )
+)]
+pub async fn finalize(
+    id: web::Path<SessionId>,
+    req: HttpRequest,
+    finalize: web::Json<Finalize>,
+    service: web::Data<ImageService>,
+    sessions: web::Data<UploadSessions>,
+) -> actix_web::Result<HttpResponse> {
+    let owner = owner(&req);
+    if let Some(session) = sessions.get(&id, &owner) {
+        if session.length.is_some_and(|length| session.offset < length) {
+            return Ok(json_error(
+                StatusCode::CONFLICT,
+                "Upload incomplete",
+                &format!(
+                    "Only {} of {} bytes have been received.",
+                    session.offset,
+                    session.length.unwrap_or_default()
+                ),
+            ));
+        }
+    }
+    // Received bytes were set aside as they arrived, and stay so until the image is stored.
+    let (session, upload, _reservation) = match sessions.remove(&id, &owner) {
+        Ok(removed) => removed,
+        Err(e) => return Ok(session_error(e)),
+    };
+    let staged = web::block(move || upload.finish()).await??;
+    if !staged.digest.eq_ignore_ascii_case(finalize.sha256.trim()) {
+        warn!(
+            "Upload session {} for '{}' ended with sha256:{}, expected {}",
+            session.id, session.filename, staged.digest, finalize.sha256
+        );
+        return Ok(json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Digest mismatch",
+            &format!(
+                "The received {} bytes have SHA-256 {}, not {}. The upload was discarded.",
+                staged.size, staged.digest, finalize.sha256
+            ),
+        ));
+    }
+
+    Ok(
+        match service
+            .add_image(&session.filename, staged, session.details)
+            .await
+        {
+            Ok(Added::Created(image)) => HttpResponse::Created().json(image),
+            Ok(Added::Unchanged(image)) => HttpResponse::Ok().json(image),
+            Ok(Added::Conflict(image)) => ServiceError::image_exists(&image).to_json(),
+            Err(e) => e.to_json(),
+        },
+    )
+}
+
+/// Abandons an upload, deleting what was received.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/uploads/{id}",
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "Upload session ID")),
+    responses(
+        (status = 204, description = "The upload was discarded"),
+        (status = 404, description = "No such session", body = ErrorBody),
+    )
+)]
+pub async fn cancel(
+    id: web::Path<SessionId>,
+    req: HttpRequest,
+    sessions: web::Data<UploadSessions>,
+) -> actix_web::Result<HttpResponse> {
+    Ok(match sessions.remove(&id, &owner(&req)) {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => session_error(e),
+    })
+}
@@ -0,0 +1,1104 @@
+//! Operations shared by the HTML pages and the JSON API.
+//!
+//! Handlers parse requests and render responses; everything else, including
+//! which status code a failure maps to, lives here so both front ends behave
+//! the same.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, web::Bytes};
+use chrono::Utc;
+use futures_util::{Stream, StreamExt as _};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditAction, AuditEntry, AuditLog},
+    catalog::{Added, Catalog, ImageDetails, ImageRef, Renamed},
+    cfg::Cfg,
+    compression::{Compressed, Compression},
+    convert::{convert, parse_address, Conversion, ConvertError, ConvertOptions, Format},
+    delta::{self, Delta},
+    filename::validate_filename,
+    image_type::{ImageTypes, Unsupported},
+    job::{JobId, JobQueue},
+    manifest::{record::ManifestRecord, ManifestRequest},
+    staging::{Reservation, Staged, StagedUpload},
+    store::ByteStream,
+};
+
+/// A failed operation, carrying the status code and message to report.
+#[derive(Debug)]
+pub struct ServiceError {
+    pub status: StatusCode,
+    pub title: &'static str,
+    pub detail: String,
+}
+
+impl ServiceError {
+    pub fn new(status: StatusCode, title: &'static str, detail: impl Into<String>) -> Self {
+        ServiceError {
+            status,
+            title,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn bad_request(title: &'static str, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, title, detail)
+    }
+
+    pub fn image_exists(image: &ImageRef) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "Image exists",
+            format!(
+                "'{}' already refers to different content (sha256:{}).",
+                image.name, image.digest
+            ),
+        )
+    }
+
+    fn in_use(image: &ImageRef, references: &[String], action: &str) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "Image in use",
+            format!(
+                "'{}' is referenced by generated manifests: {}. {} it anyway with force.",
+                image.name,
+                references.join(", "),
+                action
+            ),
+        )
+    }
+
+    pub fn unsupported(name: &str, e: Unsupported) -> Self {
+        Self::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported image type",
+            format!("Cannot store '{}': {}.", name, e),
+        )
+    }
+
+    fn image_not_found(name: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "Image not found",
+            format!("There is no uploaded image named '{}'.", name),
+        )
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.title, self.detail)
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl From<io::Error> for ServiceError {
+    fn from(e: io::Error) -> Self {
+        warn!("Storage error: {}", e);
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Storage error",
+            e.to_string(),
+        )
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ServiceError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal error",
+            e.to_string(),
+        )
+    }
+}
+
+impl From<actix_multipart::MultipartError> for ServiceError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        Self::bad_request("Malformed upload", e.to_string())
+    }
+}
+
+/// An image together with the manifests generated from it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ImageView {
+    pub image: ImageRef,
+    pub manifests: Vec<ManifestRecord>,
+    /// Manifests generated from the image under its current name, which
+    /// deleting or renaming it would leave pointing at nothing.
+    pub references: Vec<String>,
+}
+
+/// The images stored by one upload request.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct Uploaded {
+    /// Images that did not exist before.
+    pub created: Vec<ImageRef>,
+    /// Images that already existed with identical content.
+    pub unchanged: Vec<ImageRef>,
+}
+
+impl Uploaded {
+    fn push(&mut self, added: Added) -> Result<(), ServiceError> {
+        match added {
+            Added::Created(image) => self.created.push(image),
+            Added::Unchanged(image) => self.unchanged.push(image),
+            Added::Conflict(image) => return Err(ServiceError::image_exists(&image)),
+        }
+        Ok(())
+    }
+
+    /// The image uploaded last, if any.
+    pub fn last(&self) -> Option<&ImageRef> {
+        self.created.last().or(self.unchanged.last())
+    }
+}
+
+/// How to convert an image to a raw binary. Unset fields fall back to the configuration.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ConvertRequest {
+    /// `ihex`, `srec`, `elf` or `uf2`. Detected from the content when unset.
+    pub format: Option<String>,
+    /// Byte written into gaps, in decimal or `0x` hexadecimal.
+    pub fill_byte: Option<String>,
+    /// Address the first byte of the binary belongs at, e.g. `0x08000000`.
+    pub base_address: Option<String>,
+}
+
+impl ConvertRequest {
+    /// Sets the field named `name` from a form value, ignoring unknown names and blank values.
+    ///
+    /// Returns whether `name` is a conversion field.
+    pub fn set(&mut self, name: &str, value: String) -> bool {
+        let field = match name {
+            "format" => &mut self.format,
+            "fill_byte" => &mut self.fill_byte,
+            "base_address" => &mut self.base_address,
+            _ => return false,
+        };
+        *field = Some(value).filter(|value| !value.trim().is_empty() && value != "auto");
+        true
+    }
+
+    /// Resolves the request against the configured defaults.
+    pub fn options(&self, cfg: &Cfg) -> Result<(Option<Format>, ConvertOptions), ConvertError> {
+        let mut options = ConvertOptions::from_cfg(cfg)?;
+        if let Some(fill_byte) = &self.fill_byte {
+            options.fill_byte = parse_address(fill_byte)?
+                .and_then(|fill_byte| u8::try_from(fill_byte).ok())
+                .ok_or_else(|| {
+                    ConvertError::Invalid(format!("'{}' is not a byte value", fill_byte))
+                })?;
+        }
+        if let Some(base_address) = &self.base_address {
+            options.base_address = parse_address(base_address)?;
+        }
+        let format = self.format.as_deref().map(str::parse).transpose()?;
+        Ok((format, options))
+    }
+}
+
+/// Whether a checkbox or flag value means yes.
+pub fn is_checked(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "on" | "true" | "1" | "yes"
+    )
+}
+
+#[derive(Clone)]
+pub struct ImageService {
+    cfg: Cfg,
+    catalog: Catalog,
+    jobs: JobQueue,
+    audit: AuditLog,
+    types: ImageTypes,
+    /// Limits the deltas, conversions and compressions holding images in memory at once.
+    derivations: Arc<Semaphore>,
+}
+
+impl ImageService {
+    pub fn new(cfg: Cfg, catalog: Catalog, jobs: JobQueue) -> Self {
+        let audit = AuditLog::from_cfg(&cfg);
+        let types = ImageTypes::new(cfg.image_types.clone());
+        let derivations = Arc::new(Semaphore::new(cfg.derive_workers.max(1)));
+        ImageService {
+            cfg,
+            catalog,
+            jobs,
+            audit,
+            types,
+            derivations,
+        }
+    }
+
+    pub fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    /// Waits until a delta, conversion or compression may read its images into memory.
+    async fn derivation_slot(&self) -> SemaphorePermit<'_> {
+        self.derivations
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    pub fn jobs(&self) -> &JobQueue {
+        &self.jobs
+    }
+
+    pub fn image_types(&self) -> &ImageTypes {
+        &self.types
+    }
+
+    /// Lists the stored firmware images.
+    pub async fn list_images(&self) -> Result<Vec<ImageRef>, ServiceError> {
+        Ok(self
+            .catalog
+            .list()
+            .await?
+            .into_iter()
+            .filter(|image| self.types.for_name(&image.name).is_ok())
+            .collect())
+    }
+
+    pub async fn image(&self, name: &str) -> Result<ImageRef, ServiceError> {
+        self.catalog
+            .get(name)
+            .await?
+            .ok_or_else(|| ServiceError::image_not_found(name))
+    }
+
+    pub async fn image_view(&self, name: &str) -> Result<ImageView, ServiceError> {
+        let image = self.image(name).await?;
+        let manifests_dir = PathBuf::from(&self.cfg.manifests_dir);
+        let digest = image.digest.clone();
+        let manifests =
+            web::block(move || ManifestRecord::for_digest(&manifests_dir, &digest)).await??;
+        let references = manifests
+            .iter()
+            .filter(|record| record.image == image.name)
+            .map(|record| record.file.clone())
+            .collect();
+        Ok(ImageView {
+            image,
+            manifests,
+            references,
+        })
+    }
+
+    /// Reads part of an image found with [`Self::image`].
+    pub async fn read_image_range(
+        &self,
+        image: &ImageRef,
+        start: u64,
+        len: u64,
+    ) -> Result<Bytes, ServiceError> {
+        Ok(self.catalog.read_range(image, start, len).await?)
+    }
+
+    /// Streams part of an image found with [`Self::image`].
+    pub async fn stream_image_range(
+        &self,
+        image: &ImageRef,
+        start: u64,
+        len: u64,
+    ) -> Result<ByteStream, ServiceError> {
+        Ok(self.catalog.read_stream(image, start, len).await?)
+    }
+
+    /// Deletes the image called `name`.
+    ///
+    /// Refuses with 409 while generated manifests refer to the image, unless `force` is set.
+    pub async fn delete_image(
+        &self,
+        name: &str,
+        force: bool,
+        actor: &str,
+    ) -> Result<ImageRef, ServiceError> {
+        // Held until the image is gone, so the checks below still hold then.
+        let changes = self.catalog.lock().await;
+        let image = self.image(name).await?;
+        let references = self.references(&image).await?;
+        if !references.is_empty() && !force {
+            return Err(ServiceError::in_use(&image, &references, "Delete"));
+        }
+        let deleted = match self.catalog.delete(&changes, name).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(ServiceError::image_not_found(name)),
+            Err(e) => Err(ServiceError::from(e)),
+        };
+        drop(changes);
+        let recorded = self
+            .audit(AuditEntry {
+                time: Utc::now(),
+                action: AuditAction::Delete,
+                actor: actor.to_string(),
+                image: image.name.clone(),
+                digest: image.digest.clone(),
+                renamed_to: None,
+                manifests: references,
+                error: deleted.as_ref().err().map(|e| e.detail.clone()),
+            })
+            .await;
+        deleted?;
+        recorded?;
+        info!("Deleted image '{}' (sha256:{})", image.name, image.digest);
+        Ok(image)
+    }
+
+    /// Renames the image called `name` to `to`.
+    ///
+    /// Refuses with 409 when `to` is taken, or while generated manifests refer
+    /// to the image by its old name unless `force` is set.
+    pub async fn rename_image(
+        &self,
+        name: &str,
+        to: &str,
+        force: bool,
+        actor: &str,
+    ) -> Result<ImageRef, ServiceError> {
+        let to = validate_filename(to).map_err(|e| {
+            ServiceError::bad_request(
+                "Invalid image name",
+                format!("'{}' cannot name an image: {}.", to.escape_debug(), e),
+            )
+        })?;
+        let image_type = self
+            .types
+            .for_name(to)
+            .map_err(|e| ServiceError::unsupported(to, e))?;
+        // Held until the image is renamed, so the checks below still hold then.
+        let changes = self.catalog.lock().await;
+        let image = self.image(name).await?;
+        if to == image.name {
+            return Ok(image);
+        }
+        let head_len = (self.types.head_len() as u64).min(image.size);
+        let head = self.catalog.read_range(&image, 0, head_len).await?;
+        if !image_type.matches(&head) {
+            return Err(ServiceError::unsupported(
+                to,
+                Unsupported::Content(image_type.name.clone()),
+            ));
+        }
+        let references = self.references(&image).await?;
+        if !references.is_empty() && !force {
+            return Err(ServiceError::in_use(&image, &references, "Rename"));
+        }
+        let renamed = match self.catalog.rename(&changes, &image, to).await {
+            Ok(Renamed::Renamed(renamed)) => Ok(renamed),
+            Ok(Renamed::Conflict(_)) => {
+                return Err(ServiceError::new(
+                    StatusCode::CONFLICT,
+                    "Image exists",
+                    format!(
+                        "'{}' already names an image. Delete or rename it first.",
+                        to
+                    ),
+                ))
+            }
+            Err(e) => Err(e),
+        };
+        drop(changes);
+        let recorded = self
+            .audit(AuditEntry {
+                time: Utc::now(),
+                action: AuditAction::Rename,
+                actor: actor.to_string(),
+                image: image.name.clone(),
+                digest: image.digest.clone(),
+                renamed_to: Some(to.to_string()),
+                manifests: references,
+                error: renamed.as_ref().err().map(|e| e.to_string()),
+            })
+            .await;
+        let renamed = renamed?;
+        recorded?;
+        info!("Renamed image '{}' to '{}'", image.name, renamed.name);
+        Ok(renamed)
+    }
+
+    /// Files of the manifests generated from `image` under its current name.
+    async fn references(&self, image: &ImageRef) -> Result<Vec<String>, ServiceError> {
+        let manifests_dir = PathBuf::from(&self.cfg.manifests_dir);
+        let image = image.clone();
+        let records =
+            web::block(move || ManifestRecord::for_image(&manifests_dir, &image)).await??;
+        Ok(records.into_iter().map(|record| record.file).collect())
+    }
+
+    /// Records a change once its outcome is known.
+    ///
+    /// A failed change is reported as such rather than as this error, which
+    /// therefore means the change was made.
+    async fn audit(&self, entry: AuditEntry) -> Result<(), ServiceError> {
+        let audit = self.audit.clone();
+        web::block(move || audit.record(&entry))
+            .await?
+            .map_err(|e| {
+                warn!("Cannot write audit log: {}", e);
+                ServiceError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Audit log unavailable",
+                    format!("The change was made, but could not be recorded: {}.", e),
+                )
+            })
+    }
+
+    /// Receives `content` into the staging directory, setting storage aside as it arrives.
+    pub async fn receive<S, E>(
+        &self,
+        filename: &str,
+        mut content: S,
+        reservation: &mut Reservation,
+    ) -> Result<Staged, ServiceError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        // File::create is blocking operation, use threadpool
+        let staging_dir = self.cfg.staging_dir.clone();
+        let mut upload = web::block(move || StagedUpload::create(staging_dir)).await??;
+
+        while let Some(chunk) = content.next().await {
+            let data = chunk
+                .map_err(|e| ServiceError::bad_request("Upload interrupted", e.to_string()))?;
+            let more = data.len() as u64;
+            if let Err(e) = reservation.take(upload.size() + more, more) {
+                warn!("Rejected upload of '{}': {}", filename, e);
+                // Dropping `upload` removes what was written so far.
+                return Err(ServiceError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Image too large",
+                    format!("Cannot store '{}': {}.", filename, e),
+                ));
+            }
+            // filesystem operations are blocking, we have to use threadpool
+            upload = web::block(move || upload.write(&data).map(|_| upload)).await??;
+        }
+        Ok(web::block(move || upload.finish()).await??)
+    }
+
+    /// Starts setting storage aside for an upload; hold it until the upload is stored.
+    pub async fn reserve(&self) -> Result<Reservation, ServiceError> {
+        Ok(self.catalog.reserve(&self.cfg).await?)
+    }
+
+    /// Stores a received file under `filename` once its content is found to fit the name.
+    pub async fn add_image(
+        &self,
+        filename: &str,
+        staged: Staged,
+        details: ImageDetails,
+    ) -> Result<Added, ServiceError> {
+        let path = staged.path.to_path_buf();
+        let head_len = self.types.head_len();
+        let head = web::block(move || read_head(&path, head_len)).await??;
+        if let Err(e) = self.types.check(filename, &head) {
+            warn!("Rejected upload of '{}': {}", filename, e);
+            return Err(ServiceError::unsupported(filename, e));
+        }
+        // The staged file is removed when `staged` is dropped, even if storing it fails.
+        let added = self.catalog.add(filename, staged, details).await?;
+        match &added {
+            Added::Created(image) => {
+                info!("Stored '{}' as sha256:{}", image.name, image.digest)
+            }
+            Added::Unchanged(image) => {
+                debug!("'{}' was uploaded again unchanged", image.name)
+            }
+            Added::Conflict(_) => {}
+        }
+        Ok(added)
+    }
+
+    /// Stores the files of a multipart upload form.
+    ///
+    /// Every part with a file name is an image; the `uploader`, `version`,
+    /// `hardware_class` and `notes` parts apply to all of them and may come
+    /// before or after the files. When `convert` is set, each image is also
+    /// converted to a raw binary as described by the [`ConvertRequest`] parts.
+    /// The `compression` part, or `payload_compression` when absent, also
+    /// stores each payload compressed: the binary when converting, the image
+    /// otherwise. When any part fails, including storing a later image, the
+    /// images this upload already created are removed again.
+    /// An authenticated `uploader` replaces the one the form names.
+    pub async fn upload(
+        &self,
+        mut payload: Multipart,
+        uploader: Option<&str>,
+    ) -> Result<Uploaded, ServiceError> {
+        let mut details = ImageDetails::default();
+        let mut convert = false;
+        let mut conversion = ConvertRequest::default();
+        let mut compression = None;
+        let mut received = Vec::new();
+        // Covers every file received and derived, until they are stored.
+        let mut reservation = self.reserve().await?;
+        while let Some(item) = payload.next().await {
+            let mut field = item?;
+            let content_disposition = field.content_disposition();
+
+            if let Some(filename) = content_disposition.get_filename() {
+                let filename = validate_filename(filename)
+                    .map_err(|e| {
+                        ServiceError::bad_request(
+                            "Invalid file name",
+                            format!("Cannot store '{}': {}.", filename.escape_debug(), e),
+                        )
+                    })?
+                    .to_string();
+                self.types
+                    .for_name(&filename)
+                    .map_err(|e| ServiceError::unsupported(&filename, e))?;
+                debug!("Writing file '{}'", filename);
+                let staged = self
+                    .receive(&filename, &mut field, &mut reservation)
+                    .await?;
+                received.push((filename, staged));
+            } else if let Some(name) = content_disposition.get_name().map(str::to_owned) {
+                let value = read_text_field(&mut field).await?;
+                if name == "convert" {
+                    convert = is_checked(&value);
+                } else if name == "compression" {
+                    compression = Some(value);
+                } else if !details.set(&name, value.clone()) {
+                    conversion.set(&name, value);
+                }
+            }
+        }
+
+        if let Some(uploader) = uploader {
+            details.uploader = Some(uploader.to_string());
+        }
+        let compression = self.compression_choice(compression.as_deref())?;
+        let _slot = if convert || compression.is_some() {
+            Some(self.derivation_slot().await)
+        } else {
+            None
+        };
+        let mut binaries = Vec::new();
+        if convert {
+            for (filename, staged) in &received {
+                let path = staged.path.to_path_buf();
+                let content = web::block(move || std::fs::read(path)).await??;
+                let (output, binary, converted_from) = self
+                    .convert_content(
+                        filename,
+                        &staged.digest,
+                        content,
+                        &conversion,
+                        &mut reservation,
+                    )
+                    .await?;
+                // The binary carries the metadata given for the original.
+                let binary_details = ImageDetails {
+                    converted_from: Some(converted_from),
+                    ..details.clone()
+                };
+                binaries.push((output, binary, binary_details));
+            }
+        }
+
+        let mut compressed = Vec::new();
+        if let Some(compression) = compression {
+            let payloads: Vec<(&String, &Staged, &ImageDetails)> = if convert {
+                binaries
+                    .iter()
+                    .map(|(output, binary, binary_details)| (output, binary, binary_details))
+                    .collect()
+            } else {
+                received
+                    .iter()
+                    .map(|(filename, staged)| (filename, staged, &details))
+                    .collect()
+            };
+            for (name, staged, payload_details) in payloads {
+                let path = staged.path.to_path_buf();
+                let content = web::block(move || std::fs::read(path)).await??;
+                let (output, payload, compressed_from) = self
+                    .compress_content(name, &staged.digest, content, compression, &mut reservation)
+                    .await?;
+                let payload_details = ImageDetails {
+                    compression: Some(compressed_from),
+                    ..payload_details.clone()
+                };
+                compressed.push((output, payload, payload_details));
+            }
+        }
+
+        let mut uploaded = Uploaded::default();
+        let stored = async {
+            for (filename, staged) in received {
+                uploaded.push(self.add_image(&filename, staged, details.clone()).await?)?;
+            }
+            for (output, derived, derived_details) in binaries.into_iter().chain(compressed) {
+                uploaded.push(
+                    self.store_derived(&output, derived, derived_details)
+                        .await?,
+                )?;
+            }
+            Ok::<_, ServiceError>(())
+        }
+        .await;
+        if let Err(e) = stored {
+            self.discard(&uploaded.created).await;
+            return Err(e);
+        }
+        Ok(uploaded)
+    }
+
+    /// Removes the images an upload created before a later part of it failed.
+    async fn discard(&self, created: &[ImageRef]) {
+        let changes = self.catalog.lock().await;
+        for image in created {
+            // Leave the name alone if it has since been given to other content.
+            let current = match self.catalog.get(&image.name).await {
+                Ok(Some(current)) if current.digest == image.digest => current,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(
+                        "Could not remove '{}' after a failed upload: {}",
+                        image.name, e
+                    );
+                    continue;
+                }
+            };
+            match self.catalog.delete(&changes, &current.name).await {
+                Ok(_) => info!("Removed '{}' after a failed upload", current.name),
+                Err(e) => warn!(
+                    "Could not remove '{}' after a failed upload: {}",
+                    current.name, e
+                ),
+            }
+        }
+    }
+
+    /// Converts the stored image called `name` to a raw binary, stored alongside as `<name>.bin`.
+    pub async fn convert_image(
+        &self,
+        name: &str,
+        request: &ConvertRequest,
+    ) -> Result<Added, ServiceError> {
+        let image = self.image(name).await?;
+        let _slot = self.derivation_slot().await;
+        let mut reservation = self.reserve().await?;
+        let content = self.catalog.read_range(&image, 0, image.size).await?;
+        let (output, staged, converted_from) = self
+            .convert_content(
+                &image.name,
+                &image.digest,
+                content.to_vec(),
+                request,
+                &mut reservation,
+            )
+            .await?;
+        let details = ImageDetails {
+            converted_from: Some(converted_from),
+            ..image.details
+        };
+        self.store_derived(&output, staged, details).await
+    }
+
+    /// Converts `content`, the image `name` with `digest`, and stages the binary.
+    ///
+    /// Returns the name the binary is stored under and how it was converted.
+    async fn convert_content(
+        &self,
+        name: &str,
+        digest: &str,
+        content: Vec<u8>,
+        request: &ConvertRequest,
+        reservation: &mut Reservation,
+    ) -> Result<(String, Staged, Conversion), ServiceError> {
+        let cannot_convert = |e: ConvertError| {
+            let status = match e {
+                ConvertError::Invalid(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            ServiceError::new(
+                status,
+                "Conversion failed",
+                format!("Cannot convert '{}': {}.", name, e),
+            )
+        };
+        let (format, options) = request.options(&self.cfg).map_err(cannot_convert)?;
+        let converted = web::block(move || convert(&content, format, &options))
+            .await?
+            .map_err(cannot_convert)?;
+
+        let output = format!("{}.bin", file_stem(name));
+        let size = converted.binary.len() as u64;
+        if let Err(e) = reservation.take(size, size) {
+            return Err(ServiceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Image too large",
+                format!("Cannot store '{}': {}.", output, e),
+            ));
+        }
+        let conversion = Conversion {
+            image: name.to_string(),
+            digest: digest.to_string(),
+            format: converted.format,
+            base_address: converted.base_address,
+            fill_byte: options.fill_byte,
+        };
+        let staging_dir = self.cfg.staging_dir.clone();
+        let staged = web::block(move || {
+            let mut upload = StagedUpload::create(staging_dir)?;
+            upload.write(&converted.binary)?;
+            upload.finish()
+        })
+        .await??;
+        info!(
+            "Converted '{}' from {} to '{}' based at {:#x}",
+            name, converted.format, output, converted.base_address
+        );
+        Ok((output, staged, conversion))
+    }
+
+    /// The compression chosen by an upload, or the configured default when it does not choose.
+    fn compression_choice(&self, value: Option<&str>) -> Result<Option<Compression>, ServiceError> {
+        Compression::parse_choice(value.unwrap_or(&self.cfg.payload_compression))
+            .map_err(|e| ServiceError::bad_request("Unknown compression", format!("{}.", e)))
+    }
+
+    /// Compresses the stored image called `name`, stored alongside as `<name>.<extension>`.
+    pub async fn compress_image(
+        &self,
+        name: &str,
+        compression: Compression,
+    ) -> Result<Added, ServiceError> {
+        let image = self.image(name).await?;
+        if let Some(compressed) = &image.details.compression {
+            return Err(ServiceError::bad_request(
+                "Already compressed",
+                format!(
+                    "'{}' is '{}' compressed with {}.",
+                    image.name, compressed.image, compressed.algorithm
+                ),
+            ));
+        }
+        let _slot = self.derivation_slot().await;
+        let mut reservation = self.reserve().await?;
+        let content = self.catalog.read_range(&image, 0, image.size).await?;
+        let (output, staged, compressed) = self
+            .compress_content(
+                &image.name,
+                &image.digest,
+                content.to_vec(),
+                compression,
+                &mut reservation,
+            )
+            .await?;
+        let details = ImageDetails {
+            compression: Some(compressed),
+            ..image.details
+        };
+        self.store_derived(&output, staged, details).await
+    }
+
+    /// Compresses `content`, the image `name` with `digest`, and stages the result.
+    ///
+    /// Returns the name the payload is stored under and what it was compressed from.
+    async fn compress_content(
+        &self,
+        name: &str,
+        digest: &str,
+        content: Vec<u8>,
+        compression: Compression,
+        reservation: &mut Reservation,
+    ) -> Result<(String, Staged, Compressed), ServiceError> {
+        let uncompressed_size = content.len() as u64;
+        let payload = web::block(move || compression.compress(&content)).await??;
+        let output = format!("{}.{}", name, compression.extension());
+        let size = payload.len() as u64;
+        if let Err(e) = reservation.take(size, size) {
+            return Err(ServiceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Image too large",
+                format!("Cannot store '{}': {}.", output, e),
+            ));
+        }
+        info!(
+            "Compressed '{}' with {} to '{}': {} of {} bytes",
+            name,
+            compression,
+            output,
+            payload.len(),
+            uncompressed_size
+        );
+        let staging_dir = self.cfg.staging_dir.clone();
+        let staged = web::block(move || {
+            let mut upload = StagedUpload::create(staging_dir)?;
+            upload.write(&payload)?;
+            upload.finish()
+        })
+        .await??;
+        let compressed = Compressed {
+            algorithm: compression,
+            image: name.to_string(),
+            uncompressed_digest: digest.to_string(),
+            uncompressed_size,
+        };
+        Ok((output, staged, compressed))
+    }
+
+    /// Computes the delta that turns the image `base` into the image `target`.
+    ///
+    /// The delta is stored as `<target>-from-<base>.delta` with the target's
+    /// metadata and both digests, so manifests for it become delta manifests.
+    pub async fn create_delta(&self, target: &str, base: &str) -> Result<Added, ServiceError> {
+        if base.is_empty() {
+            return Err(ServiceError::bad_request(
+                "No base image",
+                "Choose the image devices run now to compute the delta from.",
+            ));
+        }
+        let target = self.image(target).await?;
+        let base = self.image(base).await?;
+        if base.digest == target.digest {
+            return Err(ServiceError::bad_request(
+                "Identical images",
+                format!(
+                    "'{}' and '{}' have the same content; there is nothing to update.",
+                    base.name, target.name
+                ),
+            ));
+        }
+        let output = format!(
+            "{}-from-{}.delta",
+            file_stem(&target.name),
+            file_stem(&base.name)
+        );
+        let limit = self.cfg.max_delta_image_size as u64;
+        if let Some(image) = [&base, &target]
+            .into_iter()
+            .find(|image| limit > 0 && image.size > limit)
+        {
+            return Err(ServiceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Image too large",
+                format!(
+                    "Cannot compute '{}': '{}' is {} bytes, and deltas are only computed \
+                     between images of at most {} bytes.",
+                    output, image.name, image.size, limit
+                ),
+            ));
+        }
+        let _slot = self.derivation_slot().await;
+        let old = self.catalog.read_range(&base, 0, base.size).await?;
+        let new = self.catalog.read_range(&target, 0, target.size).await?;
+        let patch = web::block(move || {
+            let patch = delta::diff(&old, &new);
+            // A delta that does not rebuild the target would brick devices.
+            let rebuilt = delta::apply(&old, &patch).map_err(|e| e.to_string())?;
+            if rebuilt != new {
+                return Err("the delta does not rebuild the target".to_string());
+            }
+            Ok(patch)
+        })
+        .await?
+        .map_err(|e| {
+            ServiceError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Delta failed",
+                format!("Cannot compute '{}': {}.", output, e),
+            )
+        })?;
+
+        let mut reservation = self.reserve().await?;
+        let size = patch.len() as u64;
+        if let Err(e) = reservation.take(size, size) {
+            return Err(ServiceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Image too large",
+                format!("Cannot store '{}': {}.", output, e),
+            ));
+        }
+        info!(
+            "Computed '{}' from '{}' to '{}': {} bytes for {}",
+            output,
+            base.name,
+            target.name,
+            patch.len(),
+            target.size
+        );
+        let staging_dir = self.cfg.staging_dir.clone();
+        let staged = web::block(move || {
+            let mut upload = StagedUpload::create(staging_dir)?;
+            upload.write(&patch)?;
+            upload.finish()
+        })
+        .await??;
+        let details = ImageDetails {
+            converted_from: None,
+            compression: None,
+            delta: Some(Delta {
+                base: base.name,
+                base_digest: base.digest,
+                target: target.name,
+                target_digest: target.digest,
+                target_size: target.size,
+            }),
+            ..target.details
+        };
+        self.store_derived(&output, staged, details).await
+    }
+
+    /// Stores an image made from another; different content under its name is a conflict.
+    async fn store_derived(
+        &self,
+        output: &str,
+        staged: Staged,
+        details: ImageDetails,
+    ) -> Result<Added, ServiceError> {
+        match self.add_image(output, staged, details).await? {
+            Added::Conflict(image) => Err(ServiceError::image_exists(&image)),
+            added => Ok(added),
+        }
+    }
+
+    /// Reads a generated manifest by file name.
+    pub async fn read_manifest(&self, file: &str) -> Result<Vec<u8>, ServiceError> {
+        let not_found = || {
+            ServiceError::new(
+                StatusCode::NOT_FOUND,
+                "Manifest not found",
+                format!("There is no generated manifest named '{}'.", file),
+            )
+        };
+        // Only files with a record are manifests; the check also keeps `file` inside the directory.
+        if validate_filename(file).is_err() {
+            return Err(not_found());
+        }
+        let manifests_dir = PathBuf::from(&self.cfg.manifests_dir);
+        let name = file.to_string();
+        let manifest = web::block(move || {
+            if ManifestRecord::find(&manifests_dir, &name)?.is_none() {
+                return Ok(None);
+            }
+            std::fs::read(manifests_dir.join(&name)).map(Some)
+        })
+        .await?;
+        match manifest {
+            Ok(Some(manifest)) => Ok(manifest),
+            Ok(None) => Err(not_found()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Queues generation of a manifest for the image called `name`.
+    pub async fn generate_manifest(
+        &self,
+        name: &str,
+        payload_uri: &str,
+        requested_by: Option<&str>,
+    ) -> Result<JobId, ServiceError> {
+        if name.is_empty() {
+            return Err(ServiceError::bad_request(
+                "No image selected",
+                "Choose an uploaded image to generate a manifest for.",
+            ));
+        }
+        if payload_uri.is_empty() {
+            return Err(ServiceError::bad_request(
+                "No payload URI",
+                "Enter the URI devices will download the payload from.",
+            ));
+        }
+        if let Err(e) = validate_filename(name) {
+            return Err(ServiceError::bad_request(
+                "Invalid image name",
+                format!("'{}' cannot name an image: {}.", name.escape_debug(), e),
+            ));
+        }
+        let image = self.image(name).await?;
+        let extended = image.details.delta.is_some() || image.details.compression.is_some();
+        if extended && self.cfg.manifest_encoder != "fixme" {
+            return Err(ServiceError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Manifest unsupported",
+                format!(
+                    "'{}' is a delta or compressed, which only manifests in fixme's format can describe.",
+                    name
+                ),
+            ));
+        }
+        let request = ManifestRequest::for_upload(&self.cfg, &image, payload_uri);
+        debug!("Queueing manifest job {:?}", request);
+        let requested_by = requested_by.map(str::to_owned);
+        self.jobs
+            .enqueue(&image, request, requested_by)
+            .map_err(|e| {
+                warn!("Rejected manifest job for '{}': {}", name, e);
+                ServiceError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many manifest jobs",
+                    "The manifest job queue is full. Try again once running jobs finish.",
+                )
+            })
+    }
+}
+
+/// The part of an image name before its extension.
+fn file_stem(name: &str) -> &str {
+    Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name)
+}
+
+/// Reads up to `len` bytes from the start of the file at `path`.
+fn read_head(path: &Path, len: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    std::fs::File::open(path)?
+        .take(len as u64)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Longest text field, in bytes, a multipart form may carry.
+pub const MAX_TEXT_FIELD: usize = 64 * 1024;
+
+/// Reads a multipart text field into a string, refusing fields over [`MAX_TEXT_FIELD`].
+pub async fn read_text_field(field: &mut actix_multipart::Field) -> Result<String, ServiceError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_TEXT_FIELD {
+            return Err(ServiceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Field too large",
+                format!(
+                    "The form field '{}' is longer than {} bytes.",
+                    field
+                        .content_disposition()
+                        .get_name()
+                        .unwrap_or_default()
+                        .escape_debug(),
+                    MAX_TEXT_FIELD
+                ),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&bytes).trim().to_string())
+}
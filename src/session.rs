@@ -0,0 +1,685 @@
+//! Signed session cookies, and the middleware that enforces roles with them.
+//!
+//! A session cookie names the user, when the session expires and a random
+//! session ID, signed with HMAC-SHA256 so clients cannot forge or alter it.
+//! Roles are looked up in the [`UserStore`] on every request, so removing a
+//! user or changing their role applies at once.
+//!
+//! API routes also accept an [`ApiToken`] as a `Bearer` authorization header,
+//! which then needs the scope matching the role, and a personal token's owner
+//! still needs the role.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::{time, Cookie, SameSite},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::ErrorUnauthorized,
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::future::{ready, Ready};
+use log::{info, warn};
+use openssl::{
+    hash::MessageDigest,
+    memcmp,
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    sign::Signer,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+
+use crate::{
+    cfg::Cfg,
+    client_auth::Principal,
+    manifest::native::to_hex,
+    route::{routed_path, wants_json},
+    service::ServiceError,
+    tokens::{ApiToken, Scope, TokenStore},
+    users::{Role, User, UserStore},
+};
+
+/// Name of the session cookie.
+pub const SESSION_COOKIE: &str = "fixme_session";
+
+/// Name of the cookie holding the nonce the sign-in form's token is bound to.
+pub const LOGIN_COOKIE: &str = "fixme_login";
+
+/// A verified session cookie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user: String,
+    /// Unix time the session ends at.
+    pub expires: i64,
+    /// Random hex ID, unique to the session.
+    pub id: String,
+}
+
+/// The user a request is made by, as seen by handlers and templates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CurrentUser {
+    pub name: String,
+    pub role: Role,
+    /// The session the user signed in with; `None` when their client certificate
+    /// or an API token names them.
+    #[serde(skip)]
+    pub session: Option<Session>,
+    /// The API token the request was made with.
+    #[serde(skip)]
+    pub token: Option<ApiToken>,
+}
+
+impl FromRequest for CurrentUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CurrentUser>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Not signed in")),
+        )
+    }
+}
+
+/// Signs sessions in and out. Registered as app data only when `users_file` is set.
+#[derive(Clone)]
+pub struct Sessions {
+    users: UserStore,
+    tokens: TokenStore,
+    key: PKey<Private>,
+    ttl: i64,
+    secure: bool,
+    /// Sessions signed out before they expired, by ID, with their expiry.
+    revoked: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl Sessions {
+    pub fn new(
+        users: UserStore,
+        tokens: TokenStore,
+        secret: &[u8],
+        ttl: i64,
+        secure: bool,
+    ) -> io::Result<Self> {
+        Ok(Sessions {
+            users,
+            tokens,
+            key: PKey::hmac(secret).map_err(io::Error::other)?,
+            ttl,
+            secure,
+            revoked: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// The sessions described by `cfg`, or `None` when user accounts are off.
+    pub fn from_cfg(cfg: &Cfg) -> io::Result<Option<Self>> {
+        if cfg.users_file.is_empty() {
+            return Ok(None);
+        }
+        let secret = if cfg.session_secret.is_empty() {
+            info!("session_secret is not set, sessions end when the server restarts");
+            let mut secret = vec![0; 32];
+            rand_bytes(&mut secret).map_err(io::Error::other)?;
+            secret
+        } else {
+            if cfg.session_secret.len() < 32 {
+                warn!("session_secret is shorter than 32 characters");
+            }
+            cfg.session_secret.as_bytes().to_vec()
+        };
+        let secure = !cfg.tls_certificate.is_empty();
+        let users = UserStore::new(&cfg.users_file);
+        let tokens = TokenStore::new(&cfg.tokens_file);
+        Self::new(users, tokens, &secret, cfg.session_ttl as i64, secure).map(Some)
+    }
+
+    pub fn users(&self) -> &UserStore {
+        &self.users
+    }
+
+    pub fn tokens(&self) -> &TokenStore {
+        &self.tokens
+    }
+
+    fn mac(&self, data: &str) -> String {
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &self.key).expect("HMAC-SHA256 is available");
+        signer
+            .update(data.as_bytes())
+            .expect("HMAC accepts any input");
+        to_hex(&signer.sign_to_vec().expect("HMAC signs any input"))
+    }
+
+    /// Starts a session for `user`, returning the cookie that carries it.
+    pub fn start(&self, user: &User) -> io::Result<Cookie<'static>> {
+        let mut id = [0; 16];
+        rand_bytes(&mut id).map_err(io::Error::other)?;
+        let data = format!(
+            "{}:{}:{}",
+            user.name,
+            Utc::now().timestamp() + self.ttl,
+            to_hex(&id)
+        );
+        let value = format!("{}:{}", data, self.mac(&data));
+        Ok(Cookie::build(SESSION_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.ttl))
+            .finish())
+    }
+
+    /// The session in cookie `value`, unless it is forged, expired or signed out.
+    pub fn verify(&self, value: &str) -> Option<Session> {
+        let (data, mac) = value.rsplit_once(':')?;
+        let expected = self.mac(data);
+        if mac.len() != expected.len() || !memcmp::eq(mac.as_bytes(), expected.as_bytes()) {
+            return None;
+        }
+        let mut parts = data.splitn(3, ':');
+        let session = Session {
+            user: parts.next()?.to_string(),
+            expires: parts.next()?.parse().ok()?,
+            id: parts.next()?.to_string(),
+        };
+        let now = Utc::now().timestamp();
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, expires| *expires > now);
+        (session.expires > now && !revoked.contains_key(&session.id)).then_some(session)
+    }
+
+    /// The anti-forgery token of `session`'s forms.
+    pub fn csrf_token(&self, session: &Session) -> String {
+        self.mac(&format!("csrf:{}", session.id))
+    }
+
+    /// A cookie with a fresh nonce to bind the sign-in form's token to.
+    pub fn login_cookie(&self) -> io::Result<Cookie<'static>> {
+        let mut nonce = [0; 16];
+        rand_bytes(&mut nonce).map_err(io::Error::other)?;
+        Ok(Cookie::build(LOGIN_COOKIE, to_hex(&nonce))
+            .path("/login")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .finish())
+    }
+
+    /// The anti-forgery token of the sign-in form shown with `nonce`.
+    pub fn login_csrf_token(&self, nonce: &str) -> String {
+        self.mac(&format!("login-csrf:{}", nonce))
+    }
+
+    /// Signs `session` out, returning a cookie that removes it from the browser.
+    pub fn end(&self, session: &Session) -> Cookie<'static> {
+        self.revoked
+            .lock()
+            .unwrap()
+            .insert(session.id.clone(), session.expires);
+        let mut cookie = Cookie::build(SESSION_COOKIE, "")
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    /// The user `req` is made by: the session's, else the one its client certificate names.
+    async fn current_user(
+        &self,
+        req: &ServiceRequest,
+    ) -> Result<Option<CurrentUser>, ServiceError> {
+        let session = req
+            .cookie(SESSION_COOKIE)
+            .and_then(|cookie| self.verify(cookie.value()));
+        let name = match (&session, req.conn_data::<Principal>()) {
+            (Some(session), _) => session.user.clone(),
+            (None, Some(principal)) => principal.name.clone(),
+            (None, None) => return Ok(None),
+        };
+        let users = self.users.clone();
+        let user = web::block(move || users.find(&name)).await??;
+        Ok(user.map(|user| CurrentUser {
+            name: user.name,
+            role: user.role,
+            session,
+            token: None,
+        }))
+    }
+
+    /// The user API `token` acts as: its owner for personal tokens, else the
+    /// token itself. `None` if the token is unknown, expired or its owner is gone.
+    async fn token_user(&self, token: String) -> Result<Option<CurrentUser>, ServiceError> {
+        let (tokens, users) = (self.tokens.clone(), self.users.clone());
+        let user = web::block(move || -> io::Result<Option<CurrentUser>> {
+            let Some(token) = tokens.authenticate(&token)? else {
+                return Ok(None);
+            };
+            let (name, role) = match &token.owner {
+                Some(owner) => match users.find(owner)? {
+                    Some(user) => (user.name, user.role),
+                    None => return Ok(None),
+                },
+                None => (token.name.clone(), token.role()),
+            };
+            Ok(Some(CurrentUser {
+                name,
+                role,
+                session: None,
+                token: Some(token),
+            }))
+        })
+        .await??;
+        Ok(user)
+    }
+}
+
+/// The token in a `Bearer` authorization header.
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+/// Answers `req` with `error`. On API routes a 401 asks for a bearer token,
+/// with `challenge` saying what was wrong with the one sent.
+fn refuse<B>(
+    req: ServiceRequest,
+    error: ServiceError,
+    challenge: Option<&'static str>,
+) -> ServiceResponse<EitherBody<B>> {
+    let mut response = error.to_response(req.request());
+    let challenge = challenge.or((error.status == StatusCode::UNAUTHORIZED).then_some("Bearer"));
+    let is_api = req.match_info().as_str().starts_with("/api/");
+    if let (true, Some(challenge)) = (is_api, challenge) {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static(challenge),
+        );
+    }
+    req.into_response(response).map_into_right_body()
+}
+
+/// The role a request needs, or `None` for the pages that sign users in and out.
+pub fn required_role(method: &Method, path: &str) -> Option<Role> {
+    if matches!(path, "/login" | "/logout") {
+        return None;
+    }
+    // Everyone manages their own API tokens.
+    let manages_tokens = path == "/tokens" || path.starts_with("/tokens/");
+    if method == Method::GET
+        || method == Method::HEAD
+        || path == "/manifests/inspect"
+        || manages_tokens
+    {
+        return Some(Role::Viewer);
+    }
+    let generates_manifest = path == "/generate-manifest"
+        || (path.starts_with("/api/v1/images/") && path.ends_with("/manifests"));
+    if generates_manifest {
+        Some(Role::ReleaseManager)
+    } else {
+        // Every other change touches the stored images.
+        Some(Role::Uploader)
+    }
+}
+
+/// Middleware letting a request through only if its user has the role it needs.
+///
+/// Pages redirect anonymous visitors to `/login`; everything else answers
+/// `401 Unauthorized`. On API routes a bearer token takes the place of the
+/// session. Does nothing unless user accounts are on.
+pub async fn enforce_roles<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let Some(sessions) = req.app_data::<web::Data<Sessions>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    // Roles follow the path the router decodes, not the one the client sent.
+    let path = match routed_path(req.request()) {
+        Ok(path) => path.to_string(),
+        Err(e) => return Ok(refuse(req, e, None)),
+    };
+    let bearer = if path.starts_with("/api/") {
+        bearer_token(&req)
+    } else {
+        None
+    };
+    let user = match bearer {
+        Some(token) => match sessions.token_user(token).await {
+            Ok(Some(user)) => Some(user),
+            Ok(None) => {
+                let error = ServiceError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "Invalid token",
+                    "The API token is unknown, expired or revoked.",
+                );
+                return Ok(refuse(req, error, Some("Bearer error=\"invalid_token\"")));
+            }
+            Err(e) => return Ok(refuse(req, e, None)),
+        },
+        None => match sessions.current_user(&req).await {
+            Ok(user) => user,
+            Err(e) => return Ok(refuse(req, e, None)),
+        },
+    };
+    if let Some(user) = &user {
+        req.extensions_mut().insert(user.clone());
+    }
+
+    let refusal = match (required_role(req.method(), &path), &user) {
+        (None, _) => None,
+        (
+            Some(role),
+            Some(CurrentUser {
+                token: Some(token), ..
+            }),
+        ) if !token.allows(Scope::for_role(role)) => {
+            let error = ServiceError::new(
+                StatusCode::FORBIDDEN,
+                "Insufficient scope",
+                format!(
+                    "The token '{}' needs the {} scope for this.",
+                    token.name,
+                    Scope::for_role(role)
+                ),
+            );
+            return Ok(refuse(
+                req,
+                error,
+                Some("Bearer error=\"insufficient_scope\""),
+            ));
+        }
+        (Some(role), Some(user)) if user.role >= role => None,
+        (Some(role), Some(user)) => Some(ServiceError::new(
+            StatusCode::FORBIDDEN,
+            "Not allowed",
+            format!(
+                "{} is a {} and this needs a {}.",
+                user.name,
+                user.role.as_str().replace('_', " "),
+                role.as_str().replace('_', " ")
+            ),
+        )),
+        (Some(_), None) => {
+            let is_page = !path.starts_with("/api/") && !wants_json(req.request());
+            if is_page && (req.method() == Method::GET || req.method() == Method::HEAD) {
+                let target = match req.uri().path_and_query() {
+                    Some(target) => target.as_str(),
+                    None => req.path(),
+                };
+                let location = format!(
+                    "/login?next={}",
+                    utf8_percent_encode(target, NON_ALPHANUMERIC)
+                );
+                let response = HttpResponse::SeeOther()
+                    .append_header((header::LOCATION, location))
+                    .finish();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Some(ServiceError::new(
+                StatusCode::UNAUTHORIZED,
+                "Not signed in",
+                "Sign in at /login first.",
+            ))
+        }
+    };
+    match refusal {
+        None => next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body),
+        Some(refusal) => Ok(refuse(req, refusal, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::users::hash_password;
+
+    fn sessions(dir: &std::path::Path) -> Sessions {
+        let store = UserStore::new(dir.join("users.json"));
+        let password = hash_password("secret").unwrap();
+        for (name, role) in [
+            ("vera", Role::Viewer),
+            ("ulla", Role::Uploader),
+            ("rita", Role::ReleaseManager),
+        ] {
+            store
+                .save(User {
+                    name: name.to_string(),
+                    role,
+                    password: password.clone(),
+                })
+                .unwrap();
+        }
+        let tokens = TokenStore::new(dir.join("tokens.json"));
+        Sessions::new(
+            store,
+            tokens,
+            b"0123456789abcdef0123456789abcdef",
+            3600,
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cookies_cannot_be_altered_or_reused_after_logout() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(dir.path());
+        let user = sessions.users().find("vera").unwrap().unwrap();
+        let cookie = sessions.start(&user).unwrap();
+        let session = sessions.verify(cookie.value()).unwrap();
+        assert_eq!("vera", session.user);
+
+        assert_eq!(
+            None,
+            sessions.verify(&cookie.value().replacen("vera", "rita", 1))
+        );
+        let other_key = Sessions {
+            key: PKey::hmac(b"another key").unwrap(),
+            ..sessions.clone()
+        };
+        assert_eq!(None, other_key.verify(cookie.value()));
+        let expired = Sessions {
+            ttl: -1,
+            ..sessions.clone()
+        };
+        let stale = expired.start(&user).unwrap();
+        assert_eq!(None, sessions.verify(stale.value()));
+
+        sessions.end(&session);
+        assert_eq!(None, sessions.verify(cookie.value()));
+    }
+
+    #[actix_web::test]
+    async fn roles_decide_what_users_may_do() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(dir.path());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(sessions.clone()))
+                .wrap(from_fn(enforce_roles))
+                .default_service(web::to(|user: CurrentUser| async move {
+                    HttpResponse::Ok().body(user.name)
+                }))
+                .route("/login", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let cookies: HashMap<String, Cookie> = sessions
+            .users()
+            .users()
+            .unwrap()
+            .iter()
+            .map(|user| (user.name.clone(), sessions.start(user).unwrap()))
+            .collect();
+        let call = |method: Method, path: &str, user: Option<&str>| {
+            let mut request = TestRequest::default().method(method).uri(path);
+            if let Some(user) = user {
+                request = request.cookie(cookies[user].clone());
+            }
+            request.to_request()
+        };
+
+        let response = call_service(&app, call(Method::GET, "/images?x=1", None)).await;
+        assert_eq!(StatusCode::SEE_OTHER, response.status());
+        assert_eq!(
+            "/login?next=%2Fimages%3Fx%3D1",
+            response.headers().get(header::LOCATION).unwrap()
+        );
+        let response = call_service(&app, call(Method::GET, "/login", None)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = call_service(&app, call(Method::GET, "/api/v1/images", None)).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let expectations = [
+            (Method::GET, "/images", "vera", StatusCode::OK),
+            (Method::POST, "/image-upload", "vera", StatusCode::FORBIDDEN),
+            (Method::POST, "/image-upload", "ulla", StatusCode::OK),
+            (
+                Method::DELETE,
+                "/api/v1/images/a.bin",
+                "ulla",
+                StatusCode::OK,
+            ),
+            (
+                Method::POST,
+                "/generate-manifest",
+                "ulla",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Method::POST,
+                "/api/v1/images/a.bin/manifests",
+                "ulla",
+                StatusCode::FORBIDDEN,
+            ),
+            (Method::POST, "/generate-manifest", "rita", StatusCode::OK),
+            (
+                Method::POST,
+                "/generate%2Dmanifest",
+                "ulla",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Method::POST,
+                "/api/v1/images/a.bin/manifest%73",
+                "ulla",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Method::POST,
+                "/api/v1/images/../manifests",
+                "rita",
+                StatusCode::BAD_REQUEST,
+            ),
+            (Method::POST, "/manifests/inspect", "vera", StatusCode::OK),
+        ];
+        for (method, path, user, status) in expectations {
+            let response = call_service(&app, call(method.clone(), path, Some(user))).await;
+            assert_eq!(status, response.status(), "{} {} as {}", method, path, user);
+        }
+
+        sessions.users().remove("rita").unwrap();
+        let response =
+            call_service(&app, call(Method::POST, "/generate-manifest", Some("rita"))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[actix_web::test]
+    async fn tokens_act_within_their_scopes() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(dir.path());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(sessions.clone()))
+                .wrap(from_fn(enforce_roles))
+                .default_service(web::to(|user: CurrentUser| async move {
+                    HttpResponse::Ok().body(user.name)
+                })),
+        )
+        .await;
+        let tokens = sessions.tokens();
+        let (_, ci) = tokens
+            .create(
+                "ci",
+                None,
+                vec![Scope::ImagesRead, Scope::ImagesWrite],
+                None,
+            )
+            .unwrap();
+        // Vera may only view, whatever her token says.
+        let (_, vera) = tokens
+            .create("laptop", Some("vera"), vec![Scope::ImagesWrite], None)
+            .unwrap();
+        let (revoked, stale) = tokens
+            .create("old", None, vec![Scope::ImagesRead], None)
+            .unwrap();
+        tokens.revoke(&revoked.id).unwrap();
+        let call = |method: Method, path: &str, token: &str| {
+            TestRequest::default()
+                .method(method)
+                .uri(path)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let response = call_service(&app, call(Method::GET, "/api/v1/images", &ci)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = call_service(&app, call(Method::POST, "/api/v1/images", &ci)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let expectations = [
+            (
+                Method::POST,
+                "/api/v1/images/a.bin/manifests",
+                &ci,
+                StatusCode::FORBIDDEN,
+            ),
+            (Method::POST, "/api/v1/images", &vera, StatusCode::FORBIDDEN),
+            (Method::GET, "/api/v1/images", &vera, StatusCode::FORBIDDEN),
+            (
+                Method::GET,
+                "/api/v1/images",
+                &stale,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (Method::GET, "/images", &ci, StatusCode::SEE_OTHER),
+        ];
+        for (method, path, token, status) in expectations {
+            let response = call_service(&app, call(method.clone(), path, token)).await;
+            assert_eq!(status, response.status(), "{} {}", method, path);
+        }
+        let response = call_service(&app, call(Method::GET, "/api/v1/jobs", &stale)).await;
+        assert_eq!(
+            "Bearer error=\"invalid_token\"",
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap()
+        );
+    }
+}
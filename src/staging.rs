@@ -0,0 +1,261 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use openssl::sha::Sha256;
+use tempfile::{NamedTempFile, TempPath};
+
+use crate::{cfg::Cfg, manifest::native::to_hex};
+
+/// A file being received, hashed with SHA-256 as it is written.
+///
+/// The file is removed again unless it is handed to a store before the
+/// [`Staged`] it turns into is dropped.
+pub struct StagedUpload {
+    file: File,
+    path: TempPath,
+    hasher: Sha256,
+    size: u64,
+}
+
+/// A completely received file.
+pub struct Staged {
+    pub path: TempPath,
+    /// Lowercase hexadecimal SHA-256 of the contents.
+    pub digest: String,
+    pub size: u64,
+}
+
+impl StagedUpload {
+    /// Creates an empty file in `staging_dir`.
+    pub fn create(staging_dir: impl AsRef<Path>) -> io::Result<Self> {
+        std::fs::create_dir_all(staging_dir.as_ref())?;
+        let (file, path) = NamedTempFile::new_in(staging_dir)?.into_parts();
+        Ok(StagedUpload {
+            file,
+            path,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<Staged> {
+        self.file.flush()?;
+        Ok(Staged {
+            path: self.path,
+            digest: to_hex(&self.hasher.finish()),
+            size: self.size,
+        })
+    }
+}
+
+/// Why an upload was cut off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The file is larger than the per-file limit.
+    File { limit: u64 },
+    /// Storing the file would exceed the total storage limit.
+    Storage { free: u64 },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::File { limit } => {
+                write!(f, "files may be at most {} bytes", limit)
+            }
+            LimitExceeded::Storage { free } => write!(
+                f,
+                "image storage is nearly full, only {} more bytes can be stored",
+                free
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Bytes of image storage in use, and set aside for uploads still under way.
+///
+/// Every way of uploading reserves space here before it stages data, so
+/// uploads received side by side cannot together exceed `max_storage_size`.
+#[derive(Clone, Default)]
+pub struct StorageUsage {
+    inner: Arc<Mutex<Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    /// Bytes taken up by stored images, once they have been counted.
+    stored: Option<u64>,
+    /// Bytes set aside by uploads that are not stored yet.
+    reserved: u64,
+}
+
+impl StorageUsage {
+    /// Bytes taken up by stored images, if they have been counted yet.
+    pub fn stored(&self) -> Option<u64> {
+        self.inner.lock().unwrap().stored
+    }
+
+    /// Records what counting the stored images found.
+    pub fn set_stored(&self, bytes: u64) {
+        self.inner.lock().unwrap().stored = Some(bytes);
+    }
+
+    /// Records that `bytes` more are stored, once the stored images have been counted.
+    pub fn stored_more(&self, bytes: u64) {
+        if let Some(stored) = &mut self.inner.lock().unwrap().stored {
+            *stored += bytes;
+        }
+    }
+
+    /// Records that `bytes` fewer are stored, once the stored images have been counted.
+    pub fn stored_less(&self, bytes: u64) {
+        if let Some(stored) = &mut self.inner.lock().unwrap().stored {
+            *stored = stored.saturating_sub(bytes);
+        }
+    }
+
+    /// Bytes set aside by uploads that are not stored yet.
+    #[cfg(test)]
+    pub fn reserved(&self) -> u64 {
+        self.inner.lock().unwrap().reserved
+    }
+
+    /// Starts setting space aside for an upload, under the limits configured in `cfg`.
+    pub fn reserve(&self, cfg: &Cfg) -> Reservation {
+        Reservation {
+            usage: self.clone(),
+            max_file: cfg.max_upload_size as u64,
+            max_storage: match cfg.max_storage_size {
+                0 => None,
+                max => Some(max as u64),
+            },
+            bytes: 0,
+        }
+    }
+}
+
+/// Space set aside for one upload, given back when dropped.
+///
+/// Drop it once the upload is stored, when its size counts as stored instead.
+pub struct Reservation {
+    usage: StorageUsage,
+    max_file: u64,
+    /// `None` when there is no limit.
+    max_storage: Option<u64>,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Bytes set aside so far.
+    #[cfg(test)]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Sets aside `more` bytes for a file that then holds `file_size` bytes.
+    pub fn take(&mut self, file_size: u64, more: u64) -> Result<(), LimitExceeded> {
+        if file_size > self.max_file {
+            return Err(LimitExceeded::File {
+                limit: self.max_file,
+            });
+        }
+        let mut usage = self.usage.inner.lock().unwrap();
+        if let Some(max) = self.max_storage {
+            let used = usage.stored.unwrap_or(0).saturating_add(usage.reserved);
+            let free = max.saturating_sub(used);
+            if more > free {
+                return Err(LimitExceeded::Storage { free });
+            }
+        }
+        usage.reserved += more;
+        self.bytes += more;
+        Ok(())
+    }
+
+    /// Makes sure at least `size` bytes are set aside, for a file of that size.
+    pub fn cover(&mut self, size: u64) -> Result<(), LimitExceeded> {
+        self.take(size, size.saturating_sub(self.bytes))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.usage.inner.lock().unwrap().reserved -= self.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_apply_to_the_file_and_the_remaining_storage() {
+        let cfg = Cfg {
+            max_upload_size: 100,
+            max_storage_size: 1000,
+            ..Cfg::default()
+        };
+        let usage = StorageUsage::default();
+        usage.set_stored(900);
+        let mut reservation = usage.reserve(&cfg);
+        assert_eq!(
+            Err(LimitExceeded::File { limit: 100 }),
+            reservation.take(101, 1)
+        );
+        assert_eq!(Ok(()), reservation.take(60, 60));
+        assert_eq!(
+            Err(LimitExceeded::Storage { free: 40 }),
+            reservation.take(100, 41)
+        );
+        assert_eq!(Ok(()), reservation.cover(100));
+        assert_eq!(100, reservation.bytes());
+        assert_eq!(
+            Err(LimitExceeded::Storage { free: 0 }),
+            usage.reserve(&cfg).take(1, 1)
+        );
+
+        usage.stored_less(500);
+        let mut other = usage.reserve(&cfg);
+        assert_eq!(Ok(()), other.take(100, 100));
+        assert_eq!(200, usage.reserved());
+        drop(reservation);
+        drop(other);
+        assert_eq!(0, usage.reserved());
+
+        let unlimited = Cfg {
+            max_storage_size: 0,
+            ..cfg
+        };
+        usage.set_stored(u64::MAX);
+        assert_eq!(Ok(()), usage.reserve(&unlimited).take(100, 100));
+    }
+
+    #[test]
+    fn unfinished_uploads_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut upload = StagedUpload::create(dir.path()).unwrap();
+        upload.write(b"firmware").unwrap();
+        assert_eq!(8, upload.size());
+        drop(upload);
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+}
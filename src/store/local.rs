@@ -0,0 +1,218 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use actix_web::{rt, web::Bytes};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{check_name, ByteStream, ImageStore, ObjectInfo, CHUNK_SIZE};
+
+/// Keeps images as plain files in one directory.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        LocalStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        check_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    /// Opens the object called `name` at `start`, refusing ranges that run past its end.
+    async fn open_range(&self, name: &str, start: u64, len: u64) -> io::Result<tokio::fs::File> {
+        let mut file = tokio::fs::File::open(self.path(name)?).await?;
+        let size = file.metadata().await?.len();
+        if start.checked_add(len).is_none_or(|end| end > size) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is shorter than the requested range", name),
+            ));
+        }
+        file.seek(io::SeekFrom::Start(start)).await?;
+        Ok(file)
+    }
+
+    fn info(name: &str, metadata: &std::fs::Metadata) -> ObjectInfo {
+        ObjectInfo {
+            name: name.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageStore for LocalStore {
+    async fn list(&self) -> io::Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            // Nothing has been uploaded yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(objects),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() && !name.starts_with('.') {
+                objects.push(Self::info(&name, &metadata));
+            }
+        }
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(objects)
+    }
+
+    async fn put(&self, name: &str, source: &Path) -> io::Result<ObjectInfo> {
+        let destination = self.path(name)?;
+        tokio::fs::create_dir_all(&self.root).await?;
+        // Renaming fails across filesystems, so fall back to copying.
+        if tokio::fs::rename(source, &destination).await.is_err() {
+            tokio::fs::copy(source, &destination).await?;
+        }
+        let metadata = tokio::fs::metadata(&destination).await?;
+        Ok(Self::info(name, &metadata))
+    }
+
+    async fn put_bytes(&self, name: &str, data: Bytes) -> io::Result<ObjectInfo> {
+        let destination = self.path(name)?;
+        tokio::fs::create_dir_all(&self.root).await?;
+        // Write next to the destination and rename so readers never see half an
+        // object; every writer gets its own hidden temporary file.
+        let root = self.root.clone();
+        let metadata = rt::task::spawn_blocking(move || -> io::Result<std::fs::Metadata> {
+            let mut staged = tempfile::NamedTempFile::new_in(&root)?;
+            staged.write_all(&data)?;
+            staged.persist(&destination).map_err(|e| e.error)?;
+            std::fs::metadata(&destination)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(Self::info(name, &metadata))
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Bytes> {
+        Ok(Bytes::from(tokio::fs::read(self.path(name)?).await?))
+    }
+
+    async fn get_range(&self, name: &str, start: u64, len: u64) -> io::Result<Bytes> {
+        let mut file = self.open_range(name, start, len).await?;
+        let mut buf = vec![0; len as usize];
+        file.read_exact(&mut buf).await?;
+        Ok(Bytes::from(buf))
+    }
+
+    async fn get_stream(&self, name: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        let file = self.open_range(name, start, len).await?;
+        // Blobs are replaced by renaming, so the open file keeps its content.
+        let chunks = futures_util::stream::try_unfold((file, len), |(mut file, left)| async move {
+            if left == 0 {
+                return Ok(None);
+            }
+            let mut buf = vec![0; left.min(CHUNK_SIZE as u64) as usize];
+            file.read_exact(&mut buf).await?;
+            let read = buf.len() as u64;
+            Ok(Some((Bytes::from(buf), (file, left - read))))
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(name)?).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<Option<ObjectInfo>> {
+        match tokio::fs::metadata(self.path(name)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(Self::info(name, &metadata))),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        self.path(name).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn local_store_conformance() {
+        let dir = tempfile::tempdir().unwrap();
+        super::super::tests::conformance(&LocalStore::new(dir.path().join("uploads"))).await;
+    }
+
+    #[actix_web::test]
+    async fn concurrent_writes_to_one_name_do_not_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path());
+        let writes = (0..16u8).map(|i| store.put_bytes("ref", Bytes::from(vec![i; 4096])));
+        for result in futures_util::future::join_all(writes).await {
+            assert_eq!(4096, result.unwrap().size);
+        }
+        let stored = store.get("ref").await.unwrap();
+        assert!(stored.iter().all(|byte| *byte == stored[0]));
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[actix_web::test]
+    async fn ranges_past_the_end_are_refused_without_allocating_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path());
+        store
+            .put_bytes("a.sgi", Bytes::from_static(b"firmware"))
+            .await
+            .unwrap();
+        for (start, len) in [(0, u64::MAX / 2), (4, 5), (u64::MAX, 1)] {
+            assert_eq!(
+                io::ErrorKind::UnexpectedEof,
+                store
+                    .get_range("a.sgi", start, len)
+                    .await
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+        assert_eq!(
+            Bytes::from_static(b"ware"),
+            store.get_range("a.sgi", 4, 4).await.unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn large_objects_are_streamed_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path());
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3 + 5).map(|i| i as u8).collect();
+        store
+            .put_bytes("a.sgi", Bytes::from(data.clone()))
+            .await
+            .unwrap();
+        let chunks: Vec<Bytes> = store
+            .get_stream("a.sgi", 1, data.len() as u64 - 1)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(4, chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        assert_eq!(&data[1..], &chunks.concat()[..]);
+    }
+}
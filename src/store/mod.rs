@@ -0,0 +1,210 @@
+pub mod local;
+pub mod s3;
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, BoxStream};
+use serde::Serialize;
+
+use crate::cfg::Cfg;
+
+/// How many bytes a streamed object is read in at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An object read a chunk at a time, so it never has to fit in memory whole.
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// What a backend knows about one stored object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Where uploaded firmware images live.
+///
+/// Objects are addressed by a flat name without any directory components.
+/// Missing objects are reported as [`io::ErrorKind::NotFound`].
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    /// Lists every stored object.
+    async fn list(&self) -> io::Result<Vec<ObjectInfo>>;
+
+    /// Stores the contents of the local file `source` as `name`, replacing any
+    /// existing object. `source` may be moved rather than copied.
+    async fn put(&self, name: &str, source: &Path) -> io::Result<ObjectInfo>;
+
+    /// Stores `data` as `name`, replacing any existing object.
+    async fn put_bytes(&self, name: &str, data: Bytes) -> io::Result<ObjectInfo>;
+
+    /// Reads a whole object.
+    async fn get(&self, name: &str) -> io::Result<Bytes>;
+
+    /// Reads `len` bytes of an object starting at `start`.
+    ///
+    /// The range must lie within the object. The default reads the whole
+    /// object; backends that can seek should override it.
+    async fn get_range(&self, name: &str, start: u64, len: u64) -> io::Result<Bytes> {
+        let bytes = self.get(name).await?;
+        let end = start
+            .checked_add(len)
+            .filter(|end| *end <= bytes.len() as u64);
+        match end {
+            Some(end) => Ok(bytes.slice(start as usize..end as usize)),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is shorter than the requested range", name),
+            )),
+        }
+    }
+
+    /// Streams `len` bytes of an object starting at `start`.
+    ///
+    /// The range must lie within the object. The default reads the range in
+    /// one piece; backends should override it to send it in chunks.
+    async fn get_stream(&self, name: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        let bytes = self.get_range(name, start, len).await?;
+        Ok(Box::pin(stream::once(async { Ok(bytes) })))
+    }
+
+    /// Removes an object. Removing a missing object is not an error.
+    async fn delete(&self, name: &str) -> io::Result<()>;
+
+    /// Returns `None` when there is no object called `name`.
+    async fn stat(&self, name: &str) -> io::Result<Option<ObjectInfo>>;
+
+    /// Where the object lives on the local filesystem, for backends that keep it there.
+    fn local_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Rejects names that would address something other than a single object.
+pub fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid object name '{}'", name),
+        ));
+    }
+    Ok(())
+}
+
+/// Opens the `namespace` area of the backend selected by the `storage_backend` setting.
+///
+/// Namespaces keep different kinds of objects apart: a subdirectory of
+/// `uploads_dir` for the local backend, a key prefix for S3.
+pub fn open(cfg: &Cfg, namespace: &str) -> io::Result<Arc<dyn ImageStore>> {
+    check_name(namespace)?;
+    match cfg.storage_backend.as_str() {
+        "local" => Ok(Arc::new(local::LocalStore::new(
+            Path::new(&cfg.uploads_dir).join(namespace),
+        ))),
+        "s3" => Ok(Arc::new(s3::S3Store::from_cfg(cfg, namespace)?)),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "unknown storage_backend '{}', expected 'local' or 's3'",
+                other
+            ),
+        )),
+    }
+}
+
+/// Makes an object available as a local file for tools that need a path.
+///
+/// Backends without local files are downloaded into a temporary file, which is
+/// removed when the returned guard is dropped.
+pub async fn materialize(
+    store: &dyn ImageStore,
+    name: &str,
+    staging_dir: &str,
+) -> io::Result<(PathBuf, Option<tempfile::TempPath>)> {
+    if let Some(path) = store.local_path(name) {
+        return Ok((path, None));
+    }
+    let bytes = store.get(name).await?;
+    tokio::fs::create_dir_all(staging_dir).await?;
+    let file = tempfile::NamedTempFile::new_in(staging_dir)?;
+    tokio::fs::write(file.path(), &bytes).await?;
+    let path = file.into_temp_path();
+    Ok((path.to_path_buf(), Some(path)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    /// Exercises every operation of a backend that starts out empty.
+    pub async fn conformance(store: &dyn ImageStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+
+        assert!(store.list().await.unwrap().is_empty());
+        assert_eq!(None, store.stat("a.sgi").await.unwrap());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            store.get("a.sgi").await.unwrap_err().kind()
+        );
+
+        std::fs::write(&source, b"firmware").unwrap();
+        let info = store.put("a.sgi", &source).await.unwrap();
+        assert_eq!("a.sgi", info.name);
+        assert_eq!(8, info.size);
+        assert_eq!(
+            Bytes::from_static(b"firmware"),
+            store.get("a.sgi").await.unwrap()
+        );
+        assert_eq!(Some(8), store.stat("a.sgi").await.unwrap().map(|i| i.size));
+        assert_eq!(
+            Bytes::from_static(b"rmwa"),
+            store.get_range("a.sgi", 2, 4).await.unwrap()
+        );
+
+        let streamed: Vec<Bytes> = store
+            .get_stream("a.sgi", 2, 4)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(&b"rmwa"[..], &streamed.concat()[..]);
+
+        store
+            .put_bytes("a.sgi", Bytes::from_static(b"new firmware"))
+            .await
+            .unwrap();
+        assert_eq!(
+            Bytes::from_static(b"new firmware"),
+            store.get("a.sgi").await.unwrap()
+        );
+        let names: Vec<String> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        assert_eq!(vec!["a.sgi".to_string()], names);
+
+        store.delete("a.sgi").await.unwrap();
+        assert_eq!(None, store.stat("a.sgi").await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+        store.delete("a.sgi").await.unwrap();
+
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            store.put("../a.sgi", &source).await.unwrap_err().kind()
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::{io, path::Path};
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, TryStreamExt};
+use s3::{
+    command::Command,
+    creds::Credentials,
+    error::S3Error,
+    request::{tokio_backend::ReqwestRequest, Request as _},
+    Bucket, Region,
+};
+
+use super::{check_name, ByteStream, ImageStore, ObjectInfo};
+use crate::cfg::Cfg;
+
+/// Keeps images in a bucket of an S3-compatible service such as MinIO.
+pub struct S3Store {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+fn to_io_error(e: S3Error) -> io::Error {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+impl S3Store {
+    pub fn new(bucket: Box<Bucket>, prefix: &str) -> Self {
+        S3Store {
+            bucket,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Connects to the configured bucket, keeping objects under `<s3_prefix><namespace>/`.
+    pub fn from_cfg(cfg: &Cfg, namespace: &str) -> io::Result<Self> {
+        if cfg.s3_bucket.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "s3_bucket must be set when storage_backend is 's3'",
+            ));
+        }
+        let region = if cfg.s3_endpoint.is_empty() {
+            cfg.s3_region
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))?
+        } else {
+            Region::Custom {
+                region: cfg.s3_region.clone(),
+                endpoint: cfg.s3_endpoint.clone(),
+            }
+        };
+        let credentials = Credentials::new(
+            Some(&cfg.s3_access_key)
+                .filter(|key| !key.is_empty())
+                .map(String::as_str),
+            Some(&cfg.s3_secret_key)
+                .filter(|key| !key.is_empty())
+                .map(String::as_str),
+            None,
+            None,
+            None,
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut bucket = Bucket::new(&cfg.s3_bucket, region, credentials).map_err(to_io_error)?;
+        if cfg.s3_path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(S3Store::new(
+            bucket,
+            &format!("{}{}/", cfg.s3_prefix, namespace),
+        ))
+    }
+
+    fn key(&self, name: &str) -> io::Result<String> {
+        check_name(name)?;
+        Ok(format!("{}{}", self.prefix, name))
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    async fn list(&self) -> io::Result<Vec<ObjectInfo>> {
+        let pages = self
+            .bucket
+            .list(self.prefix.clone(), Some("/".to_string()))
+            .await
+            .map_err(to_io_error)?;
+        let mut objects: Vec<ObjectInfo> = pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| {
+                let name = object.key.strip_prefix(&self.prefix)?.to_string();
+                Some(ObjectInfo {
+                    name,
+                    size: object.size,
+                    modified: DateTime::parse_from_rfc3339(&object.last_modified)
+                        .ok()
+                        .map(|modified| modified.with_timezone(&Utc)),
+                })
+            })
+            .filter(|info| !info.name.is_empty())
+            .collect();
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(objects)
+    }
+
+    async fn put(&self, name: &str, source: &Path) -> io::Result<ObjectInfo> {
+        let key = self.key(name)?;
+        let mut file = tokio::fs::File::open(source).await?;
+        self.bucket
+            .put_object_stream(&mut file, &key)
+            .await
+            .map_err(to_io_error)?;
+        self.stat(name)
+            .await?
+            .ok_or_else(|| io::Error::other(format!("object '{}' missing right after upload", key)))
+    }
+
+    async fn put_bytes(&self, name: &str, data: Bytes) -> io::Result<ObjectInfo> {
+        let key = self.key(name)?;
+        self.bucket
+            .put_object(&key, &data)
+            .await
+            .map_err(to_io_error)?;
+        self.stat(name)
+            .await?
+            .ok_or_else(|| io::Error::other(format!("object '{}' missing right after upload", key)))
+    }
+
+    async fn get(&self, name: &str) -> io::Result<Bytes> {
+        let response = self
+            .bucket
+            .get_object(self.key(name)?)
+            .await
+            .map_err(to_io_error)?;
+        Ok(response.into_bytes())
+    }
+
+    async fn get_range(&self, name: &str, start: u64, len: u64) -> io::Result<Bytes> {
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        // S3 ranges include their last byte.
+        let response = self
+            .bucket
+            .get_object_range(self.key(name)?, start, Some(start + len - 1))
+            .await
+            .map_err(to_io_error)?;
+        Ok(response.into_bytes())
+    }
+
+    async fn get_stream(&self, name: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        if len == 0 {
+            return Ok(Box::pin(stream::empty()));
+        }
+        // rust-s3 only streams whole objects, so ask for the range directly.
+        let key = self.key(name)?;
+        let command = Command::GetObjectRange {
+            start,
+            end: Some(start + len - 1),
+        };
+        let response = ReqwestRequest::new(&self.bucket, &key, command)
+            .await
+            .map_err(to_io_error)?
+            .response_data_to_stream()
+            .await
+            .map_err(to_io_error)?;
+        match response.status_code {
+            200..=299 => Ok(Box::pin(response.bytes.map_err(to_io_error))),
+            404 => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no object '{}'", key),
+            )),
+            status => Err(io::Error::other(format!(
+                "reading '{}' failed with status {}",
+                key, status
+            ))),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        // S3 answers 204 either way, but some compatible stores answer 404.
+        match self.bucket.delete_object(self.key(name)?).await {
+            Ok(_) => Ok(()),
+            Err(e) => match to_io_error(e) {
+                e if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<Option<ObjectInfo>> {
+        match self.bucket.head_object(self.key(name)?).await {
+            Ok((head, _)) => Ok(Some(ObjectInfo {
+                name: name.to_string(),
+                size: head.content_length.unwrap_or(0).max(0) as u64,
+                modified: head
+                    .last_modified
+                    .and_then(|modified| DateTime::parse_from_rfc2822(&modified).ok())
+                    .map(|modified| modified.with_timezone(&Utc)),
+            })),
+            Err(e) => match to_io_error(e) {
+                e if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against the MinIO service in `docker-compose.yml`:
+    ///
+    /// ```text
+    /// docker compose up -d minio
+    /// FIXME_TEST_S3_ENDPOINT=http://127.0.0.1:9000 cargo test -- --ignored
+    /// ```
+    #[actix_web::test]
+    #[ignore = "needs an S3-compatible service"]
+    async fn s3_store_conformance() {
+        let endpoint = std::env::var("FIXME_TEST_S3_ENDPOINT")
+            .unwrap_or_else(|_| "http://127.0.0.1:9000".to_string());
+        let cfg = Cfg {
+            storage_backend: "s3".to_string(),
+            s3_endpoint: endpoint,
+            s3_bucket: "fixme-test".to_string(),
+            s3_access_key: "minioadmin".to_string(),
+            s3_secret_key: "minioadmin".to_string(),
+            s3_prefix: format!("{}/", uuid::Uuid::new_v4()),
+            ..Cfg::default()
+        };
+        super::super::tests::conformance(&S3Store::from_cfg(&cfg, "images").unwrap()).await;
+    }
+}
@@ -0,0 +1,70 @@
+//! Throwaway P-256 keys and certificates for tests.
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{
+        extension::{BasicConstraints, SubjectAlternativeName},
+        X509Builder, X509NameBuilder, X509,
+    },
+};
+
+pub struct Identity {
+    pub key: PKey<Private>,
+    pub certificate: X509,
+}
+
+/// Issues a self-signed CA certificate for `cn`.
+pub fn self_signed(cn: &str) -> Identity {
+    issue(cn, None, None)
+}
+
+/// Issues a certificate for `cn` and `dns`, signed by `issuer` or self-signed.
+pub fn issue(cn: &str, dns: Option<&str>, issuer: Option<&Identity>) -> Identity {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Example")
+        .unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder
+        .set_issuer_name(issuer.map_or(&name, |issuer| issuer.certificate.subject_name()))
+        .unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    if issuer.is_none() {
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+    }
+    if let Some(dns) = dns {
+        let san = SubjectAlternativeName::new()
+            .dns(dns)
+            .build(&builder.x509v3_context(issuer.map(|issuer| &*issuer.certificate), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+    }
+    let signer = issuer.map_or(&key, |issuer| &issuer.key);
+    builder.sign(signer, MessageDigest::sha256()).unwrap();
+    Identity {
+        key,
+        certificate: builder.build(),
+    }
+}
@@ -0,0 +1,208 @@
+//! HTTPS for the web server, configured by the `tls_*` settings.
+
+use std::fmt;
+
+use actix_web::{
+    http::{header, Uri},
+    web, HttpRequest, HttpResponse,
+};
+use log::warn;
+use openssl::{
+    error::ErrorStack,
+    pkey::PKey,
+    ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslVerifyMode},
+    x509::X509,
+};
+
+use crate::cfg::Cfg;
+
+/// Why HTTPS could not be set up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsError(pub String);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot serve HTTPS: {}", self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<ErrorStack> for TlsError {
+    fn from(value: ErrorStack) -> Self {
+        TlsError(value.to_string())
+    }
+}
+
+/// Reads every PEM certificate in the file `setting` names.
+fn read_certificates(setting: &str, path: &str) -> Result<Vec<X509>, TlsError> {
+    let cannot_load = |e: &dyn fmt::Display| TlsError(format!("{} '{}': {}", setting, path, e));
+    let pem = std::fs::read(path).map_err(|e| cannot_load(&e))?;
+    let certificates = X509::stack_from_pem(&pem).map_err(|e| cannot_load(&e))?;
+    if certificates.is_empty() {
+        return Err(cannot_load(&"no PEM certificates found"));
+    }
+    Ok(certificates)
+}
+
+/// Builds the TLS acceptor described by `cfg`, or `None` when HTTPS is off.
+///
+/// Fails when the private key does not belong to the certificate, so a
+/// misconfigured server does not start.
+pub fn acceptor(cfg: &Cfg) -> Result<Option<SslAcceptorBuilder>, TlsError> {
+    match (cfg.tls_certificate.is_empty(), cfg.tls_key.is_empty()) {
+        (true, true) => {
+            if !cfg.tls_client_ca.is_empty() || !cfg.client_certificate_scopes.is_empty() {
+                return Err(TlsError(
+                    "client certificates need tls_certificate and tls_key".to_string(),
+                ));
+            }
+            if cfg.http_redirect_port != 0 {
+                warn!("Ignoring http_redirect_port, HTTPS is not configured");
+            }
+            return Ok(None);
+        }
+        (false, false) => {}
+        _ => {
+            return Err(TlsError(
+                "tls_certificate and tls_key must be set together".to_string(),
+            ))
+        }
+    }
+
+    let chain = read_certificates("tls_certificate", &cfg.tls_certificate)?;
+    let key = std::fs::read(&cfg.tls_key)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| PKey::private_key_from_pem(&pem).map_err(|e| e.to_string()))
+        .map_err(|e| TlsError(format!("tls_key '{}': {}", cfg.tls_key, e)))?;
+    let (leaf, intermediates) = chain.split_first().expect("chain is not empty");
+    if !leaf.public_key()?.public_eq(&key) {
+        return Err(TlsError(format!(
+            "tls_key '{}' does not match the first certificate in tls_certificate '{}'",
+            cfg.tls_key, cfg.tls_certificate
+        )));
+    }
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate(leaf)?;
+    for certificate in intermediates {
+        builder.add_extra_chain_cert(certificate.clone())?;
+    }
+    builder.set_private_key(&key)?;
+    builder.check_private_key()?;
+
+    if cfg.tls_client_ca.is_empty() {
+        if !cfg.client_certificate_scopes.is_empty() {
+            return Err(TlsError(
+                "client_certificate_scopes need tls_client_ca to verify certificates".to_string(),
+            ));
+        }
+    } else {
+        for ca in read_certificates("tls_client_ca", &cfg.tls_client_ca)? {
+            builder.cert_store_mut().add_cert(ca.clone())?;
+            builder.add_client_ca(&ca)?;
+        }
+        // Presented certificates must verify; whether one is required is up to the routes.
+        builder.set_verify(SslVerifyMode::PEER);
+    }
+    Ok(Some(builder))
+}
+
+/// The HTTPS URL a plain HTTP request for `uri` on `host` is redirected to.
+pub fn https_location(host: &str, uri: &Uri, https_port: u16) -> String {
+    // Drop the port, minding IPv6 literals such as `[::1]:8080`.
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    }
+}
+
+/// Answers every request on the plain HTTP port with a redirect to HTTPS.
+///
+/// `308 Permanent Redirect` keeps the method and body, so API clients follow it too.
+pub async fn redirect_to_https(req: HttpRequest, cfg: web::Data<Cfg>) -> HttpResponse {
+    let location = https_location(req.connection_info().host(), req.uri(), cfg.port);
+    HttpResponse::PermanentRedirect()
+        .append_header((header::LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::test_certs::{self_signed, Identity};
+
+    fn write(dir: &Path, name: &str, pem: Vec<u8>) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn the_key_must_match_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let Identity { key, certificate } = self_signed("localhost");
+        let other_key = self_signed("other").key;
+        let cfg = Cfg {
+            tls_certificate: write(dir.path(), "cert.pem", certificate.to_pem().unwrap()),
+            tls_key: write(
+                dir.path(),
+                "key.pem",
+                key.private_key_to_pem_pkcs8().unwrap(),
+            ),
+            ..Cfg::default()
+        };
+        assert!(acceptor(&cfg).unwrap().is_some());
+
+        let with_client_ca = Cfg {
+            tls_client_ca: cfg.tls_certificate.clone(),
+            ..cfg.clone()
+        };
+        assert!(acceptor(&with_client_ca).unwrap().is_some());
+
+        let mismatched = Cfg {
+            tls_key: write(
+                dir.path(),
+                "other.pem",
+                other_key.private_key_to_pem_pkcs8().unwrap(),
+            ),
+            ..cfg.clone()
+        };
+        let Err(error) = acceptor(&mismatched) else {
+            panic!("a mismatched key was accepted");
+        };
+        assert!(error.0.contains("does not match"), "{}", error);
+
+        let missing = Cfg {
+            tls_key: String::new(),
+            ..cfg
+        };
+        assert!(acceptor(&missing).is_err());
+        assert!(acceptor(&Cfg::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn redirects_keep_the_host_and_path() {
+        let uri: Uri = "/images?x=1".parse().unwrap();
+        assert_eq!(
+            "https://fixme.example.com:8443/images?x=1",
+            https_location("fixme.example.com:8080", &uri, 8443)
+        );
+        assert_eq!(
+            "https://[::1]/images?x=1",
+            https_location("[::1]:80", &uri, 443)
+        );
+        assert_eq!(
+            "https://[::1]/images?x=1",
+            https_location("[::1]", &uri, 443)
+        );
+    }
+}
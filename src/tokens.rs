@@ -0,0 +1,361 @@
+//! API tokens for clients without a browser session, kept in `tokens_file`.
+//!
+//! A token is shown once when it is created; only its SHA-256 hash is stored.
+//! Tokens are long random strings, so a fast hash is enough to keep a leaked
+//! file from being replayed.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Duration, TimeDelta, Utc};
+use openssl::{memcmp, rand::rand_bytes, sha::sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::{manifest::native::to_hex, users::Role};
+
+/// Prefix of every token, so leaked tokens are easy to search for.
+pub const TOKEN_PREFIX: &str = "fixme_";
+
+/// How stale `last_used` may get before a use of the token writes the file again.
+const LAST_USED_INTERVAL: Duration = Duration::minutes(1);
+
+/// What a token may be used for. Unlike roles, scopes do not include each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Scope {
+    /// Lists and downloads images, manifests and jobs.
+    #[serde(rename = "images:read")]
+    ImagesRead,
+    /// Uploads, converts, compresses, renames and deletes images.
+    #[serde(rename = "images:write")]
+    ImagesWrite,
+    /// Queues manifest jobs.
+    #[serde(rename = "manifests:create")]
+    ManifestsCreate,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [
+        Scope::ImagesRead,
+        Scope::ImagesWrite,
+        Scope::ManifestsCreate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ImagesRead => "images:read",
+            Scope::ImagesWrite => "images:write",
+            Scope::ManifestsCreate => "manifests:create",
+        }
+    }
+
+    /// The scope a request needing `role` needs.
+    pub fn for_role(role: Role) -> Scope {
+        match role {
+            Role::Viewer => Scope::ImagesRead,
+            Role::Uploader => Scope::ImagesWrite,
+            Role::ReleaseManager => Scope::ManifestsCreate,
+        }
+    }
+
+    /// The role a user needs to grant the scope.
+    pub fn role(&self) -> Role {
+        match self {
+            Scope::ImagesRead => Role::Viewer,
+            Scope::ImagesWrite => Role::Uploader,
+            Scope::ManifestsCreate => Role::ReleaseManager,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown scope '{}', expected images:read, images:write or manifests:create",
+                    s
+                )
+            })
+    }
+}
+
+/// Checks that `name` can name a token: 1 to 64 printable characters.
+pub fn validate_token_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name.chars().count() > 64 || name.chars().any(char::is_control) {
+        return Err(format!(
+            "'{}' is not a valid token name: use 1 to 64 printable characters",
+            name.escape_debug()
+        ));
+    }
+    Ok(())
+}
+
+/// When a token made now expires if it lasts `days`; `None` for 0, which never expires.
+pub fn expiry_after_days(days: i64) -> Result<Option<DateTime<Utc>>, String> {
+    if days < 0 {
+        return Err("the expiry must not be in the past".to_string());
+    }
+    if days == 0 {
+        return Ok(None);
+    }
+    TimeDelta::try_days(days)
+        .and_then(|lifetime| Utc::now().checked_add_signed(lifetime))
+        .map(Some)
+        .ok_or_else(|| format!("{} days is too far in the future", days))
+}
+
+/// A stored token, without the token itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Random hex ID, also embedded in the token.
+    pub id: String,
+    /// What the token is for, e.g. `release pipeline`.
+    pub name: String,
+    /// The user a personal token acts as; `None` for service tokens.
+    pub owner: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub created: DateTime<Utc>,
+    /// `None` for tokens that never expire.
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    /// Hex SHA-256 of the token.
+    pub hash: String,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The highest role the scopes need, which service tokens act with.
+    pub fn role(&self) -> Role {
+        self.scopes
+            .iter()
+            .map(Scope::role)
+            .max()
+            .unwrap_or(Role::Viewer)
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&sha256(token.as_bytes()))
+}
+
+/// The ID embedded in `token`, if it looks like one of ours.
+fn token_id(token: &str) -> Option<&str> {
+    let (id, secret) = token.strip_prefix(TOKEN_PREFIX)?.split_once('_')?;
+    (!id.is_empty() && !secret.is_empty()).then_some(id)
+}
+
+/// The tokens as last read, with the modification time of the file they were read from.
+type Cached = Option<(SystemTime, Vec<ApiToken>)>;
+
+/// The tokens in `tokens_file`, a JSON array edited by the `token` subcommand and the `/tokens` page.
+///
+/// The file is read again whenever it changes, so edits apply to a running server.
+#[derive(Clone)]
+pub struct TokenStore {
+    path: PathBuf,
+    cache: Arc<Mutex<Cached>>,
+    /// Held while the file is read, changed and written back.
+    update: Arc<Mutex<()>>,
+}
+
+impl TokenStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        TokenStore {
+            path: path.as_ref().to_path_buf(),
+            cache: Arc::new(Mutex::new(None)),
+            update: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Every token; none when the file does not exist yet. Blocking.
+    pub fn tokens(&self) -> io::Result<Vec<ApiToken>> {
+        let modified = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached, tokens)) = cache.as_ref() {
+            if *cached == modified {
+                return Ok(tokens.clone());
+            }
+        }
+        let tokens: Vec<ApiToken> = serde_json::from_slice(&std::fs::read(&self.path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        *cache = Some((modified, tokens.clone()));
+        Ok(tokens)
+    }
+
+    /// Stores a new token, returning it with the token to hand out. Blocking.
+    pub fn create(
+        &self,
+        name: &str,
+        owner: Option<&str>,
+        scopes: Vec<Scope>,
+        expires: Option<DateTime<Utc>>,
+    ) -> io::Result<(ApiToken, String)> {
+        let (mut id, mut secret) = ([0; 8], [0; 20]);
+        rand_bytes(&mut id).map_err(io::Error::other)?;
+        rand_bytes(&mut secret).map_err(io::Error::other)?;
+        let id = to_hex(&id);
+        let token = format!("{}{}_{}", TOKEN_PREFIX, id, to_hex(&secret));
+        let mut scopes = scopes;
+        scopes.sort();
+        scopes.dedup();
+        let stored = ApiToken {
+            id,
+            name: name.to_string(),
+            owner: owner.map(str::to_string),
+            scopes,
+            created: Utc::now(),
+            expires,
+            last_used: None,
+            hash: hash_token(&token),
+        };
+        let _update = self.update.lock().unwrap();
+        let mut tokens = self.tokens()?;
+        tokens.push(stored.clone());
+        self.write(&tokens)?;
+        Ok((stored, token))
+    }
+
+    /// Removes the token with `id`, returning it if there was one. Blocking.
+    pub fn revoke(&self, id: &str) -> io::Result<Option<ApiToken>> {
+        let _update = self.update.lock().unwrap();
+        let mut tokens = self.tokens()?;
+        let Some(index) = tokens.iter().position(|token| token.id == id) else {
+            return Ok(None);
+        };
+        let revoked = tokens.remove(index);
+        self.write(&tokens)?;
+        Ok(Some(revoked))
+    }
+
+    /// The stored token `token` was made as, unless it is unknown or expired,
+    /// recording that it was used. Blocking.
+    pub fn authenticate(&self, token: &str) -> io::Result<Option<ApiToken>> {
+        let Some(id) = token_id(token) else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let hash = hash_token(token);
+        let matches = |stored: &ApiToken| {
+            stored.id == id
+                && stored.hash.len() == hash.len()
+                && memcmp::eq(stored.hash.as_bytes(), hash.as_bytes())
+        };
+        let Some(found) = self.tokens()?.into_iter().find(matches) else {
+            return Ok(None);
+        };
+        if found.is_expired(now) {
+            return Ok(None);
+        }
+        let stale = found
+            .last_used
+            .is_none_or(|last_used| now - last_used >= LAST_USED_INTERVAL);
+        if !stale {
+            return Ok(Some(found));
+        }
+        let _update = self.update.lock().unwrap();
+        let mut tokens = self.tokens()?;
+        let Some(stored) = tokens.iter_mut().find(|stored| matches(stored)) else {
+            // Revoked meanwhile.
+            return Ok(None);
+        };
+        stored.last_used = Some(now);
+        let used = stored.clone();
+        self.write(&tokens)?;
+        Ok(Some(used))
+    }
+
+    fn write(&self, tokens: &[ApiToken]) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::create_dir_all(dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, tokens).map_err(io::Error::other)?;
+        file.write_all(b"\n")?;
+        file.persist(&self.path).map_err(|e| e.error)?;
+        *self.cache.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_stored_hashed_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path().join("tokens.json"));
+        let (created, token) = store
+            .create(
+                "ci",
+                Some("alice"),
+                vec![Scope::ImagesWrite, Scope::ImagesRead],
+                None,
+            )
+            .unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(vec![Scope::ImagesRead, Scope::ImagesWrite], created.scopes);
+        let file = std::fs::read_to_string(dir.path().join("tokens.json")).unwrap();
+        assert!(!file.contains(&token));
+
+        let used = store.authenticate(&token).unwrap().unwrap();
+        assert_eq!(created.id, used.id);
+        assert!(used.last_used.is_some());
+        assert_eq!(None, store.authenticate(&format!("{}0", token)).unwrap());
+        assert_eq!(None, store.authenticate("fixme_").unwrap());
+
+        let (_, expired) = store
+            .create("old", None, vec![Scope::ImagesRead], Some(Utc::now()))
+            .unwrap();
+        assert_eq!(None, store.authenticate(&expired).unwrap());
+
+        assert!(store.revoke(&created.id).unwrap().is_some());
+        assert_eq!(None, store.authenticate(&token).unwrap());
+        assert!(store.revoke(&created.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn scopes_map_to_roles() {
+        assert_eq!(Ok(Scope::ManifestsCreate), "manifests:create".parse());
+        assert!("images:delete".parse::<Scope>().is_err());
+        for role in [Role::Viewer, Role::Uploader, Role::ReleaseManager] {
+            assert_eq!(role, Scope::for_role(role).role());
+        }
+        assert!(validate_token_name("release pipeline").is_ok());
+        assert!(validate_token_name(" ").is_err());
+        assert_eq!(Ok(None), expiry_after_days(0));
+        assert!(expiry_after_days(90).unwrap().is_some());
+        assert!(expiry_after_days(-1).is_err());
+        assert!(expiry_after_days(i64::MAX).is_err());
+        assert!(expiry_after_days(200_000_000).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    catalog::ImageDetails,
+    cfg::Cfg,
+    staging::{LimitExceeded, Reservation, StagedUpload},
+};
+
+pub type SessionId = Uuid;
+
+/// A resumable upload in progress.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UploadSession {
+    #[schema(value_type = Uuid)]
+    pub id: SessionId,
+    /// Name the image is stored under once the upload is finalized.
+    pub filename: String,
+    /// Total size announced when the session was created, if any.
+    pub length: Option<u64>,
+    /// Metadata recorded with the image once it is stored.
+    #[serde(flatten)]
+    pub details: ImageDetails,
+    /// Bytes received and written so far. The next chunk must start here.
+    pub offset: u64,
+    pub created: DateTime<Utc>,
+    /// When the session last received data. Sessions idle for longer than the
+    /// TTL are discarded.
+    pub updated: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    /// The user, or client address, that opened the session.
+    #[serde(skip)]
+    pub owner: String,
+}
+
+struct Entry {
+    session: UploadSession,
+    /// `None` while a request is writing to the file.
+    file: Option<SessionFile>,
+}
+
+/// The bytes a session received and the storage set aside for them: its
+/// announced length, or as much as it holds.
+struct SessionFile {
+    upload: StagedUpload,
+    reservation: Reservation,
+}
+
+/// Why a session could not be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    NotFound,
+    /// Another request is currently writing to the session.
+    Busy,
+    /// The chunk does not start where the previous one ended.
+    OffsetMismatch {
+        expected: u64,
+    },
+    /// The owner already has as many sessions open as they may.
+    TooMany {
+        limit: usize,
+    },
+    /// The announced length does not fit the upload limits.
+    TooLarge(LimitExceeded),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::NotFound => {
+                write!(f, "there is no such upload session, it may have expired")
+            }
+            SessionError::Busy => write!(f, "another request is writing to this upload session"),
+            SessionError::OffsetMismatch { expected } => {
+                write!(f, "the upload continues at offset {}", expected)
+            }
+            SessionError::TooMany { limit } => write!(
+                f,
+                "at most {} unfinished uploads may be open at once, finish or cancel one first",
+                limit
+            ),
+            SessionError::TooLarge(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Resumable uploads that have not been finalized yet.
+///
+/// Received bytes live in the staging directory and are hashed as they arrive,
+/// so a session only exists for as long as the server runs. Sessions that see
+/// no data for `upload_session_ttl` seconds are removed along with their file.
+#[derive(Clone)]
+pub struct UploadSessions {
+    sessions: Arc<Mutex<HashMap<SessionId, Entry>>>,
+    ttl: chrono::Duration,
+    /// Sessions one owner may have open; 0 is unlimited.
+    max_per_owner: usize,
+}
+
+impl UploadSessions {
+    /// Refuses an `upload_session_ttl` too long to compute expiry times with.
+    pub fn new(cfg: &Cfg) -> io::Result<Self> {
+        let ttl = i64::try_from(cfg.upload_session_ttl)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .filter(|ttl| Utc::now().checked_add_signed(*ttl).is_some())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "upload_session_ttl of {} seconds is too long",
+                        cfg.upload_session_ttl
+                    ),
+                )
+            })?;
+        Ok(UploadSessions {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            max_per_owner: cfg.max_upload_sessions,
+        })
+    }
+
+    /// Creates the sessions and spawns a task on the current actix runtime that expires them.
+    pub fn start(cfg: &Cfg) -> io::Result<Self> {
+        let sessions = UploadSessions::new(cfg)?;
+        let reaper = sessions.clone();
+        let period = Duration::from_secs((cfg.upload_session_ttl as u64).clamp(1, 60));
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(period);
+            loop {
+                interval.tick().await;
+                reaper.expire(Utc::now());
+            }
+        });
+        Ok(sessions)
+    }
+
+    /// Opens a session for `owner` that stores its data in `upload`.
+    ///
+    /// `reservation` sets aside the announced length for as long as the
+    /// session is open, and grows with the data received beyond it.
+    pub fn create(
+        &self,
+        filename: &str,
+        length: Option<u64>,
+        details: ImageDetails,
+        owner: &str,
+        upload: StagedUpload,
+        mut reservation: Reservation,
+    ) -> Result<UploadSession, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let owned = sessions
+            .values()
+            .filter(|entry| entry.session.owner == owner)
+            .count();
+        if self.max_per_owner > 0 && owned >= self.max_per_owner {
+            return Err(SessionError::TooMany {
+                limit: self.max_per_owner,
+            });
+        }
+        reservation
+            .cover(length.unwrap_or(0))
+            .map_err(SessionError::TooLarge)?;
+        let now = Utc::now();
+        let session = UploadSession {
+            id: Uuid::new_v4(),
+            filename: filename.to_string(),
+            length,
+            details,
+            offset: 0,
+            created: now,
+            updated: now,
+            expires: now + self.ttl,
+            owner: owner.to_string(),
+        };
+        sessions.insert(
+            session.id,
+            Entry {
+                session: session.clone(),
+                file: Some(SessionFile {
+                    upload,
+                    reservation,
+                }),
+            },
+        );
+        Ok(session)
+    }
+
+    /// Finds the session `owner` opened with `id`; others' sessions are not found.
+    pub fn get(&self, id: &SessionId, owner: &str) -> Option<UploadSession> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|entry| entry.session.owner == owner)
+            .map(|entry| entry.session.clone())
+    }
+
+    /// Takes the file of `owner`'s session for writing from `offset`.
+    ///
+    /// The file goes back to the session when the returned writer is released
+    /// or dropped, so it survives requests that are cancelled mid-write.
+    pub fn acquire(
+        &self,
+        id: &SessionId,
+        owner: &str,
+        offset: u64,
+    ) -> Result<(UploadSession, SessionWriter), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions
+            .get_mut(id)
+            .filter(|entry| entry.session.owner == owner)
+            .ok_or(SessionError::NotFound)?;
+        if entry.session.offset != offset {
+            return Err(SessionError::OffsetMismatch {
+                expected: entry.session.offset,
+            });
+        }
+        let file = entry.file.take().ok_or(SessionError::Busy)?;
+        let writer = SessionWriter {
+            sessions: self.clone(),
+            id: *id,
+            file: Some(file),
+            torn: false,
+        };
+        Ok((entry.session.clone(), writer))
+    }
+
+    /// Returns a file taken with [`UploadSessions::acquire`], recording how much has been written.
+    fn restore(&self, id: &SessionId, file: SessionFile) -> Option<UploadSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(id)?;
+        let now = Utc::now();
+        entry.session.offset = file.upload.size();
+        entry.session.updated = now;
+        entry.session.expires = now + self.ttl;
+        entry.file = Some(file);
+        Some(entry.session.clone())
+    }
+
+    /// Ends `owner`'s session, returning its file unless a request is still writing to it.
+    ///
+    /// The returned reservation covers the file until it is stored.
+    pub fn remove(
+        &self,
+        id: &SessionId,
+        owner: &str,
+    ) -> Result<(UploadSession, StagedUpload, Reservation), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions
+            .get(id)
+            .filter(|entry| entry.session.owner == owner)
+            .ok_or(SessionError::NotFound)?;
+        if entry.file.is_none() {
+            return Err(SessionError::Busy);
+        }
+        let entry = sessions.remove(id).unwrap();
+        let file = entry.file.unwrap();
+        Ok((entry.session, file.upload, file.reservation))
+    }
+
+    /// Forgets a session whose file was lost while a request was writing to it.
+    fn discard(&self, id: &SessionId) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// Drops idle sessions that expired before `now`, deleting their files.
+    fn expire(&self, now: DateTime<Utc>) {
+        self.sessions.lock().unwrap().retain(|id, entry| {
+            let keep = entry.file.is_none() || entry.session.expires > now;
+            if !keep {
+                debug!("Upload session {} expired", id);
+            }
+            keep
+        });
+    }
+}
+
+/// A session's file, taken for writing with [`UploadSessions::acquire`].
+///
+/// Dropping the writer hands the file back like [`SessionWriter::release`],
+/// unless a write was cut off part way, which leaves the file out of step
+/// with its digest; the session is then discarded.
+pub struct SessionWriter {
+    sessions: UploadSessions,
+    id: SessionId,
+    file: Option<SessionFile>,
+    /// Set while a write is under way, and left set if it does not complete.
+    torn: bool,
+}
+
+impl SessionWriter {
+    /// Bytes in the file, including earlier requests'.
+    pub fn size(&self) -> u64 {
+        self.file.as_ref().map_or(0, |file| file.upload.size())
+    }
+
+    /// Sets storage aside for the file to grow to `size` bytes, unless that exceeds a limit.
+    pub fn reserve(&mut self, size: u64) -> Result<(), LimitExceeded> {
+        self.file
+            .as_mut()
+            .expect("the file is held until release")
+            .reservation
+            .cover(size)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let file = self.file.as_mut().expect("the file is held until release");
+        self.torn = true;
+        file.upload.write(data)?;
+        self.torn = false;
+        Ok(())
+    }
+
+    /// Hands the file back, returning the session as it now stands.
+    pub fn release(mut self) -> Option<UploadSession> {
+        self.give_back()
+    }
+
+    fn give_back(&mut self) -> Option<UploadSession> {
+        let file = self.file.take()?;
+        if self.torn {
+            debug!("Upload session {} was interrupted mid-write", self.id);
+            self.sessions.discard(&self.id);
+            return None;
+        }
+        self.sessions.restore(&self.id, file)
+    }
+}
+
+impl Drop for SessionWriter {
+    fn drop(&mut self) {
+        self.give_back();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::StorageUsage;
+
+    fn sessions(cfg: Cfg) -> UploadSessions {
+        UploadSessions::new(&Cfg {
+            upload_session_ttl: 60,
+            ..cfg
+        })
+        .unwrap()
+    }
+
+    fn create(
+        sessions: &UploadSessions,
+        staging_dir: &std::path::Path,
+        length: Option<u64>,
+        owner: &str,
+    ) -> Result<UploadSession, SessionError> {
+        create_in(
+            sessions,
+            &StorageUsage::default(),
+            staging_dir,
+            length,
+            owner,
+        )
+    }
+
+    fn create_in(
+        sessions: &UploadSessions,
+        usage: &StorageUsage,
+        staging_dir: &std::path::Path,
+        length: Option<u64>,
+        owner: &str,
+    ) -> Result<UploadSession, SessionError> {
+        let reservation = usage.reserve(&Cfg {
+            max_upload_size: 100,
+            max_storage_size: 1000,
+            ..Cfg::default()
+        });
+        let upload = StagedUpload::create(staging_dir).unwrap();
+        sessions.create(
+            "a.sgi",
+            length,
+            ImageDetails::default(),
+            owner,
+            upload,
+            reservation,
+        )
+    }
+
+    #[test]
+    fn sessions_resume_at_the_committed_offset_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(Cfg::default());
+        let id = create(&sessions, dir.path(), Some(8), "ulla").unwrap().id;
+
+        let (_, mut writer) = sessions.acquire(&id, "ulla", 0).unwrap();
+        assert_eq!(
+            Err(SessionError::Busy),
+            sessions.acquire(&id, "ulla", 0).map(|_| ())
+        );
+        writer.write(b"firm").unwrap();
+        assert_eq!(4, writer.release().unwrap().offset);
+
+        assert_eq!(
+            Err(SessionError::OffsetMismatch { expected: 4 }),
+            sessions.acquire(&id, "ulla", 0).map(|_| ())
+        );
+        let (_, mut writer) = sessions.acquire(&id, "ulla", 4).unwrap();
+        writer.write(b"ware").unwrap();
+        writer.release();
+
+        sessions.expire(Utc::now());
+        assert_eq!(
+            Some(8),
+            sessions.get(&id, "ulla").map(|session| session.offset)
+        );
+        sessions.expire(Utc::now() + chrono::Duration::seconds(61));
+        assert!(sessions.get(&id, "ulla").is_none());
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn dropped_writers_hand_the_file_back_unless_cut_off_mid_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(Cfg::default());
+        let id = create(&sessions, dir.path(), None, "ulla").unwrap().id;
+
+        let (_, mut writer) = sessions.acquire(&id, "ulla", 0).unwrap();
+        writer.write(b"firm").unwrap();
+        drop(writer);
+        let (_, writer) = sessions.acquire(&id, "ulla", 4).unwrap();
+        drop(writer);
+        assert!(sessions.remove(&id, "ulla").is_ok());
+
+        let id = create(&sessions, dir.path(), None, "ulla").unwrap().id;
+        let (_, mut writer) = sessions.acquire(&id, "ulla", 0).unwrap();
+        writer.torn = true;
+        drop(writer);
+        assert!(sessions.get(&id, "ulla").is_none());
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn owners_may_only_open_so_many_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(Cfg {
+            max_upload_sessions: 2,
+            ..Cfg::default()
+        });
+        let first = create(&sessions, dir.path(), None, "ulla").unwrap();
+        create(&sessions, dir.path(), None, "ulla").unwrap();
+        assert_eq!(
+            Err(SessionError::TooMany { limit: 2 }),
+            create(&sessions, dir.path(), None, "ulla").map(|_| ())
+        );
+        create(&sessions, dir.path(), None, "rita").unwrap();
+
+        sessions.remove(&first.id, "ulla").unwrap();
+        create(&sessions, dir.path(), None, "ulla").unwrap();
+    }
+
+    #[test]
+    fn open_sessions_reserve_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(Cfg {
+            max_upload_sessions: 0,
+            ..Cfg::default()
+        });
+        let usage = StorageUsage::default();
+        let first = create_in(&sessions, &usage, dir.path(), Some(100), "ulla").unwrap();
+        for _ in 0..8 {
+            create_in(&sessions, &usage, dir.path(), Some(100), "ulla").unwrap();
+        }
+        let unannounced = create_in(&sessions, &usage, dir.path(), None, "ulla").unwrap();
+
+        let (_, mut writer) = sessions.acquire(&unannounced.id, "ulla", 0).unwrap();
+        writer.reserve(60).unwrap();
+        writer.write(&[0; 60]).unwrap();
+        writer.release();
+        assert_eq!(960, usage.reserved());
+        assert_eq!(
+            Err(SessionError::TooLarge(LimitExceeded::Storage { free: 40 })),
+            create_in(&sessions, &usage, dir.path(), Some(41), "ulla").map(|_| ())
+        );
+
+        let (_, _, reservation) = sessions.remove(&first.id, "ulla").unwrap();
+        assert_eq!(100, reservation.bytes());
+        drop(reservation);
+        sessions.expire(Utc::now() + chrono::Duration::seconds(61));
+        assert_eq!(0, usage.reserved());
+    }
+
+    #[test]
+    fn sessions_are_only_found_by_their_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = sessions(Cfg::default());
+        let id = create(&sessions, dir.path(), None, "ulla").unwrap().id;
+
+        assert!(sessions.get(&id, "rita").is_none());
+        assert_eq!(
+            Err(SessionError::NotFound),
+            sessions.acquire(&id, "rita", 0).map(|_| ())
+        );
+        assert_eq!(
+            Err(SessionError::NotFound),
+            sessions.remove(&id, "rita").map(|_| ())
+        );
+        assert!(sessions.get(&id, "ulla").is_some());
+    }
+
+    #[test]
+    fn overlong_ttls_are_refused() {
+        for ttl in [usize::MAX, i64::MAX as usize, 1 << 50] {
+            let cfg = Cfg {
+                upload_session_ttl: ttl,
+                ..Cfg::default()
+            };
+            assert!(UploadSessions::new(&cfg).is_err(), "{} seconds", ttl);
+        }
+    }
+}
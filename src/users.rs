@@ -0,0 +1,268 @@
+//! Local user accounts and their roles, kept in `users_file`.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use openssl::{hash::MessageDigest, memcmp, pkcs5::pbkdf2_hmac, rand::rand_bytes};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::native::to_hex;
+
+/// What a user may do. Each role includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Browses images, manifests and jobs.
+    Viewer,
+    /// Also uploads, converts, renames and deletes images.
+    Uploader,
+    /// Also generates manifests.
+    ReleaseManager,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Uploader => "uploader",
+            Role::ReleaseManager => "release_manager",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "uploader" => Ok(Role::Uploader),
+            "release_manager" => Ok(Role::ReleaseManager),
+            _ => Err(format!(
+                "unknown role '{}', expected viewer, uploader or release_manager",
+                s
+            )),
+        }
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 rounds for new password hashes.
+const PBKDF2_ITERATIONS: usize = 600_000;
+
+/// Hashes `password` with a random salt, as `pbkdf2-sha256$<iterations>$<salt>$<hash>`.
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let mut salt = [0; 16];
+    rand_bytes(&mut salt).map_err(io::Error::other)?;
+    let salt = to_hex(&salt);
+    let hash = pbkdf2(password, &salt, PBKDF2_ITERATIONS)?;
+    Ok(format!(
+        "pbkdf2-sha256${}${}${}",
+        PBKDF2_ITERATIONS, salt, hash
+    ))
+}
+
+/// Whether `password` is the one `hash` was made from.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(expected), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let Ok(iterations) = iterations.parse() else {
+        return false;
+    };
+    match pbkdf2(password, salt, iterations) {
+        Ok(actual) => {
+            actual.len() == expected.len() && memcmp::eq(actual.as_bytes(), expected.as_bytes())
+        }
+        Err(_) => false,
+    }
+}
+
+fn pbkdf2(password: &str, salt: &str, iterations: usize) -> io::Result<String> {
+    let mut hash = [0; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt.as_bytes(),
+        iterations,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .map_err(io::Error::other)?;
+    Ok(to_hex(&hash))
+}
+
+/// Checks that `name` can name a user: 1 to 64 ASCII letters, digits, `.`, `_`, `@` or `-`.
+pub fn validate_user_name(name: &str) -> Result<(), String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '@' | '-');
+    if name.is_empty() || name.len() > 64 || !name.chars().all(allowed) {
+        return Err(format!(
+            "'{}' is not a valid user name: use 1 to 64 letters, digits, '.', '_', '@' or '-'",
+            name.escape_debug()
+        ));
+    }
+    Ok(())
+}
+
+/// A local account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Produced by [`hash_password`].
+    pub password: String,
+}
+
+/// The users as last read, with the modification time of the file they were read from.
+type Cached = Option<(SystemTime, Vec<User>)>;
+
+/// The users in `users_file`, a JSON array edited by the `user` subcommand.
+///
+/// The file is read again whenever it changes, so edits apply to a running server.
+#[derive(Clone)]
+pub struct UserStore {
+    path: PathBuf,
+    cache: Arc<Mutex<Cached>>,
+}
+
+impl UserStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        UserStore {
+            path: path.as_ref().to_path_buf(),
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Every user; none when the file does not exist yet. Blocking.
+    pub fn users(&self) -> io::Result<Vec<User>> {
+        let modified = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached, users)) = cache.as_ref() {
+            if *cached == modified {
+                return Ok(users.clone());
+            }
+        }
+        let users: Vec<User> = serde_json::from_slice(&std::fs::read(&self.path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        *cache = Some((modified, users.clone()));
+        Ok(users)
+    }
+
+    pub fn find(&self, name: &str) -> io::Result<Option<User>> {
+        Ok(self.users()?.into_iter().find(|user| user.name == name))
+    }
+
+    /// The user called `name`, if `password` is theirs. Blocking.
+    pub fn authenticate(&self, name: &str, password: &str) -> io::Result<Option<User>> {
+        Ok(self
+            .find(name)?
+            .filter(|user| verify_password(password, &user.password)))
+    }
+
+    /// Adds `user`, replacing any user of the same name. Blocking.
+    pub fn save(&self, user: User) -> io::Result<()> {
+        let mut users = self.users()?;
+        match users.iter_mut().find(|existing| existing.name == user.name) {
+            Some(existing) => *existing = user,
+            None => users.push(user),
+        }
+        self.write(&users)
+    }
+
+    /// Removes the user called `name`, returning whether there was one. Blocking.
+    pub fn remove(&self, name: &str) -> io::Result<bool> {
+        let mut users = self.users()?;
+        let count = users.len();
+        users.retain(|user| user.name != name);
+        if users.len() == count {
+            return Ok(false);
+        }
+        self.write(&users)?;
+        Ok(true)
+    }
+
+    fn write(&self, users: &[User]) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::create_dir_all(dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, users).map_err(io::Error::other)?;
+        file.write_all(b"\n")?;
+        file.persist(&self.path).map_err(|e| e.error)?;
+        *self.cache.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_against_their_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("pbkdf2-sha256$600000$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "plaintext"));
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+
+    #[test]
+    fn users_are_saved_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path().join("users.json"));
+        assert!(store.users().unwrap().is_empty());
+        let password = hash_password("secret").unwrap();
+        let alice = User {
+            name: "alice".to_string(),
+            role: Role::Viewer,
+            password,
+        };
+        store.save(alice.clone()).unwrap();
+        store
+            .save(User {
+                role: Role::ReleaseManager,
+                ..alice.clone()
+            })
+            .unwrap();
+        let found = store.authenticate("alice", "secret").unwrap().unwrap();
+        assert_eq!(Role::ReleaseManager, found.role);
+        assert_eq!(None, store.authenticate("alice", "wrong").unwrap());
+        assert!(store.remove("alice").unwrap());
+        assert!(!store.remove("alice").unwrap());
+        assert_eq!(None, store.find("alice").unwrap());
+    }
+
+    #[test]
+    fn roles_include_the_ones_before_them() {
+        assert!(Role::ReleaseManager > Role::Uploader);
+        assert!(Role::Uploader > Role::Viewer);
+        assert_eq!(Ok(Role::ReleaseManager), "release-manager".parse());
+        assert!(validate_user_name("ci@example.com").is_ok());
+        assert!(validate_user_name("a:b").is_err());
+    }
+}